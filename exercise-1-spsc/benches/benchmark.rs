@@ -1,70 +1,395 @@
 #![allow(dead_code)]
 
-use std::sync::mpsc;
-use std::thread;
+use std::hint::black_box;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use criterion::{Criterion, BenchmarkId, criterion_group, criterion_main};
+use criterion::{Criterion, BenchmarkId, Throughput, criterion_group, criterion_main};
+use spsc::sync::Barrier;
+use spsc::WaitStrategy;
 
-fn spsc(count: usize) -> usize {
-	let (px, cx) = spsc::channel();
+// message counts of the throughput benchmark, as powers of two
+const THROUGHPUT_EXPONENTS: RangeInclusive<u32> = 8 ..= 12;
+
+// buffer capacities of the payload matrix, 64 to 65536
+const CAPACITIES: [usize; 6] = [1 << 6, 1 << 8, 1 << 10, 1 << 12, 1 << 14, 1 << 16];
+
+// a cache line worth of payload
+#[derive(Clone, Copy)]
+struct Line([u64; 8]);
+
+// A producer and a consumer thread that live as long as the benchmark, so
+// neither thread::spawn nor a new thread warming up is measured. Each
+// iteration, the start barrier lets both go, the producer sends count
+// messages, the consumer receives as many, and both meet the measuring
+// thread again at the end barrier. The channel stays the same throughout.
+struct Workers {
+	shared: Arc<WorkerState>,
+	threads: Vec<JoinHandle<()>>,
+}
+
+struct WorkerState {
+	count: AtomicUsize,
+	stop: AtomicBool,
+	// the two workers and the measuring thread
+	start: Barrier,
+	end: Barrier,
+}
+
+impl Workers {
+	fn spawn(
+		mut send: impl FnMut(usize) + Send + 'static,
+		mut recv: impl FnMut() -> usize + Send + 'static,
+	) -> Self {
+		let shared = Arc::new(WorkerState {
+			count: AtomicUsize::new(0),
+			stop: AtomicBool::new(false),
+			start: Barrier::new(3),
+			end: Barrier::new(3),
+		});
+		
+		let state = shared.clone();
+		let producer = thread::spawn(move || Self::run(&state, |count| {
+			for i in 0 .. count {
+				send(i);
+			}
+		}));
+		
+		let state = shared.clone();
+		let consumer = thread::spawn(move || Self::run(&state, |count| {
+			let mut sum = 0usize;
+			for _ in 0 .. count {
+				sum += recv();
+			}
+			black_box(sum);
+		}));
+		
+		Workers { shared, threads: vec![producer, consumer] }
+	}
 	
-	thread::spawn(move || {
-		for i in 0 .. count {
-			px.send(i).unwrap();
+	fn run(state: &WorkerState, mut work: impl FnMut(usize)) {
+		loop {
+			state.start.wait();
+			if state.stop.load(Ordering::Relaxed) {
+				return;
+			}
+			work(state.count.load(Ordering::Relaxed));
+			state.end.wait();
 		}
-	});
+	}
 	
-	thread::spawn(move || {
-		let mut sum = 0usize;
+	// Time `iters` iterations of `count` messages each
+	fn measure(&self, count: usize, iters: u64) -> Duration {
+		// the start barrier publishes it to the workers
+		self.shared.count.store(count, Ordering::Relaxed);
 		
+		let start = Instant::now();
+		for _ in 0 .. iters {
+			self.shared.start.wait();
+			self.shared.end.wait();
+		}
+		start.elapsed()
+	}
+}
+
+impl Drop for Workers {
+	fn drop(&mut self) {
+		self.shared.stop.store(true, Ordering::Relaxed);
+		self.shared.start.wait();
+		for thread in self.threads.drain(..) {
+			thread.join().unwrap();
+		}
+	}
+}
+
+fn spsc_workers() -> Workers {
+	let (mut px, mut cx) = spsc::channel();
+	Workers::spawn(move |i| px.send(i).unwrap(), move || cx.recv().unwrap())
+}
+
+fn mpsc_workers() -> Workers {
+	let (sx, rx) = mpsc::channel();
+	Workers::spawn(move |i| sx.send(i).unwrap(), move || rx.recv().unwrap())
+}
+
+fn spsc_vs_mpsc(c: &mut Criterion) {
+	let mut group = c.benchmark_group("spsc vs mpsc");
+	let spsc_threads = spsc_workers();
+	let mpsc_threads = mpsc_workers();
+	
+	for ref i in THROUGHPUT_EXPONENTS.map(|n| 1usize << n) {
+		group.throughput(Throughput::Elements(*i as u64));
+		group.bench_with_input(
+			BenchmarkId::new("spsc", i),
+			i,
+			|b, &i| b.iter_custom(|iters| spsc_threads.measure(i, iters))
+		);
+		group.bench_with_input(
+			BenchmarkId::new("mpsc", i),
+			i,
+			|b, &i| b.iter_custom(|iters| mpsc_threads.measure(i, iters))
+		);
+	}
+	
+	group.finish();
+}
+
+// Time `iters` round trips through an echo thread. The echo thread is spawned
+// outside of the measurement, so this is dominated by the wake-up latency of
+// an empty channel.
+fn spsc_ping_pong(iters: u64) -> Duration {
+	let (mut px, mut cx) = spsc::channel();
+	let (mut echo_px, mut echo_cx) = spsc::channel();
+	
+	let echo = thread::spawn(move || {
 		while let Ok(i) = cx.recv() {
-			sum += i;
+			echo_px.send(i).unwrap();
 		}
-		
-		sum
-	}).join().unwrap()
+	});
+	
+	let start = Instant::now();
+	for i in 0 .. iters {
+		px.send(i).unwrap();
+		assert_eq!(echo_cx.recv().unwrap(), i);
+	}
+	let elapsed = start.elapsed();
+	
+	drop(px);
+	echo.join().unwrap();
+	elapsed
 }
 
-fn mpsc(count: usize) -> usize {
+fn mpsc_ping_pong(iters: u64) -> Duration {
 	let (sx, rx) = mpsc::channel();
+	let (echo_sx, echo_rx) = mpsc::channel();
 	
-	thread::spawn(move || {
-		for i in 0 .. count {
-			sx.send(i).unwrap();
+	let echo = thread::spawn(move || {
+		while let Ok(i) = rx.recv() {
+			echo_sx.send(i).unwrap();
 		}
 	});
 	
-	thread::spawn(move || {
-		let mut sum = 0usize;
-		
+	let start = Instant::now();
+	for i in 0 .. iters {
+		sx.send(i).unwrap();
+		assert_eq!(echo_rx.recv().unwrap(), i);
+	}
+	let elapsed = start.elapsed();
+	
+	drop(sx);
+	echo.join().unwrap();
+	elapsed
+}
+
+fn round_trip_latency(c: &mut Criterion) {
+	let mut group = c.benchmark_group("round trip latency");
+	
+	group.bench_function("spsc", |b| b.iter_custom(spsc_ping_pong));
+	group.bench_function("mpsc", |b| b.iter_custom(mpsc_ping_pong));
+	
+	group.finish();
+}
+
+// Time `iters` messages streamed to a consumer thread that is already
+// running, in one go rather than in iterations of a fixed count. Both sides
+// stay busy, which is where caching the opposite index pays off.
+fn spsc_stream(iters: u64, wait_strategy: WaitStrategy) -> Duration {
+	let (mut px, mut cx) = spsc::channel_with_strategy(4096, wait_strategy);
+	let (mut done_px, mut done_cx) = spsc::channel();
+	
+	let consumer = thread::spawn(move || {
+		let mut sum = 0u64;
+		while let Ok(i) = cx.recv() {
+			sum += i;
+			if i + 1 == iters {
+				done_px.send(()).unwrap();
+			}
+		}
+		sum
+	});
+	
+	let start = Instant::now();
+	for i in 0 .. iters {
+		px.send(i).unwrap();
+	}
+	done_cx.recv().unwrap();
+	let elapsed = start.elapsed();
+	
+	drop(px);
+	consumer.join().unwrap();
+	elapsed
+}
+
+fn mpsc_stream(iters: u64) -> Duration {
+	let (sx, rx) = mpsc::sync_channel(4096);
+	let (done_sx, done_rx) = mpsc::channel();
+	
+	let consumer = thread::spawn(move || {
+		let mut sum = 0u64;
 		while let Ok(i) = rx.recv() {
 			sum += i;
+			if i + 1 == iters {
+				done_sx.send(()).unwrap();
+			}
 		}
-		
 		sum
-	}).join().unwrap()
+	});
+	
+	let start = Instant::now();
+	for i in 0 .. iters {
+		sx.send(i).unwrap();
+	}
+	done_rx.recv().unwrap();
+	let elapsed = start.elapsed();
+	
+	drop(sx);
+	consumer.join().unwrap();
+	elapsed
 }
 
-fn spsc_vs_mpsc(c: &mut Criterion) {
-	let mut group = c.benchmark_group("spsc vs mpsc");
+// The same with the mutex and condvar baseline in place of the ring
+fn locked_stream(iters: u64) -> Duration {
+	let (px, cx) = spsc::locked::channel_with_capacity(4096);
+	let (mut done_px, mut done_cx) = spsc::channel();
+	
+	let consumer = thread::spawn(move || {
+		let mut sum = 0u64;
+		while let Ok(i) = cx.recv() {
+			sum += i;
+			if i + 1 == iters {
+				done_px.send(()).unwrap();
+			}
+		}
+		sum
+	});
+	
+	let start = Instant::now();
+	for i in 0 .. iters {
+		px.send(i).unwrap();
+	}
+	done_cx.recv().unwrap();
+	let elapsed = start.elapsed();
+	
+	drop(px);
+	consumer.join().unwrap();
+	elapsed
+}
+
+fn streaming_throughput(c: &mut Criterion) {
+	let mut group = c.benchmark_group("streaming throughput");
 	
-	for ref i in (8 ..= 12).map(|n| 1 << n) {
+	group.bench_function("spsc", |b| b.iter_custom(|iters| spsc_stream(iters, WaitStrategy::Park)));
+	group.bench_function("mpsc", |b| b.iter_custom(mpsc_stream));
+	group.bench_function("locked", |b| b.iter_custom(locked_stream));
+	
+	// the default above parks, these trade CPU time for latency
+	for wait_strategy in [WaitStrategy::BusySpin, WaitStrategy::Yield, WaitStrategy::Backoff] {
 		group.bench_with_input(
-			BenchmarkId::new("spsc", i),
-			i,
-			|b, &i| b.iter(|| spsc(i))
+			BenchmarkId::new("spsc", format!("{:?}", wait_strategy)),
+			&wait_strategy,
+			|b, &wait_strategy| b.iter_custom(|iters| spsc_stream(iters, wait_strategy)),
 		);
+	}
+	
+	group.finish();
+}
+
+// Like spsc_stream, for any payload. Making the payload is part of the
+// measurement, which for String includes its allocation.
+fn spsc_stream_of<T: Send + 'static>(iters: u64, capacity: usize, payload: fn(u64) -> T) -> Duration {
+	let (mut px, mut cx) = spsc::channel_with_capacity(capacity);
+	let (mut done_px, mut done_cx) = spsc::channel();
+	
+	let consumer = thread::spawn(move || {
+		for _ in 0 .. iters {
+			black_box(cx.recv().unwrap());
+		}
+		done_px.send(()).unwrap();
+	});
+	
+	let start = Instant::now();
+	for i in 0 .. iters {
+		px.send(payload(i)).unwrap();
+	}
+	done_cx.recv().unwrap();
+	let elapsed = start.elapsed();
+	
+	consumer.join().unwrap();
+	elapsed
+}
+
+// messages per batch of the same thread benchmark, and its capacity
+const BATCH: usize = 256;
+
+// Both ends on one thread, a batch in and out again at a time, so all that is
+// measured is the cost of the calls themselves
+fn same_thread(c: &mut Criterion) {
+	let mut group = c.benchmark_group("same thread");
+	group.throughput(Throughput::Elements(BATCH as u64));
+	
+	let (mut px, mut cx) = spsc::channel_with_capacity(BATCH);
+	group.bench_function("spsc", |b| b.iter(|| {
+		for i in 0 .. BATCH {
+			px.try_send(i).unwrap();
+		}
+		let mut sum = 0usize;
+		for _ in 0 .. BATCH {
+			sum += cx.try_recv().unwrap();
+		}
+		black_box(sum)
+	}));
+	
+	let (px, cx) = spsc::local::channel_with_capacity(BATCH);
+	group.bench_function("local", |b| b.iter(|| {
+		for i in 0 .. BATCH {
+			px.try_send(i).unwrap();
+		}
+		let mut sum = 0usize;
+		for _ in 0 .. BATCH {
+			sum += cx.try_recv().unwrap();
+		}
+		black_box(sum)
+	}));
+	
+	group.finish();
+}
+
+// One group per payload, one benchmark per capacity
+fn payload_group<T: Send + 'static>(c: &mut Criterion, name: &str, payload: fn(u64) -> T) {
+	let mut group = c.benchmark_group(format!("payload {}", name));
+	group.throughput(Throughput::Elements(1));
+	
+	for capacity in CAPACITIES {
 		group.bench_with_input(
-			BenchmarkId::new("mpsc", i),
-			i,
-			|b, &i| b.iter(|| mpsc(i))
+			BenchmarkId::from_parameter(capacity),
+			&capacity,
+			|b, &capacity| b.iter_custom(|iters| spsc_stream_of(iters, capacity, payload)),
 		);
 	}
 	
 	group.finish();
 }
 
+fn payload_matrix(c: &mut Criterion) {
+	payload_group(c, "u8", |i| i as u8);
+	payload_group(c, "usize", |i| i as usize);
+	payload_group(c, "64 bytes", |i| Line([i; 8]));
+	payload_group(c, "1 KiB", |i| [i as u8; 1024]);
+	payload_group(c, "String", |i| i.to_string());
+}
+
 criterion_group!(benches,
 	spsc_vs_mpsc,
+	streaming_throughput,
+	same_thread,
+);
+criterion_group!(matrix,
+	payload_matrix,
+);
+criterion_group!(latency,
+	round_trip_latency,
 );
-criterion_main!(benches);
+criterion_main!(benches, latency, matrix);
@@ -0,0 +1,104 @@
+// Round trip latencies through an echo thread, one sample per message, with
+// the percentiles of the distribution. The criterion suite reports means,
+// which hide the tail that decides between an SPSC ring and a queue with
+// locks.
+//
+//     cargo bench --bench latency
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use spsc::WaitStrategy;
+
+// round trips before the measurement, so both threads are running
+const WARMUP: usize = 10_000;
+const ROUND_TRIPS: usize = 200_000;
+
+const PERCENTILES: [f64; 4] = [50.0, 99.0, 99.9, 99.99];
+
+fn spsc_ping_pong(round_trips: usize, wait_strategy: WaitStrategy) -> Vec<u64> {
+	let (mut px, mut cx) = spsc::channel_with_strategy(64, wait_strategy);
+	let (mut echo_px, mut echo_cx) = spsc::channel_with_strategy(64, wait_strategy);
+
+	let echo = thread::spawn(move || {
+		while let Ok(i) = cx.recv() {
+			echo_px.send(i).unwrap();
+		}
+	});
+
+	let mut samples = Vec::with_capacity(round_trips);
+	for i in 0 .. WARMUP + round_trips {
+		let start = Instant::now();
+		px.send(i).unwrap();
+		assert_eq!(echo_cx.recv().unwrap(), i);
+		if i >= WARMUP {
+			samples.push(start.elapsed().as_nanos() as u64);
+		}
+	}
+
+	drop(px);
+	echo.join().unwrap();
+	samples
+}
+
+fn mpsc_ping_pong(round_trips: usize) -> Vec<u64> {
+	let (sx, rx) = mpsc::sync_channel(64);
+	let (echo_sx, echo_rx) = mpsc::sync_channel(64);
+
+	let echo = thread::spawn(move || {
+		while let Ok(i) = rx.recv() {
+			echo_sx.send(i).unwrap();
+		}
+	});
+
+	let mut samples = Vec::with_capacity(round_trips);
+	for i in 0 .. WARMUP + round_trips {
+		let start = Instant::now();
+		sx.send(i).unwrap();
+		assert_eq!(echo_rx.recv().unwrap(), i);
+		if i >= WARMUP {
+			samples.push(start.elapsed().as_nanos() as u64);
+		}
+	}
+
+	drop(sx);
+	echo.join().unwrap();
+	samples
+}
+
+// The sample at or below which `percentile` percent of them are, by nearest
+// rank. Exact, unlike a histogram, at the cost of sorting every sample.
+fn percentile(sorted: &[u64], percentile: f64) -> u64 {
+	let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+	sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(name: &str, mut samples: Vec<u64>) {
+	samples.sort_unstable();
+	print!("{:<16}", name);
+	for p in PERCENTILES {
+		print!("  {:>7} {:>8} ns", format!("p{}", p), percentile(&samples, p));
+	}
+	println!("  {:>7} {:>8} ns", "max", samples[samples.len() - 1]);
+}
+
+fn main() {
+	// `cargo bench` passes --bench, `cargo test --benches` does not: there
+	// a single short run is enough to know it works
+	if !std::env::args().any(|arg| arg == "--bench") {
+		report("spsc", spsc_ping_pong(1000, WaitStrategy::Park));
+		return;
+	}
+
+	println!("round trip latency, {} samples each", ROUND_TRIPS);
+	report("spsc", spsc_ping_pong(ROUND_TRIPS, WaitStrategy::Park));
+	// spinning on a single core only waits out the time slice of the
+	// other side
+	if thread::available_parallelism().is_ok_and(|n| n.get() > 1) {
+		for wait_strategy in [WaitStrategy::BusySpin, WaitStrategy::Yield, WaitStrategy::Backoff] {
+			report(&format!("spsc {:?}", wait_strategy), spsc_ping_pong(ROUND_TRIPS, wait_strategy));
+		}
+	}
+	report("mpsc", mpsc_ping_pong(ROUND_TRIPS));
+}
@@ -0,0 +1,10 @@
+// The worker script of wasm_workers.rs: instantiates the module on the
+// memory of the page and runs the work it was sent.
+import init, { worker_entry } from "./pkg/wasm_workers.js";
+
+self.onmessage = async (event) => {
+  const [module, memory, work] = event.data;
+  await init({ module_or_path: module, memory });
+  worker_entry(work);
+  close();
+};
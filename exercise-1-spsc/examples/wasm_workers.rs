@@ -0,0 +1,74 @@
+// Two web workers connected by a channel in the shared memory of a wasm
+// module: one sends the numbers up to a count, the other sums them up.
+//
+// The channel needs threads that share memory, which wasm32 only has with
+// the atomics proposal. The standard library must be rebuilt with it:
+//
+//     RUSTFLAGS="-C target-feature=+atomics,+bulk-memory" \
+//         cargo +nightly build --release --example wasm_workers \
+//         --target wasm32-unknown-unknown -Z build-std=std,panic_abort
+//     wasm-bindgen --target web --out-dir examples/pkg \
+//         target/wasm32-unknown-unknown/release/examples/wasm_workers.wasm
+//
+// and the page must be served cross-origin isolated (the headers
+// Cross-Origin-Opener-Policy: same-origin and
+// Cross-Origin-Embedder-Policy: require-corp), or browsers do not share
+// memory with workers. Then open examples/wasm_workers.html.
+//
+// A blocked send or recv parks its worker with `memory.atomic.wait32`,
+// which is what `Atomics.wait` does in JavaScript. Browsers do not let the
+// main thread wait, so there only `try_send`, `try_recv` and the async calls
+// work; the main thread here only starts the workers. `Instant` does not
+// work on wasm32-unknown-unknown either, so neither do the calls with a
+// timeout or deadline, nor `WaitStrategy::Backoff`.
+#![cfg(target_arch = "wasm32")]
+
+use js_sys::Array;
+use wasm_bindgen::prelude::*;
+use web_sys::{console, Worker, WorkerOptions, WorkerType};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Called by the page: starts both workers and returns right away.
+#[wasm_bindgen]
+pub fn start(count: u32) -> Result<(), JsValue> {
+    let (mut px, cx) = spsc::channel_with_capacity(64);
+    spawn(move || {
+        for i in 0..count {
+            px.send(i).unwrap();
+        }
+    })?;
+    spawn(move || {
+        let sum: u64 = cx.map(u64::from).sum();
+        console::log_1(&format!("received {count} numbers, sum {sum}").into());
+    })?;
+    Ok(())
+}
+
+// Starts a worker that instantiates this module on the same memory and runs
+// f, see wasm_workers.js
+fn spawn(f: impl FnOnce() + Send + 'static) -> Result<Worker, JsValue> {
+    let options = WorkerOptions::new();
+    options.set_type(WorkerType::Module);
+    let worker = Worker::new_with_options("./wasm_workers.js", &options)?;
+    let work = Box::into_raw(Box::new(Box::new(f) as Work));
+    let message = Array::of3(
+        &wasm_bindgen::module(),
+        &wasm_bindgen::memory(),
+        &JsValue::from(work as u32),
+    );
+    if let Err(err) = worker.post_message(&message) {
+        // the worker never gets to run it
+        drop(unsafe { Box::from_raw(work) });
+        return Err(err);
+    }
+    Ok(worker)
+}
+
+/// Called by the worker with the pointer that `spawn` sent it.
+#[wasm_bindgen]
+pub fn worker_entry(work: u32) {
+    // spawn leaked it for this worker alone
+    let work = unsafe { Box::from_raw(work as *mut Work) };
+    work();
+}
@@ -0,0 +1,225 @@
+// Runs the input as a sequence of operations on a channel and checks the
+// channel against what those operations promise:
+//
+// - messages arrive in the order they were sent, none twice or out of thin
+//   air (FIFO)
+// - every message is dropped exactly once, whether it was received, left in
+//   the buffer, or handed back by a failed send (conservation, no double
+//   drop)
+//
+// The first byte picks the capacity and the mode. In the single threaded
+// mode the operations run in input order and every result is compared with
+// a VecDeque of the same capacity. In the threaded mode, the producer and
+// the consumer run their share of the operations on two threads, so only
+// the invariants above can be checked.
+//
+//     cargo +nightly fuzz run ops
+#![no_main]
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libfuzzer_sys::fuzz_target;
+use spsc::{channel_with_capacity, Consumer, Producer, RecvError, TryRecvError, TrySendError};
+
+fuzz_target!(|data: &[u8]| run(data));
+
+// A message that records its drop
+struct Item {
+    id: u32,
+    drops: Arc<Mutex<Vec<u32>>>,
+}
+
+impl Drop for Item {
+    fn drop(&mut self) {
+        self.drops.lock().unwrap().push(self.id);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Send,
+    TrySend,
+    Recv,
+    TryRecv,
+    DropProducer,
+    DropConsumer,
+}
+
+impl Op {
+    // Mostly sends and receives, so the buffer gets to fill and drain
+    fn from_byte(byte: u8) -> Self {
+        match byte % 16 {
+            0..=3 => Op::Send,
+            4..=6 => Op::TrySend,
+            7..=10 => Op::Recv,
+            11..=13 => Op::TryRecv,
+            14 => Op::DropProducer,
+            _ => Op::DropConsumer,
+        }
+    }
+}
+
+fn run(data: &[u8]) {
+    let Some((&setup, ops)) = data.split_first() else {
+        return;
+    };
+    let capacity = 1 << (setup % 5);
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let created = if setup & 0x80 == 0 {
+        run_in_order(capacity, ops, &drops)
+    } else {
+        run_threaded(capacity, ops, &drops)
+    };
+    // everything is gone by now, each message exactly once
+    let mut dropped = drops.lock().unwrap().clone();
+    dropped.sort_unstable();
+    assert_eq!(
+        dropped,
+        (0..created).collect::<Vec<_>>(),
+        "lost or doubly dropped"
+    );
+}
+
+// Returns how many messages were created
+fn run_in_order(capacity: usize, ops: &[u8], drops: &Arc<Mutex<Vec<u32>>>) -> u32 {
+    let (px, cx) = channel_with_capacity::<Item>(capacity);
+    let (mut px, mut cx) = (Some(px), Some(cx));
+    let mut model = VecDeque::new();
+    let mut next = 0;
+    let item = |next: &mut u32| {
+        *next += 1;
+        Item {
+            id: *next - 1,
+            drops: drops.clone(),
+        }
+    };
+
+    for &byte in ops {
+        match Op::from_byte(byte) {
+            Op::Send | Op::TrySend => {
+                let Some(producer) = &mut px else { continue };
+                let full = model.len() == capacity;
+                if cx.is_none() {
+                    let result = producer.try_send(item(&mut next));
+                    assert!(matches!(result, Err(TrySendError::Disconnected(_))));
+                } else if full {
+                    // a blocking send would never return here
+                    let result = producer.try_send(item(&mut next));
+                    assert!(matches!(result, Err(TrySendError::Full(_))));
+                } else {
+                    let val = item(&mut next);
+                    model.push_back(val.id);
+                    match Op::from_byte(byte) {
+                        Op::Send => assert!(producer.send(val).is_ok()),
+                        _ => assert!(producer.try_send(val).is_ok()),
+                    }
+                }
+            }
+            Op::Recv | Op::TryRecv => {
+                let Some(consumer) = &mut cx else { continue };
+                match model.pop_front() {
+                    Some(id) => {
+                        let val = match Op::from_byte(byte) {
+                            Op::Recv => consumer.recv().unwrap(),
+                            _ => consumer.try_recv().unwrap(),
+                        };
+                        assert_eq!(val.id, id, "out of order");
+                    }
+                    None if px.is_none() => {
+                        assert_eq!(consumer.try_recv().err(), Some(TryRecvError::Disconnected));
+                        assert_eq!(consumer.recv().err(), Some(RecvError));
+                    }
+                    // a blocking receive would never return here
+                    None => assert_eq!(consumer.try_recv().err(), Some(TryRecvError::Empty)),
+                }
+            }
+            Op::DropProducer => px = None,
+            Op::DropConsumer => {
+                cx = None;
+                // the buffer goes with the channel, not with the consumer
+                if px.is_none() {
+                    model.clear();
+                }
+            }
+        }
+    }
+    next
+}
+
+fn run_threaded(capacity: usize, ops: &[u8], drops: &Arc<Mutex<Vec<u32>>>) -> u32 {
+    let (px, cx) = channel_with_capacity::<Item>(capacity);
+    // the high bit says whose turn the operation is
+    let (producer_ops, consumer_ops): (Vec<Op>, Vec<Op>) = (
+        ops.iter()
+            .filter(|&&byte| byte & 0x80 == 0)
+            .map(|&byte| Op::from_byte(byte))
+            .collect(),
+        ops.iter()
+            .filter(|&&byte| byte & 0x80 != 0)
+            .map(|&byte| Op::from_byte(byte))
+            .collect(),
+    );
+
+    let drops = drops.clone();
+    let producer = thread::spawn(move || produce(px, producer_ops, drops));
+    let received = consume(cx, consumer_ops);
+    let (created, sent) = producer.join().unwrap();
+
+    // what arrived is where the sent messages start, in order
+    assert!(received.len() <= sent.len());
+    assert_eq!(received, sent[..received.len()], "out of order");
+    created
+}
+
+// Returns how many messages were created, and the ids of those sent
+fn produce(px: Producer<Item>, ops: Vec<Op>, drops: Arc<Mutex<Vec<u32>>>) -> (u32, Vec<u32>) {
+    let mut px = Some(px);
+    let mut created = 0;
+    let mut sent = Vec::new();
+    for op in ops {
+        let Some(producer) = &mut px else { break };
+        let val = Item {
+            id: created,
+            drops: drops.clone(),
+        };
+        created += 1;
+        let ok = match op {
+            // waits while the buffer is full, at worst until the consumer is
+            // gone
+            Op::Send => producer.send(val).is_ok(),
+            Op::TrySend => producer.try_send(val).is_ok(),
+            Op::DropProducer => {
+                px = None;
+                // this one was never offered
+                false
+            }
+            _ => {
+                drop(val);
+                false
+            }
+        };
+        if ok {
+            sent.push(created - 1);
+        }
+    }
+    (created, sent)
+}
+
+// Returns the ids received, in order
+fn consume(mut cx: Consumer<Item>, ops: Vec<Op>) -> Vec<u32> {
+    let mut received = Vec::new();
+    for op in ops {
+        let val = match op {
+            // waits while the buffer is empty, at worst until the producer is
+            // gone
+            Op::Recv => cx.recv().ok(),
+            Op::TryRecv => cx.try_recv().ok(),
+            Op::DropConsumer => break,
+            _ => None,
+        };
+        received.extend(val.map(|val| val.id));
+    }
+    received
+}
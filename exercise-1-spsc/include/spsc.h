@@ -0,0 +1,51 @@
+/*
+ * C API of the spsc crate, built with the `ffi` feature:
+ *
+ *     cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * A channel carries byte messages from one producer to one consumer. Each
+ * handle is used by one thread at a time and freed with spsc_free, which
+ * disconnects the other end. See src/ffi.rs for the details.
+ */
+
+#ifndef SPSC_H
+#define SPSC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SPSC_OK 0
+/* The other end is gone, and for a receive, every message is received. */
+#define SPSC_DISCONNECTED (-1)
+/* The buffer is too short for the next message, which stays queued; its
+ * length is in *len. */
+#define SPSC_BUFFER_TOO_SMALL (-2)
+/* A null pointer, a handle of the wrong end, or a capacity that is 0 or
+ * not a power of two. */
+#define SPSC_INVALID_ARGUMENT (-3)
+
+typedef struct SpscHandle SpscHandle;
+
+/* Creates a channel of capacity messages, stores its ends in *producer and
+ * *consumer. */
+int spsc_channel_new(size_t capacity, SpscHandle **producer, SpscHandle **consumer);
+
+/* Sends a copy of the len bytes at data, waiting while the buffer is full. */
+int spsc_send_bytes(SpscHandle *producer, const uint8_t *data, size_t len);
+
+/* Waits for the next message, copies it into the capacity bytes at buf and
+ * stores its length in *len. */
+int spsc_recv_bytes(SpscHandle *consumer, uint8_t *buf, size_t capacity, size_t *len);
+
+/* Frees either end of a channel. Does nothing for NULL. */
+void spsc_free(SpscHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SPSC_H */
@@ -0,0 +1,194 @@
+// Lazy adapters on the receiving end. They apply a function or a predicate
+// to each message as it is received, on the receiving thread, so a trivial
+// pipeline stage needs no thread and channel of its own. The adapters wrap
+// each other through Receive, and each is an Iterator like Consumer.
+
+use std::time::{Duration, Instant};
+
+use crate::{Consumer, RecvError, RecvTimeoutError, TryRecvError};
+
+/// The receiving calls that `Consumer` and the adapters on it share.
+pub trait Receive {
+    type Item;
+
+    fn try_recv(&mut self) -> Result<Self::Item, TryRecvError>;
+
+    /// Waits for a message until `deadline`, or for as long as it takes if
+    /// there is none.
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Self::Item, RecvTimeoutError>;
+
+    fn recv(&mut self) -> Result<Self::Item, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    /// Like `recv`, but waits at most for `timeout`. A timeout too large to
+    /// represent as an Instant is as good as none.
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Self::Item, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+}
+
+impl<T: Send> Receive for Consumer<T> {
+    type Item = T;
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Consumer::try_recv(self)
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        match deadline {
+            None => Ok(Consumer::recv(self)?),
+            Some(deadline) => self.recv_deadline(deadline),
+        }
+    }
+}
+
+/// Receives the messages of a source passed through a function, see
+/// `Consumer::map`.
+pub struct Map<S, F> {
+    source: S,
+    f: F,
+}
+
+/// Receives only the messages of a source that match a predicate, see
+/// `Consumer::filter`.
+pub struct Filter<S, P> {
+    source: S,
+    predicate: P,
+}
+
+impl<S, F> Map<S, F> {
+    pub(crate) fn new(source: S, f: F) -> Self {
+        Map { source, f }
+    }
+
+    pub fn map<G>(self, f: G) -> Map<Self, G> {
+        Map::new(self, f)
+    }
+
+    pub fn filter<P>(self, predicate: P) -> Filter<Self, P> {
+        Filter::new(self, predicate)
+    }
+
+    /// Returns the source, for messages that should skip the function.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S, P> Filter<S, P> {
+    pub(crate) fn new(source: S, predicate: P) -> Self {
+        Filter { source, predicate }
+    }
+
+    pub fn map<G>(self, f: G) -> Map<Self, G> {
+        Map::new(self, f)
+    }
+
+    pub fn filter<Q>(self, predicate: Q) -> Filter<Self, Q> {
+        Filter::new(self, predicate)
+    }
+
+    /// Returns the source, for messages that should skip the predicate.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S: Receive, U, F: FnMut(S::Item) -> U> Receive for Map<S, F> {
+    type Item = U;
+
+    fn try_recv(&mut self) -> Result<U, TryRecvError> {
+        self.source.try_recv().map(&mut self.f)
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<U, RecvTimeoutError> {
+        self.source.recv_until(deadline).map(&mut self.f)
+    }
+}
+
+impl<S: Receive, P: FnMut(&S::Item) -> bool> Receive for Filter<S, P> {
+    type Item = S::Item;
+
+    // Drops the messages that do not match until one does, or the source
+    // runs empty
+    fn try_recv(&mut self) -> Result<S::Item, TryRecvError> {
+        loop {
+            let val = self.source.try_recv()?;
+            if (self.predicate)(&val) {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<S::Item, RecvTimeoutError> {
+        loop {
+            let val = self.source.recv_until(deadline)?;
+            if (self.predicate)(&val) {
+                return Ok(val);
+            }
+        }
+    }
+}
+
+impl<S: Receive, U, F: FnMut(S::Item) -> U> Iterator for Map<S, F> {
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        self.recv().ok()
+    }
+}
+
+impl<S: Receive, P: FnMut(&S::Item) -> bool> Iterator for Filter<S, P> {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::channel_with_capacity;
+
+    #[test]
+    fn adapters_apply_on_receive() {
+        let (mut px, cx) = channel_with_capacity(4);
+        let mut lengths = cx.filter(|word: &&str| !word.is_empty()).map(str::len);
+        assert_eq!(lengths.try_recv(), Err(TryRecvError::Empty));
+
+        px.send("").unwrap();
+        px.send("abc").unwrap();
+        assert_eq!(lengths.try_recv(), Ok(3));
+        px.send("").unwrap();
+        assert_eq!(lengths.try_recv(), Err(TryRecvError::Empty));
+        px.send("de").unwrap();
+        assert_eq!(lengths.recv_timeout(Duration::MAX), Ok(2));
+        assert_eq!(
+            lengths.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        let handle = thread::spawn(move || {
+            for word in ["a", "", "bb", "ccc"] {
+                px.send(word).unwrap();
+            }
+        });
+        assert_eq!(lengths.by_ref().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(lengths.recv(), Err(RecvError));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn take_until_disconnect_leaves_consumer() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send(1).unwrap();
+        px.send(2).unwrap();
+        drop(px);
+        assert_eq!(cx.take_until_disconnect().collect::<Vec<_>>(), [1, 2]);
+        assert!(cx.is_disconnected());
+    }
+}
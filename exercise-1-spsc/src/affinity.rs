@@ -0,0 +1,95 @@
+//! Pinning threads to cores.
+//!
+//! How fast a channel is depends a lot on where its two threads run: two
+//! cores that share a cache pass the indices and the messages through it,
+//! two cores on different sockets through the interconnect. Pinning the
+//! producer and the consumer makes that a choice rather than up to the
+//! scheduler, and benchmark results comparable between runs.
+//!
+//! Only Linux and Android let a thread pin itself to a core, elsewhere the
+//! calls fail with `ErrorKind::Unsupported`.
+
+use std::io;
+
+/// Pins the calling thread to the core `core`, numbered as the operating
+/// system does (`/proc/cpuinfo` on Linux). The thread stays there until it
+/// pins itself again.
+///
+/// Fails if there is no such core, or the process may not run on it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn pin_current(core: usize) -> io::Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "core number out of range",
+        ));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        // 0 is the calling thread
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn pin_current(core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads is not supported on this platform",
+    ))
+}
+
+/// Returns the cores the calling thread may run on, in order, the ones to
+/// pick from for `pin_current`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn available_cores() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| libc::CPU_ISSET(core, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn available_cores() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads is not supported on this platform",
+    ))
+}
+
+// Miri does not emulate sched_getcpu
+#[cfg(all(test, any(target_os = "linux", target_os = "android"), not(miri)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn pinned_thread_runs_on_its_core() {
+        let cores = available_cores().unwrap();
+        assert!(!cores.is_empty());
+        let core = *cores.last().unwrap();
+        thread::spawn(move || {
+            pin_current(core).unwrap();
+            assert_eq!(available_cores().unwrap(), [core]);
+            assert_eq!(unsafe { libc::sched_getcpu() }, core as i32);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn core_out_of_range_is_an_error() {
+        let err = pin_current(usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
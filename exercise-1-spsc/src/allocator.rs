@@ -0,0 +1,225 @@
+//! Buffers from an allocator of your own, see `channel_with_allocator`.
+//!
+//! The buffer is the one allocation a channel makes that grows with its
+//! capacity, so it is the one that can come from an arena, a pool or
+//! memory a device can reach. `BufferAllocator` is all the channel needs
+//! of an allocator, and works on stable Rust. With the `allocator_api`
+//! feature, which needs a nightly compiler, every `core::alloc::Allocator`
+//! is one as well.
+//!
+//! The state the handles share, a few cache lines, still comes from the
+//! global allocator, once per channel, and so does the box the allocator
+//! is kept in until the buffer is freed. Sending and receiving allocate
+//! nothing either way.
+//!
+//! ```
+//! use std::alloc::{GlobalAlloc, Layout, System};
+//! use std::ptr::NonNull;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! use spsc::allocator::BufferAllocator;
+//!
+//! static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+//!
+//! struct Counting;
+//!
+//! unsafe impl BufferAllocator for Counting {
+//!     fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+//!         ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+//!         NonNull::new(unsafe { System.alloc(layout) })
+//!     }
+//!
+//!     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+//!         ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+//!         System.dealloc(ptr.as_ptr(), layout)
+//!     }
+//! }
+//!
+//! let (mut px, mut cx) = spsc::channel_with_allocator::<u64, _>(16, Counting);
+//! assert_eq!(ALLOCATED.load(Ordering::Relaxed), 16 * 8);
+//! px.try_send(1).unwrap();
+//! assert_eq!(cx.try_recv(), Ok(1));
+//! drop((px, cx));
+//! assert_eq!(ALLOCATED.load(Ordering::Relaxed), 0);
+//! ```
+
+use alloc::boxed::Box;
+use core::alloc::Layout;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::slice;
+
+use crate::primitives::UnsafeCell;
+use crate::ring::Slot;
+
+/// Allocates and frees the buffers of channels.
+///
+/// # Safety
+///
+/// A block returned by `allocate` must be valid for reads and writes of
+/// `layout.size()` bytes, aligned to `layout.align()`, and not used by
+/// anything else until it is passed to `deallocate`. Moving the allocator
+/// must not invalidate its blocks.
+pub unsafe trait BufferAllocator: Send + 'static {
+    /// Allocates a block for `layout`, or returns None if it can not. The
+    /// layout is never zero-sized.
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Frees a block.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `allocate` of this allocator with the same
+    /// `layout`, and not have been freed yet.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl<A: core::alloc::Allocator + Send + 'static> BufferAllocator for A {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        core::alloc::Allocator::allocate(self, layout)
+            .ok()
+            .map(NonNull::cast)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        core::alloc::Allocator::deallocate(self, ptr, layout)
+    }
+}
+
+// System is an Allocator already with allocator_api
+#[cfg(all(feature = "std", not(feature = "allocator_api")))]
+unsafe impl BufferAllocator for std::alloc::System {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { std::alloc::GlobalAlloc::alloc(self, layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        std::alloc::GlobalAlloc::dealloc(self, ptr.as_ptr(), layout)
+    }
+}
+
+// The slots of a buffer from a BufferAllocator, given back when dropped
+pub(crate) struct Allocated<T> {
+    slots: NonNull<Slot<T>>,
+    capacity: usize,
+    allocator: Box<dyn BufferAllocator>,
+}
+
+impl<T> Allocated<T> {
+    pub(crate) fn new(capacity: usize, allocator: impl BufferAllocator) -> Self {
+        let layout = Self::layout(capacity);
+        // a zero-sized T needs no memory, like in a Box
+        let slots: NonNull<Slot<T>> = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            allocator
+                .allocate(layout)
+                .unwrap_or_else(|| alloc::alloc::handle_alloc_error(layout))
+                .cast()
+        };
+        // Only the cells need initializing, the messages in them do not
+        for i in 0..capacity {
+            unsafe {
+                slots
+                    .as_ptr()
+                    .add(i)
+                    .write(UnsafeCell::new(MaybeUninit::uninit()))
+            };
+        }
+        Allocated {
+            slots,
+            capacity,
+            allocator: Box::new(allocator),
+        }
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        Layout::array::<Slot<T>>(capacity).expect("buffer capacity overflows the address space")
+    }
+}
+
+// Owns the slots like the Box of a heap buffer would
+unsafe impl<T: Send> Send for Allocated<T> {}
+
+impl<T> Deref for Allocated<T> {
+    type Target = [Slot<T>];
+
+    fn deref(&self) -> &[Slot<T>] {
+        unsafe { slice::from_raw_parts(self.slots.as_ptr(), self.capacity) }
+    }
+}
+
+impl<T> Drop for Allocated<T> {
+    // The messages still queued are dropped by Inner, this only frees
+    fn drop(&mut self) {
+        let layout = Self::layout(self.capacity);
+        if layout.size() != 0 {
+            unsafe { self.allocator.deallocate(self.slots.cast(), layout) };
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::alloc::System;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    // Counts the blocks it hands out that are not freed yet
+    struct Tracking(Arc<AtomicUsize>);
+
+    unsafe impl BufferAllocator for Tracking {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            BufferAllocator::allocate(&System, layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+            BufferAllocator::deallocate(&System, ptr, layout)
+        }
+    }
+
+    #[test]
+    fn the_buffer_goes_back_to_its_allocator() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let (mut px, mut cx) = crate::channel_with_allocator(4, Tracking(live.clone()));
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+        let producer = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(Box::new(i)).unwrap();
+            }
+        });
+        for i in 0..99 {
+            assert_eq!(*cx.recv().unwrap(), i);
+        }
+        producer.join().unwrap();
+        // the last message is dropped along with the buffer
+        drop(cx);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn zero_sized_messages_allocate_nothing() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let (mut px, mut cx) = crate::ChannelBuilder::new()
+            .capacity(8)
+            .build_with_allocator(Tracking(live.clone()));
+        px.send(()).unwrap();
+        assert_eq!(cx.recv(), Ok(()));
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn any_allocator_will_do() {
+        let (px, cx) = crate::channel_with_allocator(4, std::alloc::Global);
+        px.send(1).unwrap();
+        assert_eq!(cx.recv(), Ok(1));
+    }
+}
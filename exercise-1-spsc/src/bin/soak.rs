@@ -0,0 +1,354 @@
+// Soak test: runs producer and consumer pairs for as long as asked, with
+// random pauses, and with either side hanging up at random to start over on
+// a new channel. All the while it checks what the channel promises and
+// reports how much memory the process holds, which must not grow.
+//
+// - every message arrives in order, none lost before the hang-up, none
+//   twice (sequence numbers)
+// - every message arrives intact (a checksum of its sequence number and
+//   session, and a payload derived from them)
+// - every message is dropped exactly once, whether it was received, handed
+//   back by a failed send, or left in the buffer when the consumer hung up
+//   (a tally per session)
+//
+// Small capacities wrap the buffer around every few messages.
+//
+//     cargo run --release --features cli --bin soak -- --duration 3600
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use spsc::*;
+
+// Counts the bytes the process holds on the heap, to see it grow
+struct Counting;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let ptr = System.alloc(layout);
+		if !ptr.is_null() {
+			LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+		}
+		ptr
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout);
+		LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let new_ptr = System.realloc(ptr, layout, new_size);
+		if !new_ptr.is_null() {
+			LIVE_BYTES.fetch_add(new_size, Ordering::Relaxed);
+			LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+		}
+		new_ptr
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Totals over all pairs, for the reports
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+static MESSAGES: AtomicU64 = AtomicU64::new(0);
+static VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+struct Config {
+	pairs: usize,
+	capacity: usize,
+	duration: Duration,
+	report: Duration,
+	seed: u64,
+}
+
+fn command() -> Command {
+	Command::new("soak")
+		.about("Runs SPSC channels for a long time and checks ordering, integrity and conservation of the messages")
+		.arg(Arg::new("duration")
+			.short('d')
+			.long("duration")
+			.help("Seconds to run for")
+			.value_parser(value_parser!(f64))
+			.default_value("60"))
+		.arg(Arg::new("pairs")
+			.short('j')
+			.long("pairs")
+			.help("Producer and consumer pairs running at once")
+			.value_parser(value_parser!(usize))
+			.default_value("2"))
+		.arg(Arg::new("capacity")
+			.short('c')
+			.long("capacity")
+			.help("Largest capacity of a channel, a power of two; each session picks one up to it")
+			.value_parser(value_parser!(usize))
+			.default_value("1024"))
+		.arg(Arg::new("report")
+			.short('r')
+			.long("report")
+			.help("Seconds between reports")
+			.value_parser(value_parser!(f64))
+			.default_value("10"))
+		.arg(Arg::new("seed")
+			.long("seed")
+			.help("Seed of the random choices, to repeat a run")
+			.value_parser(value_parser!(u64))
+			.default_value("1"))
+}
+
+fn seconds(matches: &ArgMatches, name: &str) -> Duration {
+	let secs = *matches.get_one::<f64>(name).unwrap();
+	Duration::try_from_secs_f64(secs).unwrap_or_else(|_| {
+		command().error(clap::error::ErrorKind::InvalidValue, format!("the {} must be a number of seconds", name)).exit()
+	})
+}
+
+fn config(matches: &ArgMatches) -> Config {
+	let capacity = *matches.get_one::<usize>("capacity").unwrap();
+	if !capacity.is_power_of_two() {
+		command().error(clap::error::ErrorKind::InvalidValue, "the capacity must be a power of two").exit();
+	}
+	Config {
+		pairs: *matches.get_one::<usize>("pairs").unwrap(),
+		capacity,
+		duration: seconds(matches, "duration"),
+		report: seconds(matches, "report"),
+		seed: *matches.get_one("seed").unwrap(),
+	}
+}
+
+// xorshift, so a run is repeatable from its seed (up to the timing)
+struct Rng(u64);
+
+impl Rng {
+	fn new(seed: u64) -> Self {
+		// xorshift never leaves 0
+		Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+	}
+
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn below(&mut self, n: u64) -> u64 {
+		self.next() % n
+	}
+
+	// true one in n times
+	fn one_in(&mut self, n: u64) -> bool {
+		self.below(n) == 0
+	}
+
+	// A short pause, now and then, so the two sides drift against each
+	// other and the buffer runs full and empty
+	fn pause(&mut self) {
+		if self.one_in(4096) {
+			thread::sleep(Duration::from_micros(self.below(500)));
+		} else if self.one_in(256) {
+			thread::yield_now();
+		}
+	}
+}
+
+// What became of the messages of a session, counted as they are dropped
+#[derive(Default)]
+struct Tally {
+	dropped: AtomicU64,
+	// the sum of the checksums of the dropped messages
+	dropped_sum: AtomicU64,
+}
+
+struct Message {
+	session: u64,
+	seq: u64,
+	check: u64,
+	payload: Vec<u8>,
+	tally: Arc<Tally>,
+}
+
+fn checksum(session: u64, seq: u64) -> u64 {
+	seq.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ session.rotate_left(32)
+}
+
+impl Message {
+	fn new(session: u64, seq: u64, tally: &Arc<Tally>) -> Self {
+		Message {
+			session,
+			seq,
+			check: checksum(session, seq),
+			payload: vec![seq as u8; (seq % 64) as usize],
+			tally: tally.clone(),
+		}
+	}
+
+	fn is_intact(&self) -> bool {
+		self.check == checksum(self.session, self.seq)
+			&& self.payload.len() == (self.seq % 64) as usize
+			&& self.payload.iter().all(|&byte| byte == self.seq as u8)
+	}
+}
+
+impl Drop for Message {
+	fn drop(&mut self) {
+		self.tally.dropped.fetch_add(1, Ordering::Relaxed);
+		self.tally.dropped_sum.fetch_add(self.check, Ordering::Relaxed);
+	}
+}
+
+fn violation(pair: usize, session: u64, what: String) {
+	eprintln!("pair {} session {}: {}", pair, session, what);
+	VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Receives until the producer is gone or, if `limit` is some, until that
+// many messages arrived, and hangs up. Returns how many arrived.
+fn consume(pair: usize, session: u64, mut cx: Consumer<Message>, limit: Option<u64>, mut rng: Rng) -> u64 {
+	let mut expected = 0;
+	while limit.is_none_or(|limit| expected < limit) {
+		// each way of receiving, at random
+		let msg = match rng.below(3) {
+			0 => match cx.try_recv() {
+				Ok(msg) => msg,
+				Err(TryRecvError::Empty) => continue,
+				Err(TryRecvError::Disconnected) => break,
+			},
+			1 => match cx.recv_timeout(Duration::from_micros(rng.below(100))) {
+				Ok(msg) => msg,
+				Err(RecvTimeoutError::Timeout) => continue,
+				Err(RecvTimeoutError::Disconnected) => break,
+			},
+			_ => match cx.recv() {
+				Ok(msg) => msg,
+				Err(RecvError) => break,
+			},
+		};
+		if !msg.is_intact() || msg.session != session {
+			violation(pair, session, format!("message {} arrived corrupted", msg.seq));
+		}
+		if msg.seq != expected {
+			violation(pair, session, format!("expected message {}, got {}", expected, msg.seq));
+		}
+		expected = msg.seq + 1;
+		rng.pause();
+	}
+	expected
+}
+
+// One channel, from its creation until both sides hung up
+fn session(config: &Config, pair: usize, session: u64, rng: &mut Rng) {
+	let capacity = 1 << rng.below(config.capacity.trailing_zeros() as u64 + 1);
+	let (mut px, cx) = channel_with_capacity(capacity);
+	let tally = Arc::new(Tally::default());
+
+	// The producer sends up to `messages`; half of the time, the consumer
+	// hangs up somewhere before
+	let messages = rng.below(1 << 16);
+	let limit = rng.one_in(2).then(|| rng.below(messages + 1));
+	let consumer_rng = Rng::new(rng.next());
+	let consumer = thread::spawn(move || consume(pair, session, cx, limit, consumer_rng));
+
+	// Every message made is counted, whether it is sent or handed back by a
+	// send that fails because the consumer is gone
+	let (mut sent, mut made, mut made_sum) = (0, 0, 0u64);
+	'send: while sent < messages {
+		let mut msg = Message::new(session, sent, &tally);
+		made += 1;
+		made_sum = made_sum.wrapping_add(msg.check);
+		// each way of sending, at random
+		loop {
+			let result = match rng.below(3) {
+				0 => px.try_send(msg).map_err(|err| match err {
+					TrySendError::Full(msg) => Some(msg),
+					TrySendError::Disconnected(_) => None,
+				}),
+				1 => px.send_timeout(msg, Duration::from_micros(rng.below(100))).map_err(|err| match err {
+					SendTimeoutError::Timeout(msg) => Some(msg),
+					SendTimeoutError::Disconnected(_) => None,
+				}),
+				_ => px.send(msg).map_err(|_| None),
+			};
+			match result {
+				Ok(()) => break,
+				// no room yet, the same message goes again
+				Err(Some(returned)) => msg = returned,
+				Err(None) => break 'send,
+			}
+		}
+		sent += 1;
+		rng.pause();
+	}
+	drop(px);
+	let received = consumer.join().unwrap();
+
+	// Both sides are gone, so is the channel with whatever it still held
+	if limit.is_none() && received != sent {
+		violation(pair, session, format!("sent {} messages, {} arrived", sent, received));
+	}
+	let dropped = tally.dropped.load(Ordering::Relaxed);
+	if dropped != made || tally.dropped_sum.load(Ordering::Relaxed) != made_sum {
+		violation(pair, session, format!("made {} messages, {} were dropped", made, dropped));
+	}
+	SESSIONS.fetch_add(1, Ordering::Relaxed);
+	MESSAGES.fetch_add(sent, Ordering::Relaxed);
+}
+
+fn run(config: &Config) {
+	let stop = Arc::new(AtomicBool::new(false));
+	let pairs: Vec<_> = (0 .. config.pairs).map(|pair| {
+		let (config, stop) = (config.clone(), stop.clone());
+		thread::spawn(move || {
+			let mut rng = Rng::new(config.seed ^ (pair as u64) << 48);
+			let mut session_id = 0;
+			while !stop.load(Ordering::Relaxed) {
+				session(&config, pair, session_id, &mut rng);
+				session_id += 1;
+			}
+		})
+	}).collect();
+
+	let start = Instant::now();
+	// the first report is the baseline, with the threads and channels up
+	let mut baseline = None;
+	while start.elapsed() < config.duration {
+		thread::sleep(config.report.min(config.duration.saturating_sub(start.elapsed())));
+		let live = LIVE_BYTES.load(Ordering::Relaxed);
+		let baseline = *baseline.get_or_insert(live);
+		println!("{:>8.0} s  {:>10} sessions  {:>14} messages  {:>10} bytes live ({:+})  {} violations",
+			start.elapsed().as_secs_f64(),
+			SESSIONS.load(Ordering::Relaxed),
+			MESSAGES.load(Ordering::Relaxed),
+			live,
+			live as i64 - baseline as i64,
+			VIOLATIONS.load(Ordering::Relaxed));
+	}
+
+	stop.store(true, Ordering::Relaxed);
+	for pair in pairs {
+		pair.join().unwrap();
+	}
+
+	let violations = VIOLATIONS.load(Ordering::Relaxed);
+	println!("sessions    {}", SESSIONS.load(Ordering::Relaxed));
+	println!("messages    {}", MESSAGES.load(Ordering::Relaxed));
+	println!("violations  {}", violations);
+	if violations != 0 {
+		process::exit(1);
+	}
+}
+
+fn main() {
+	let config = config(&command().get_matches());
+	run(&config);
+}
@@ -0,0 +1,783 @@
+// Channel endpoints borrowing their buffer and state from the caller instead
+// of allocating them, e.g. for a buffer on the stack or in a `static`.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::MaybeUninit;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use crate::index;
+#[cfg(feature = "latency")]
+use crate::latency::LatencyHistogram;
+use crate::primitives::{AtomicBool, AtomicUsize, Ordering, UnsafeCell};
+#[cfg(feature = "record")]
+use crate::record::Trace;
+use crate::ring::{self, Ring, Slot};
+#[cfg(feature = "stats")]
+use crate::ChannelStats;
+#[cfg(feature = "std")]
+use crate::{
+    CancelToken, FlushError, OccupiedSlices, RecvCancelError, RecvCancelFuture, RecvError,
+    RecvFuture, RecvGuard, RecvTimeoutError, SendCancelError, SendCancelFuture, SendFuture,
+    SendTimeoutError, SlotGuard, Transaction, VacantSlices,
+};
+use crate::{
+    ForceSendError, PeekGuard, PeekMutGuard, SendError, TryRecvError, TrySendError, WaitStrategy,
+};
+
+/// Indices and counters of a borrowed channel.
+pub struct State {
+    inner: ring::State,
+    // endpoints still around, the last one drops what is left in the buffer
+    endpoints: AtomicUsize,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        // WaitStrategy::default, which is not const
+        Self::with_strategy(WaitStrategy::Park)
+    }
+
+    /// A state for channels whose blocked calls wait as `wait_strategy`
+    /// says instead of parking.
+    pub const fn with_strategy(wait_strategy: WaitStrategy) -> Self {
+        State {
+            inner: ring::State::new(wait_strategy),
+            endpoints: AtomicUsize::new(2),
+        }
+    }
+
+    // A fresh state for the next channel, keeping the configuration
+    fn reset(&mut self) {
+        *self = State::with_strategy(self.inner.wait_strategy);
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Producer<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    endpoints: &'a AtomicUsize,
+}
+
+pub struct Consumer<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    endpoints: &'a AtomicUsize,
+    // as in crate::Consumer
+    #[cfg(feature = "std")]
+    seq: u64,
+}
+
+/// Creates a channel using `buffer` as its storage, so its capacity is the
+/// length of the slice. Messages still queued when both endpoints are
+/// dropped are dropped along with them.
+///
+/// Panics if `buffer` is empty or its length is not a power of two.
+pub fn channel_in<'a, T: Send>(
+    buffer: &'a mut [MaybeUninit<T>],
+    state: &'a mut State,
+) -> (Producer<'a, T>, Consumer<'a, T>) {
+    ring::check_capacity(buffer.len());
+    // the state might have been used by an earlier channel
+    state.reset();
+
+    let ring = Ring {
+        buffer: UnsafeCell::from_mut_slice(buffer),
+        state: &state.inner,
+    };
+    endpoints(ring, &state.endpoints)
+}
+
+fn endpoints<'a, T: Send, const N: usize>(
+    ring: Ring<'a, T, N>,
+    endpoints: &'a AtomicUsize,
+) -> (Producer<'a, T, N>, Consumer<'a, T, N>) {
+    let consumer = Consumer {
+        ring,
+        endpoints,
+        #[cfg(feature = "std")]
+        seq: ring.state.read_index.load(Ordering::Relaxed) as u64,
+    };
+    (Producer { ring, endpoints }, consumer)
+}
+
+// Called by both endpoints on drop, the second one cleans up the buffer
+fn release_endpoint<T, const N: usize>(ring: Ring<'_, T, N>, endpoints: &AtomicUsize) {
+    // AcqRel, so the slot accesses of the other endpoint are done
+    if endpoints.fetch_sub(1, Ordering::AcqRel) == 1 {
+        unsafe { ring.drop_queued() }
+    }
+}
+
+/// Buffer and state of a channel with the fixed capacity `N`, which needs no
+/// allocation at all. The endpoints borrow it, see `Storage::split`.
+pub struct Storage<T, const N: usize> {
+    buffer: [Slot<T>; N],
+    state: State,
+}
+
+impl<T: Send, const N: usize> Storage<T, N> {
+    pub const fn new() -> Self {
+        Self::with_strategy(WaitStrategy::Park)
+    }
+
+    /// See `State::with_strategy`.
+    pub const fn with_strategy(wait_strategy: WaitStrategy) -> Self {
+        Storage {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            state: State::with_strategy(wait_strategy),
+        }
+    }
+
+    /// Like `channel_in`, but the capacity is the constant `N`, so the slot
+    /// mask is a constant as well.
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        const { ring::check_capacity(N) };
+        // &mut self, so the endpoints of an earlier split are gone and
+        // dropped what was left in the buffer
+        self.state.reset();
+
+        let ring = Ring {
+            buffer: &self.buffer,
+            state: &self.state.inner,
+        };
+        endpoints(ring, &self.state.endpoints)
+    }
+}
+
+impl<T: Send, const N: usize> Default for Storage<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `Storage` that can live in a `static`, for code that can not allocate
+/// and has no stack frame that outlives the endpoints. It is split only
+/// once, so unlike `Storage` it is never reset for another channel.
+pub struct StaticChannel<T, const N: usize> {
+    storage: Storage<T, N>,
+    split: AtomicBool,
+}
+
+impl<T: Send, const N: usize> StaticChannel<T, N> {
+    pub const fn new() -> Self {
+        Self::with_strategy(WaitStrategy::Park)
+    }
+
+    /// See `State::with_strategy`.
+    pub const fn with_strategy(wait_strategy: WaitStrategy) -> Self {
+        StaticChannel {
+            storage: Storage::with_strategy(wait_strategy),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the endpoints on the first call, and `None` on every call
+    /// after that.
+    pub fn split(&'static self) -> Option<(Producer<'static, T, N>, Consumer<'static, T, N>)> {
+        const { ring::check_capacity(N) };
+        if self.split.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        let ring = Ring {
+            buffer: &self.storage.buffer,
+            state: &self.storage.state.inner,
+        };
+        Some(endpoints(ring, &self.storage.state.endpoints))
+    }
+}
+
+impl<T: Send, const N: usize> Default for StaticChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The buffer is only reached through the one pair of endpoints split hands
+// out, which synchronize as any other channel does
+unsafe impl<T: Send, const N: usize> Sync for StaticChannel<T, N> {}
+
+impl<T: Send, const N: usize> Producer<'_, T, N> {
+    #[cfg(feature = "std")]
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        self.ring.send(val)
+    }
+
+    /// See `crate::Producer::try_send`.
+    pub fn try_send(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.ring.try_send(val)
+    }
+
+    /// See `crate::Producer::send_async`.
+    #[cfg(feature = "std")]
+    pub fn send_async(&mut self, val: T) -> SendFuture<'_, T, N> {
+        SendFuture::new(self.ring, val)
+    }
+
+    /// See `crate::Producer::send_timeout`.
+    #[cfg(feature = "std")]
+    pub fn send_timeout(&mut self, val: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.ring.send_timeout(val, timeout)
+    }
+
+    /// See `crate::Producer::send_cancellable`.
+    #[cfg(feature = "std")]
+    pub fn send_cancellable(
+        &mut self,
+        val: T,
+        token: &CancelToken,
+    ) -> Result<(), SendCancelError<T>> {
+        self.ring.send_cancellable(val, token)
+    }
+
+    /// See `crate::Producer::send_async_cancellable`.
+    #[cfg(feature = "std")]
+    pub fn send_async_cancellable<'b>(
+        &'b mut self,
+        val: T,
+        token: &'b CancelToken,
+    ) -> SendCancelFuture<'b, T, N> {
+        SendCancelFuture::new(self.ring, val, token)
+    }
+
+    /// See `crate::Producer::wait_for_space`.
+    #[cfg(feature = "std")]
+    pub fn wait_for_space(&mut self, n: usize) -> Result<usize, SendError<()>> {
+        crate::wait_for_space(self.ring, n)
+    }
+
+    /// See `crate::Producer::reserve`.
+    #[cfg(feature = "std")]
+    pub fn reserve(&mut self) -> Result<SlotGuard<'_, T, N>, SendError<()>> {
+        SlotGuard::new(self.ring)
+    }
+
+    /// See `crate::Producer::transaction`.
+    #[cfg(feature = "std")]
+    pub fn transaction(&mut self, n: usize) -> Result<Transaction<'_, T, N>, SendError<()>> {
+        Transaction::new(self.ring, n)
+    }
+
+    /// See `crate::Producer::send_with`.
+    #[cfg(feature = "std")]
+    pub fn send_with<F>(&mut self, init: F) -> Result<(), SendError<()>>
+    where
+        F: for<'s> FnOnce(&'s mut MaybeUninit<T>) -> &'s mut T,
+    {
+        self.reserve()?.write_with(init);
+        Ok(())
+    }
+
+    /// See `crate::Producer::vacant_slices`.
+    #[cfg(feature = "std")]
+    pub fn vacant_slices(&mut self) -> Result<VacantSlices<'_, T, N>, SendError<()>> {
+        VacantSlices::new(self.ring)
+    }
+
+    /// See `crate::Producer::send_overwrite`.
+    pub fn send_overwrite(&mut self, val: T) -> Result<(), ForceSendError<T>> {
+        self.force_send(val).map(drop)
+    }
+
+    /// See `crate::Producer::force_send`.
+    pub fn force_send(&mut self, val: T) -> Result<Option<T>, ForceSendError<T>> {
+        self.ring.force_send(val)
+    }
+
+    /// See `crate::Producer::send_all`.
+    #[cfg(feature = "std")]
+    pub fn send_all<I>(&mut self, iter: I) -> Result<usize, (usize, SendError<T>)>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.ring.send_all(iter)
+    }
+
+    /// See `crate::Producer::send_iter`.
+    pub fn send_iter<I>(&mut self, iter: I) -> Result<usize, SendError<()>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.ring.send_iter(iter)
+    }
+
+    /// See `crate::Producer::flush`.
+    #[cfg(feature = "std")]
+    pub fn flush(&mut self) -> Result<(), FlushError> {
+        self.ring.flush()
+    }
+
+    /// See `crate::Producer::is_consumer_alive`.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.ring.state.consumer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// See `crate::Producer::close`.
+    pub fn close(&self) {
+        self.ring.close()
+    }
+
+    /// See `crate::Producer::is_closed`.
+    pub fn is_closed(&self) -> bool {
+        self.ring.is_closed()
+    }
+
+    /// See `crate::Producer::is_poisoned`.
+    #[cfg(feature = "std")]
+    pub fn is_poisoned(&self) -> bool {
+        self.ring.is_poisoned()
+    }
+
+    /// See `crate::Producer::is_disconnected`.
+    pub fn is_disconnected(&self) -> bool {
+        self.ring.is_disconnected_from_consumer()
+    }
+
+    /// See `crate::Producer::capacity`.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// See `crate::Producer::len`.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// See `crate::Producer::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        self.ring.len() == 0
+    }
+
+    /// See `crate::Producer::is_full`.
+    pub fn is_full(&self) -> bool {
+        self.ring.len() == self.ring.capacity()
+    }
+
+    /// See `crate::Producer::high_water_mark`.
+    pub fn high_water_mark(&self) -> usize {
+        self.ring.state.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.ring.stats()
+    }
+
+    /// See `crate::Producer::start_recording`.
+    #[cfg(feature = "record")]
+    pub fn start_recording(&self, limit: usize) {
+        self.ring.start_recording(limit);
+    }
+
+    /// See `crate::Producer::trace`.
+    #[cfg(feature = "record")]
+    pub fn trace(&self) -> Trace {
+        self.ring.trace()
+    }
+}
+
+impl<T: Send, const N: usize> Consumer<'_, T, N> {
+    #[cfg(feature = "std")]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.recv_into(&mut val)?;
+        // recv_into only returns Ok after initializing val
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::recv_into`.
+    #[cfg(feature = "std")]
+    pub fn recv_into(&mut self, dst: &mut MaybeUninit<T>) -> Result<(), RecvError> {
+        self.ring.recv_into(dst)
+    }
+
+    /// See `crate::Consumer::recv_with_seq`.
+    #[cfg(feature = "std")]
+    pub fn recv_with_seq(&mut self) -> Result<(u64, T), RecvError> {
+        let mut val = MaybeUninit::uninit();
+        let read_index = self.ring.recv_into_seq(&mut val)?;
+        self.seq = index::widen(self.seq, read_index);
+        Ok((self.seq, unsafe { val.assume_init() }))
+    }
+
+    /// See `crate::Consumer::recv_with_latency`.
+    #[cfg(feature = "latency")]
+    pub fn recv_with_latency(&mut self) -> Result<(T, Option<Duration>), RecvError> {
+        let mut val = MaybeUninit::uninit();
+        let latency = self.ring.recv_into_with_latency(&mut val)?;
+        Ok((unsafe { val.assume_init() }, latency))
+    }
+
+    /// See `crate::Consumer::recv_into_slice`.
+    pub fn recv_into_slice(&mut self, out: &mut [MaybeUninit<T>]) -> usize {
+        self.ring.recv_into_slice(out)
+    }
+
+    /// See `crate::Consumer::recv_many`.
+    pub fn recv_many(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        crate::recv_many(self.ring, out, max)
+    }
+
+    /// See `crate::Consumer::wait_for_messages`.
+    #[cfg(feature = "std")]
+    pub fn wait_for_messages(&mut self, n: usize) -> Result<usize, RecvError> {
+        crate::wait_for_messages(self.ring, n)
+    }
+
+    /// See `crate::Consumer::recv_exact`.
+    #[cfg(feature = "std")]
+    pub fn recv_exact(&mut self, n: usize) -> Result<Vec<T>, RecvError> {
+        crate::recv_exact(self.ring, n)
+    }
+
+    /// See `crate::Consumer::drain`.
+    pub fn drain(&mut self) -> Vec<T> {
+        crate::drain(self.ring)
+    }
+
+    /// See `crate::Consumer::try_recv`.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring.try_recv_into(&mut val)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::recv_async`.
+    #[cfg(feature = "std")]
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T, N> {
+        RecvFuture::new(self.ring)
+    }
+
+    /// See `crate::Consumer::recv_or_else`.
+    #[cfg(feature = "std")]
+    pub fn recv_or_else<F: FnMut()>(&mut self, on_empty: F) -> Result<T, RecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring.recv_into_or_else(&mut val, on_empty)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::recv_timeout`.
+    #[cfg(feature = "std")]
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring.recv_into_timeout(&mut val, timeout)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::recv_cancellable`.
+    #[cfg(feature = "std")]
+    pub fn recv_cancellable(&mut self, token: &CancelToken) -> Result<T, RecvCancelError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring.recv_into_cancellable(&mut val, token)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::recv_async_cancellable`.
+    #[cfg(feature = "std")]
+    pub fn recv_async_cancellable<'b>(
+        &'b mut self,
+        token: &'b CancelToken,
+    ) -> RecvCancelFuture<'b, T, N> {
+        RecvCancelFuture::new(self.ring, token)
+    }
+
+    /// See `crate::Consumer::recv_deadline`.
+    #[cfg(feature = "std")]
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring.recv_into_until(&mut val, Some(deadline))?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// See `crate::Consumer::occupied_slices`.
+    #[cfg(feature = "std")]
+    pub fn occupied_slices(&mut self) -> Result<OccupiedSlices<'_, T, N>, RecvError> {
+        OccupiedSlices::new(self.ring)
+    }
+
+    /// See `crate::Consumer::peek`.
+    pub fn peek(&mut self) -> Option<PeekGuard<'_, T, N>> {
+        PeekGuard::new(self.ring)
+    }
+
+    /// See `crate::Consumer::peek_mut`.
+    pub fn peek_mut(&mut self) -> Option<PeekMutGuard<'_, T, N>> {
+        PeekMutGuard::new(self.ring)
+    }
+
+    /// See `crate::Consumer::recv_ref`.
+    #[cfg(feature = "std")]
+    pub fn recv_ref(&mut self) -> Result<RecvGuard<'_, T, N>, RecvError> {
+        RecvGuard::new(self.ring)
+    }
+
+    /// See `crate::Consumer::clear`.
+    pub fn clear(&mut self) -> usize {
+        self.ring.clear()
+    }
+
+    /// See `crate::Consumer::is_producer_alive`.
+    pub fn is_producer_alive(&self) -> bool {
+        self.ring.state.producer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// See `crate::Consumer::close`.
+    pub fn close(&self) {
+        self.ring.close()
+    }
+
+    /// See `crate::Consumer::is_closed`.
+    pub fn is_closed(&self) -> bool {
+        self.ring.is_closed()
+    }
+
+    /// See `crate::Consumer::is_poisoned`.
+    #[cfg(feature = "std")]
+    pub fn is_poisoned(&self) -> bool {
+        self.ring.is_poisoned()
+    }
+
+    /// See `crate::Consumer::is_disconnected`.
+    pub fn is_disconnected(&self) -> bool {
+        self.ring.is_disconnected_from_producers()
+    }
+
+    /// See `crate::Consumer::capacity`.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// See `crate::Consumer::len`.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// See `crate::Consumer::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        self.ring.len() == 0
+    }
+
+    /// See `crate::Consumer::is_full`.
+    pub fn is_full(&self) -> bool {
+        self.ring.len() == self.ring.capacity()
+    }
+
+    /// See `crate::Consumer::high_water_mark`.
+    pub fn high_water_mark(&self) -> usize {
+        self.ring.state.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.ring.stats()
+    }
+
+    /// See `crate::Producer::start_recording`.
+    #[cfg(feature = "record")]
+    pub fn start_recording(&self, limit: usize) {
+        self.ring.start_recording(limit);
+    }
+
+    /// See `crate::Producer::trace`.
+    #[cfg(feature = "record")]
+    pub fn trace(&self) -> Trace {
+        self.ring.trace()
+    }
+
+    /// See `crate::Consumer::measure_latency`.
+    #[cfg(feature = "latency")]
+    pub fn measure_latency(&self) {
+        self.ring.measure_latency();
+    }
+
+    /// See `crate::Consumer::latency`.
+    #[cfg(feature = "latency")]
+    pub fn latency(&self) -> LatencyHistogram {
+        self.ring.latency()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send, const N: usize> Iterator for Consumer<'_, T, N> {
+    type Item = T;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T: Send, const N: usize> futures_core::Stream for Consumer<'_, T, N> {
+    type Item = T;
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<T>> {
+        let mut val = MaybeUninit::uninit();
+        self.ring
+            .poll_recv_into(&mut val, cx)
+            .map(|result| result.ok().map(|()| unsafe { val.assume_init() }))
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Drop for Producer<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.drop_producer();
+        release_endpoint(self.ring, self.endpoints);
+    }
+}
+
+impl<T, const N: usize> Drop for Consumer<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.drop_consumer();
+        release_endpoint(self.ring, self.endpoints);
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Producer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ring.fmt_debug("Producer", f)
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Consumer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ring.fmt_debug("Consumer", f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use std::sync::Arc;
+    #[cfg(feature = "std")]
+    use std::thread;
+
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn stack_buffer_round_trip() {
+        let mut buffer = [MaybeUninit::<usize>::uninit(); 8];
+        let mut state = State::new();
+        let (mut px, mut cx) = channel_in(&mut buffer, &mut state);
+
+        // the buffer is much smaller than the message count, so this also
+        // wraps around a couple of times
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    px.send(i).unwrap();
+                }
+            });
+
+            for i in 0..100 {
+                assert_eq!(cx.recv().unwrap(), i);
+            }
+            assert!(cx.recv().is_err());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer capacity must be at least 1")]
+    fn empty_buffer_is_rejected() {
+        let mut state = State::new();
+        channel_in::<i32>(&mut [], &mut state);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn peek_leaves_message_queued() {
+        let mut storage: Storage<i32, 2> = Storage::new();
+        let (mut px, mut cx) = storage.split();
+        px.send(1).unwrap();
+        *cx.peek_mut().unwrap() += 1;
+        assert_eq!(*cx.peek().unwrap(), 2);
+        assert_eq!(cx.peek().unwrap().pop(), 2);
+        assert!(cx.peek().is_none());
+    }
+
+    #[test]
+    fn reused_state_keeps_wait_strategy() {
+        let mut buffer = [MaybeUninit::<i32>::uninit(); 2];
+        let mut state = State::with_strategy(WaitStrategy::BusySpin);
+        drop(channel_in(&mut buffer, &mut state));
+        drop(channel_in(&mut buffer, &mut state));
+        assert_eq!(state.inner.wait_strategy, WaitStrategy::BusySpin);
+
+        let mut storage: Storage<i32, 2> = Storage::with_strategy(WaitStrategy::Yield);
+        drop(storage.split());
+        assert_eq!(storage.state.inner.wait_strategy, WaitStrategy::Yield);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn leftovers_are_dropped_with_endpoints() {
+        let mut buffer = [const { MaybeUninit::uninit() }; 4];
+        let mut state = State::new();
+        let tracker = Arc::new(());
+
+        let (mut px, mut cx) = channel_in(&mut buffer, &mut state);
+        px.send(tracker.clone()).unwrap();
+        px.send(tracker.clone()).unwrap();
+        drop(cx.recv().unwrap());
+        drop(px);
+        // the consumer could still receive the second one
+        assert_eq!(Arc::strong_count(&tracker), 2);
+        drop(cx);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+
+        // reusing buffer and state starts with an empty channel
+        let (px, mut cx) = channel_in(&mut buffer, &mut state);
+        drop(px);
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn fixed_storage_round_trip() {
+        let mut storage: Storage<usize, 4> = Storage::new();
+        let (mut px, cx) = storage.split();
+        assert_eq!(px.capacity(), 4);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    px.send(i).unwrap();
+                }
+            });
+            assert_eq!(cx.collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        });
+
+        // a second split starts over
+        let (mut px, mut cx) = storage.split();
+        px.send(7).unwrap();
+        drop(px);
+        assert_eq!(cx.recv().unwrap(), 7);
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn static_channel_splits_once() {
+        static CHANNEL: StaticChannel<usize, 4> = StaticChannel::new();
+        let (mut px, cx) = CHANNEL.split().unwrap();
+        assert!(CHANNEL.split().is_none());
+
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+        });
+        assert_eq!(cx.collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! Channels between a blocking thread and a Tokio task.
+//!
+//! One end is an ordinary handle that blocks with the given `WaitStrategy`,
+//! the other one can only be awaited. The awaitable end registers the waker of
+//! its task with the channel, so the blocking thread wakes the task directly.
+//! It also takes part in Tokio's cooperative scheduling, so a producer that
+//! is always ahead does not starve the other tasks of the runtime.
+//!
+//! The blocking end must not be used on a runtime thread, move it to a plain
+//! thread or into `tokio::task::spawn_blocking`.
+
+use crate::{
+    channel_with_strategy, Consumer, Producer, RecvError, SendError, TryRecvError, TrySendError,
+    WaitStrategy,
+};
+
+/// The consumer of `sync_to_async`, for use inside a Tokio task.
+pub struct AsyncConsumer<T> {
+    consumer: Consumer<T>,
+}
+
+/// The producer of `async_to_sync`, for use inside a Tokio task.
+pub struct AsyncProducer<T> {
+    producer: Producer<T>,
+}
+
+/// A channel from a thread that blocks with `wait_strategy` while the buffer
+/// is full, to a task.
+pub fn sync_to_async<T: Send>(
+    capacity: usize,
+    wait_strategy: WaitStrategy,
+) -> (Producer<T>, AsyncConsumer<T>) {
+    let (producer, consumer) = channel_with_strategy(capacity, wait_strategy);
+    (producer, AsyncConsumer { consumer })
+}
+
+/// A channel from a task to a thread that blocks with `wait_strategy` while
+/// the buffer is empty.
+pub fn async_to_sync<T: Send>(
+    capacity: usize,
+    wait_strategy: WaitStrategy,
+) -> (AsyncProducer<T>, Consumer<T>) {
+    let (producer, consumer) = channel_with_strategy(capacity, wait_strategy);
+    (AsyncProducer { producer }, consumer)
+}
+
+impl<T: Send> AsyncConsumer<T> {
+    /// Waits for the next message without blocking the runtime. Fails once
+    /// the producer is gone and everything it sent has been received.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        tokio::task::consume_budget().await;
+        self.consumer.recv_async().await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.consumer.try_recv()
+    }
+
+    pub fn close(&self) {
+        self.consumer.close()
+    }
+
+    pub fn is_producer_alive(&self) -> bool {
+        self.consumer.is_producer_alive()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.consumer.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.consumer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumer.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.consumer.is_full()
+    }
+
+    /// Gives back the plain consumer, e.g. to block on it after all.
+    pub fn into_inner(self) -> Consumer<T> {
+        self.consumer
+    }
+}
+
+impl<T: Send> AsyncProducer<T> {
+    /// Waits for a free slot without blocking the runtime. Fails, handing
+    /// `val` back, once the consumer is gone.
+    pub async fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        tokio::task::consume_budget().await;
+        self.producer.send_async(val).await
+    }
+
+    pub fn try_send(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.producer.try_send(val)
+    }
+
+    pub fn close(&self) {
+        self.producer.close()
+    }
+
+    pub fn is_consumer_alive(&self) -> bool {
+        self.producer.is_consumer_alive()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.producer.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.producer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.producer.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.producer.is_full()
+    }
+
+    /// Gives back the plain producer, e.g. to block on it after all.
+    pub fn into_inner(self) -> Producer<T> {
+        self.producer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn blocking_thread_wakes_task() {
+        let (mut px, mut cx) = sync_to_async(4, WaitStrategy::Park);
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(cx.recv().await.unwrap(), i);
+        }
+        assert!(cx.recv().await.is_err());
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn task_wakes_blocking_thread() {
+        let (mut px, cx) = async_to_sync(4, WaitStrategy::Park);
+        let handle = thread::spawn(move || cx.collect::<Vec<i32>>());
+        for i in 0..100 {
+            px.send(i).await.unwrap();
+        }
+        drop(px);
+        assert_eq!(handle.join().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    // A current thread runtime only gets to the other task if recv yields
+    #[tokio::test]
+    async fn full_buffer_does_not_starve_runtime() {
+        let (mut px, mut cx) = sync_to_async(1024, WaitStrategy::BusySpin);
+        for i in 0..1024 {
+            px.send(i).unwrap();
+        }
+        let other = tokio::spawn(async {});
+        let mut received = 0;
+        while !other.is_finished() {
+            cx.recv().await.unwrap();
+            received += 1;
+        }
+        assert!(received < 1024);
+    }
+}
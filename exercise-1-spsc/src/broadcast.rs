@@ -0,0 +1,348 @@
+//! A channel where every consumer receives every message.
+//!
+//! Consumers are cloned from one another (or subscribed through the
+//! producer), and each one keeps its own read index into the shared ring and
+//! clones the messages out of it. The producer does not wait for them to make
+//! room: a consumer that falls more than the capacity behind finds its oldest
+//! messages overwritten, and its next receive reports how many it missed
+//! before it carries on with the oldest message still there. The one wait
+//! left is for a consumer that is cloning the very message a send
+//! overwrites, see `Producer::send`.
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::RwLock;
+
+use crate::index;
+use crate::primitives::{Arc, AtomicBool, AtomicUsize, CachePadded, Ordering};
+use crate::ring;
+use crate::wait_queue::WaitQueue;
+use crate::SendError;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The consumer fell behind, this many messages were overwritten before
+    /// it got to them. The next receive returns the oldest one left.
+    Lagged(usize),
+    /// The producer is gone and all messages have been received.
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No new message right now.
+    Empty,
+    /// See `RecvError::Lagged`.
+    Lagged(usize),
+    /// The producer is gone and all messages have been received.
+    Disconnected,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Lagged(missed) => {
+                write!(f, "receiver lagged behind, missed {missed} messages")
+            }
+            RecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Lagged(missed) => RecvError::Lagged(*missed).fmt(f),
+            TryRecvError::Disconnected => RecvError::Disconnected.fmt(f),
+        }
+    }
+}
+
+impl Error for RecvError {}
+impl Error for TryRecvError {}
+
+// A message along with its position, which tells the consumers whether it is
+// still the one they are looking for. The lock keeps the producer from
+// dropping a message while a consumer clones it. A seqlock, which checks a
+// stamp after the clone instead, would let the clone run on a message that
+// is being dropped, and T::clone may follow its pointers into freed memory.
+type Slot<T> = RwLock<Option<(usize, T)>>;
+
+struct Shared<T> {
+    slots: Box<[Slot<T>]>,
+    write_index: CachePadded<AtomicUsize>,
+    producer_alive: AtomicBool,
+    consumer_counter: AtomicUsize,
+    // consumers waiting until a message arrives or the producer goes away
+    consumers: WaitQueue,
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    // only one thread may send, and send takes &mut self like everywhere
+    // else
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    // the position of the next message this consumer receives
+    read_index: usize,
+}
+
+/// Creates a broadcast channel that keeps the last `capacity` messages for
+/// consumers that are behind.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    ring::check_capacity(capacity);
+    let shared = Arc::new(Shared {
+        slots: (0..capacity).map(|_| RwLock::new(None)).collect(),
+        write_index: CachePadded::new(AtomicUsize::new(0)),
+        producer_alive: AtomicBool::new(true),
+        consumer_counter: AtomicUsize::new(1),
+        consumers: WaitQueue::new(),
+    });
+    let producer = Producer {
+        shared: shared.clone(),
+        _not_sync: PhantomData,
+    };
+    (
+        producer,
+        Consumer {
+            shared,
+            read_index: 0,
+        },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Sends `val` to every consumer without waiting for any of them,
+    /// overwriting the oldest message once the buffer is full. Fails if
+    /// there is no consumer.
+    ///
+    /// The only time this blocks is when a consumer is cloning the message
+    /// about to be overwritten, which it is allowed to finish first. That
+    /// lasts as long as a `T::clone`, so with a slow clone a consumer that
+    /// lags by the whole capacity holds up the producer for a moment.
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        if shared.consumer_counter.load(Ordering::Acquire) == 0 {
+            return Err(SendError(val));
+        }
+        // we are the only writer of write_index
+        let position = shared.write_index.load(Ordering::Relaxed);
+        let slot = &shared.slots[index::slot(position, shared.slots.len())];
+        let overwritten = slot.write().unwrap().replace((position, val));
+        shared
+            .write_index
+            .store(index::advance(position, 1), Ordering::Release);
+        shared.consumers.notify();
+        // outside of the lock, T::drop may be slow
+        drop(overwritten);
+        Ok(())
+    }
+
+    /// Returns a new consumer that receives the messages sent from now on.
+    pub fn subscribe(&self) -> Consumer<T> {
+        let shared = &self.shared;
+        shared.consumer_counter.fetch_add(1, Ordering::Relaxed);
+        Consumer {
+            shared: shared.clone(),
+            read_index: shared.write_index.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns how many consumers there are right now.
+    pub fn consumer_count(&self) -> usize {
+        self.shared.consumer_counter.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T: Clone> Consumer<T> {
+    /// Returns a clone of the next message if there is one right now.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        // Before write_index: once the producer is gone, the index we load
+        // is its last one
+        let producer_alive = shared.producer_alive.load(Ordering::Acquire);
+        let write_index = shared.write_index.load(Ordering::Acquire);
+        let queued = index::len(self.read_index, write_index);
+        if queued == 0 {
+            return Err(if producer_alive {
+                TryRecvError::Empty
+            } else {
+                TryRecvError::Disconnected
+            });
+        }
+        if queued > shared.slots.len() {
+            return Err(TryRecvError::Lagged(self.catch_up(write_index)));
+        }
+
+        let slot = shared.slots[index::slot(self.read_index, shared.slots.len())]
+            .read()
+            .unwrap();
+        match &*slot {
+            Some((position, val)) if *position == self.read_index => {
+                let val = val.clone();
+                drop(slot);
+                self.read_index = index::advance(self.read_index, 1);
+                Ok(val)
+            }
+            // overwritten since we looked at write_index
+            _ => {
+                drop(slot);
+                let write_index = shared.write_index.load(Ordering::Acquire);
+                Err(TryRecvError::Lagged(self.catch_up(write_index)))
+            }
+        }
+    }
+
+    /// Waits for the next message and returns a clone of it.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Lagged(missed)) => return Err(RecvError::Lagged(missed)),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {
+                    let shared = &*self.shared;
+                    let read_index = self.read_index;
+                    shared.consumers.wait(None, || {
+                        !shared.producer_alive.load(Ordering::Acquire)
+                            || shared.write_index.load(Ordering::Acquire) != read_index
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<T> Consumer<T> {
+    // Skips to the oldest message that is still there, returns how many
+    // were skipped
+    fn catch_up(&mut self, write_index: usize) -> usize {
+        let missed = index::len(self.read_index, write_index) - self.shared.slots.len();
+        self.read_index = index::advance(self.read_index, missed);
+        missed
+    }
+
+    /// Returns how many messages this consumer has not received yet, lost
+    /// ones included.
+    pub fn len(&self) -> usize {
+        index::len(
+            self.read_index,
+            self.shared.write_index.load(Ordering::Acquire),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The clone receives the same messages from here on
+impl<T> Clone for Consumer<T> {
+    fn clone(&self) -> Self {
+        self.shared.consumer_counter.fetch_add(1, Ordering::Relaxed);
+        Consumer {
+            shared: self.shared.clone(),
+            read_index: self.read_index,
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.consumer_counter.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn every_consumer_sees_every_message() {
+        let (mut px, cx) = channel(4);
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let mut cx = cx.clone();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    loop {
+                        match cx.recv() {
+                            Ok(val) => received.push(val),
+                            // a slow thread may miss some
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Disconnected) => return received,
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(cx);
+
+        for i in 0..1000 {
+            px.send(i).unwrap();
+        }
+        drop(px);
+        for handle in handles {
+            let received = handle.join().unwrap();
+            // in order, and the last messages are never lost
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(received.last(), Some(&999));
+        }
+    }
+
+    #[test]
+    fn lagging_consumer_skips_to_oldest() {
+        let (mut px, mut cx) = channel(4);
+        for i in 0..10 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.len(), 10);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Lagged(6)));
+        assert_eq!(
+            RecvError::Lagged(6).to_string(),
+            "receiver lagged behind, missed 6 messages"
+        );
+        let rest: Vec<_> = (0..4).map(|_| cx.try_recv().unwrap()).collect();
+        assert_eq!(rest, [6, 7, 8, 9]);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn subscriber_starts_at_next_message() {
+        let (mut px, mut cx) = channel(4);
+        px.send(1).unwrap();
+        let mut late = px.subscribe();
+        px.send(2).unwrap();
+        assert_eq!(px.consumer_count(), 2);
+
+        assert_eq!(cx.try_recv(), Ok(1));
+        assert_eq!(cx.try_recv(), Ok(2));
+        assert_eq!(late.try_recv(), Ok(2));
+        drop(px);
+        assert_eq!(late.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn send_fails_without_consumers() {
+        let (mut px, cx) = channel(4);
+        drop(cx);
+        assert!(px.send(1).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// All the options of a channel in one place. The free functions like
+// `channel_with_capacity` stay as shortcuts for the common cases.
+
+#[cfg(not(loom))]
+use crate::allocator::BufferAllocator;
+#[cfg(all(feature = "memory", not(loom)))]
+use crate::memory::Memory;
+use crate::ring::{self, State};
+use crate::{Consumer, Producer, WaitStrategy, BUFFER_SIZE, SPSC};
+
+/// Configures a channel before creating it, e.g.
+///
+/// ```
+/// use spsc::{ChannelBuilder, WaitStrategy};
+///
+/// let (mut px, mut cx) = ChannelBuilder::new()
+///     .capacity(1024)
+///     .wait_strategy(WaitStrategy::Yield)
+///     .label("frames")
+///     .build();
+/// px.try_send(1).unwrap();
+/// assert_eq!(cx.try_recv(), Ok(1));
+/// ```
+///
+/// What is not set is as for `channel`: 4096 slots, parking on a full or
+/// empty buffer, no label, a single producer.
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelBuilder {
+    capacity: usize,
+    wait_strategy: WaitStrategy,
+    label: Option<&'static str>,
+    multi_producer: bool,
+    #[cfg(all(feature = "memory", not(loom)))]
+    memory: Option<Memory>,
+}
+
+impl ChannelBuilder {
+    pub fn new() -> Self {
+        ChannelBuilder {
+            capacity: BUFFER_SIZE,
+            wait_strategy: WaitStrategy::default(),
+            label: None,
+            multi_producer: false,
+            #[cfg(all(feature = "memory", not(loom)))]
+            memory: None,
+        }
+    }
+
+    /// The number of messages the buffer holds, a power of two.
+    pub fn capacity(self, capacity: usize) -> Self {
+        ChannelBuilder { capacity, ..self }
+    }
+
+    /// How blocked calls wait, see `WaitStrategy`.
+    pub fn wait_strategy(self, wait_strategy: WaitStrategy) -> Self {
+        ChannelBuilder {
+            wait_strategy,
+            ..self
+        }
+    }
+
+    /// Names the channel in its Debug output and, with the `tracing`
+    /// feature, in its spans and events.
+    pub fn label(self, label: &'static str) -> Self {
+        ChannelBuilder {
+            label: Some(label),
+            ..self
+        }
+    }
+
+    /// Lets the producer be cloned, see `channel_mpsc`.
+    pub fn multi_producer(self) -> Self {
+        ChannelBuilder {
+            multi_producer: true,
+            ..self
+        }
+    }
+
+    /// Allocates the buffer as `memory` says, see `channel_with_memory`.
+    #[cfg(all(feature = "memory", not(loom)))]
+    pub fn memory(self, memory: Memory) -> Self {
+        ChannelBuilder {
+            memory: Some(memory),
+            ..self
+        }
+    }
+
+    /// Creates the channel.
+    ///
+    /// Panics if the capacity is 0 or not a power of two.
+    pub fn build<T: Send>(self) -> (Producer<T>, Consumer<T>) {
+        ring::check_capacity(self.capacity);
+        #[cfg(all(feature = "memory", target_os = "linux", not(loom)))]
+        if let Some(memory) = self.memory {
+            if let Some(spsc) = SPSC::mapped(self.capacity, memory, self.state()) {
+                return (spsc.producer, spsc.consumer);
+            }
+        }
+        let spsc: SPSC<T> = SPSC::allocate(self.capacity, self.state());
+        (spsc.producer, spsc.consumer)
+    }
+
+    /// Like `build`, with the buffer allocated by `allocator` rather than
+    /// as `memory` says, see `channel_with_allocator`.
+    #[cfg(not(loom))]
+    pub fn build_with_allocator<T: Send>(
+        self,
+        allocator: impl BufferAllocator,
+    ) -> (Producer<T>, Consumer<T>) {
+        let spsc: SPSC<T> = SPSC::allocated(self.capacity, allocator, self.state());
+        (spsc.producer, spsc.consumer)
+    }
+
+    // The state of a new channel with these options
+    fn state(&self) -> State {
+        State {
+            label: self.label,
+            written: self
+                .multi_producer
+                .then(|| State::written_marks(self.capacity)),
+            ..State::new(self.wait_strategy)
+        }
+    }
+}
+
+impl Default for ChannelBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
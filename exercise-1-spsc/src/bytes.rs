@@ -0,0 +1,253 @@
+//! A channel of bytes behind `std::io::Write` and `std::io::Read`.
+//!
+//! Bytes are copied straight between the caller's buffers and the slots of
+//! the ring, a whole run of them at once, instead of sending every chunk as
+//! an allocation of its own.
+//!
+//! `FrameWriter` and `FrameReader` keep the boundaries between messages on
+//! top of that: each frame goes through as its length, 4 bytes little
+//! endian, followed by the bytes themselves.
+
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use crate::{channel_with_capacity, Consumer, Producer};
+
+/// The writing end of `pipe`.
+pub struct PipeWriter {
+    producer: Producer<u8>,
+}
+
+/// The reading end of `pipe`.
+pub struct PipeReader {
+    consumer: Consumer<u8>,
+}
+
+/// Creates a pipe that buffers up to `capacity` bytes, which must be a power
+/// of two.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    let (producer, consumer) = channel_with_capacity(capacity);
+    (PipeWriter { producer }, PipeReader { consumer })
+}
+
+/// Writes length-prefixed frames, see `FrameReader`.
+pub struct FrameWriter<W = PipeWriter> {
+    writer: W,
+}
+
+/// Reads the frames a `FrameWriter` wrote, one whole frame at a time.
+pub struct FrameReader<R = PipeReader> {
+    reader: R,
+}
+
+/// Creates a pipe of `capacity` bytes, a power of two, that passes frames.
+pub fn frame_pipe(capacity: usize) -> (FrameWriter, FrameReader) {
+    let (writer, reader) = pipe(capacity);
+    (FrameWriter::new(writer), FrameReader::new(reader))
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        FrameWriter { writer }
+    }
+
+    /// Writes `frame` whole, waiting for room as often as it takes, so it
+    /// may be larger than the pipe. Fails with `InvalidInput` for a frame
+    /// of 4 GiB or more, and as the writer does.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(frame)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader { reader }
+    }
+
+    /// Reads the next frame into `frame`, replacing what it held. Returns
+    /// false once the writer is gone and every frame is read; a frame cut
+    /// short fails with `UnexpectedEof`.
+    pub fn read_frame(&mut self, frame: &mut Vec<u8>) -> io::Result<bool> {
+        let mut header = [0; 4];
+        if !self.read_header(&mut header)? {
+            return Ok(false);
+        }
+        frame.clear();
+        frame.resize(u32::from_le_bytes(header) as usize, 0);
+        self.reader.read_exact(frame)?;
+        Ok(true)
+    }
+
+    // Like read_exact, but false if the stream ends before the first byte
+    fn read_header(&mut self, header: &mut [u8; 4]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+// Copies as much of src as fits into the runs, returns how much that was
+fn copy_into(src: &[u8], runs: [&mut [MaybeUninit<u8>]; 2]) -> usize {
+    let mut copied = 0;
+    for run in runs {
+        let count = run.len().min(src.len() - copied);
+        // distinct allocations, and count is within both
+        unsafe {
+            ptr::copy_nonoverlapping(src[copied..].as_ptr(), run.as_mut_ptr().cast(), count);
+        }
+        copied += count;
+    }
+    copied
+}
+
+// Copies as much of the runs as fits into dst, returns how much that was
+fn copy_from(runs: [&[u8]; 2], dst: &mut [u8]) -> usize {
+    let mut copied = 0;
+    for run in runs {
+        let count = run.len().min(dst.len() - copied);
+        // distinct allocations, and count is within both
+        unsafe {
+            ptr::copy_nonoverlapping(run.as_ptr(), dst[copied..].as_mut_ptr(), count);
+        }
+        copied += count;
+    }
+    copied
+}
+
+impl Write for PipeWriter {
+    // Blocks until there is room for at least one byte. Fails with
+    // BrokenPipe once the reader is gone.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut vacant = self
+            .producer
+            .vacant_slices()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let (first, second) = vacant.as_mut_slices();
+        let written = copy_into(buf, [first, second]);
+        // copy_into initialized the first written slots
+        unsafe { vacant.commit(written) };
+        Ok(written)
+    }
+
+    // Written bytes are in the pipe already, there is nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for PipeReader {
+    // Blocks until there is at least one byte. Returns 0 once the writer is
+    // gone and everything it wrote has been read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let Ok(occupied) = self.consumer.occupied_slices() else {
+            return Ok(0);
+        };
+        let (first, second) = occupied.as_slices();
+        let read = copy_from([first, second], buf);
+        occupied.release(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn bytes_arrive_in_order() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let (mut writer, mut reader) = pipe(64);
+        let expected = data.clone();
+        let handle = thread::spawn(move || writer.write_all(&data).unwrap());
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn partial_reads_and_writes_wrap_around() {
+        let (mut writer, mut reader) = pipe(8);
+        assert_eq!(writer.write(b"abcdef").unwrap(), 6);
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        // only 6 are free, across the end of the buffer
+        assert_eq!(writer.write(b"ghijklmnop").unwrap(), 6);
+
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        assert_eq!(&buf[..8], b"efghijkl");
+    }
+
+    #[test]
+    fn frames_keep_their_boundaries() {
+        let (mut writer, mut reader) = frame_pipe(16);
+        let handle = thread::spawn(move || {
+            // from empty to several times the pipe, across its end
+            for len in 0..100 {
+                writer.write_frame(&vec![len as u8; len]).unwrap();
+            }
+        });
+        let mut frame = vec![0xff; 3];
+        for len in 0..100 {
+            assert!(reader.read_frame(&mut frame).unwrap());
+            assert_eq!(frame, vec![len as u8; len]);
+        }
+        handle.join().unwrap();
+        assert!(!reader.read_frame(&mut frame).unwrap());
+    }
+
+    #[test]
+    fn frame_cut_short_is_an_error() {
+        let (mut writer, reader) = pipe(16);
+        let mut reader = FrameReader::new(reader);
+        writer.write_all(&[8, 0, 0, 0, 1, 2]).unwrap();
+        drop(writer);
+        let err = reader.read_frame(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn closed_ends_are_reported() {
+        let (mut writer, reader) = pipe(8);
+        drop(reader);
+        assert_eq!(
+            writer.write(b"x").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        let (writer, mut reader) = pipe(8);
+        drop(writer);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,288 @@
+// Cancelling blocked calls from another thread. A token is shared by its
+// clones; cancelling any of them sets the flag and notifies the queue of
+// the token, which the blocked calls wait on along with the queue of their
+// channel. The flag is checked the same way a deadline is, between
+// attempts, so a call that can go through still does.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::primitives::{Arc, AtomicBool, AtomicUsize, Ordering};
+use crate::wait_queue::WaitQueue;
+
+/// Cancels the `send_cancellable` and `recv_cancellable` calls it was
+/// passed to, which then return `Cancelled` instead of waiting on.
+///
+/// Clones share the same state, so one can be moved to another thread to
+/// cancel from there. Cancelling is for good: a token can not be reset, a
+/// new one is needed for the next calls.
+///
+/// ```
+/// use std::thread;
+///
+/// let (_px, mut cx) = spsc::channel_with_capacity::<u32>(4);
+/// let token = spsc::CancelToken::new();
+/// let canceller = token.clone();
+/// let handle = thread::spawn(move || canceller.cancel());
+/// assert_eq!(cx.recv_cancellable(&token), Err(spsc::RecvCancelError::Cancelled));
+/// handle.join().unwrap();
+/// ```
+#[derive(Clone)]
+pub struct CancelToken {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    cancelled: AtomicBool,
+    // the threads and tasks blocked on calls with the token
+    waiters: WaitQueue,
+    // the key of the next CancelledFuture in waiters
+    next_key: AtomicUsize,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken {
+            shared: Arc::new(Shared {
+                cancelled: AtomicBool::new(false),
+                waiters: WaitQueue::new(),
+                next_key: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Cancels every call waiting with the token, now or later.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Release);
+        self.shared.waiters.notify();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::Acquire)
+    }
+
+    /// A future that completes once the token is cancelled, to race async
+    /// calls against it.
+    pub fn cancelled(&self) -> CancelledFuture<'_> {
+        CancelledFuture {
+            token: self,
+            key: self.shared.next_key.fetch_add(1, Ordering::Relaxed),
+            registered: false,
+        }
+    }
+
+    pub(crate) fn waiters(&self) -> &WaitQueue {
+        &self.shared.waiters
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Future of `CancelToken::cancelled`.
+#[must_use = "futures do nothing unless polled"]
+pub struct CancelledFuture<'a> {
+    token: &'a CancelToken,
+    // the waker is registered under key, which is the future's alone, so
+    // polling again replaces it and dropping the future takes it out
+    key: usize,
+    registered: bool,
+}
+
+impl Future for CancelledFuture<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if this.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        this.token.waiters().register_keyed(this.key, cx.waker());
+        this.registered = true;
+        // a cancel between the check and the registration did not see us
+        if this.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for CancelledFuture<'_> {
+    fn drop(&mut self) {
+        if self.registered {
+            self.token.waiters().deregister(self.key);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    // the std one, which Wake is for, whatever primitives has
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::task::noop_waker;
+
+    use super::*;
+    use crate::{channel_with_capacity, RecvCancelError, SendCancelError};
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn a_blocked_recv_returns_on_cancel() {
+        let (_px, mut cx) = channel_with_capacity::<u32>(4);
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        assert_eq!(cx.recv_cancellable(&token), Err(RecvCancelError::Cancelled));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn a_blocked_send_returns_its_message_on_cancel() {
+        let (mut px, _cx) = channel_with_capacity(1);
+        px.send(1).unwrap();
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let err = px.send_cancellable(2, &token).unwrap_err();
+        assert!(err.is_cancelled());
+        assert_eq!(err.into_inner(), 2);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn a_cancelled_token_still_lets_ready_calls_through() {
+        let (mut px, mut cx) = channel_with_capacity(1);
+        let token = CancelToken::new();
+        token.cancel();
+        px.send_cancellable(1, &token).unwrap();
+        assert!(matches!(
+            px.send_cancellable(2, &token),
+            Err(SendCancelError::Cancelled(2))
+        ));
+        assert_eq!(cx.recv_cancellable(&token), Ok(1));
+        assert_eq!(cx.recv_cancellable(&token), Err(RecvCancelError::Cancelled));
+        drop(px);
+        assert_eq!(
+            cx.recv_cancellable(&token),
+            Err(RecvCancelError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn the_future_completes_on_cancel() {
+        let token = CancelToken::new();
+        let mut cancelled = token.cancelled();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(Pin::new(&mut cancelled).poll(&mut cx), Poll::Pending);
+        token.clone().cancel();
+        assert_eq!(Pin::new(&mut cancelled).poll(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    fn every_pending_future_is_woken() {
+        let token = CancelToken::new();
+        let flags: Vec<_> = (0..3)
+            .map(|_| Arc::new(Flag(AtomicBool::new(false))))
+            .collect();
+        let mut futures: Vec<_> = flags.iter().map(|_| token.cancelled()).collect();
+        for (future, flag) in futures.iter_mut().zip(&flags) {
+            let waker = Waker::from(flag.clone());
+            let mut cx = Context::from_waker(&waker);
+            assert_eq!(Pin::new(future).poll(&mut cx), Poll::Pending);
+        }
+        token.cancel();
+        assert!(flags.iter().all(|flag| flag.0.load(Ordering::SeqCst)));
+    }
+
+    #[test]
+    fn a_future_keeps_one_waker_and_takes_it_out_on_drop() {
+        let token = CancelToken::new();
+        let flags = [
+            Arc::new(Flag(AtomicBool::new(false))),
+            Arc::new(Flag(AtomicBool::new(false))),
+        ];
+        let mut cancelled = token.cancelled();
+        for flag in &flags {
+            let waker = Waker::from(flag.clone());
+            let mut cx = Context::from_waker(&waker);
+            assert_eq!(Pin::new(&mut cancelled).poll(&mut cx), Poll::Pending);
+        }
+        // the old waker is gone, the new one is woken
+        assert_eq!(Arc::strong_count(&flags[0]), 1);
+        token.cancel();
+        assert!(!flags[0].0.load(Ordering::SeqCst));
+        assert!(flags[1].0.load(Ordering::SeqCst));
+
+        let token = CancelToken::new();
+        let mut cancelled = token.cancelled();
+        let waker = Waker::from(flags[0].clone());
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(Pin::new(&mut cancelled).poll(&mut cx), Poll::Pending);
+        assert!(token.waiters().has_waiters());
+        drop(cancelled);
+        assert!(!token.waiters().has_waiters());
+    }
+
+    #[test]
+    fn pending_async_calls_return_on_cancel() {
+        let waker = noop_waker();
+        let mut task = Context::from_waker(&waker);
+        let token = CancelToken::new();
+
+        let (mut px, mut cx) = channel_with_capacity(1);
+        let mut recv = cx.recv_async_cancellable(&token);
+        assert!(Pin::new(&mut recv).poll(&mut task).is_pending());
+        token.cancel();
+        assert_eq!(
+            Pin::new(&mut recv).poll(&mut task),
+            Poll::Ready(Err(RecvCancelError::Cancelled))
+        );
+        drop(recv);
+
+        let token = CancelToken::new();
+        px.send(1).unwrap();
+        let mut send = px.send_async_cancellable(2, &token);
+        assert!(Pin::new(&mut send).poll(&mut task).is_pending());
+        token.cancel();
+        assert!(matches!(
+            Pin::new(&mut send).poll(&mut task),
+            Poll::Ready(Err(SendCancelError::Cancelled(2)))
+        ));
+        drop(send);
+
+        // a message that is there is still received
+        let mut recv = cx.recv_async_cancellable(&token);
+        assert_eq!(Pin::new(&mut recv).poll(&mut task), Poll::Ready(Ok(1)));
+    }
+}
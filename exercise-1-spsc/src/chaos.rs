@@ -0,0 +1,176 @@
+// Chaos mode (the `chaos` feature): every operation on an atomic of the
+// channels and every access to a slot may yield the thread or sleep for a
+// moment first and after, at random. The threads of a test then interleave
+// in many more ways than they would on their own, which is what finds the
+// races loom would, on machines or in tests where loom is not practical.
+//
+// The choices are drawn from a generator per thread, seeded from
+// SPSC_CHAOS_SEED (0 if unset) and the order in which the threads first hit
+// a chaos point. A failure with a seed repeats the same choices, though the
+// scheduler may still run the threads differently.
+//
+//     SPSC_CHAOS_SEED=7 cargo test --features chaos
+
+use core::cell::Cell;
+use core::fmt;
+// not the wrappers below, which would recurse
+use core::sync::atomic::{self, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+fn seed() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        std::env::var("SPSC_CHAOS_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(0)
+    })
+}
+
+thread_local! {
+    static RNG: Cell<u64> = {
+        static THREADS: atomic::AtomicU64 = atomic::AtomicU64::new(0);
+        let thread = THREADS.fetch_add(1, Ordering::Relaxed);
+        // xorshift never leaves 0
+        Cell::new((seed() ^ thread.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    };
+}
+
+// xorshift
+fn next() -> Option<u64> {
+    // None while the thread is being torn down
+    RNG.try_with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+    .ok()
+}
+
+// Yields one time in 8, and sleeps up to 20 µs one time in 512. Short
+// enough for the tests with timeouts to pass.
+pub(crate) fn point() {
+    let Some(x) = next() else { return };
+    if x % 512 == 0 {
+        thread::sleep(Duration::from_micros(x >> 32 & 15));
+    } else if x % 8 == 0 {
+        thread::yield_now();
+    }
+}
+
+// The atomics of core, with a chaos point before and after each operation
+macro_rules! atomic {
+    ($name:ident, $ty:ty) => {
+        #[repr(transparent)]
+        #[derive(Default)]
+        pub(crate) struct $name(atomic::$name);
+
+        #[allow(dead_code)]
+        impl $name {
+            pub(crate) const fn new(val: $ty) -> Self {
+                $name(atomic::$name::new(val))
+            }
+
+            pub(crate) fn get_mut(&mut self) -> &mut $ty {
+                self.0.get_mut()
+            }
+
+            pub(crate) fn into_inner(self) -> $ty {
+                self.0.into_inner()
+            }
+
+            pub(crate) fn load(&self, order: Ordering) -> $ty {
+                chaotic(|| self.0.load(order))
+            }
+
+            pub(crate) fn store(&self, val: $ty, order: Ordering) {
+                chaotic(|| self.0.store(val, order))
+            }
+
+            pub(crate) fn swap(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.swap(val, order))
+            }
+
+            pub(crate) fn compare_exchange(
+                &self,
+                current: $ty,
+                new: $ty,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$ty, $ty> {
+                chaotic(|| self.0.compare_exchange(current, new, success, failure))
+            }
+
+            pub(crate) fn compare_exchange_weak(
+                &self,
+                current: $ty,
+                new: $ty,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$ty, $ty> {
+                chaotic(|| self.0.compare_exchange_weak(current, new, success, failure))
+            }
+
+            pub(crate) fn fetch_or(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_or(val, order))
+            }
+
+            pub(crate) fn fetch_and(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_and(val, order))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+// The same, for the arithmetic of the integers
+macro_rules! atomic_int {
+    ($name:ident, $ty:ty) => {
+        atomic!($name, $ty);
+
+        #[allow(dead_code)]
+        impl $name {
+            pub(crate) fn fetch_add(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_add(val, order))
+            }
+
+            pub(crate) fn fetch_sub(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_sub(val, order))
+            }
+
+            pub(crate) fn fetch_max(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_max(val, order))
+            }
+
+            pub(crate) fn fetch_min(&self, val: $ty, order: Ordering) -> $ty {
+                chaotic(|| self.0.fetch_min(val, order))
+            }
+        }
+    };
+}
+
+atomic!(AtomicBool, bool);
+atomic_int!(AtomicUsize, usize);
+#[cfg(any(feature = "stats", feature = "latency"))]
+atomic_int!(AtomicU64, u64);
+
+pub(crate) fn fence(order: Ordering) {
+    chaotic(|| atomic::fence(order))
+}
+
+pub(crate) fn chaotic<R>(f: impl FnOnce() -> R) -> R {
+    point();
+    let result = f();
+    point();
+    result
+}
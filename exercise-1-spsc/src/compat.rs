@@ -0,0 +1,43 @@
+// Names matching std::sync::mpsc, so simple users of a bounded std channel
+// can switch to this crate by changing the import, and declaring the
+// handles `mut`: sending and receiving take `&mut self` here.
+
+pub use crate::{
+    RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+
+pub type Sender<T> = crate::Producer<T>;
+pub type Receiver<T> = crate::Consumer<T>;
+
+/// Counterpart of `std::sync::mpsc::sync_channel`. Unlike std, `bound` must
+/// be a power of two, and 0 (a rendezvous channel) is not supported either.
+pub fn sync_channel<T: Send>(bound: usize) -> (Sender<T>, Receiver<T>) {
+    crate::channel_with_capacity(bound)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // the example from the std::sync::mpsc module documentation
+    #[test]
+    fn mpsc_doc_example() {
+        let (mut tx, mut rx) = sync_channel(1);
+
+        thread::spawn(move || {
+            tx.send(10).unwrap();
+        });
+
+        assert_eq!(rx.recv().unwrap(), 10);
+    }
+
+    #[test]
+    fn disconnected_sender() {
+        let (mut tx, rx) = sync_channel::<i32>(1);
+        drop(rx);
+        let err: SendError<i32> = tx.send(1).unwrap_err();
+        assert_eq!(err.0, 1);
+    }
+}
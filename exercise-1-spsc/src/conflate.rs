@@ -0,0 +1,229 @@
+//! A channel that conflates messages by key, e.g. quotes per instrument in
+//! a market data feed, where only the latest one of each is of interest.
+//!
+//! Every message is sent under a key. While a message of that key is still
+//! queued, a new one replaces it in place, so it keeps the position of the
+//! first and the consumer gets the latest value once per key. Messages of
+//! different keys arrive in the order their keys were first queued. Like
+//! `watch` for a set of values, the buffer grows with the number of keys
+//! that are queued at once, and a send never waits.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::primitives::{Arc, AtomicBool, Ordering};
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+
+struct Shared<K, V> {
+    queue: Mutex<Queue<K, V>>,
+    producer_alive: AtomicBool,
+    consumer_alive: AtomicBool,
+    // the consumer, waiting for a message or the producer to go away
+    consumers: WaitQueue,
+}
+
+// The keys in the order they were queued, and the latest value of each
+struct Queue<K, V> {
+    order: VecDeque<K>,
+    values: HashMap<K, V>,
+}
+
+pub struct Producer<K, V> {
+    shared: Arc<Shared<K, V>>,
+}
+
+pub struct Consumer<K, V> {
+    shared: Arc<Shared<K, V>>,
+}
+
+/// Creates a conflating channel.
+pub fn channel<K: Eq + Hash + Clone, V>() -> (Producer<K, V>, Consumer<K, V>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            order: VecDeque::new(),
+            values: HashMap::new(),
+        }),
+        producer_alive: AtomicBool::new(true),
+        consumer_alive: AtomicBool::new(true),
+        consumers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<K: Eq + Hash + Clone, V> Producer<K, V> {
+    /// Queues `val` under `key`, or replaces the value still queued under
+    /// it and returns that one. Fails if the consumer is gone.
+    pub fn send(&self, key: K, val: V) -> Result<Option<V>, SendError<(K, V)>> {
+        let shared = &*self.shared;
+        if !shared.consumer_alive.load(Ordering::Acquire) {
+            return Err(SendError((key, val)));
+        }
+        let replaced = {
+            let mut queue = shared.queue.lock().unwrap();
+            let queue = &mut *queue;
+            match queue.values.get_mut(&key) {
+                Some(queued) => Some(std::mem::replace(queued, val)),
+                None => {
+                    queue.order.push_back(key.clone());
+                    queue.values.insert(key, val);
+                    None
+                }
+            }
+        };
+        // a replacement does not make another message
+        if replaced.is_none() {
+            shared.consumers.notify();
+        }
+        Ok(replaced)
+    }
+
+    /// Returns whether the consumer is gone, so every send fails.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.consumer_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Consumer<K, V> {
+    /// Receives the key that was queued first along with its latest value,
+    /// if there is one.
+    pub fn try_recv(&self) -> Result<(K, V), TryRecvError> {
+        let shared = &*self.shared;
+        // Before the queue: once the producer is gone, the queue we look at
+        // holds all it sent
+        let producer_alive = shared.producer_alive.load(Ordering::Acquire);
+        let mut queue = shared.queue.lock().unwrap();
+        match queue.order.pop_front() {
+            Some(key) => {
+                let val = queue.values.remove(&key).expect("a queued key has a value");
+                Ok((key, val))
+            }
+            None if producer_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Waits for a message, see `try_recv`. Fails once the producer is gone
+    /// and the queue is drained.
+    pub fn recv(&self) -> Result<(K, V), RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<(K, V), RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<(K, V), RecvTimeoutError> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_recv() {
+                Ok(msg) => return Ok(msg),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            shared.consumers.wait(deadline, || {
+                !shared.producer_alive.load(Ordering::Acquire)
+                    || !shared.queue.lock().unwrap().order.is_empty()
+            });
+        }
+    }
+
+    /// Returns how many keys are queued right now.
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the producer is gone, so no more messages arrive
+    /// than those queued.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.producer_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Iterator for Consumer<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.recv().ok()
+    }
+}
+
+impl<K, V> Drop for Producer<K, V> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<K, V> Drop for Consumer<K, V> {
+    fn drop(&mut self) {
+        self.shared.consumer_alive.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn latest_value_keeps_the_first_position() {
+        let (px, cx) = channel();
+        assert_eq!(px.send("a", 1), Ok(None));
+        assert_eq!(px.send("b", 1), Ok(None));
+        assert_eq!(px.send("a", 2), Ok(Some(1)));
+        assert_eq!(px.send("c", 1), Ok(None));
+        assert_eq!(cx.len(), 3);
+
+        assert_eq!(cx.try_recv(), Ok(("a", 2)));
+        // received, so a new one queues up behind the others
+        assert_eq!(px.send("a", 3), Ok(None));
+        drop(px);
+        assert_eq!(cx.collect::<Vec<_>>(), [("b", 1), ("c", 1), ("a", 3)]);
+    }
+
+    #[test]
+    fn recv_waits_for_a_key() {
+        let (px, cx) = channel();
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                px.send(i % 4, i).unwrap();
+            }
+        });
+        // per key, the values only ever move forward
+        let mut last = [None; 4];
+        while let Ok((key, val)) = cx.recv() {
+            assert!(last[key] < Some(val));
+            last[key] = Some(val);
+        }
+        producer.join().unwrap();
+        assert_eq!(last, [996, 997, 998, 999].map(Some));
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn sends_fail_once_the_consumer_is_gone() {
+        let (px, cx) = channel::<u8, u8>();
+        drop(cx);
+        assert!(px.is_disconnected());
+        assert_eq!(px.send(1, 2), Err(SendError((1, 2))));
+    }
+}
@@ -0,0 +1,362 @@
+//! A channel whose messages pass through stages in place, after the LMAX
+//! disruptor: e.g. a journaler, then a deserializer, then the handler, each
+//! on a thread of its own, without moving a message from one ring to the
+//! next.
+//!
+//! There is one ring and one cursor per participant: the producer's is its
+//! write index, each stage's the position up to which it is done. A stage
+//! only gets to the slots its predecessor is done with, so a slot belongs to
+//! exactly one of them at a time, and each stage can change the message as
+//! it goes. The consumer at the end takes the messages out, and only the
+//! slots it is past are free for the producer again. A stage works through
+//! everything its predecessor has finished in one go, and moves its cursor
+//! once for the lot.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+use crate::index;
+use crate::primitives::{Arc, AtomicBool, AtomicUsize, CachePadded, Ordering, UnsafeCell};
+use crate::ring;
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError, TryRecvError, TrySendError};
+
+// Everything is indexed by participant: the producer is 0, the stages come
+// next in order, and the consumer is last
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // the position up to which each participant is done
+    cursors: Box<[CachePadded<AtomicUsize>]>,
+    alive: Box<[AtomicBool]>,
+    // notified as the cursor of the same index moves, or its participant
+    // goes away: the next one waits on it, and the producer on the last
+    queues: Box<[WaitQueue]>,
+}
+
+impl<T> Shared<T> {
+    fn last(&self) -> usize {
+        self.cursors.len() - 1
+    }
+
+    fn slot(&self, position: usize) -> &UnsafeCell<MaybeUninit<T>> {
+        &self.slots[index::slot(position, self.slots.len())]
+    }
+
+    // Whether the producer has nobody left to take its messages to the end
+    fn is_disconnected_downstream(&self) -> bool {
+        self.alive[1..]
+            .iter()
+            .any(|alive| !alive.load(Ordering::Acquire))
+    }
+
+    // Marks the participant as gone and wakes whoever may be waiting for it
+    fn leave(&self, participant: usize) {
+        self.alive[participant].store(false, Ordering::Release);
+        self.queues[participant].notify();
+        self.queues[self.last()].notify();
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    // Whatever the producer wrote and the consumer has not taken
+    fn drop(&mut self) {
+        let read_index = self.cursors[self.last()].load(Ordering::Relaxed);
+        let write_index = self.cursors[0].load(Ordering::Relaxed);
+        for position in index::range(read_index, write_index) {
+            self.slot(position)
+                .with_mut(|slot| unsafe { (*slot).assume_init_drop() });
+        }
+    }
+}
+
+// A slot is only ever accessed by the one participant whose turn it is, see
+// the cursors
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    // one thread sends, like everywhere else
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// A stage between the producer and the consumer, see `channel`.
+pub struct Stage<T> {
+    shared: Arc<Shared<T>>,
+    participant: usize,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Creates a channel of `capacity` slots, with `stages` stages that each
+/// see every message in turn before the consumer receives it. The stages
+/// are returned in the order they see the messages.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel<T: Send>(
+    capacity: usize,
+    stages: usize,
+) -> (Producer<T>, Vec<Stage<T>>, Consumer<T>) {
+    ring::check_capacity(capacity);
+    let participants = stages + 2;
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        cursors: (0..participants)
+            .map(|_| CachePadded::new(AtomicUsize::new(0)))
+            .collect(),
+        alive: (0..participants).map(|_| AtomicBool::new(true)).collect(),
+        queues: (0..participants).map(|_| WaitQueue::new()).collect(),
+    });
+    let stages = (1..=stages)
+        .map(|participant| Stage {
+            shared: shared.clone(),
+            participant,
+        })
+        .collect();
+    let producer = Producer {
+        shared: shared.clone(),
+        _not_sync: PhantomData,
+    };
+    (
+        producer,
+        stages,
+        Consumer {
+            shared,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Waits until the consumer has made room and sends `val` to the first
+    /// stage. Fails once any stage or the consumer is gone, the message
+    /// would never make it to the end.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        let mut val = val;
+        loop {
+            match self.try_send(val) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(msg)) => return Err(SendError(msg)),
+                Err(TrySendError::Full(msg)) => val = msg,
+            }
+            let write_index = shared.cursors[0].load(Ordering::Relaxed);
+            shared.queues[shared.last()].wait(None, || {
+                let read_index = shared.cursors[shared.last()].load(Ordering::Acquire);
+                !index::is_full(read_index, write_index, shared.slots.len())
+                    || shared.is_disconnected_downstream()
+            });
+        }
+    }
+
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        if shared.is_disconnected_downstream() {
+            return Err(TrySendError::Disconnected(val));
+        }
+        // our own cursor, and the consumer's: the slot is free once it is
+        // done with it
+        let write_index = shared.cursors[0].load(Ordering::Relaxed);
+        let read_index = shared.cursors[shared.last()].load(Ordering::Acquire);
+        if index::is_full(read_index, write_index, shared.slots.len()) {
+            return Err(TrySendError::Full(val));
+        }
+        shared
+            .slot(write_index)
+            .with_mut(|slot| unsafe { (*slot).write(val) });
+        shared.cursors[0].store(index::advance(write_index, 1), Ordering::Release);
+        shared.queues[0].notify();
+        Ok(())
+    }
+
+    /// Returns whether a stage or the consumer is gone, so every send
+    /// fails.
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.is_disconnected_downstream()
+    }
+}
+
+impl<T: Send> Stage<T> {
+    /// Waits until the previous stage is done with at least one message,
+    /// then calls `f` on each message it is done with, in order, and hands
+    /// them on to the next. Returns how many there were. Fails once the
+    /// previous stage (or the producer) is gone and everything it passed on
+    /// is processed.
+    pub fn process(&mut self, mut f: impl FnMut(&mut T)) -> Result<usize, RecvError> {
+        let previous = self.participant - 1;
+        loop {
+            match self.try_process(&mut f) {
+                Ok(count) => return Ok(count),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+            let shared = &*self.shared;
+            let position = shared.cursors[self.participant].load(Ordering::Relaxed);
+            shared.queues[previous].wait(None, || {
+                shared.cursors[previous].load(Ordering::Acquire) != position
+                    || !shared.alive[previous].load(Ordering::Acquire)
+            });
+        }
+    }
+
+    /// Like `process`, but without waiting if there is nothing to do.
+    pub fn try_process(&mut self, mut f: impl FnMut(&mut T)) -> Result<usize, TryRecvError> {
+        let shared = &*self.shared;
+        let previous = self.participant - 1;
+        // Before the cursor: once the previous one is gone, the cursor we
+        // load is final
+        let previous_alive = shared.alive[previous].load(Ordering::Acquire);
+        let end = shared.cursors[previous].load(Ordering::Acquire);
+        let start = shared.cursors[self.participant].load(Ordering::Relaxed);
+        if index::is_empty(start, end) {
+            return Err(if previous_alive {
+                TryRecvError::Empty
+            } else {
+                TryRecvError::Disconnected
+            });
+        }
+        for position in index::range(start, end) {
+            // written by the producer, and ours until the cursor moves past
+            shared
+                .slot(position)
+                .with_mut(|slot| f(unsafe { (*slot).assume_init_mut() }));
+        }
+        shared.cursors[self.participant].store(end, Ordering::Release);
+        shared.queues[self.participant].notify();
+        Ok(index::len(start, end))
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Waits for a message that every stage is done with and takes it out.
+    /// Fails once the last stage is gone and everything it passed on is
+    /// received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        let previous = shared.last() - 1;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+            let position = shared.cursors[shared.last()].load(Ordering::Relaxed);
+            shared.queues[previous].wait(None, || {
+                shared.cursors[previous].load(Ordering::Acquire) != position
+                    || !shared.alive[previous].load(Ordering::Acquire)
+            });
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        let last = shared.last();
+        let previous_alive = shared.alive[last - 1].load(Ordering::Acquire);
+        let end = shared.cursors[last - 1].load(Ordering::Acquire);
+        let read_index = shared.cursors[last].load(Ordering::Relaxed);
+        if index::is_empty(read_index, end) {
+            return Err(if previous_alive {
+                TryRecvError::Empty
+            } else {
+                TryRecvError::Disconnected
+            });
+        }
+        let val = shared
+            .slot(read_index)
+            .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+        // hands the slot back to the producer
+        shared.cursors[last].store(index::advance(read_index, 1), Ordering::Release);
+        shared.queues[last].notify();
+        Ok(val)
+    }
+}
+
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.leave(0);
+    }
+}
+
+impl<T> Drop for Stage<T> {
+    fn drop(&mut self) {
+        self.shared.leave(self.participant);
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.leave(self.shared.last());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn stages_see_every_message_in_order() {
+        let (px, stages, cx) = channel(8, 2);
+        let mut stages = stages.into_iter();
+        let (mut double, mut add) = (stages.next().unwrap(), stages.next().unwrap());
+        let threads = [
+            thread::spawn(move || while double.process(|val| *val *= 2).is_ok() {}),
+            thread::spawn(move || while add.process(|val| *val += 1).is_ok() {}),
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    px.send(i).unwrap();
+                }
+            }),
+        ];
+        assert_eq!(
+            cx.collect::<Vec<_>>(),
+            (0..1000).map(|i| i * 2 + 1).collect::<Vec<_>>()
+        );
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn slots_are_free_once_the_consumer_is_past_them() {
+        let (px, mut stages, cx) = channel(2, 1);
+        px.try_send(String::from("a")).unwrap();
+        px.try_send(String::from("b")).unwrap();
+        assert!(px.try_send(String::from("c")).unwrap_err().is_full());
+        // the consumer only gets what the stage is done with
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(stages[0].try_process(|val| val.push('!')), Ok(2));
+        assert!(px.try_send(String::from("c")).unwrap_err().is_full());
+
+        assert_eq!(cx.try_recv().as_deref(), Ok("a!"));
+        px.try_send(String::from("c")).unwrap();
+        drop(px);
+        // "c" is dropped with the channel, it never made it through
+        assert_eq!(cx.try_recv().as_deref(), Ok("b!"));
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        drop(stages);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn sends_fail_once_a_stage_is_gone() {
+        let (px, mut stages, _cx) = channel::<i32>(4, 2);
+        drop(stages.pop());
+        assert!(px.is_disconnected());
+        assert_eq!(px.send(1), Err(SendError(1)));
+    }
+}
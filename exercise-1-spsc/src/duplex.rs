@@ -0,0 +1,118 @@
+// Two channels in opposite directions, bundled into one endpoint per side.
+// An endpoint owns the producer of one direction and the consumer of the
+// other, so both directions go away together: once one side is dropped (or
+// closed), the other side's sends fail and its receives end once drained.
+
+use std::time::Duration;
+
+use crate::{
+    channel_with_capacity, Consumer, Producer, RecvError, RecvTimeoutError, SendError,
+    SendTimeoutError, TryRecvError, TrySendError, BUFFER_SIZE,
+};
+
+/// One side of a duplex channel, sending `S` and receiving `R`.
+pub struct Endpoint<S: Send, R: Send> {
+    tx: Producer<S>,
+    rx: Consumer<R>,
+}
+
+/// Creates a duplex channel: the first endpoint sends `A` and receives `B`,
+/// the second the other way around, e.g. requests and their responses.
+pub fn duplex<A: Send, B: Send>() -> (Endpoint<A, B>, Endpoint<B, A>) {
+    duplex_with_capacity(BUFFER_SIZE)
+}
+
+/// Like `duplex`, with a buffer of `capacity` messages in each direction.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn duplex_with_capacity<A: Send, B: Send>(capacity: usize) -> (Endpoint<A, B>, Endpoint<B, A>) {
+    let (a_tx, a_rx) = channel_with_capacity(capacity);
+    let (b_tx, b_rx) = channel_with_capacity(capacity);
+    (
+        Endpoint { tx: a_tx, rx: b_rx },
+        Endpoint { tx: b_tx, rx: a_rx },
+    )
+}
+
+impl<S: Send, R: Send> Endpoint<S, R> {
+    pub fn send(&mut self, val: S) -> Result<(), SendError<S>> {
+        self.tx.send(val)
+    }
+
+    pub fn try_send(&mut self, val: S) -> Result<(), TrySendError<S>> {
+        self.tx.try_send(val)
+    }
+
+    pub fn send_timeout(&mut self, val: S, timeout: Duration) -> Result<(), SendTimeoutError<S>> {
+        self.tx.send_timeout(val, timeout)
+    }
+
+    pub fn recv(&mut self) -> Result<R, RecvError> {
+        self.rx.recv()
+    }
+
+    pub fn try_recv(&mut self) -> Result<R, TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<R, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+
+    /// Closes both directions without dropping the endpoint, as dropping it
+    /// would. The messages already sent can still be received on either side.
+    pub fn close(&self) {
+        self.tx.close();
+        self.rx.close();
+    }
+
+    /// Returns whether the other side is gone or either side closed. Like
+    /// `Producer::is_disconnected`, this stays true once it is.
+    pub fn is_disconnected(&self) -> bool {
+        self.tx.is_disconnected()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn requests_get_responses() {
+        let (mut client, mut server) = duplex_with_capacity::<u32, String>(4);
+        let handle = thread::spawn(move || {
+            while let Ok(request) = server.recv() {
+                server.send(request.to_string()).unwrap();
+            }
+        });
+        for i in 0..100 {
+            client.send(i).unwrap();
+            assert_eq!(client.recv().unwrap(), i.to_string());
+        }
+        drop(client);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_one_side_disconnects_both_directions() {
+        let (mut left, mut right) = duplex_with_capacity::<i32, i32>(4);
+        right.send(1).unwrap();
+        drop(right);
+        assert!(left.is_disconnected());
+        assert_eq!(left.send(2), Err(SendError(2)));
+        // what was sent before still arrives
+        assert_eq!(left.recv(), Ok(1));
+        assert_eq!(left.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn close_wakes_the_other_side() {
+        let (left, mut right) = duplex_with_capacity::<i32, i32>(4);
+        let handle = thread::spawn(move || right.recv());
+        left.close();
+        assert_eq!(handle.join().unwrap(), Err(RecvError));
+        assert!(left.is_disconnected());
+    }
+}
@@ -0,0 +1,413 @@
+// The errors of the channel operations. Those that fail to send hand the
+// message back; Debug leaves it out, like std's channels do, so the errors
+// work with `?` and boxed errors whatever T is.
+//
+// A blocking receive can not come back empty, so RecvError is the
+// disconnect alone. TryRecvError, RecvTimeoutError and RecvCancelError add
+// the one other way each can fail, and a RecvError converts into all three.
+
+use core::error::Error;
+use core::fmt;
+
+use crate::{Consumer, Producer};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The buffer has no free slot right now.
+    Full(T),
+    /// The consumer is gone (or the channel was closed).
+    Disconnected(T),
+}
+
+/// A `force_send` that found nothing it may evict.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ForceSendError<T> {
+    /// The buffer is full and a guard holds on to its oldest message, or
+    /// another producer holds a reservation on the next slot. Either lasts
+    /// until the guard is dropped.
+    Held(T),
+    /// The consumer is gone (or the channel was closed).
+    Disconnected(T),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    /// The buffer stayed full until the timeout.
+    Timeout(T),
+    /// The consumer is gone (or the channel was closed).
+    Disconnected(T),
+}
+
+/// The producer is gone (or the channel was closed), and all messages have
+/// been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The buffer holds no message right now.
+    Empty,
+    /// The producer is gone and all messages have been received.
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// No message arrived in time.
+    Timeout,
+    /// The producer is gone and all messages have been received.
+    Disconnected,
+}
+
+/// A send given up because its `CancelToken` was cancelled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendCancelError<T> {
+    /// The token was cancelled while the buffer was full.
+    Cancelled(T),
+    /// The consumer is gone (or the channel was closed).
+    Disconnected(T),
+}
+
+/// A receive given up because its `CancelToken` was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvCancelError {
+    /// The token was cancelled while the buffer was empty.
+    Cancelled,
+    /// The producer is gone and all messages have been received.
+    Disconnected,
+}
+
+/// The consumer went away before it received everything that was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushError;
+
+/// The endpoints passed to `SPSC::reunite` are not the last two handles of
+/// one channel. Both are handed back.
+pub struct ReuniteError<T>(pub Producer<T>, pub Consumer<T>);
+
+impl<T> SendError<T> {
+    /// Returns the message that could not be sent.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> TrySendError<T> {
+    /// Returns the message that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(val) | TrySendError::Disconnected(val) => val,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        matches!(self, TrySendError::Full(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, TrySendError::Disconnected(_))
+    }
+}
+
+impl<T> ForceSendError<T> {
+    /// Returns the message that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            ForceSendError::Held(val) | ForceSendError::Disconnected(val) => val,
+        }
+    }
+
+    pub fn is_held(&self) -> bool {
+        matches!(self, ForceSendError::Held(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, ForceSendError::Disconnected(_))
+    }
+}
+
+impl<T> SendTimeoutError<T> {
+    /// Returns the message that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendTimeoutError::Timeout(val) | SendTimeoutError::Disconnected(val) => val,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, SendTimeoutError::Timeout(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, SendTimeoutError::Disconnected(_))
+    }
+}
+
+impl<T> SendCancelError<T> {
+    /// Returns the message that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendCancelError::Cancelled(val) | SendCancelError::Disconnected(val) => val,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, SendCancelError::Cancelled(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, SendCancelError::Disconnected(_))
+    }
+}
+
+impl TryRecvError {
+    pub fn is_empty(&self) -> bool {
+        *self == TryRecvError::Empty
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        *self == TryRecvError::Disconnected
+    }
+}
+
+impl RecvTimeoutError {
+    pub fn is_timeout(&self) -> bool {
+        *self == RecvTimeoutError::Timeout
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        *self == RecvTimeoutError::Disconnected
+    }
+}
+
+impl RecvCancelError {
+    pub fn is_cancelled(&self) -> bool {
+        *self == RecvCancelError::Cancelled
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        *self == RecvCancelError::Disconnected
+    }
+}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(SendError(val): SendError<T>) -> Self {
+        TrySendError::Disconnected(val)
+    }
+}
+
+impl<T> From<SendError<T>> for ForceSendError<T> {
+    fn from(SendError(val): SendError<T>) -> Self {
+        ForceSendError::Disconnected(val)
+    }
+}
+
+impl<T> From<SendError<T>> for SendTimeoutError<T> {
+    fn from(SendError(val): SendError<T>) -> Self {
+        SendTimeoutError::Disconnected(val)
+    }
+}
+
+impl<T> From<SendError<T>> for SendCancelError<T> {
+    fn from(SendError(val): SendError<T>) -> Self {
+        SendCancelError::Disconnected(val)
+    }
+}
+
+impl From<RecvError> for TryRecvError {
+    fn from(RecvError: RecvError) -> Self {
+        TryRecvError::Disconnected
+    }
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    fn from(RecvError: RecvError) -> Self {
+        RecvTimeoutError::Disconnected
+    }
+}
+
+impl From<RecvError> for RecvCancelError {
+    fn from(RecvError: RecvError) -> Self {
+        RecvCancelError::Disconnected
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for ForceSendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForceSendError::Held(_) => f.write_str("Held(..)"),
+            ForceSendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => f.write_str("Timeout(..)"),
+            SendTimeoutError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendCancelError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendCancelError::Cancelled(_) => f.write_str("Cancelled(..)"),
+            SendCancelError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T: Send> fmt::Debug for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReuniteError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a disconnected channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl<T> fmt::Display for ForceSendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForceSendError::Held(_) => f.write_str("sending on a full channel whose head is held"),
+            ForceSendError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => f.write_str("timed out waiting on a full channel"),
+            SendTimeoutError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendCancelError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendCancelError::Cancelled(_) => f.write_str("cancelled waiting on a full channel"),
+            SendCancelError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on a disconnected channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on an empty channel"),
+            RecvTimeoutError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvCancelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvCancelError::Cancelled => f.write_str("cancelled waiting on an empty channel"),
+            RecvCancelError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the consumer disconnected before receiving every message")
+    }
+}
+
+impl<T: Send> fmt::Display for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the endpoints are not the only ones of the same channel")
+    }
+}
+
+impl<T> Error for SendError<T> {}
+impl<T> Error for TrySendError<T> {}
+impl<T> Error for ForceSendError<T> {}
+impl<T> Error for SendTimeoutError<T> {}
+impl<T> Error for SendCancelError<T> {}
+impl Error for RecvError {}
+impl Error for TryRecvError {}
+impl Error for RecvTimeoutError {}
+impl Error for RecvCancelError {}
+impl Error for FlushError {}
+impl<T: Send> Error for ReuniteError<T> {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn errors_box_without_debug_message() {
+        struct NoDebug;
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(SendError(NoDebug));
+        assert_eq!(boxed.to_string(), "sending on a disconnected channel");
+        assert_eq!(format!("{:?}", TrySendError::Full(NoDebug)), "Full(..)");
+    }
+
+    #[test]
+    fn conversions_keep_the_message() {
+        assert_eq!(SendError(1).into_inner(), 1);
+        let err: TrySendError<_> = SendError(2).into();
+        assert!(err.is_disconnected() && !err.is_full());
+        assert_eq!(err.into_inner(), 2);
+        let err: ForceSendError<_> = SendError(3).into();
+        assert!(err.is_disconnected() && !err.is_held());
+        let err: RecvTimeoutError = RecvError.into();
+        assert!(err.is_disconnected());
+        assert_eq!(TryRecvError::from(RecvError), TryRecvError::Disconnected);
+    }
+}
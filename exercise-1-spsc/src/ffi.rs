@@ -0,0 +1,212 @@
+//! A C API for channels of byte messages, declared in `include/spsc.h`.
+//!
+//! Both ends are opaque `SpscHandle` pointers, each owned by one thread at
+//! a time. A handle is either created together with the other end by
+//! `spsc_channel_new`, or made from an end on the Rust side with
+//! `producer_into_raw` or `consumer_into_raw`, e.g. so a C driver can feed a
+//! Rust consumer thread. The functions return `SPSC_OK` or one of the
+//! negative `SPSC_*` codes and never unwind into C.
+//!
+//! The crate is a plain library by default; build it as a shared library
+//! for C with `cargo rustc --release --features ffi --crate-type cdylib`.
+
+use std::ffi::c_int;
+use std::ptr;
+use std::slice;
+
+use crate::{channel_with_capacity, Consumer, Producer, RecvError};
+
+pub const SPSC_OK: c_int = 0;
+/// The other end is gone, and for a receive, every message is received.
+pub const SPSC_DISCONNECTED: c_int = -1;
+/// The buffer is too short for the next message, which stays queued; its
+/// length is in `*len`.
+pub const SPSC_BUFFER_TOO_SMALL: c_int = -2;
+/// A null pointer, a handle of the wrong end, or a capacity that is 0 or
+/// not a power of two.
+pub const SPSC_INVALID_ARGUMENT: c_int = -3;
+
+/// One end of a channel, as C sees it.
+pub struct SpscHandle(End);
+
+enum End {
+    Producer(Producer<Vec<u8>>),
+    // The message that did not fit the last buffer, ahead of the channel
+    Consumer {
+        consumer: Consumer<Vec<u8>>,
+        pending: Option<Vec<u8>>,
+    },
+}
+
+/// Hands `producer` over to C, which frees it with `spsc_free`.
+pub fn producer_into_raw(producer: Producer<Vec<u8>>) -> *mut SpscHandle {
+    Box::into_raw(Box::new(SpscHandle(End::Producer(producer))))
+}
+
+/// Hands `consumer` over to C, which frees it with `spsc_free`.
+pub fn consumer_into_raw(consumer: Consumer<Vec<u8>>) -> *mut SpscHandle {
+    Box::into_raw(Box::new(SpscHandle(End::Consumer {
+        consumer,
+        pending: None,
+    })))
+}
+
+/// Creates a channel of `capacity` messages and stores its ends in
+/// `*producer` and `*consumer`.
+///
+/// # Safety
+///
+/// `producer` and `consumer` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_channel_new(
+    capacity: usize,
+    producer: *mut *mut SpscHandle,
+    consumer: *mut *mut SpscHandle,
+) -> c_int {
+    // channel_with_capacity would panic
+    if producer.is_null() || consumer.is_null() || !capacity.is_power_of_two() {
+        return SPSC_INVALID_ARGUMENT;
+    }
+    let (px, cx) = channel_with_capacity(capacity);
+    *producer = producer_into_raw(px);
+    *consumer = consumer_into_raw(cx);
+    SPSC_OK
+}
+
+/// Sends a copy of the `len` bytes at `data`, waiting while the buffer is
+/// full.
+///
+/// # Safety
+///
+/// `producer` must be a live handle that no other thread uses right now,
+/// and `data` valid for `len` bytes of reads (or anything if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn spsc_send_bytes(
+    producer: *mut SpscHandle,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let Some(SpscHandle(End::Producer(producer))) = producer.as_mut() else {
+        return SPSC_INVALID_ARGUMENT;
+    };
+    let message = match len {
+        0 => Vec::new(),
+        _ if data.is_null() => return SPSC_INVALID_ARGUMENT,
+        _ => slice::from_raw_parts(data, len).to_vec(),
+    };
+    match producer.send(message) {
+        Ok(()) => SPSC_OK,
+        Err(_) => SPSC_DISCONNECTED,
+    }
+}
+
+/// Waits for the next message and copies it into the `capacity` bytes at
+/// `buf`, storing its length in `*len`.
+///
+/// # Safety
+///
+/// `consumer` must be a live handle that no other thread uses right now,
+/// `buf` valid for `capacity` bytes of writes (or anything if `capacity` is
+/// 0), and `len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_recv_bytes(
+    consumer: *mut SpscHandle,
+    buf: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+) -> c_int {
+    let Some(SpscHandle(End::Consumer { consumer, pending })) = consumer.as_mut() else {
+        return SPSC_INVALID_ARGUMENT;
+    };
+    if len.is_null() || (buf.is_null() && capacity != 0) {
+        return SPSC_INVALID_ARGUMENT;
+    }
+    let message = match pending.take() {
+        Some(message) => message,
+        None => match consumer.recv() {
+            Ok(message) => message,
+            Err(RecvError) => return SPSC_DISCONNECTED,
+        },
+    };
+    *len = message.len();
+    if message.len() > capacity {
+        *pending = Some(message);
+        return SPSC_BUFFER_TOO_SMALL;
+    }
+    // buf may only be null for a capacity of 0, which only an empty message fits
+    if !message.is_empty() {
+        ptr::copy_nonoverlapping(message.as_ptr(), buf, message.len());
+    }
+    SPSC_OK
+}
+
+/// Frees either end of a channel, which disconnects the other. Does
+/// nothing for null.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, which is gone afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_free(handle: *mut SpscHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        let (mut px, mut cx) = (ptr::null_mut(), ptr::null_mut());
+        unsafe {
+            assert_eq!(spsc_channel_new(3, &mut px, &mut cx), SPSC_INVALID_ARGUMENT);
+            assert_eq!(spsc_channel_new(4, &mut px, &mut cx), SPSC_OK);
+            assert_eq!(spsc_send_bytes(px, b"hello".as_ptr(), 5), SPSC_OK);
+            assert_eq!(spsc_send_bytes(px, ptr::null(), 0), SPSC_OK);
+            assert_eq!(spsc_send_bytes(cx, b"x".as_ptr(), 1), SPSC_INVALID_ARGUMENT);
+
+            let mut buf = [0u8; 8];
+            let mut len = 0;
+            assert_eq!(
+                spsc_recv_bytes(cx, buf.as_mut_ptr(), 2, &mut len),
+                SPSC_BUFFER_TOO_SMALL
+            );
+            assert_eq!(len, 5);
+            // the message waited for a buffer that fits
+            assert_eq!(spsc_recv_bytes(cx, buf.as_mut_ptr(), 8, &mut len), SPSC_OK);
+            assert_eq!(&buf[..len], b"hello");
+            assert_eq!(spsc_recv_bytes(cx, ptr::null_mut(), 0, &mut len), SPSC_OK);
+            assert_eq!(len, 0);
+
+            spsc_free(px);
+            assert_eq!(
+                spsc_recv_bytes(cx, buf.as_mut_ptr(), 8, &mut len),
+                SPSC_DISCONNECTED
+            );
+            spsc_free(cx);
+        }
+    }
+
+    #[test]
+    fn c_producer_feeds_rust_consumer() {
+        let (px, cx) = channel_with_capacity(4);
+        // as a pointer would be passed to a C thread
+        let handle = producer_into_raw(px) as usize;
+        let sender = thread::spawn(move || unsafe {
+            let handle = handle as *mut SpscHandle;
+            for i in 0..100u32 {
+                let bytes = i.to_le_bytes();
+                assert_eq!(spsc_send_bytes(handle, bytes.as_ptr(), 4), SPSC_OK);
+            }
+            spsc_free(handle);
+        });
+        let received: Vec<Vec<u8>> = cx.collect();
+        let expected: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert_eq!(received, expected);
+        sender.join().unwrap();
+    }
+}
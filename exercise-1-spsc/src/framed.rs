@@ -0,0 +1,122 @@
+//! Typed messages over a channel of bytes, for where a `T` can not be moved
+//! through the buffer as it is, e.g. between processes over `shm`.
+//!
+//! The producer serializes each message with serde, as JSON, and writes it
+//! as a frame of a `bytes::FrameWriter`. The consumer reads a frame at a
+//! time and deserializes it again. Both ends work on any `io::Write` and
+//! `io::Read`, by default the ends of a `bytes::pipe`; a frame may be larger
+//! than the buffer, it then goes through in parts.
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bytes::{pipe, FrameReader, FrameWriter, PipeReader, PipeWriter};
+
+/// Sends the messages of a framed channel, see `framed`.
+pub struct FramedProducer<T, W = PipeWriter> {
+    writer: FrameWriter<W>,
+    // reused for every message
+    buf: Vec<u8>,
+    _marker: PhantomData<fn(T)>,
+}
+
+/// Receives the messages of a framed channel, see `framed`.
+pub struct FramedConsumer<T, R = PipeReader> {
+    reader: FrameReader<R>,
+    buf: Vec<u8>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+/// Creates a framed channel on a pipe of `capacity` bytes, which must be a
+/// power of two.
+pub fn framed<T: Serialize + DeserializeOwned>(
+    capacity: usize,
+) -> (FramedProducer<T>, FramedConsumer<T>) {
+    let (writer, reader) = pipe(capacity);
+    (FramedProducer::new(writer), FramedConsumer::new(reader))
+}
+
+impl<T: Serialize, W: Write> FramedProducer<T, W> {
+    pub fn new(writer: W) -> Self {
+        FramedProducer {
+            writer: FrameWriter::new(writer),
+            buf: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Serializes `val` and writes it as one frame, blocking as the writer
+    /// does. Fails if the other end is gone, or `val` does not serialize.
+    pub fn send(&mut self, val: &T) -> io::Result<()> {
+        self.buf.clear();
+        serde_json::to_writer(&mut self.buf, val)?;
+        self.writer.write_frame(&self.buf)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+impl<T: DeserializeOwned, R: Read> FramedConsumer<T, R> {
+    pub fn new(reader: R) -> Self {
+        FramedConsumer {
+            reader: FrameReader::new(reader),
+            buf: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Reads the next frame and deserializes it, blocking as the reader
+    /// does. Returns `None` once the other end is gone and every frame is
+    /// read; a frame cut short fails with `UnexpectedEof`, one that does
+    /// not deserialize into a `T` with `InvalidData`.
+    pub fn recv(&mut self) -> io::Result<Option<T>> {
+        if !self.reader.read_frame(&mut self.buf)? {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&self.buf)?))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn messages_larger_than_the_pipe_arrive_whole() {
+        let (mut px, mut cx) = framed::<(u32, String)>(16);
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(&(i, "x".repeat(i as usize))).unwrap();
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(cx.recv().unwrap(), Some((i, "x".repeat(i as usize))));
+        }
+        handle.join().unwrap();
+        assert_eq!(cx.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn broken_frames_are_errors() {
+        let (mut writer, reader) = pipe(16);
+        let mut cx = FramedConsumer::<u32>::new(reader);
+        writer.write_all(&3u32.to_le_bytes()).unwrap();
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(cx.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        writer.write_all(&[1, 0]).unwrap();
+        drop(writer);
+        assert_eq!(cx.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
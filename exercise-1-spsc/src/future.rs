@@ -0,0 +1,283 @@
+// Futures for sending and receiving from async code. They rely on nothing
+// but the waker of the context they are polled with, so any executor can
+// drive them, and a pending one does not block its thread.
+
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::ring::{self, Ring};
+use crate::{CancelToken, CancelledFuture, RecvCancelError, RecvError, SendCancelError, SendError};
+
+/// Future of `Producer::send_async`.
+#[must_use = "futures do nothing unless polled"]
+pub struct SendFuture<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    // None once sent (or handed back)
+    val: Option<T>,
+}
+
+/// Future of `Consumer::recv_async`.
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvFuture<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+}
+
+/// Future of `Producer::send_async_cancellable`.
+#[must_use = "futures do nothing unless polled"]
+pub struct SendCancelFuture<'a, T, const N: usize = { ring::DYNAMIC }> {
+    send: SendFuture<'a, T, N>,
+    cancelled: CancelledFuture<'a>,
+}
+
+/// Future of `Consumer::recv_async_cancellable`.
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvCancelFuture<'a, T, const N: usize = { ring::DYNAMIC }> {
+    recv: RecvFuture<'a, T, N>,
+    cancelled: CancelledFuture<'a>,
+}
+
+impl<'a, T, const N: usize> SendFuture<'a, T, N> {
+    pub(crate) fn new(ring: Ring<'a, T, N>, val: T) -> Self {
+        SendFuture {
+            ring,
+            val: Some(val),
+        }
+    }
+}
+
+impl<'a, T, const N: usize> RecvFuture<'a, T, N> {
+    pub(crate) fn new(ring: Ring<'a, T, N>) -> Self {
+        RecvFuture { ring }
+    }
+}
+
+impl<'a, T, const N: usize> SendCancelFuture<'a, T, N> {
+    pub(crate) fn new(ring: Ring<'a, T, N>, val: T, token: &'a CancelToken) -> Self {
+        SendCancelFuture {
+            send: SendFuture::new(ring, val),
+            cancelled: token.cancelled(),
+        }
+    }
+}
+
+impl<'a, T, const N: usize> RecvCancelFuture<'a, T, N> {
+    pub(crate) fn new(ring: Ring<'a, T, N>, token: &'a CancelToken) -> Self {
+        RecvCancelFuture {
+            recv: RecvFuture::new(ring),
+            cancelled: token.cancelled(),
+        }
+    }
+}
+
+impl<T, const N: usize> Future for SendFuture<'_, T, N> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.ring.poll_send(&mut this.val, cx)
+    }
+}
+
+impl<T, const N: usize> Future for RecvFuture<'_, T, N> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut val = MaybeUninit::uninit();
+        self.ring
+            .poll_recv_into(&mut val, cx)
+            // poll_recv_into only returns Ready(Ok) after initializing val
+            .map_ok(|()| unsafe { val.assume_init() })
+    }
+}
+
+// The channel goes first, so a free slot is taken even once the token is
+// cancelled, as in send_cancellable
+impl<T, const N: usize> Future for SendCancelFuture<'_, T, N> {
+    type Output = Result<(), SendCancelError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.send).poll(cx) {
+            return Poll::Ready(
+                result.map_err(|SendError(val)| SendCancelError::Disconnected(val)),
+            );
+        }
+        ready!(Pin::new(&mut this.cancelled).poll(cx));
+        let val = this
+            .send
+            .val
+            .take()
+            .expect("send future polled after completion");
+        Poll::Ready(Err(SendCancelError::Cancelled(val)))
+    }
+}
+
+impl<T, const N: usize> Future for RecvCancelFuture<'_, T, N> {
+    type Output = Result<T, RecvCancelError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.recv).poll(cx) {
+            return Poll::Ready(result.map_err(|RecvError| RecvCancelError::Disconnected));
+        }
+        ready!(Pin::new(&mut this.cancelled).poll(cx));
+        Poll::Ready(Err(RecvCancelError::Cancelled))
+    }
+}
+
+// The value is moved out, never pinned in place
+impl<T, const N: usize> Unpin for SendFuture<'_, T, N> {}
+
+// Like the handles they borrow from, the futures only reach the buffer
+// through the protocol in ring, which copes with either side being used from
+// more than one thread
+unsafe impl<T: Send, const N: usize> Send for SendFuture<'_, T, N> {}
+unsafe impl<T: Send, const N: usize> Send for RecvFuture<'_, T, N> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use crate::{channel_with_capacity, channel_with_strategy, WaitStrategy};
+
+    use super::*;
+
+    // Just enough of an executor: polls on the current thread and parks
+    // until woken
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn async_messages_arrive_in_order() {
+        // a small buffer, so both futures have to wait for each other
+        let (mut px, mut cx) = channel_with_capacity(2);
+        let handle = thread::spawn(move || {
+            for i in 0..1000 {
+                block_on(px.send_async(i)).unwrap();
+            }
+        });
+        for i in 0..1000 {
+            assert_eq!(block_on(cx.recv_async()).unwrap(), i);
+        }
+        assert!(block_on(cx.recv_async()).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn pending_futures_are_woken() {
+        // tasks are woken whatever the strategy for blocked threads
+        let (mut px, mut cx) = channel_with_strategy(1, WaitStrategy::BusySpin);
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx_task = Context::from_waker(&waker);
+
+        let mut recv = pin!(cx.recv_async());
+        assert!(recv.as_mut().poll(&mut cx_task).is_pending());
+        // polled again before anything happened, still one registration
+        assert!(recv.as_mut().poll(&mut cx_task).is_pending());
+        px.send(1).unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            recv.as_mut().poll(&mut cx_task),
+            Poll::Ready(Ok(1))
+        ));
+
+        px.send(2).unwrap();
+        let mut send = pin!(px.send_async(3));
+        assert!(send.as_mut().poll(&mut cx_task).is_pending());
+        assert_eq!(cx.recv().unwrap(), 2);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+        assert!(matches!(
+            send.as_mut().poll(&mut cx_task),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(cx.recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn a_new_waker_replaces_the_old_one() {
+        let (mut px, mut cx) = channel_with_capacity(1);
+        let (old, new) = (
+            Arc::new(CountingWaker::default()),
+            Arc::new(CountingWaker::default()),
+        );
+        let mut recv = pin!(cx.recv_async());
+        for counter in [&old, &new] {
+            let waker = Waker::from(counter.clone());
+            assert!(recv
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending());
+        }
+        px.send(1).unwrap();
+        assert_eq!(old.0.load(Ordering::SeqCst), 0);
+        assert_eq!(new.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn every_blocked_mpsc_producer_is_woken() {
+        let (mut px, mut cx) = crate::channel_mpsc(1);
+        px.send(0).unwrap();
+        let mut other = px.clone();
+        let counters = [
+            Arc::new(CountingWaker::default()),
+            Arc::new(CountingWaker::default()),
+        ];
+        let mut first = pin!(px.send_async(1));
+        let mut second = pin!(other.send_async(2));
+        for (send, counter) in [first.as_mut(), second.as_mut()].into_iter().zip(&counters) {
+            let waker = Waker::from(counter.clone());
+            assert!(send.poll(&mut Context::from_waker(&waker)).is_pending());
+        }
+        assert_eq!(cx.recv(), Ok(0));
+        for counter in &counters {
+            assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn disconnect_completes_pending_futures() {
+        let (px, mut cx) = channel_with_capacity::<i32>(1);
+        let handle = thread::spawn(move || block_on(cx.recv_async()));
+        drop(px);
+        assert!(handle.join().unwrap().is_err());
+
+        let (mut px, cx) = channel_with_capacity(1);
+        px.send(1).unwrap();
+        let handle = thread::spawn(move || block_on(px.send_async(2)));
+        drop(cx);
+        assert_eq!(handle.join().unwrap().unwrap_err().0, 2);
+    }
+}
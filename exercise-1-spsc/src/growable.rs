@@ -0,0 +1,425 @@
+//! A channel that starts with a small buffer and grows it while the
+//! consumer keeps falling behind, for many channels that are idle most of
+//! the time.
+//!
+//! The buffer is a ring whose slots carry a flag that says whether they hold
+//! a message, so each end only needs its own index. To grow, the producer
+//! does not move the messages: it starts a ring twice the size and links it
+//! behind the current one. The consumer drains the old ring first and then
+//! follows the link, so the order is kept, and frees the old ring once it
+//! has moved on.
+
+use std::cell::Cell;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use crate::index;
+use crate::primitives::{Arc, AtomicBool, AtomicPtr, Ordering, UnsafeCell};
+use crate::ring;
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError, TryRecvError, TrySendError};
+
+// How many sends in a row have to find the buffer full before it grows
+const GROW_AFTER: usize = 8;
+
+struct Slot<T> {
+    // set by the producer once the message is in, cleared by the consumer
+    // once it is out again
+    ready: AtomicBool,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Segment<T> {
+    slots: Box<[Slot<T>]>,
+    // the next, larger ring; null while the producer still writes to this one
+    next: AtomicPtr<Segment<T>>,
+}
+
+impl<T> Segment<T> {
+    fn alloc(capacity: usize) -> *mut Segment<T> {
+        Box::into_raw(Box::new(Segment {
+            slots: (0..capacity)
+                .map(|_| Slot {
+                    ready: AtomicBool::new(false),
+                    val: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    fn slot(&self, position: usize) -> &Slot<T> {
+        &self.slots[index::slot(position, self.slots.len())]
+    }
+}
+
+struct Shared<T> {
+    // The consumer's segment. Only kept here so the last end can free the
+    // list, the consumer works with its own copy.
+    head: AtomicPtr<Segment<T>>,
+    max_capacity: usize,
+    producer_alive: AtomicBool,
+    consumer_alive: AtomicBool,
+    // as in the SPSC channel: the consumer waits for a message, the producer
+    // for a free slot, either for the other side to go away
+    consumers: WaitQueue,
+    producers: WaitQueue,
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Both ends are gone, the whole list is ours
+        let mut segment = self.head.load(Ordering::Relaxed);
+        while !segment.is_null() {
+            let boxed = unsafe { Box::from_raw(segment) };
+            for slot in boxed.slots.iter() {
+                if slot.ready.load(Ordering::Relaxed) {
+                    slot.val
+                        .with_mut(|val| unsafe { (*val).assume_init_drop() });
+                }
+            }
+            segment = boxed.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    // the segment we write to and the position of the next message in it
+    tail: Cell<*mut Segment<T>>,
+    write_index: Cell<usize>,
+    // how many sends in a row found the buffer full
+    full_streak: Cell<usize>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    // the segment we read from and the position of the next message in it
+    head: Cell<*mut Segment<T>>,
+    read_index: Cell<usize>,
+}
+
+/// Creates a channel with a buffer of `initial_capacity` messages, which
+/// doubles up to `max_capacity` while sends keep finding it full. Growing
+/// does not move the queued messages, they stay in order.
+///
+/// Panics if either capacity is 0 or not a power of two, or if
+/// `max_capacity` is below `initial_capacity`.
+pub fn channel<T: Send>(
+    initial_capacity: usize,
+    max_capacity: usize,
+) -> (Producer<T>, Consumer<T>) {
+    ring::check_capacity(initial_capacity);
+    ring::check_capacity(max_capacity);
+    assert!(
+        initial_capacity <= max_capacity,
+        "the maximum capacity must not be below the initial one"
+    );
+    let segment = Segment::alloc(initial_capacity);
+    let shared = Arc::new(Shared {
+        head: AtomicPtr::new(segment),
+        max_capacity,
+        producer_alive: AtomicBool::new(true),
+        consumer_alive: AtomicBool::new(true),
+        consumers: WaitQueue::new(),
+        producers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+            tail: Cell::new(segment),
+            write_index: Cell::new(0),
+            full_streak: Cell::new(0),
+        },
+        Consumer {
+            shared,
+            head: Cell::new(segment),
+            read_index: Cell::new(0),
+        },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.consumer_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(val));
+        }
+        match self.push(val) {
+            Ok(()) => {
+                self.full_streak.set(0);
+                Ok(())
+            }
+            Err(val) => self.push_or_grow(val).map_err(TrySendError::Full),
+        }
+    }
+
+    /// Waits for a free slot, growing the buffer if it stays full. Fails
+    /// once the consumer is gone.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        // A send that has to wait counts as one that found the buffer full,
+        // however long the wait, so a consumer that keeps falling behind
+        // makes the buffer grow
+        let mut val = match self.try_send(val) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(val)) => return Err(SendError(val)),
+            Err(TrySendError::Full(val)) => val,
+        };
+        loop {
+            let tail = unsafe { &*self.tail.get() };
+            let slot = tail.slot(self.write_index.get());
+            shared.producers.wait(None, || {
+                !slot.ready.load(Ordering::Acquire)
+                    || !shared.consumer_alive.load(Ordering::Acquire)
+            });
+            if !shared.consumer_alive.load(Ordering::Acquire) {
+                return Err(SendError(val));
+            }
+            match self.push(val) {
+                Ok(()) => return Ok(()),
+                Err(back) => val = back,
+            }
+        }
+    }
+
+    /// Returns how many messages fit in the buffer we write to right now.
+    pub fn capacity(&self) -> usize {
+        let tail = unsafe { &*self.tail.get() };
+        tail.slots.len()
+    }
+
+    /// Returns whether the consumer still exists. This is only a snapshot.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.shared.consumer_alive.load(Ordering::Relaxed)
+    }
+
+    // Writes val to the next slot of the tail, or hands it back if the slot
+    // is still taken
+    fn push(&self, val: T) -> Result<(), T> {
+        let position = self.write_index.get();
+        let slot = unsafe { (*self.tail.get()).slot(position) };
+        if slot.ready.load(Ordering::Acquire) {
+            return Err(val);
+        }
+        // The consumer leaves the slot alone until it is ready
+        slot.val.with_mut(|slot| unsafe { (*slot).write(val) });
+        slot.ready.store(true, Ordering::Release);
+        self.write_index.set(index::advance(position, 1));
+        self.shared.consumers.notify();
+        Ok(())
+    }
+
+    // Counts a send that found the buffer full. Once GROW_AFTER in a row
+    // did, grows the buffer and writes val to the new segment, otherwise
+    // val comes back.
+    fn push_or_grow(&self, val: T) -> Result<(), T> {
+        self.full_streak.set(self.full_streak.get() + 1);
+        if self.full_streak.get() < GROW_AFTER || self.capacity() == self.shared.max_capacity {
+            return Err(val);
+        }
+        self.grow();
+        // the new segment is empty
+        if self.push(val).is_err() {
+            unreachable!("a new segment is full");
+        }
+        Ok(())
+    }
+
+    fn grow(&self) {
+        let next = Segment::alloc(self.capacity() * 2);
+        // Release, so the consumer sees every message of the old segment
+        // once it sees the link. We do not touch the old segment anymore.
+        unsafe { (*self.tail.get()).next.store(next, Ordering::Release) };
+        self.tail.set(next);
+        self.write_index.set(0);
+        self.full_streak.set(0);
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        // Before the slots: once the producer is gone, all its messages are
+        // in
+        let producer_alive = self.shared.producer_alive.load(Ordering::Acquire);
+        loop {
+            let head = unsafe { &*self.head.get() };
+            // Before the slot: with the link we see the last message the
+            // producer wrote to this segment
+            let next = head.next.load(Ordering::Acquire);
+            let slot = head.slot(self.read_index.get());
+            if slot.ready.load(Ordering::Acquire) {
+                let val = slot
+                    .val
+                    .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+                slot.ready.store(false, Ordering::Release);
+                self.read_index
+                    .set(index::advance(self.read_index.get(), 1));
+                self.shared.producers.notify();
+                return Ok(val);
+            }
+            if next.is_null() {
+                return Err(if producer_alive {
+                    TryRecvError::Empty
+                } else {
+                    TryRecvError::Disconnected
+                });
+            }
+            // This segment is drained, and the producer has moved on
+            self.shared.head.store(next, Ordering::Relaxed);
+            drop(unsafe { Box::from_raw(self.head.get()) });
+            self.head.set(next);
+            self.read_index.set(0);
+        }
+    }
+
+    /// Waits for a message, fails once the producer is gone and all its
+    /// messages are received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {
+                    let head = unsafe { &*self.head.get() };
+                    let slot = head.slot(self.read_index.get());
+                    shared.consumers.wait(None, || {
+                        slot.ready.load(Ordering::Acquire)
+                            || !head.next.load(Ordering::Acquire).is_null()
+                            || !shared.producer_alive.load(Ordering::Acquire)
+                    });
+                }
+            }
+        }
+    }
+
+    /// Returns whether the producer still exists. This is only a snapshot.
+    pub fn is_producer_alive(&self) -> bool {
+        self.shared.producer_alive.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    // The messages still queued are dropped along with the list
+    fn drop(&mut self) {
+        self.shared.consumer_alive.store(false, Ordering::Release);
+        self.shared.producers.notify();
+    }
+}
+
+// Each end only touches its own segment and the slots as described above.
+// Neither is Sync, the cells hold the position of a single thread.
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn grows_while_full_up_to_max() {
+        let (px, cx) = channel(4, 16);
+        let mut sent = 0;
+        for _ in 0..100 {
+            if px.try_send(sent).is_ok() {
+                sent += 1;
+            }
+        }
+        // every GROW_AFTER failed sends it doubled, until it reached 16
+        assert_eq!(px.capacity(), 16);
+        assert_eq!(sent, 4 + 8 + 16);
+
+        drop(px);
+        for i in 0..sent {
+            assert_eq!(cx.try_recv(), Ok(i));
+        }
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn order_is_kept_across_threads() {
+        let (px, cx) = channel(2, 1024);
+        let handle = thread::spawn(move || {
+            for i in 0..100_000 {
+                px.send(i).unwrap();
+            }
+        });
+        for i in 0..100_000 {
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+        assert!(cx.recv().is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn blocking_sends_grow_for_a_slow_consumer() {
+        let (px, cx) = channel(2, 64);
+        let handle = thread::spawn(move || {
+            // much slower than the producer, so the sends keep waiting
+            while cx.recv().is_ok() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        for i in 0..4 * GROW_AFTER {
+            px.send(i).unwrap();
+        }
+        assert!(px.capacity() > 2);
+        drop(px);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn queued_messages_are_dropped_with_channel() {
+        let tracker = std::sync::Arc::new(());
+        let (px, cx) = channel(2, 4);
+        for _ in 0..2 + GROW_AFTER {
+            let _ = px.try_send(tracker.clone());
+        }
+        drop(cx.recv().unwrap());
+        drop(cx);
+        assert!(px.send(tracker.clone()).is_err());
+        drop(px);
+        assert_eq!(std::sync::Arc::strong_count(&tracker), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn consumer_follows_into_larger_segment() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(2);
+        builder.check(|| {
+            let (px, cx) = channel(1, 2);
+            let handle = thread::spawn(move || {
+                let mut sent = 0;
+                // the first message fills the segment, the rest may grow it
+                for i in 0..GROW_AFTER + 1 {
+                    if px.try_send(i).is_ok() {
+                        sent += 1;
+                    }
+                }
+                sent
+            });
+            let mut received: Vec<_> = (0..2).filter_map(|_| cx.try_recv().ok()).collect();
+            let sent = handle.join().unwrap();
+            received.extend(std::iter::from_fn(|| cx.try_recv().ok()));
+            assert_eq!(received.len(), sent);
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+        });
+    }
+}
@@ -0,0 +1,197 @@
+// Callbacks that one side of a channel installs for the other side to run
+// on the transitions it may be waiting for: a send into the empty buffer,
+// or a receive out of the full one.
+//
+// Like the notifier, the side that moves its index checks the other index
+// after the fence of WaitQueue::notify, and only runs the callback if the
+// other side has not moved since. A side that found the buffer empty (or
+// full) and did not move either way gets its callback; one that moved on
+// already had no reason to wait.
+//
+// The locks here only guard the callbacks and the state of the watermarks,
+// no callback runs under one. A callback is cloned out and called once the
+// lock is released, so it may call back into the channel, e.g. to install
+// another one, and a slow one holds up no other call.
+
+use alloc::sync::Arc;
+
+use crate::primitives::{const_fn, fence, AtomicBool, AtomicUsize, Mutex, Ordering};
+
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+pub(crate) struct Hook {
+    // whether there is a callback, so the other side can skip the lock
+    installed: AtomicBool,
+    callback: Mutex<Option<Callback>>,
+}
+
+impl Hook {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Hook {
+                installed: AtomicBool::new(false),
+                callback: Mutex::new(None),
+            }
+        }
+    }
+
+    pub(crate) fn set(&self, callback: impl Fn() + Send + Sync + 'static) {
+        let mut slot = self.callback.lock().unwrap();
+        *slot = Some(Arc::new(callback));
+        self.installed.store(true, Ordering::Relaxed);
+        drop(slot);
+        // A transition from before is the caller's to check
+        fence(Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_installed(&self) -> bool {
+        self.installed.load(Ordering::Relaxed)
+    }
+
+    // Runs the callback installed at the time, after letting go of the lock.
+    // The producers of channel_mpsc may run it on several threads at once.
+    pub(crate) fn run(&self) {
+        let callback = self.callback.lock().unwrap().clone();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
+
+// Occupancy thresholds with a callback each, see Producer::set_watermarks.
+// The send that fills the buffer up to the high mark crosses it, then the
+// receive that drains it down to the low mark crosses that one, and so on
+// in turn. The crossings are decided under the lock, which puts them in
+// order.
+//
+// A send only needs the lock at or above the high mark, and a receive only
+// at or below the low one after the high one was crossed. The length a side
+// computes on its own can only be off to the safe side: the producer may
+// see too many messages, the consumer too few.
+//
+// The callbacks run outside of the lock, so on_low could otherwise run for
+// a receive before on_high ran for the send just before it, leaving the
+// source paused for good. Instead one thread at a time runs the callbacks
+// for the crossings none ran for yet, see report, and they alternate in the
+// order of the crossings whichever thread runs them.
+pub(crate) struct Watermarks {
+    installed: AtomicBool,
+    high: AtomicUsize,
+    low: AtomicUsize,
+    // the high mark was crossed last, so the low one is next
+    above: AtomicBool,
+    // what above was when the last callback ran for it
+    reported: AtomicBool,
+    // a thread is in report
+    running: AtomicBool,
+    callbacks: Mutex<Option<(Callback, Callback)>>,
+}
+
+impl Watermarks {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Watermarks {
+                installed: AtomicBool::new(false),
+                high: AtomicUsize::new(0),
+                low: AtomicUsize::new(0),
+                above: AtomicBool::new(false),
+                reported: AtomicBool::new(false),
+                running: AtomicBool::new(false),
+                callbacks: Mutex::new(None),
+            }
+        }
+    }
+
+    pub(crate) fn set(
+        &self,
+        high: usize,
+        low: usize,
+        on_high: impl Fn() + Send + Sync + 'static,
+        on_low: impl Fn() + Send + Sync + 'static,
+    ) {
+        let mut callbacks = self.callbacks.lock().unwrap();
+        *callbacks = Some((Arc::new(on_high), Arc::new(on_low)));
+        self.high.store(high, Ordering::Relaxed);
+        self.low.store(low, Ordering::Relaxed);
+        self.above.store(false, Ordering::Relaxed);
+        self.reported.store(false, Ordering::Relaxed);
+        self.installed.store(true, Ordering::Relaxed);
+    }
+
+    // After a send, with len counting the queued messages afresh
+    pub(crate) fn sent(&self, len: impl Fn() -> usize) {
+        if !self.installed.load(Ordering::Relaxed) || len() < self.high.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self.cross(true, || len() >= self.high.load(Ordering::Relaxed)) {
+            return;
+        }
+        // A receive that drained the buffer in the meantime may not have
+        // seen above yet. Either it does after this fence, or the fresh
+        // length here counts it, like the fence of notify on its side.
+        fence(Ordering::SeqCst);
+        if len() <= self.low.load(Ordering::Relaxed) {
+            self.cross(false, || true);
+        }
+        self.report();
+    }
+
+    // After a receive, behind the fence of notify
+    pub(crate) fn received(&self, len: impl Fn() -> usize) {
+        if !self.above.load(Ordering::Relaxed) || len() > self.low.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.cross(false, || len() <= self.low.load(Ordering::Relaxed)) {
+            self.report();
+        }
+    }
+
+    // Sets above under the lock, unless it already is or the buffer is no
+    // longer past the mark
+    fn cross(&self, above: bool, past: impl Fn() -> bool) -> bool {
+        let callbacks = self.callbacks.lock().unwrap();
+        if callbacks.is_none() || self.above.load(Ordering::Relaxed) == above || !past() {
+            return false;
+        }
+        self.above.store(above, Ordering::Relaxed);
+        true
+    }
+
+    // Runs the callbacks for the crossings that none ran for yet, unless
+    // another thread is at it, which then runs ours as well. Two crossings
+    // that cancel out before the first one's callback ran get none.
+    fn report(&self) {
+        loop {
+            // Against a crossing that found the thread before still running:
+            // as in WaitQueue::notify, either that thread sees the crossing
+            // after clearing running, or this one sees running cleared
+            fence(Ordering::SeqCst);
+            if self
+                .running
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                return;
+            }
+            loop {
+                let callbacks = self.callbacks.lock().unwrap();
+                let above = self.above.load(Ordering::Relaxed);
+                let Some((on_high, on_low)) = &*callbacks else {
+                    break;
+                };
+                if above == self.reported.load(Ordering::Relaxed) {
+                    break;
+                }
+                self.reported.store(above, Ordering::Relaxed);
+                let callback = if above { on_high } else { on_low }.clone();
+                drop(callbacks);
+                callback();
+            }
+            self.running.store(false, Ordering::Release);
+            fence(Ordering::SeqCst);
+            if self.above.load(Ordering::Relaxed) == self.reported.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+// Index arithmetic shared by the ring buffers. Read and write indices only
+// ever count up, with read <= write <= read + capacity, and are mapped onto a
+// slot only when the buffer is accessed.
+//
+// Counting up eventually overflows usize, so everything here wraps around.
+// Since the indices are never more than a capacity apart, the distance
+// between them stays meaningful across the wrap.
+
+// The capacity is a power of two, so masking the low bits picks the slot
+// without a division
+pub(crate) fn slot(index: usize, capacity: usize) -> usize {
+    debug_assert!(capacity.is_power_of_two());
+    index & (capacity - 1)
+}
+
+pub(crate) fn len(read: usize, write: usize) -> usize {
+    write.wrapping_sub(read)
+}
+
+pub(crate) fn advance(index: usize, count: usize) -> usize {
+    index.wrapping_add(count)
+}
+
+// The positions from read up to write, in order
+pub(crate) fn range(read: usize, write: usize) -> impl Iterator<Item = usize> {
+    (0..len(read, write)).map(move |offset| advance(read, offset))
+}
+
+pub(crate) fn is_empty(read: usize, write: usize) -> bool {
+    read == write
+}
+
+pub(crate) fn is_full(read: usize, write: usize, capacity: usize) -> bool {
+    len(read, write) == capacity
+}
+
+// Whether there is a message at read, according to a write index that may be
+// an outdated copy. Unlike !is_empty, this tolerates a read index that moved
+// past the copy, which shows up as a negative distance.
+pub(crate) fn has_message(read: usize, write: usize) -> bool {
+    (len(read, write) as isize) > 0
+}
+
+// Turns index into a count that does not wrap at usize::MAX, given the count
+// of an earlier index that is less than usize::MAX positions behind it
+#[cfg(any(feature = "std", kani))]
+pub(crate) fn widen(earlier: u64, index: usize) -> u64 {
+    earlier.wrapping_add(len(earlier as usize, index) as u64)
+}
+
+// Proofs of the invariants above for all indices, including those next to
+// the wrap, which the tests only reach by starting there. The harnesses are
+// only built by Kani:
+//
+//     cargo kani --lib
+//
+// The invariant read <= write can not be stated with <= on wrapping
+// indices, it is len(read, write) <= capacity: write is at most a capacity
+// ahead of read, and never behind it (that would be a huge len).
+#[cfg(kani)]
+mod verification {
+    use super::*;
+
+    // Any power of two up to 2^7, more does not add anything to the proofs
+    // but time
+    fn any_capacity() -> usize {
+        let bits: u32 = kani::any();
+        kani::assume(bits < 8);
+        1 << bits
+    }
+
+    // Any indices that satisfy the invariant
+    fn any_indices(capacity: usize) -> (usize, usize) {
+        let read: usize = kani::any();
+        let len: usize = kani::any();
+        kani::assume(len <= capacity);
+        (read, advance(read, len))
+    }
+
+    #[kani::proof]
+    fn send_and_recv_keep_the_invariant() {
+        let capacity = any_capacity();
+        let (read, write) = any_indices(capacity);
+        if !is_full(read, write, capacity) {
+            let write = advance(write, 1);
+            assert!(len(read, write) <= capacity);
+            assert!(!is_empty(read, write));
+        }
+        if !is_empty(read, write) {
+            let read = advance(read, 1);
+            assert!(len(read, write) <= capacity);
+            assert!(!is_full(read, write, capacity));
+        }
+    }
+
+    // The slot the producer writes next is none of those the consumer may
+    // read, and those are all distinct
+    #[kani::proof]
+    fn the_written_slot_is_not_readable() {
+        let capacity = any_capacity();
+        let (read, write) = any_indices(capacity);
+        kani::assume(!is_full(read, write, capacity));
+        let offset: usize = kani::any();
+        kani::assume(offset < len(read, write));
+        let readable = slot(advance(read, offset), capacity);
+        assert!(readable < capacity);
+        assert_ne!(readable, slot(write, capacity));
+
+        let other: usize = kani::any();
+        kani::assume(other < len(read, write) && other != offset);
+        assert_ne!(readable, slot(advance(read, other), capacity));
+    }
+
+    // A copy of the write index from any time before, as the consumer
+    // caches it, never shows a message that is not there
+    #[kani::proof]
+    fn an_outdated_write_index_is_safe() {
+        let capacity = any_capacity();
+        let (read, write) = any_indices(capacity);
+        let behind: usize = kani::any();
+        kani::assume(behind <= isize::MAX as usize);
+        let copy = write.wrapping_sub(behind);
+        if has_message(read, copy) {
+            assert!(!is_empty(read, write));
+        }
+    }
+
+    // Widening any index that is not too far ahead of an earlier one gives
+    // the earlier count plus the distance, whatever wrapped in between
+    #[kani::proof]
+    fn widening_counts_across_the_wrap() {
+        let earlier: u64 = kani::any();
+        let distance: usize = kani::any();
+        kani::assume(distance < usize::MAX);
+        let index = advance(earlier as usize, distance);
+        assert_eq!(widen(earlier, index), earlier.wrapping_add(distance as u64));
+    }
+
+    // Runs both sides in any interleaving: each only moves its own index,
+    // one step at a time, and the invariant holds after every step
+    #[kani::proof]
+    #[kani::unwind(9)]
+    fn any_interleaving_keeps_the_invariant() {
+        let capacity = any_capacity();
+        let start: usize = kani::any();
+        let (mut read, mut write) = (start, start);
+        for _ in 0..8 {
+            let (old_read, old_write) = (read, write);
+            if kani::any() {
+                if !is_full(read, write, capacity) {
+                    write = advance(write, 1);
+                }
+                assert_eq!(read, old_read);
+            } else {
+                if !is_empty(read, write) {
+                    read = advance(read, 1);
+                }
+                assert_eq!(write, old_write);
+            }
+            assert!(len(old_read, read) <= 1 && len(old_write, write) <= 1);
+            assert!(len(read, write) <= capacity);
+            assert_eq!(
+                range(read, write).count(),
+                len(read, write),
+                "the range covers the queued messages"
+            );
+        }
+    }
+}
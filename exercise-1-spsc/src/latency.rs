@@ -0,0 +1,274 @@
+//! Measuring how long messages wait in the queue (the `latency` feature).
+//!
+//! Once `measure_latency` is called on the consumer, every send stamps the
+//! slots it publishes with the time, and every receive adds the time since
+//! to a histogram, whichever call took the message. `recv_with_latency`
+//! also returns it for the message received. The messages sent before the
+//! measurement started are left out.
+//!
+//! The histogram counts powers of two of nanoseconds, which is coarse but
+//! takes one atomic add per message and never allocates after the start.
+//!
+//! ```
+//! let (mut px, mut cx) = spsc::channel_with_capacity(4);
+//! cx.measure_latency();
+//! px.send(1).unwrap();
+//! let (val, latency) = cx.recv_with_latency().unwrap();
+//! assert_eq!(val, 1);
+//! assert!(latency.is_some());
+//!
+//! let histogram = cx.latency();
+//! assert_eq!(histogram.count, 1);
+//! assert!(histogram.percentile(0.99) >= histogram.max);
+//! ```
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::index;
+use crate::primitives::{AtomicU64, Ordering};
+
+/// The number of buckets of `LatencyHistogram`, enough for any `u64` of
+/// nanoseconds.
+pub const BUCKETS: usize = 65;
+
+/// Snapshot of the time the received messages spent in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Messages measured.
+    pub count: u64,
+    /// Their time in the queue, together.
+    pub total: Duration,
+    /// The longest time in the queue.
+    pub max: Duration,
+    /// Bucket 0 counts the messages received within the nanosecond they
+    /// were sent, bucket `i` those that waited from `2^(i-1)` up to `2^i`
+    /// nanoseconds.
+    pub buckets: [u64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// The average time in the queue, None before any message was measured.
+    pub fn mean(&self) -> Option<Duration> {
+        let total = self.total.as_nanos();
+        (self.count > 0).then(|| Duration::from_nanos((total / u128::from(self.count)) as u64))
+    }
+
+    /// The time in the queue that the fraction `q` of the messages did not
+    /// exceed, rounded up to the end of its bucket. Zero before any message
+    /// was measured.
+    pub fn percentile(&self, q: f64) -> Duration {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if count > 0 && seen >= rank {
+                return Duration::from_nanos(upper_bound(bucket));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+// The largest latency in nanoseconds that falls into bucket
+fn upper_bound(bucket: usize) -> u64 {
+    match bucket {
+        0 => 0,
+        64 => u64::MAX,
+        _ => (1 << bucket) - 1,
+    }
+}
+
+fn bucket(nanos: u64) -> usize {
+    (u64::BITS - nanos.leading_zeros()) as usize
+}
+
+// The latency measurement of a channel, part of its state. Until it starts,
+// stamping a send is an acquire load.
+pub(crate) struct Latency {
+    measured: OnceLock<Measured>,
+}
+
+struct Measured {
+    start: Instant,
+    // The time each slot was published at, in nanoseconds since start plus
+    // one, 0 for none. Twice as many as there are slots, so the consumer
+    // still finds the stamp of a message after handing its slot back: the
+    // producer only comes round to the same stamp once the consumer has
+    // taken another capacity of messages. (Unless force_send evicts them
+    // all in between, which at worst gets a measurement wrong.)
+    stamps: Box<[AtomicU64]>,
+    count: AtomicU64,
+    // in nanoseconds
+    total: AtomicU64,
+    max: AtomicU64,
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Latency {
+    pub(crate) const fn new() -> Self {
+        Latency {
+            measured: OnceLock::new(),
+        }
+    }
+
+    // Starts measuring, if it has not already
+    pub(crate) fn start(&self, capacity: usize) {
+        let atomics = |len| (0..len).map(|_| AtomicU64::new(0)).collect();
+        self.measured.get_or_init(|| Measured {
+            start: Instant::now(),
+            stamps: atomics(2 * capacity),
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            buckets: atomics(BUCKETS),
+        });
+    }
+
+    // Stamps the positions from previous up to write_index, before they are
+    // published
+    pub(crate) fn sent(&self, previous: usize, write_index: usize) {
+        let Some(measured) = self.measured.get() else {
+            return;
+        };
+        let now = measured.now();
+        for position in index::range(previous, write_index) {
+            measured.stamp(position).store(now, Ordering::Relaxed);
+        }
+    }
+
+    // Measures the messages from previous up to read_index, which the
+    // consumer just handed back
+    pub(crate) fn received(&self, previous: usize, read_index: usize) {
+        let Some(measured) = self.measured.get() else {
+            return;
+        };
+        let now = measured.now();
+        for position in index::range(previous, read_index) {
+            if let Some(nanos) = measured.since(position, now) {
+                measured.count.fetch_add(1, Ordering::Relaxed);
+                measured.total.fetch_add(nanos, Ordering::Relaxed);
+                measured.max.fetch_max(nanos, Ordering::Relaxed);
+                measured.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // The latency of the message the consumer just received at position
+    pub(crate) fn of(&self, position: usize) -> Option<Duration> {
+        let measured = self.measured.get()?;
+        measured
+            .since(position, measured.now())
+            .map(Duration::from_nanos)
+    }
+
+    pub(crate) fn histogram(&self) -> LatencyHistogram {
+        let Some(measured) = self.measured.get() else {
+            return LatencyHistogram::default();
+        };
+        let load = |atomic: &AtomicU64| atomic.load(Ordering::Relaxed);
+        let mut buckets = [0; BUCKETS];
+        for (bucket, atomic) in buckets.iter_mut().zip(measured.buckets.iter()) {
+            *bucket = load(atomic);
+        }
+        LatencyHistogram {
+            count: load(&measured.count),
+            total: Duration::from_nanos(load(&measured.total)),
+            max: Duration::from_nanos(load(&measured.max)),
+            buckets,
+        }
+    }
+}
+
+impl Measured {
+    fn now(&self) -> u64 {
+        let nanos = self.start.elapsed().as_nanos().min(u64::MAX.into()) as u64;
+        nanos.saturating_add(1)
+    }
+
+    fn stamp(&self, position: usize) -> &AtomicU64 {
+        &self.stamps[index::slot(position, self.stamps.len())]
+    }
+
+    // None for a message sent before the measurement started
+    fn since(&self, position: usize, now: u64) -> Option<u64> {
+        match self.stamp(position).load(Ordering::Relaxed) {
+            0 => None,
+            stamp => Some(now.saturating_sub(stamp)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn buckets_are_powers_of_two() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(4), 3);
+        assert_eq!(bucket(u64::MAX), 64);
+        for nanos in [0, 1, 5, 1000, u64::MAX] {
+            assert!(nanos <= upper_bound(bucket(nanos)));
+        }
+    }
+
+    #[test]
+    fn every_receive_is_measured_once_started() {
+        let (mut px, mut cx) = crate::channel_with_capacity(4);
+        // not measured, the measurement has not started
+        px.send(0).unwrap();
+        cx.measure_latency();
+        for i in 1..4 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.recv_with_latency().unwrap(), (0, None));
+        thread::sleep(Duration::from_millis(2));
+        let (val, latency) = cx.recv_with_latency().unwrap();
+        assert_eq!(val, 1);
+        assert!(latency.unwrap() >= Duration::from_millis(2));
+        let mut out = Vec::new();
+        cx.recv_many(&mut out, 8);
+        assert_eq!(out, [2, 3]);
+
+        let histogram = cx.latency();
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 3);
+        assert!(histogram.max >= Duration::from_millis(2));
+        assert!(histogram.total >= histogram.max);
+        assert!(histogram.percentile(1.0) >= histogram.max);
+    }
+
+    #[test]
+    fn stamps_survive_the_wrap_around() {
+        let (mut px, mut cx) = crate::channel_with_capacity(2);
+        cx.measure_latency();
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                px.send(i).unwrap();
+            }
+        });
+        for i in 0..1000 {
+            let (val, latency) = cx.recv_with_latency().unwrap();
+            assert_eq!(val, i);
+            assert!(latency.is_some());
+        }
+        producer.join().unwrap();
+        assert_eq!(cx.latency().count, 1000);
+    }
+}
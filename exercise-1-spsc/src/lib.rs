@@ -1,300 +1,4533 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 #![allow(unused_variables)]
 
-use std::cell::UnsafeCell;
-use std::marker::PhantomData;
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+extern crate alloc;
+// the tests have the standard library either way
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
 
-// Check if we can tweak the buffer size for performance
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+mod adapters;
+#[cfg(all(feature = "affinity", not(loom)))]
+pub mod affinity;
+#[cfg(not(loom))]
+pub mod allocator;
+#[cfg(not(loom))]
+pub mod borrowed;
+#[cfg(all(feature = "tokio", not(loom)))]
+pub mod bridge;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod broadcast;
+mod builder;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod bytes;
+#[cfg(feature = "std")]
+mod cancel;
+#[cfg(all(feature = "chaos", not(loom)))]
+mod chaos;
+#[cfg(feature = "std")]
+pub mod compat;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod conflate;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod disruptor;
+#[cfg(feature = "std")]
+mod duplex;
+mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(all(feature = "serde", not(loom)))]
+pub mod framed;
+#[cfg(feature = "std")]
+mod future;
+#[cfg(feature = "std")]
+pub mod growable;
+#[cfg(feature = "std")]
+mod hooks;
+mod index;
+#[cfg(feature = "latency")]
+pub mod latency;
+pub mod local;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod locked;
+#[cfg(all(feature = "memory", not(loom)))]
+pub mod memory;
+#[cfg(feature = "std")]
+mod merge;
+#[cfg(feature = "std")]
+pub mod mpmc;
+#[cfg(all(feature = "fd", unix, not(loom)))]
+mod notifier;
+#[cfg(feature = "std")]
+pub mod oneshot;
+#[cfg(feature = "std")]
+mod pipeline;
+mod primitives;
+#[cfg(feature = "std")]
+pub mod priority;
+mod queue;
+#[cfg(feature = "record")]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod rendezvous;
+mod ring;
+#[cfg(feature = "std")]
+pub mod select;
+#[cfg(all(feature = "shm", unix, not(loom)))]
+pub mod shm;
+#[cfg(feature = "stats")]
+mod stats;
+pub mod sync;
+#[cfg(feature = "std")]
+mod tee;
+#[cfg(feature = "std")]
+pub mod ttl;
+#[cfg(feature = "std")]
+pub mod unbounded;
+mod wait_queue;
+mod wait_strategy;
+#[cfg(all(feature = "std", not(loom)))]
+pub mod watch;
+
+#[cfg(feature = "std")]
+pub use adapters::{Filter, Map, Receive};
+pub use builder::ChannelBuilder;
+#[cfg(feature = "std")]
+pub use cancel::{CancelToken, CancelledFuture};
+#[cfg(feature = "std")]
+pub use duplex::{duplex, duplex_with_capacity, Endpoint};
+pub use error::{
+    FlushError, ForceSendError, RecvCancelError, RecvError, RecvTimeoutError, ReuniteError,
+    SendCancelError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+#[cfg(feature = "std")]
+pub use future::{RecvCancelFuture, RecvFuture, SendCancelFuture, SendFuture};
+#[cfg(feature = "std")]
+pub use merge::{merge, Merged};
+#[cfg(all(feature = "fd", unix, not(loom)))]
+pub use notifier::Notifier;
+#[cfg(feature = "std")]
+pub use pipeline::{Complete, Pipeline};
+use primitives::{Arc, Ordering, UnsafeCell};
+pub use queue::Queue;
+use ring::{Ring, Slot, State};
+#[cfg(feature = "stats")]
+pub use stats::ChannelStats;
+#[cfg(feature = "std")]
+pub use tee::{tee, Lag};
+pub use wait_strategy::WaitStrategy;
+
+// The capacity of `channel()`, other sizes go through `channel_with_capacity`
+#[cfg(not(loom))]
 const BUFFER_SIZE: usize = 4096;
+// Keep the model small so loom can also explore the full buffer
+#[cfg(loom)]
+const BUFFER_SIZE: usize = 2;
+
+// The index arithmetic masks with the buffer length
+const _: () = ring::check_capacity(BUFFER_SIZE);
+
+// What clone and upgrade panic with on a channel that allows one producer
+const SINGLE_PRODUCER: &str = "only the producers of channel_mpsc can be cloned or upgraded";
+
+// A slice rather than an array so every channel can pick its capacity. Boxed,
+// because loom's Arc can not hold unsized values.
+enum Buffer<T> {
+    Heap(Box<[Slot<T>]>),
+    // in pages of their own, see `memory`
+    #[cfg(all(feature = "memory", target_os = "linux", not(loom)))]
+    Mapped(memory::Mapping<T>),
+    // from an allocator of the caller, see `allocator`
+    #[cfg(not(loom))]
+    Allocated(allocator::Allocated<T>),
+}
+
+impl<T> Deref for Buffer<T> {
+    type Target = [Slot<T>];
+
+    fn deref(&self) -> &[Slot<T>] {
+        match self {
+            Buffer::Heap(slots) => slots,
+            #[cfg(all(feature = "memory", target_os = "linux", not(loom)))]
+            Buffer::Mapped(slots) => slots,
+            #[cfg(not(loom))]
+            Buffer::Allocated(slots) => slots,
+        }
+    }
+}
+
+// Everything the endpoints of a channel share, behind a single Arc
+struct Inner<T> {
+    message_buffer: Buffer<T>,
+    state: State,
+}
+
+impl<T> Drop for Inner<T> {
+    // The slots do not drop their content on their own
+    fn drop(&mut self) {
+        let ring: Ring<'_, T> = Ring {
+            buffer: &self.message_buffer,
+            state: &self.state,
+        };
+        // the Arc is gone, so nobody else can see the ring anymore
+        unsafe { ring.drop_queued() }
+    }
+}
+
+/// The sending end of a channel. It can be moved to another thread, but not
+/// shared with one, and sending takes `&mut self`, so it is used from one
+/// place at a time. The producer of a channel from `channel_mpsc` can be
+/// cloned for another thread, that of any other channel is the only one.
+///
+/// ```compile_fail
+/// fn shared<T: Sync>(_: &T) {}
+/// let (px, _cx) = spsc::channel::<i32>();
+/// shared(&px);
+/// ```
+///
+/// ```compile_fail
+/// let (mut px, _cx) = spsc::channel::<i32>();
+/// let pending = px.send_async(1);
+/// // the future still has the producer
+/// px.try_send(2);
+/// drop(pending);
+/// ```
+pub struct Producer<T> {
+    inner: Arc<Inner<T>>,
+    // the write index of the slot Sink::poll_ready reserved for start_send
+    #[cfg(feature = "futures")]
+    sink_slot: Option<usize>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+/// The receiving end of a channel. Like the producer, it can be moved to
+/// another thread but not shared with one, receiving takes `&mut self`, and
+/// there is only ever one.
+///
+/// ```compile_fail
+/// let (_px, cx) = spsc::channel::<i32>();
+/// let cx = std::sync::Arc::new(cx);
+/// std::thread::spawn(move || cx.recv());
+/// ```
+pub struct Consumer<T> {
+    inner: Arc<Inner<T>>,
+    // the sequence number of a read index recv_with_seq saw, widened so
+    // it does not wrap with the index on 32-bit targets
+    #[cfg(feature = "std")]
+    seq: u64,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// A producer handle that does not keep the channel open, see
+/// `Producer::downgrade`.
+pub struct WeakProducer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// A handle that can only look at a channel, see `Producer::monitor`.
+pub struct Monitor<T> {
+    inner: Arc<Inner<T>>,
+}
+
+pub struct SPSC<T> {
+    producer: Producer<T>,
+    consumer: Consumer<T>,
+}
+
+impl<T: Send> SPSC<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(BUFFER_SIZE)
+    }
+
+    /// Creates a channel that buffers up to `capacity` messages.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_strategy(capacity, WaitStrategy::default())
+    }
+
+    /// Like `with_capacity`, but blocked calls wait as `wait_strategy` says
+    /// instead of parking.
+    pub fn with_strategy(capacity: usize, wait_strategy: WaitStrategy) -> Self {
+        Self::allocate(capacity, State::new(wait_strategy))
+    }
+
+    /// Like `with_capacity`, with `label` naming the channel in its Debug
+    /// output and, with the `tracing` feature, in its spans and events.
+    pub fn with_label(capacity: usize, label: &'static str) -> Self {
+        let state = State {
+            label: Some(label),
+            ..State::new(WaitStrategy::default())
+        };
+        Self::allocate(capacity, state)
+    }
+
+    // The state carries the options of the channel, see ChannelBuilder
+    fn allocate(capacity: usize, state: State) -> Self {
+        ring::check_capacity(capacity);
+        // The only way I found for 2 threads to share a buffer is unsafe cells.
+        // Collecting allocates the slots right on the heap, a temporary array
+        // would have to fit on the stack for large T. For a zero-sized T,
+        // like () as a token, it allocates nothing and the slot accesses
+        // compile to nothing, so only the indices are left.
+        let cells: Box<[Slot<T>]> = (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect();
+        Self::from_cells(Buffer::Heap(cells), state)
+    }
+
+    /// Like `with_capacity`, but allocates the buffer as `memory` says, e.g.
+    /// on a NUMA node or in huge pages. On other systems than Linux, or if
+    /// the buffer can not be mapped, it is allocated as usual.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    #[cfg(all(feature = "memory", not(loom)))]
+    pub fn with_memory(capacity: usize, memory: memory::Memory) -> Self {
+        ring::check_capacity(capacity);
+        #[cfg(target_os = "linux")]
+        if let Some(spsc) = Self::mapped(capacity, memory, State::new(WaitStrategy::default())) {
+            return spsc;
+        }
+        Self::with_capacity(capacity)
+    }
+
+    // A channel on a mapped buffer, unless it can not be mapped
+    #[cfg(all(feature = "memory", target_os = "linux", not(loom)))]
+    fn mapped(capacity: usize, memory: memory::Memory, state: State) -> Option<Self> {
+        let mapping = memory::Mapping::new(capacity, memory)?;
+        Some(Self::from_cells(Buffer::Mapped(mapping), state))
+    }
+
+    /// Like `with_capacity`, but the buffer comes from `allocator`, and goes
+    /// back to it along with the channel. See `allocator`.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two, and aborts like a
+    /// `Box` would if `allocator` runs out of memory.
+    #[cfg(not(loom))]
+    pub fn with_allocator(capacity: usize, allocator: impl allocator::BufferAllocator) -> Self {
+        Self::allocated(capacity, allocator, State::new(WaitStrategy::default()))
+    }
+
+    // A channel on a buffer from allocator
+    #[cfg(not(loom))]
+    fn allocated(
+        capacity: usize,
+        allocator: impl allocator::BufferAllocator,
+        state: State,
+    ) -> Self {
+        ring::check_capacity(capacity);
+        Self::from_cells(
+            Buffer::Allocated(allocator::Allocated::new(capacity, allocator)),
+            state,
+        )
+    }
+
+    /// Creates a channel on `buffer` instead of allocating one, so its
+    /// capacity is the length of `buffer`. The buffer is freed along with
+    /// the channel.
+    ///
+    /// Panics if `buffer` is empty or its length is not a power of two.
+    #[cfg(not(loom))]
+    pub fn in_buffer(buffer: Box<[MaybeUninit<T>]>) -> Self {
+        ring::check_capacity(buffer.len());
+        Self::from_cells(
+            Buffer::Heap(UnsafeCell::from_boxed_slice(buffer)),
+            State::new(WaitStrategy::default()),
+        )
+    }
+
+    fn from_cells(cells: Buffer<T>, state: State) -> Self {
+        let inner: Arc<Inner<T>> = Arc::new(Inner {
+            message_buffer: cells,
+            state,
+        });
+
+        let producer = Producer::new(inner.clone());
+
+        let consumer = Consumer::new(inner.clone());
+
+        SPSC { producer, consumer }
+    }
+
+    /// Turns the endpoints of a channel back into a new channel on the same
+    /// buffer, so a pipeline that is set up once per job does not allocate
+    /// a buffer for each. The messages still queued are dropped, and the new
+    /// channel starts out as the old one did: open, with its capacity, wait
+    /// strategy, label and number of producers it allows, but no hooks and
+    /// no stats.
+    ///
+    /// Fails if the two belong to different channels, or if another handle
+    /// still exists: a clone of the producer, a weak one or a monitor.
+    pub fn reunite(
+        producer: Producer<T>,
+        consumer: Consumer<T>,
+    ) -> Result<(Producer<T>, Consumer<T>), ReuniteError<T>> {
+        if !Arc::ptr_eq(&producer.inner, &consumer.inner) || Arc::strong_count(&producer.inner) != 2
+        {
+            return Err(ReuniteError(producer, consumer));
+        }
+        let mut inner = producer.inner.clone();
+        drop((producer, consumer));
+        let inner_mut = Arc::get_mut(&mut inner).expect("the endpoints were the last handles");
+        let ring: Ring<'_, T> = Ring {
+            buffer: &inner_mut.message_buffer,
+            state: &inner_mut.state,
+        };
+        // the endpoints are gone, so nobody else can see the ring anymore
+        unsafe { ring.drop_queued() };
+        inner_mut.state = State {
+            label: inner_mut.state.label,
+            // none of the slots is written in the new one
+            written: inner_mut
+                .state
+                .written
+                .as_ref()
+                .map(|written| State::written_marks(written.len())),
+            ..State::new(inner_mut.state.wait_strategy)
+        };
+
+        let producer = Producer::new(inner.clone());
+        let consumer = Consumer::new(inner);
+        Ok((producer, consumer))
+    }
+}
+
+impl<T: Send> Default for SPSC<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Producer<T> {
+    fn new(inner: Arc<Inner<T>>) -> Self {
+        Producer {
+            inner,
+            #[cfg(feature = "futures")]
+            sink_slot: None,
+            _not_sync: PhantomData,
+        }
+    }
+
+    fn ring(&self) -> Ring<'_, T> {
+        Ring {
+            buffer: &self.inner.message_buffer,
+            state: &self.inner.state,
+        }
+    }
+}
+
+impl<T: Send> Producer<T> {
+    #[cfg(feature = "std")]
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        self.ring().send(val)
+    }
+
+    /// Sends `val` if there is a free slot right now, without waiting for the
+    /// consumer. Otherwise the value is handed back in the error.
+    pub fn try_send(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.ring().try_send(val)
+    }
+
+    /// Like `send`, but instead of blocking the thread while the buffer is
+    /// full, the returned future is pending. Works with any executor.
+    #[cfg(feature = "std")]
+    pub fn send_async(&mut self, val: T) -> SendFuture<'_, T> {
+        SendFuture::new(self.ring(), val)
+    }
+
+    /// Like `send`, but waits at most for `timeout` for a free slot. On
+    /// failure the value is handed back in the error.
+    #[cfg(feature = "std")]
+    pub fn send_timeout(&mut self, val: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.ring().send_timeout(val, timeout)
+    }
+
+    /// Like `send`, but gives up once `token` is cancelled, from this thread
+    /// or another. A free slot is taken even if the token already is
+    /// cancelled. On failure the value is handed back in the error.
+    #[cfg(feature = "std")]
+    pub fn send_cancellable(
+        &mut self,
+        val: T,
+        token: &CancelToken,
+    ) -> Result<(), SendCancelError<T>> {
+        self.ring().send_cancellable(val, token)
+    }
+
+    /// The async counterpart of `send_cancellable`: like `send_async`, but
+    /// the future completes with the value handed back once `token` is
+    /// cancelled.
+    #[cfg(feature = "std")]
+    pub fn send_async_cancellable<'a>(
+        &'a mut self,
+        val: T,
+        token: &'a CancelToken,
+    ) -> SendCancelFuture<'a, T> {
+        SendCancelFuture::new(self.ring(), val, token)
+    }
+
+    /// Waits until at least `n` slots are free, with the channel's wait
+    /// strategy, and returns how many are. A burst of up to `n` messages
+    /// then goes out without blocking halfway, e.g. through `send_iter`,
+    /// unless a clone of this producer takes some of the room first. Fails
+    /// once the consumer is gone.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than the capacity, it would wait forever.
+    #[cfg(feature = "std")]
+    pub fn wait_for_space(&mut self, n: usize) -> Result<usize, SendError<()>> {
+        wait_for_space(self.ring(), n)
+    }
+
+    /// Waits for a free slot and reserves it. The message is sent by calling
+    /// `SlotGuard::write`; dropping the guard without writing releases the
+    /// slot again.
+    #[cfg(feature = "std")]
+    pub fn reserve(&mut self) -> Result<SlotGuard<'_, T>, SendError<()>> {
+        SlotGuard::new(self.ring())
+    }
+
+    /// Waits for a free slot like `reserve`, but reserves every slot that is
+    /// free by then. They can be written in place through
+    /// `VacantSlices::as_mut_slices` and are sent with `VacantSlices::commit`.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub fn vacant_slices(&mut self) -> Result<VacantSlices<'_, T>, SendError<()>> {
+        VacantSlices::new(self.ring())
+    }
+
+    /// Waits until `n` slots are free and reserves them for a group of
+    /// messages that the consumer sees all at once or not at all: they are
+    /// written with `Transaction::push` and sent together by
+    /// `Transaction::commit`. Dropping the transaction without committing
+    /// drops the messages written so far, nothing is sent.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than the capacity, it would wait forever.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub fn transaction(&mut self, n: usize) -> Result<Transaction<'_, T>, SendError<()>> {
+        Transaction::new(self.ring(), n)
+    }
+
+    /// Waits for a free slot and builds the message right in it, which saves
+    /// moving a large `T` into the buffer. See `SlotGuard::write_with`.
+    #[cfg(feature = "std")]
+    pub fn send_with<F>(&mut self, init: F) -> Result<(), SendError<()>>
+    where
+        F: for<'s> FnOnce(&'s mut MaybeUninit<T>) -> &'s mut T,
+    {
+        self.reserve()?.write_with(init);
+        Ok(())
+    }
+
+    /// Sends `val` without ever waiting for the consumer: if the buffer is
+    /// full, the oldest unread message is dropped to make room.
+    ///
+    /// A message the consumer is still using can not be dropped. While the
+    /// oldest one is being received, this waits the moment it takes. When
+    /// it is borrowed by a `RecvGuard`, `PeekGuard`, `PeekMutGuard` or
+    /// `OccupiedSlices`, which may last any time, this fails with
+    /// `ForceSendError::Held` instead, and so it does while the `SlotGuard`
+    /// of another producer holds the next slot. The message comes back with
+    /// the error.
+    pub fn send_overwrite(&mut self, val: T) -> Result<(), ForceSendError<T>> {
+        self.force_send(val).map(drop)
+    }
+
+    /// Like `send_overwrite`, but returns the message that was evicted to
+    /// make room, if any, instead of dropping it.
+    pub fn force_send(&mut self, val: T) -> Result<Option<T>, ForceSendError<T>> {
+        self.ring().force_send(val)
+    }
+
+    /// Sends every element of `iter`, blocking on a full buffer like `send`.
+    /// Returns the number of elements sent, or if the consumer disconnects,
+    /// how many made it and the element that could not be sent.
+    #[cfg(feature = "std")]
+    pub fn send_all<I>(&mut self, iter: I) -> Result<usize, (usize, SendError<T>)>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.ring().send_all(iter)
+    }
+
+    /// Sends elements of `iter` into the free slots of the buffer without
+    /// waiting for more room, and publishes them to the consumer all at once.
+    /// Returns how many were sent. `iter` is only advanced for the elements
+    /// that fit, so pass `iter.by_ref()` to keep the rest. Fails only if the
+    /// consumer is gone.
+    pub fn send_iter<I>(&mut self, iter: I) -> Result<usize, SendError<()>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.ring().send_iter(iter)
+    }
+
+    /// Blocks until the consumer has received every message sent so far.
+    /// Fails if the consumer is dropped before the buffer is drained.
+    #[cfg(feature = "std")]
+    pub fn flush(&mut self) -> Result<(), FlushError> {
+        self.ring().flush()
+    }
+
+    /// Returns whether the consumer still exists. This is only a snapshot,
+    /// the consumer may be dropped right after the check.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.inner.state.consumer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// Installs `f` to run whenever the consumer takes a message out of the
+    /// full buffer, unless a send got in before, e.g. to wake an event loop
+    /// that has a message waiting after a failed `try_send`. Replaces the
+    /// one of a clone of this producer. A full buffer from before is up to
+    /// the caller to check for.
+    ///
+    /// `f` runs on the consumer's thread, within the receive, but holds no
+    /// lock of the channel: it may call into it, e.g. to install another
+    /// callback. It must not wait for the consumer though, which is busy
+    /// running `f`.
+    #[cfg(feature = "std")]
+    pub fn set_notify(&self, f: impl Fn() + Send + Sync + 'static) {
+        self.inner.state.on_space.set(f);
+    }
+
+    /// Installs `on_high` to run when a send fills the buffer up to `high`
+    /// messages, and `on_low` when a receive drains it down to `low`, e.g.
+    /// to stop reading from a socket before the buffer is full and to start
+    /// again once the consumer caught up. They take turns: after `on_high`,
+    /// neither runs again until the buffer is down to `low`. Replaces the
+    /// watermarks of a clone of this producer.
+    ///
+    /// Each runs within a call that crossed a mark, usually the one that
+    /// crossed its own. While one side runs either of them, the other side
+    /// leaves the next one to it rather than run both at once, so a send
+    /// may run `on_low` and a receive `on_high`. Like the callback of
+    /// `set_notify` they hold no lock of the channel and may call into it,
+    /// but must not wait for the side that runs them.
+    ///
+    /// Panics unless `low < high <= capacity`.
+    #[cfg(feature = "std")]
+    pub fn set_watermarks(
+        &self,
+        high: usize,
+        low: usize,
+        on_high: impl Fn() + Send + Sync + 'static,
+        on_low: impl Fn() + Send + Sync + 'static,
+    ) {
+        assert!(
+            low < high && high <= self.capacity(),
+            "watermarks need low < high <= capacity"
+        );
+        self.inner
+            .state
+            .watermarks
+            .set(high, low, on_high, on_low);
+    }
+
+    /// Closes the channel for both sides, without dropping either handle.
+    /// Further sends fail, the consumer still receives what is buffered.
+    pub fn close(&self) {
+        self.ring().close()
+    }
+
+    /// Returns whether either side closed the channel.
+    pub fn is_closed(&self) -> bool {
+        self.ring().is_closed()
+    }
+
+    /// Returns whether a handle or guard of the channel was dropped by a
+    /// panic, which closed the channel. Receives still drain what was sent
+    /// before; a send or receive that the panic cut short did not happen.
+    #[cfg(feature = "std")]
+    pub fn is_poisoned(&self) -> bool {
+        self.ring().is_poisoned()
+    }
+
+    /// Returns whether sends fail from now on, because the consumer is gone
+    /// or the channel was closed. Cheap enough to check before building an
+    /// expensive message; like `is_consumer_alive` only a snapshot, though
+    /// once it returns true it stays so.
+    pub fn is_disconnected(&self) -> bool {
+        self.ring().is_disconnected_from_consumer()
+    }
+
+    /// Creates a handle that does not count as a producer, so the consumer
+    /// still sees the channel disconnect once all producers are dropped.
+    pub fn downgrade(&self) -> WeakProducer<T> {
+        WeakProducer {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Creates a handle that reports how the channel is doing, e.g. to a
+    /// metrics thread, but can neither send nor receive. It keeps neither
+    /// side connected, only the buffer and what is left in it allocated.
+    pub fn monitor(&self) -> Monitor<T> {
+        Monitor {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns how many messages the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.message_buffer.len()
+    }
+
+    /// Returns how many messages are queued right now. The other side may
+    /// change that at any moment, so this is only a snapshot.
+    pub fn len(&self) -> usize {
+        self.ring().len()
+    }
+
+    /// Returns whether no message is queued right now, see `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether every slot is taken right now, see `len`.
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Returns the largest number of messages that were queued at once
+    /// over the lifetime of the channel.
+    pub fn high_water_mark(&self) -> usize {
+        self.inner.state.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters collected for this channel so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.ring().stats()
+    }
+
+    /// Starts recording the operations on the channel, from both sides,
+    /// keeping the last `limit` of them. Starts over if it was recording
+    /// already. See `record`.
+    #[cfg(feature = "record")]
+    pub fn start_recording(&self, limit: usize) {
+        self.ring().start_recording(limit);
+    }
+
+    /// Returns the operations recorded so far, empty unless
+    /// `start_recording` was called.
+    #[cfg(feature = "record")]
+    pub fn trace(&self) -> record::Trace {
+        self.ring().trace()
+    }
+}
+
+impl<T> Consumer<T> {
+    fn new(inner: Arc<Inner<T>>) -> Self {
+        Consumer {
+            #[cfg(feature = "std")]
+            seq: inner.state.read_index.load(Ordering::Relaxed) as u64,
+            inner,
+            _not_sync: PhantomData,
+        }
+    }
+
+    fn ring(&self) -> Ring<'_, T> {
+        Ring {
+            buffer: &self.inner.message_buffer,
+            state: &self.inner.state,
+        }
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    #[cfg(feature = "std")]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.recv_into(&mut val)?;
+        // recv_into only returns Ok after initializing val
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Moves the next message directly into `dst` instead of returning it,
+    /// which saves a copy for large `T`. On success `dst` is initialized.
+    #[cfg(feature = "std")]
+    pub fn recv_into(&mut self, dst: &mut MaybeUninit<T>) -> Result<(), RecvError> {
+        self.ring().recv_into(dst)
+    }
+
+    /// Like `recv`, along with the sequence number of the message: the
+    /// number of messages sent on the channel before it, counting from 0.
+    /// The numbers of the messages received go up by one each, unless
+    /// `send_overwrite` dropped some in between, which shows as a gap.
+    ///
+    /// The numbers are 64 bits wide on every target, but the consumer can
+    /// only keep count of fewer than `usize::MAX` messages received or
+    /// dropped between two calls.
+    #[cfg(feature = "std")]
+    pub fn recv_with_seq(&mut self) -> Result<(u64, T), RecvError> {
+        let mut val = MaybeUninit::uninit();
+        let read_index = self.ring().recv_into_seq(&mut val)?;
+        self.seq = index::widen(self.seq, read_index);
+        Ok((self.seq, unsafe { val.assume_init() }))
+    }
+
+    /// Like `recv`, along with the time the message spent in the queue. None
+    /// unless the message was sent after `measure_latency` was called.
+    #[cfg(feature = "latency")]
+    pub fn recv_with_latency(&mut self) -> Result<(T, Option<Duration>), RecvError> {
+        let mut val = MaybeUninit::uninit();
+        let latency = self.ring().recv_into_with_latency(&mut val)?;
+        Ok((unsafe { val.assume_init() }, latency))
+    }
+
+    /// Moves up to `out.len()` of the currently queued messages into `out`
+    /// without blocking, and returns how many leading entries it initialized.
+    pub fn recv_into_slice(&mut self, out: &mut [MaybeUninit<T>]) -> usize {
+        self.ring().recv_into_slice(out)
+    }
+
+    /// Moves up to `max` of the currently queued messages to the end of `out`
+    /// without blocking, and returns how many it moved. Like
+    /// `recv_into_slice`, the slots are handed back to the producer at once.
+    pub fn recv_many(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        recv_many(self.ring(), out, max)
+    }
+
+    /// Waits until at least `n` messages are queued and returns how many
+    /// are. If the producer goes away first, returns with the ones that are
+    /// left, and fails if there are none.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than the capacity, it would wait forever.
+    #[cfg(feature = "std")]
+    pub fn wait_for_messages(&mut self, n: usize) -> Result<usize, RecvError> {
+        wait_for_messages(self.ring(), n)
+    }
+
+    /// Waits for `n` messages like `wait_for_messages` and receives them in
+    /// one go, e.g. a frame of samples, instead of synchronizing with the
+    /// producer for each. Returns fewer only if the producer went away.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than the capacity.
+    #[cfg(feature = "std")]
+    pub fn recv_exact(&mut self, n: usize) -> Result<Vec<T>, RecvError> {
+        recv_exact(self.ring(), n)
+    }
+
+    /// Takes every message queued right now, with a single hand back of
+    /// their slots to the producer, e.g. for a consumer that wakes up now
+    /// and then to work through whatever piled up. Does not block.
+    pub fn drain(&mut self) -> Vec<T> {
+        drain(self.ring())
+    }
+
+    /// Receives a message if one is buffered right now, without waiting for
+    /// the producer.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring().try_recv_into(&mut val)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Returns an iterator over the messages queued right now, which ends
+    /// once the buffer is empty instead of waiting for the producer. Messages
+    /// that arrive while iterating are included.
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter { consumer: self }
+    }
+
+    /// Returns a file descriptor that turns readable when a message arrives
+    /// in the empty buffer or the channel disconnects, for a poll loop that
+    /// waits for sockets as well. See `Notifier` for how to use it. The
+    /// producers only pay for it once it is created, with the first call.
+    #[cfg(all(feature = "fd", unix, not(loom)))]
+    pub fn notifier(&self) -> std::io::Result<&Notifier> {
+        self.ring().notifier()
+    }
+
+    /// Like `recv`, but instead of blocking the thread while the buffer is
+    /// empty, the returned future is pending. Works with any executor.
+    #[cfg(feature = "std")]
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture::new(self.ring())
+    }
+
+    /// Like `recv`, but calls `on_empty` every time it finds the buffer empty
+    /// before checking again, e.g. to run other work of an event loop.
+    #[cfg(feature = "std")]
+    pub fn recv_or_else<F: FnMut()>(&mut self, on_empty: F) -> Result<T, RecvError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring().recv_into_or_else(&mut val, on_empty)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Like `recv`, but waits at most for `timeout`.
+    #[cfg(feature = "std")]
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring().recv_into_timeout(&mut val, timeout)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Like `recv`, but gives up once `token` is cancelled, from this thread
+    /// or another. A message that is already available is returned even if
+    /// the token already is cancelled.
+    #[cfg(feature = "std")]
+    pub fn recv_cancellable(&mut self, token: &CancelToken) -> Result<T, RecvCancelError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring().recv_into_cancellable(&mut val, token)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// The async counterpart of `recv_cancellable`: like `recv_async`, but
+    /// the future completes with an error once `token` is cancelled.
+    #[cfg(feature = "std")]
+    pub fn recv_async_cancellable<'a>(
+        &'a mut self,
+        token: &'a CancelToken,
+    ) -> RecvCancelFuture<'a, T> {
+        RecvCancelFuture::new(self.ring(), token)
+    }
+
+    /// Like `recv`, but gives up once `deadline` has passed. A message that is
+    /// already available is returned even if the deadline is in the past.
+    #[cfg(feature = "std")]
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        let mut val = MaybeUninit::uninit();
+        self.ring().recv_into_until(&mut val, Some(deadline))?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Returns an iterator that blocks like `recv`, but ends once no message
+    /// arrived for `timeout`, e.g. for a batch job that should finish soon
+    /// after the producer goes quiet, even if it is not dropped.
+    #[cfg(feature = "std")]
+    pub fn iter_timeout(&mut self, timeout: Duration) -> TimeoutIter<'_, T> {
+        TimeoutIter {
+            consumer: self,
+            until: Until::Idle(timeout),
+        }
+    }
+
+    /// Like `iter_timeout`, but ends once `deadline` has passed however busy
+    /// the channel is. The messages already queued by then are still
+    /// yielded.
+    #[cfg(feature = "std")]
+    pub fn iter_deadline(&mut self, deadline: Instant) -> TimeoutIter<'_, T> {
+        TimeoutIter {
+            consumer: self,
+            until: Until::Deadline(deadline),
+        }
+    }
+
+    /// Passes each message through `f` as it is received, on this thread.
+    /// The result receives (and iterates) like the consumer, see `Receive`.
+    #[cfg(feature = "std")]
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> Map<Self, F> {
+        Map::new(self, f)
+    }
+
+    /// Receives only the messages for which `predicate` holds and drops the
+    /// others, like `map`.
+    #[cfg(feature = "std")]
+    pub fn filter<P: FnMut(&T) -> bool>(self, predicate: P) -> Filter<Self, P> {
+        Filter::new(self, predicate)
+    }
+
+    /// Iterates like the consumer itself, until the channel is disconnected
+    /// (or closed) and drained, but only borrows it.
+    #[cfg(feature = "std")]
+    pub fn take_until_disconnect(&mut self) -> impl Iterator<Item = T> + '_ {
+        core::iter::from_fn(|| self.recv().ok())
+    }
+
+    /// Waits for the next message like `recv`, but leaves it in the buffer and
+    /// hands out a reference to it. The slot is released once the guard is
+    /// dropped. Takes `&mut self` so the message cannot also be received while
+    /// it is borrowed.
+    #[cfg(feature = "std")]
+    pub fn recv_ref(&mut self) -> Result<RecvGuard<'_, T>, RecvError> {
+        RecvGuard::new(self.ring())
+    }
+
+    /// Returns a reference to the message at the head of the buffer without
+    /// receiving it, or `None` if the buffer is empty right now. The message
+    /// stays queued and its slot stays taken until the guard is dropped or
+    /// popped.
+    pub fn peek(&mut self) -> Option<PeekGuard<'_, T>> {
+        PeekGuard::new(self.ring())
+    }
+
+    /// Like `peek`, but the message can be changed in place before it is
+    /// received.
+    pub fn peek_mut(&mut self) -> Option<PeekMutGuard<'_, T>> {
+        PeekMutGuard::new(self.ring())
+    }
+
+    /// Waits for a message like `recv_ref`, but borrows every message that is
+    /// queued by then. They can be used in place through
+    /// `OccupiedSlices::as_slices` and are received with
+    /// `OccupiedSlices::release`.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub fn occupied_slices(&mut self) -> Result<OccupiedSlices<'_, T>, RecvError> {
+        OccupiedSlices::new(self.ring())
+    }
+
+    /// Drops all messages currently in the buffer and returns their number.
+    pub fn clear(&mut self) -> usize {
+        self.ring().clear()
+    }
+
+    /// Closes the channel for both sides, without dropping either handle.
+    /// Further sends fail, the consumer still receives what is buffered. A
+    /// producer blocked on a full buffer is woken right away, and its send
+    /// fails with the message it could not send.
+    pub fn close(&self) {
+        self.ring().close()
+    }
+
+    /// Returns whether either side closed the channel.
+    pub fn is_closed(&self) -> bool {
+        self.ring().is_closed()
+    }
+
+    /// Returns whether a handle or guard of the channel was dropped by a
+    /// panic, which closed the channel. Receives still drain what was sent
+    /// before; a send or receive that the panic cut short did not happen.
+    #[cfg(feature = "std")]
+    pub fn is_poisoned(&self) -> bool {
+        self.ring().is_poisoned()
+    }
+
+    /// Returns whether the channel was closed or all producers are gone, so
+    /// no more messages arrive. The queued ones can still be received: the
+    /// stream is finished once this is true and `is_empty` as well.
+    pub fn is_disconnected(&self) -> bool {
+        self.ring().is_disconnected_from_producers()
+    }
+
+    /// Returns whether the producer still exists. This is only a snapshot,
+    /// the producer may be dropped right after the check.
+    pub fn is_producer_alive(&self) -> bool {
+        self.inner.state.producer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// See `Producer::monitor`.
+    pub fn monitor(&self) -> Monitor<T> {
+        Monitor {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Installs `f` to run whenever a producer sends into the empty buffer,
+    /// unless a receive got in before, e.g. to wake an event loop that found
+    /// `try_recv` empty. Replaces the one before. Messages from before are
+    /// up to the caller to check for.
+    ///
+    /// `f` runs on the producer's thread, within the send, but holds no
+    /// lock of the channel: it may call into it, e.g. to install another
+    /// callback. It must not wait for the producer though, which is busy
+    /// running `f`. The producers of `channel_mpsc` may run it on several
+    /// threads at once.
+    #[cfg(feature = "std")]
+    pub fn set_notify(&self, f: impl Fn() + Send + Sync + 'static) {
+        self.inner.state.on_message.set(f);
+    }
+
+    /// Returns how many messages the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.message_buffer.len()
+    }
+
+    /// Returns how many messages are queued right now. The other side may
+    /// change that at any moment, so this is only a snapshot.
+    pub fn len(&self) -> usize {
+        self.ring().len()
+    }
+
+    /// Returns whether no message is queued right now, see `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether every slot is taken right now, see `len`.
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Returns the largest number of messages that were queued at once
+    /// over the lifetime of the channel.
+    pub fn high_water_mark(&self) -> usize {
+        self.inner.state.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters collected for this channel so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.ring().stats()
+    }
+
+    /// See `Producer::start_recording`.
+    #[cfg(feature = "record")]
+    pub fn start_recording(&self, limit: usize) {
+        self.ring().start_recording(limit);
+    }
+
+    /// See `Producer::trace`.
+    #[cfg(feature = "record")]
+    pub fn trace(&self) -> record::Trace {
+        self.ring().trace()
+    }
+
+    /// Starts measuring the time each message spends in the queue, from
+    /// the next send on. Allocates a stamp per slot the first time, and does
+    /// nothing after. See `latency`.
+    #[cfg(feature = "latency")]
+    pub fn measure_latency(&self) {
+        self.ring().measure_latency();
+    }
+
+    /// Returns the time in the queue of the messages received so far,
+    /// empty unless `measure_latency` was called.
+    #[cfg(feature = "latency")]
+    pub fn latency(&self) -> latency::LatencyHistogram {
+        self.ring().latency()
+    }
+}
+
+/// A message borrowed from the head of the buffer, see `Consumer::recv_ref`.
+#[cfg(feature = "std")]
+pub struct RecvGuard<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    // A pointer rather than a reference, which would be assumed to stay
+    // valid until the guard is gone, while drop hands the slot back first
+    val: *const T,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, const N: usize> RecvGuard<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Result<Self, RecvError> {
+        let val = ring.peek_head()?;
+        Ok(RecvGuard { ring, val })
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, const N: usize> Deref for RecvGuard<'_, T, N> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // the producer does not touch the slot before read_index moves on
+        unsafe { &*self.val }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, const N: usize> Drop for RecvGuard<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.poison_if_panicking();
+        self.ring.release_head();
+    }
+}
+
+/// The message at the head of the buffer, see `Consumer::peek`.
+pub struct PeekGuard<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    // a pointer for the same reason as in RecvGuard, `pop` moves the
+    // message out while the guard is still around
+    val: *const T,
+}
+
+/// The message at the head of the buffer, see `Consumer::peek_mut`.
+pub struct PeekMutGuard<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    val: *mut T,
+}
+
+impl<'a, T, const N: usize> PeekGuard<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Option<Self> {
+        let val = ring.try_peek_head()?;
+        Some(PeekGuard { ring, val })
+    }
+
+    /// Receives the message after all.
+    pub fn pop(self) -> T {
+        ManuallyDrop::new(self).ring.pop_head()
+    }
+}
+
+impl<'a, T, const N: usize> PeekMutGuard<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Option<Self> {
+        let val = ring.try_peek_head()?;
+        Some(PeekMutGuard { ring, val })
+    }
+
+    /// Receives the message after all, including any changes made to it.
+    pub fn pop(self) -> T {
+        ManuallyDrop::new(self).ring.pop_head()
+    }
+}
+
+impl<T, const N: usize> Deref for PeekGuard<'_, T, N> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // the producer does not touch the slot before read_index moves on
+        unsafe { &*self.val }
+    }
+}
+
+impl<T, const N: usize> Deref for PeekMutGuard<'_, T, N> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.val }
+    }
+}
+
+impl<T, const N: usize> DerefMut for PeekMutGuard<'_, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        // as in PeekGuard, and the head claim keeps other receives away
+        unsafe { &mut *self.val }
+    }
+}
+
+impl<T, const N: usize> Drop for PeekGuard<'_, T, N> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        self.ring.poison_if_panicking();
+        self.ring.unclaim_head();
+    }
+}
+
+impl<T, const N: usize> Drop for PeekMutGuard<'_, T, N> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        self.ring.poison_if_panicking();
+        self.ring.unclaim_head();
+    }
+}
+
+/// A reserved slot in the buffer, see `Producer::reserve`.
+#[cfg(feature = "std")]
+pub struct SlotGuard<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    write_index: usize,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, const N: usize> SlotGuard<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Result<Self, SendError<()>> {
+        let write_index = ring.reserve()?;
+        Ok(SlotGuard { ring, write_index })
+    }
+
+    /// Writes `val` into the reserved slot and publishes it to the consumer.
+    pub fn write(self, val: T) {
+        let this = ManuallyDrop::new(self);
+        this.ring.commit_reserved(this.write_index, val);
+    }
+
+    /// Returns the reserved slot, to build the message right in the buffer.
+    /// It is sent by `commit`.
+    pub fn slot(&mut self) -> &mut MaybeUninit<T> {
+        // the slot is ours until the guard is gone
+        unsafe { &mut *self.ring.reserved_slot(self.write_index) }
+    }
+
+    /// Publishes the message built through `slot` to the consumer.
+    ///
+    /// # Safety
+    ///
+    /// The slot must be initialized.
+    pub unsafe fn commit(self) {
+        let this = ManuallyDrop::new(self);
+        this.ring.commit_written(this.write_index);
+    }
+
+    /// Builds the message in the reserved slot with `init` and publishes it.
+    /// `init` has to return the reference `MaybeUninit::write` gives back.
+    ///
+    /// # Panics
+    ///
+    /// If `init` returns a reference to anything but the slot. The slot is
+    /// released again then, as it is if `init` panics.
+    pub fn write_with<F>(mut self, init: F)
+    where
+        F: for<'s> FnOnce(&'s mut MaybeUninit<T>) -> &'s mut T,
+    {
+        let slot = self.slot();
+        let expected = slot.as_ptr();
+        let written: *const T = init(slot);
+        // only the slot itself proves that it was initialized
+        assert!(
+            core::ptr::eq(written, expected),
+            "init returned a reference to something other than the slot"
+        );
+        unsafe { self.commit() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, const N: usize> Drop for SlotGuard<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.poison_if_panicking();
+        self.ring.cancel_reserved(self.write_index);
+    }
+}
+
+/// The free slots of the buffer, see `Producer::vacant_slices`. Dropping it
+/// without committing releases them again.
+#[cfg(all(feature = "std", not(loom)))]
+pub struct VacantSlices<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    write_index: usize,
+    len: usize,
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<'a, T, const N: usize> VacantSlices<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Result<Self, SendError<()>> {
+        let (write_index, len) = ring.reserve_vacant()?;
+        Ok(VacantSlices {
+            ring,
+            write_index,
+            len,
+        })
+    }
+
+    /// Returns the reserved slots in the order they are sent. The second
+    /// slice is empty unless the slots wrap around the end of the buffer.
+    pub fn as_mut_slices(&mut self) -> (&mut [MaybeUninit<T>], &mut [MaybeUninit<T>]) {
+        let (first, second) = self.ring.runs(self.write_index, self.len);
+        // the consumer does not look at the slots before they are committed,
+        // and the other producers wait for the reservation to go away
+        unsafe { (&mut *first, &mut *second) }
+    }
+
+    /// Sends the first `n` of the reserved slots and releases the rest.
+    /// There is at least one reserved slot.
+    ///
+    /// # Safety
+    ///
+    /// The first `n` slots, counted across both slices, must be initialized.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than `len()`.
+    pub unsafe fn commit(self, n: usize) {
+        assert!(n <= self.len, "committed more slots than were reserved");
+        let this = ManuallyDrop::new(self);
+        this.ring.commit_vacant(this.write_index, n);
+    }
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<T, const N: usize> Drop for VacantSlices<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.poison_if_panicking();
+        self.ring.cancel_reserved(self.write_index);
+    }
+}
+
+/// A group of messages that is sent all at once, see
+/// `Producer::transaction`.
+#[cfg(all(feature = "std", not(loom)))]
+pub struct Transaction<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    write_index: usize,
+    reserved: usize,
+    len: usize,
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<'a, T, const N: usize> Transaction<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>, reserved: usize) -> Result<Self, SendError<()>> {
+        assert!(
+            reserved <= ring.capacity(),
+            "a transaction can not be larger than the buffer"
+        );
+        let write_index = ring.reserve_many(reserved)?;
+        Ok(Transaction {
+            ring,
+            write_index,
+            reserved,
+            len: 0,
+        })
+    }
+
+    /// Writes `val` into the next reserved slot, to be sent by `commit`.
+    /// Hands `val` back if every reserved slot is written already.
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.len == self.reserved {
+            return Err(val);
+        }
+        let slot = self
+            .ring
+            .reserved_slot(index::advance(self.write_index, self.len));
+        // the slot is ours until the transaction is gone
+        unsafe { (*slot).write(val) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns how many messages are written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns how many more messages fit into the transaction.
+    pub fn remaining(&self) -> usize {
+        self.reserved - self.len
+    }
+
+    /// Sends the written messages, which the consumer sees all at once.
+    pub fn commit(self) {
+        let this = ManuallyDrop::new(self);
+        this.ring.commit_vacant(this.write_index, this.len);
+    }
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<T, const N: usize> Drop for Transaction<'_, T, N> {
+    // Rolls back: the consumer never saw the messages, so they are dropped
+    // here
+    fn drop(&mut self) {
+        self.ring.poison_if_panicking();
+        for i in 0..self.len {
+            let slot = self.ring.reserved_slot(index::advance(self.write_index, i));
+            unsafe { (*slot).assume_init_drop() };
+        }
+        self.ring.cancel_reserved(self.write_index);
+    }
+}
+
+/// The queued messages, see `Consumer::occupied_slices`. Dropping it without
+/// releasing leaves them queued.
+#[cfg(all(feature = "std", not(loom)))]
+pub struct OccupiedSlices<'a, T, const N: usize = { ring::DYNAMIC }> {
+    ring: Ring<'a, T, N>,
+    read_index: usize,
+    len: usize,
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<'a, T, const N: usize> OccupiedSlices<'a, T, N> {
+    fn new(ring: Ring<'a, T, N>) -> Result<Self, RecvError> {
+        let (read_index, len) = ring.claim_occupied()?;
+        Ok(OccupiedSlices {
+            ring,
+            read_index,
+            len,
+        })
+    }
+
+    /// Returns the messages in the order they were sent. The second slice is
+    /// empty unless they wrap around the end of the buffer.
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let (first, second) = self.ring.runs(self.read_index, self.len);
+        // the slots were initialized before write_index was published, and
+        // the producer does not touch them before read_index moves on
+        unsafe { (&*(first as *const [T]), &*(second as *const [T])) }
+    }
+
+    /// Like `as_slices`, but the messages can be changed in place.
+    pub fn as_mut_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let (first, second) = self.ring.runs(self.read_index, self.len);
+        // as above, and the head claim keeps other receives away
+        unsafe { (&mut *(first as *mut [T]), &mut *(second as *mut [T])) }
+    }
+
+    /// Drops the first `n` messages, counted across both slices, and hands
+    /// their slots back to the producer. The rest stays queued.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is larger than the number of borrowed messages.
+    pub fn release(self, n: usize) {
+        assert!(n <= self.len, "released more messages than were borrowed");
+        let this = ManuallyDrop::new(self);
+        this.ring.release_occupied(this.read_index, n);
+    }
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl<T, const N: usize> Drop for OccupiedSlices<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.poison_if_panicking();
+        self.ring.unclaim_occupied(self.read_index);
+    }
+}
+
+/// An iterator over the queued messages, see `Consumer::try_iter`.
+pub struct TryIter<'a, T> {
+    consumer: &'a mut Consumer<T>,
+}
+
+impl<T: Send> Iterator for TryIter<'_, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.consumer.try_recv().ok()
+    }
+}
+
+/// An iterator that gives up waiting at some point, see
+/// `Consumer::iter_timeout` and `Consumer::iter_deadline`.
+#[cfg(feature = "std")]
+pub struct TimeoutIter<'a, T> {
+    consumer: &'a mut Consumer<T>,
+    until: Until,
+}
+
+#[cfg(feature = "std")]
+enum Until {
+    // for this long after the last message
+    Idle(Duration),
+    Deadline(Instant),
+}
+
+#[cfg(feature = "std")]
+impl<T: Send> Iterator for TimeoutIter<'_, T> {
+    type Item = T;
+    // Ends on a timeout as well as on a disconnect
+    fn next(&mut self) -> Option<T> {
+        match self.until {
+            Until::Idle(timeout) => self.consumer.recv_timeout(timeout).ok(),
+            Until::Deadline(deadline) => self.consumer.recv_deadline(deadline).ok(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+    // Blocks like recv, the iterator ends once the channel is disconnected
+    // (or closed) and drained
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().ok()
+    }
+}
+
+// poll_ready reserves a slot, so start_send can not fail. Closing the sink
+// closes the channel, which ends the consumer's stream once it is drained.
+#[cfg(feature = "futures")]
+impl<T: Send> futures_sink::Sink<T> for Producer<T> {
+    type Error = SendError<()>;
+
+    fn poll_ready(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.sink_slot.is_none() {
+            let write_index = std::task::ready!(this.ring().poll_reserve(cx))?;
+            this.sink_slot = Some(write_index);
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    // Without a slot from poll_ready, item is only sent if there is room
+    fn start_send(self: std::pin::Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match this.sink_slot.take() {
+            Some(write_index) => {
+                this.ring().commit_reserved(write_index, item);
+                Ok(())
+            }
+            None => this.ring().try_send(item).map_err(|_| SendError(())),
+        }
+    }
+
+    // Sent items are in the buffer already, Producer::flush is the one that
+    // waits for the consumer. Fails once nobody is going to receive them.
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        if self.is_consumer_alive() {
+            std::task::Poll::Ready(Ok(()))
+        } else {
+            std::task::Poll::Ready(Err(SendError(())))
+        }
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if let Some(write_index) = this.sink_slot.take() {
+            this.ring().cancel_reserved(write_index);
+        }
+        this.close();
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T: Send> futures_core::Stream for Consumer<T> {
+    type Item = T;
+    // Waits like recv_async, and ends where the iterator does
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<T>> {
+        let mut val = MaybeUninit::uninit();
+        self.ring()
+            .poll_recv_into(&mut val, cx)
+            // poll_recv_into only returns Ready(Ok) after initializing val
+            .map(|result| result.ok().map(|()| unsafe { val.assume_init() }))
+    }
+}
+
+impl<T: Send> WeakProducer<T> {
+    /// Returns a new producer if the channel still has one. Once all
+    /// producers are gone, the channel stays disconnected.
+    ///
+    /// Panics unless the channel is from `channel_mpsc`: any other has a
+    /// producer already, and never more than one.
+    pub fn upgrade(&self) -> Option<Producer<T>> {
+        assert!(self.inner.state.multi_producer(), "{}", SINGLE_PRODUCER);
+        let counter = &self.inner.state.producer_counter;
+        // Like Arc::clone, the counter orders nothing: the producers
+        // synchronize through the indices they claim slots by
+        let mut producers = counter.load(Ordering::Relaxed);
+        loop {
+            if producers == 0 {
+                return None;
+            }
+            match counter.compare_exchange_weak(
+                producers,
+                producers + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => producers = current,
+            }
+        }
+
+        Some(Producer::new(self.inner.clone()))
+    }
+}
+
+// Everything a monitor reports is a snapshot, like the methods of the same
+// name on the endpoints
+impl<T> Monitor<T> {
+    fn ring(&self) -> Ring<'_, T> {
+        Ring {
+            buffer: &self.inner.message_buffer,
+            state: &self.inner.state,
+        }
+    }
+
+    /// Returns how many messages the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.message_buffer.len()
+    }
+
+    /// Returns how many messages are queued right now.
+    pub fn len(&self) -> usize {
+        self.ring().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// See `Consumer::high_water_mark`.
+    pub fn high_water_mark(&self) -> usize {
+        self.inner.state.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether a producer still exists.
+    pub fn is_producer_alive(&self) -> bool {
+        self.inner.state.producer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// Returns whether the consumer still exists.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.inner.state.consumer_counter.load(Ordering::Acquire) != 0
+    }
+
+    /// Returns whether either side closed the channel.
+    pub fn is_closed(&self) -> bool {
+        self.ring().is_closed()
+    }
+
+    /// See `Producer::is_poisoned`.
+    #[cfg(feature = "std")]
+    pub fn is_poisoned(&self) -> bool {
+        self.ring().is_poisoned()
+    }
+
+    /// Returns the counters collected for this channel so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.ring().stats()
+    }
+
+    /// See `Consumer::latency`.
+    #[cfg(feature = "latency")]
+    pub fn latency(&self) -> latency::LatencyHistogram {
+        self.ring().latency()
+    }
+}
+
+impl<T> Clone for Monitor<T> {
+    fn clone(&self) -> Self {
+        Monitor {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+// The slots are only accessed by the protocol in ring, which hands each
+// message from exactly one thread to another
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+// The handles get Send and Sync from their fields, with no unsafe impls of
+// their own: the Arc<Inner<T>> is both exactly when T is Send, since the
+// handle that drops the last reference also drops the queued messages. An
+// endpoint moves to another thread, but is not shared between threads, the
+// "single" in SPSC, so its marker takes Sync away again. The ring relies on
+// it: the consumer hands the slots back in the order it claimed them, and
+// only the producers of channel_mpsc claim theirs by CAS. Weak producers and
+// monitors only look at the state, they are Sync as well.
+
+// Like Arc::clone, the counter orders nothing: the new producer synchronizes
+// with the others through the indices they claim slots by. Only the channels
+// of channel_mpsc claim that way, any other panics.
+impl<T: Send> Clone for Producer<T> {
+    fn clone(&self) -> Self {
+        assert!(self.inner.state.multi_producer(), "{}", SINGLE_PRODUCER);
+        self.inner
+            .state
+            .producer_counter
+            .fetch_add(1, Ordering::Relaxed);
+        Producer::new(self.inner.clone())
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "futures")]
+        if let Some(write_index) = self.sink_slot.take() {
+            self.ring().cancel_reserved(write_index);
+        }
+        self.ring().drop_producer();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.ring().drop_consumer();
+    }
+}
+
+// Shows where the channel stands, never the messages, so T needs no Debug
+impl<T> fmt::Debug for Producer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ring().fmt_debug("Producer", f)
+    }
+}
+
+impl<T> fmt::Debug for Consumer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ring().fmt_debug("Consumer", f)
+    }
+}
+
+impl<T> fmt::Debug for Monitor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ring().fmt_debug("Monitor", f)
+    }
+}
+
+// Shared by the Consumer types, which only differ in their ring
+fn recv_many<T, const N: usize>(ring: Ring<'_, T, N>, out: &mut Vec<T>, max: usize) -> usize {
+    let max = max.min(ring.capacity());
+    out.reserve(max);
+    let count = ring.recv_into_slice(&mut out.spare_capacity_mut()[..max]);
+    // recv_into_slice initialized the first count of the spare slots
+    unsafe { out.set_len(out.len() + count) };
+    count
+}
+
+#[cfg(feature = "std")]
+fn wait_for_space<T, const N: usize>(
+    ring: Ring<'_, T, N>,
+    n: usize,
+) -> Result<usize, SendError<()>> {
+    assert!(
+        n <= ring.capacity(),
+        "can not wait for more slots than the buffer holds"
+    );
+    ring.wait_for_space(n)
+}
+
+#[cfg(feature = "std")]
+fn wait_for_messages<T, const N: usize>(
+    ring: Ring<'_, T, N>,
+    n: usize,
+) -> Result<usize, RecvError> {
+    assert!(
+        n <= ring.capacity(),
+        "can not wait for more messages than the buffer holds"
+    );
+    ring.wait_for_messages(n)
+}
+
+#[cfg(feature = "std")]
+fn recv_exact<T, const N: usize>(ring: Ring<'_, T, N>, n: usize) -> Result<Vec<T>, RecvError> {
+    wait_for_messages(ring, n)?;
+    let mut out = Vec::new();
+    recv_many(ring, &mut out, n);
+    Ok(out)
+}
+
+// The messages queued when it is called, the ones sent meanwhile stay for
+// the next receive
+fn drain<T, const N: usize>(ring: Ring<'_, T, N>) -> Vec<T> {
+    let mut out = Vec::new();
+    recv_many(ring, &mut out, ring.len());
+    out
+}
+
+/// Creates a channel that buffers up to 4096 messages. `T` does not need to
+/// be `'static`: moved into the threads of a `std::thread::scope`, the
+/// endpoints can pass on data borrowed from outside of it, which the scope
+/// keeps alive until both are done.
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::new();
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel`, but the buffer holds `capacity` messages instead of the
+/// default 4096.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_capacity<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::with_capacity(capacity);
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel_with_capacity`, with `label` naming the channel in its
+/// Debug output and, with the `tracing` feature, in its spans and events.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_label<T: Send>(
+    capacity: usize,
+    label: &'static str,
+) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::with_label(capacity, label);
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel_with_capacity`, with the buffer allocated as `memory`
+/// says, e.g. on a NUMA node or in huge pages. See `memory` for what
+/// happens where that is not possible.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+#[cfg(all(feature = "memory", not(loom)))]
+pub fn channel_with_memory<T: Send>(
+    capacity: usize,
+    memory: memory::Memory,
+) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::with_memory(capacity, memory);
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel_with_capacity`, with the buffer allocated by `allocator`,
+/// e.g. in an arena or a pool. See `allocator`.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+#[cfg(not(loom))]
+pub fn channel_with_allocator<T: Send, A: allocator::BufferAllocator>(
+    capacity: usize,
+    allocator: A,
+) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::with_allocator(capacity, allocator);
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel_with_capacity`, but on memory the caller allocated, e.g. a
+/// block that is already paged in. The capacity is the length of `buffer`.
+/// For memory that is not a `Box`, see `borrowed::channel_in`.
+///
+/// Panics if `buffer` is empty or its length is not a power of two.
+#[cfg(not(loom))]
+pub fn channel_in<T: Send>(buffer: Box<[MaybeUninit<T>]>) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::in_buffer(buffer);
+    (spsc.producer, spsc.consumer)
+}
+
+/// Like `channel_with_capacity`, for several threads sending to one
+/// consumer. Only the producer of such a channel can be cloned (or a weak
+/// one upgraded): its producers claim their slots by CAS, which the lone
+/// producer of any other channel does without, and a producer stalled in the
+/// middle of a send only holds back the messages sent after its own until it
+/// is done. The messages of each producer arrive in the order it sent them;
+/// the channel disconnects once every producer is gone.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_mpsc<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    ChannelBuilder::new()
+        .capacity(capacity)
+        .multi_producer()
+        .build()
+}
+
+/// Like `channel_mpsc`, but every producer gets a channel of its own with
+/// `capacity` slots, so the producers never contend for a slot. The
+/// consumer receives from the channels in turn, see `Merged`, and the order
+/// holds per producer. Each producer is the lone one of its channel, as
+/// with `channel_with_capacity`, so it can not be cloned.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+#[cfg(feature = "std")]
+pub fn sharded_mpsc<T: Send>(producers: usize, capacity: usize) -> (Vec<Producer<T>>, Merged<T>) {
+    let (producers, consumers) = (0..producers)
+        .map(|_| channel_with_capacity(capacity))
+        .unzip();
+    (producers, merge(consumers))
+}
+
+/// A channel for any number of producers and consumers, see `mpmc`. The
+/// SPSC channel stays the faster choice where it fits.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+#[cfg(feature = "std")]
+pub fn channel_mpmc<T: Send>(capacity: usize) -> (mpmc::Producer<T>, mpmc::Consumer<T>) {
+    mpmc::channel(capacity)
+}
+
+/// A channel whose buffer starts at `initial_capacity` and grows up to
+/// `max_capacity` while the consumer falls behind, see `growable`.
+///
+/// Panics if either capacity is 0 or not a power of two, or if
+/// `max_capacity` is below `initial_capacity`.
+#[cfg(feature = "std")]
+pub fn channel_growable<T: Send>(
+    initial_capacity: usize,
+    max_capacity: usize,
+) -> (growable::Producer<T>, growable::Consumer<T>) {
+    growable::channel(initial_capacity, max_capacity)
+}
+
+/// A channel without a bound, see `unbounded`. `send` never waits for the
+/// consumer, so the buffer grows for as long as the consumer falls behind.
+#[cfg(feature = "std")]
+pub fn channel_unbounded<T: Send>() -> (unbounded::Producer<T>, unbounded::Consumer<T>) {
+    unbounded::channel()
+}
+
+/// Like `channel_with_capacity`, but blocked calls wait as `wait_strategy`
+/// says instead of parking.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_strategy<T: Send>(
+    capacity: usize,
+    wait_strategy: WaitStrategy,
+) -> (Producer<T>, Consumer<T>) {
+    let spsc: SPSC<T> = SPSC::with_strategy(capacity, wait_strategy);
+    (spsc.producer, spsc.consumer)
+}
+
+// vorimplementierte Testsuite; bei Bedarf erweitern!
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    #[cfg(feature = "std")]
+    use lazy_static::lazy_static;
+    #[cfg(feature = "std")]
+    use std::collections::HashSet;
+    #[cfg(feature = "std")]
+    use std::panic;
+    #[cfg(feature = "std")]
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    #[cfg(feature = "std")]
+    use std::sync::Mutex;
+    #[cfg(feature = "std")]
+    use std::thread;
+
+    use super::*;
+
+    // the helpers of the blocking tests, which only exist with std
+    #[cfg(feature = "std")]
+    lazy_static! {
+        static ref FOO_SET: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+    }
+
+    // Tests checking FOO_SET for leaks must not run concurrently
+    #[cfg(feature = "std")]
+    static FOO_TESTS: Mutex<()> = Mutex::new(());
+
+    #[cfg(feature = "std")]
+    fn lock_foo_tests() -> std::sync::MutexGuard<'static, ()> {
+        FOO_TESTS.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    #[cfg(feature = "std")]
+    #[derive(Debug)]
+    struct Foo(i32);
+
+    #[cfg(feature = "std")]
+    impl Foo {
+        fn new(key: i32) -> Self {
+            assert!(
+                FOO_SET.lock().unwrap().insert(key),
+                "double initialisation of element {}",
+                key
+            );
+            Foo(key)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Drop for Foo {
+        fn drop(&mut self) {
+            assert!(
+                FOO_SET.lock().unwrap().remove(&self.0),
+                "double free of element {}",
+                self.0
+            );
+        }
+    }
+
+    // range of elements to be moved across the channel during testing
+    #[cfg(feature = "std")]
+    const ELEMS: std::ops::Range<i32> = 0..1000;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn unused_elements_are_dropped() {
+        let _lock = lock_foo_tests();
+        lazy_static::initialize(&FOO_SET);
+
+        for i in 0..100 {
+            println!("Thread {} ", i);
+            let (mut px, mut cx) = channel();
+            let handle = thread::spawn(move || {
+                for i in 0.. {
+                    if px.send(Foo::new(i)).is_err() {
+                        println!("AHHHHH on i: {}", i);
+                        return;
+                    }
+                }
+            });
+
+            for _ in 0..i {
+                cx.recv().unwrap();
+            }
+
+            drop(cx);
+
+            assert!(handle.join().is_ok());
+
+            let map = FOO_SET.lock().unwrap();
+            if !map.is_empty() {
+                panic!("FOO_MAP not empty: {:?}", *map);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn elements_arrive_ordered() {
+        let (mut px, mut cx) = channel();
+
+        thread::spawn(move || {
+            for i in ELEMS {
+                px.send(i).unwrap();
+            }
+        });
+
+        for i in ELEMS {
+            assert_eq!(i, cx.recv().unwrap());
+        }
+
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_into_moves_large_payload() {
+        let (mut px, mut cx) = channel::<[u8; 4096]>();
+
+        for i in 0..4u8 {
+            px.send([i; 4096]).unwrap();
+        }
+        drop(px);
+
+        let mut dst = MaybeUninit::uninit();
+        for i in 0..4u8 {
+            cx.recv_into(&mut dst).unwrap();
+            let payload = unsafe { dst.assume_init_ref() };
+            assert!(payload.iter().all(|&b| b == i));
+        }
+
+        assert!(cx.recv_into(&mut dst).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn buffer_does_not_live_on_the_stack() {
+        // 4096 slots of 16 KiB are far more than the stack of a test thread
+        let (mut px, mut cx) = channel::<[u8; 16 << 10]>();
+        px.send([1; 16 << 10]).unwrap();
+        assert!(cx.recv().unwrap().iter().all(|&b| b == 1));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn liveness_is_observed() {
+        let (mut px, cx) = channel::<i32>();
+        assert!(px.is_consumer_alive());
+        assert!(cx.is_producer_alive());
+
+        drop(cx);
+        assert!(!px.is_consumer_alive());
+        assert!(px.send(1).is_err());
+
+        let (px, cx) = channel::<i32>();
+        drop(px);
+        assert!(!cx.is_producer_alive());
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn consumer_is_a_stream() {
+        use futures::StreamExt;
+
+        let (mut px, cx) = channel_with_capacity(4);
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+        });
+        // the stream ends once the producer is gone and everything arrived.
+        // Consumer is an Iterator as well, hence the qualified call.
+        let evens = StreamExt::filter(cx, |i| std::future::ready(i % 2 == 0)).collect::<Vec<i32>>();
+        let evens = futures::executor::block_on(evens);
+        assert_eq!(evens, (0..100).step_by(2).collect::<Vec<_>>());
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn stream_forwards_into_producer() {
+        use futures::{stream, StreamExt};
+
+        let (px, cx) = channel_with_capacity(4);
+        let handle = thread::spawn(move || {
+            // forward closes the sink at the end, which ends the consumer
+            futures::executor::block_on(stream::iter(0..100).map(Ok).forward(px)).unwrap();
+        });
+        assert_eq!(
+            Iterator::collect::<Vec<i32>>(cx),
+            (0..100).collect::<Vec<_>>()
+        );
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn sink_is_ready_while_there_is_room() {
+        use futures::task::{noop_waker, Context, Poll};
+        use futures::Sink;
+        use std::pin::Pin;
+
+        let waker = noop_waker();
+        let mut ctx = Context::from_waker(&waker);
+        let (mut px, mut cx) = channel_with_capacity(2);
+        for i in 0..2 {
+            assert!(matches!(
+                Pin::new(&mut px).poll_ready(&mut ctx),
+                Poll::Ready(Ok(()))
+            ));
+            Pin::new(&mut px).start_send(i).unwrap();
+        }
+        assert!(Pin::new(&mut px).poll_ready(&mut ctx).is_pending());
+        assert_eq!(cx.recv().unwrap(), 0);
+        assert!(matches!(
+            Pin::new(&mut px).poll_ready(&mut ctx),
+            Poll::Ready(Ok(()))
+        ));
+        // a slot reserved before the consumer went away can still be filled,
+        // the next one is refused
+        drop(cx);
+        assert!(matches!(
+            Pin::new(&mut px).poll_flush(&mut ctx),
+            Poll::Ready(Err(_))
+        ));
+        Pin::new(&mut px).start_send(2).unwrap();
+        assert!(matches!(
+            Pin::new(&mut px).poll_ready(&mut ctx),
+            Poll::Ready(Err(_))
+        ));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn full_buffer_is_counted() {
+        let (mut px, mut cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+        assert_eq!(px.stats().full_stalls, 0);
+
+        let handle = thread::spawn(move || px.send(BUFFER_SIZE).unwrap());
+        while cx.stats().full_stalls == 0 {
+            thread::yield_now();
+        }
+
+        assert_eq!(cx.recv().unwrap(), 0);
+        handle.join().unwrap();
+
+        let stats = cx.stats();
+        assert_eq!(stats.sends, BUFFER_SIZE + 1);
+        assert_eq!(stats.recvs, 1);
+        assert_eq!(stats.full_stalls, 1);
+        assert_eq!(stats.empty_stalls, 0);
+        assert!(stats.full_wait > Duration::ZERO);
+        assert_eq!(stats.empty_wait, Duration::ZERO);
+        assert_eq!(stats.len, BUFFER_SIZE);
+        assert_eq!(stats.high_water_mark, BUFFER_SIZE);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn failed_tries_and_waits_are_counted() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        px.send(1).unwrap();
+        px.send(2).unwrap();
+        assert_eq!(px.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(px.try_send(3), Err(TrySendError::Full(3)));
+        let stats = px.stats();
+        assert_eq!(stats.failed_try_sends, 2);
+        assert_eq!(stats.failed_try_recvs, 1);
+        assert_eq!(stats.len, 2);
+
+        cx.recv().unwrap();
+        cx.recv().unwrap();
+        // times out after waiting the whole timeout
+        let timeout = Duration::from_millis(20);
+        assert_eq!(cx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+        let stats = cx.stats();
+        assert_eq!(stats.empty_stalls, 1);
+        assert!(stats.empty_wait >= timeout);
+        assert_eq!(stats.len, 0);
+        assert_eq!(stats.high_water_mark, 2);
+    }
+
+    #[test]
+    fn stalled_producer_does_not_block_the_others() {
+        let (px, mut cx) = channel_mpsc(BUFFER_SIZE);
+        let mut other = px.clone();
+
+        // a producer that claimed a slot and has yet to write it
+        let ring = px.ring();
+        let position = ring.try_slot().unwrap();
+        other.try_send(2).unwrap();
+        other.try_send(3).unwrap();
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+
+        ring.push(position, 1);
+        assert_eq!(cx.try_recv(), Ok(1));
+        assert_eq!(cx.try_recv(), Ok(2));
+        assert_eq!(cx.try_recv(), Ok(3));
+    }
+
+    // A fresh channel whose indices are about to overflow
+    #[cfg(feature = "std")]
+    fn channel_near_wrap(capacity: usize) -> (Producer<Foo>, Consumer<Foo>) {
+        let (px, cx) = channel_with_capacity(capacity);
+        let state = &px.inner.state;
+        let start = usize::MAX - capacity / 2;
+        for index in [
+            &state.read_index,
+            &state.write_index,
+            &state.released_index,
+            &state.cached_released_index,
+            &state.cached_write_index,
+        ] {
+            index.store(start, Ordering::SeqCst);
+        }
+        (px, cx)
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn indices_wrap_around_usize() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel_near_wrap(8);
+
+        for i in 0..20 {
+            px.send(Foo::new(i)).unwrap();
+            assert_eq!(cx.recv().unwrap().0, i);
+        }
+        // fill the buffer across the wrap and check it is seen as full
+        for i in 20..28 {
+            px.try_send(Foo::new(i)).unwrap();
+        }
+        assert!(matches!(
+            px.try_send(Foo::new(28)),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(px.high_water_mark(), 8);
+
+        let mut out: [MaybeUninit<Foo>; 3] = [const { MaybeUninit::uninit() }; 3];
+        assert_eq!(cx.recv_into_slice(&mut out), 3);
+        for (i, val) in (20..).zip(&mut out) {
+            assert_eq!(unsafe { val.assume_init_read() }.0, i);
+        }
+        assert_eq!(cx.recv_ref().unwrap().0, 23);
+        assert_eq!(cx.clear(), 4);
+        assert!(cx.try_recv().is_err());
+
+        for i in 30..40 {
+            px.send_overwrite(Foo::new(i)).unwrap();
+        }
+        assert_eq!(cx.recv().unwrap().0, 32);
+        drop((px, cx));
+        assert!(FOO_SET.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn indices_do_not_share_a_cache_line() {
+        let (px, _cx) = channel::<i32>();
+        let state = &px.inner.state;
+        let read = &*state.read_index as *const _ as usize;
+        let write = &*state.write_index as *const _ as usize;
+        assert!(read.abs_diff(write) >= 64);
+        assert_eq!(read % 64, 0);
+        assert_eq!(write % 64, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_ref_releases_slot_on_drop() {
+        let (mut px, mut cx) = channel();
+        px.send((1, String::from("one"))).unwrap();
+        px.send((2, String::from("two"))).unwrap();
+
+        let msg = cx.recv_ref().unwrap();
+        assert_eq!(msg.0, 1);
+        assert_eq!(msg.1, "one");
+        drop(msg);
+
+        assert_eq!(cx.recv().unwrap().0, 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn peek_leaves_message_queued() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        assert!(cx.peek().is_none());
+        px.send(String::from("one")).unwrap();
+        px.send(String::from("two")).unwrap();
+
+        assert_eq!(*cx.peek().unwrap(), "one");
+        assert_eq!(*cx.peek().unwrap(), "one");
+        // the peeked slot is still taken
+        assert!(px.try_send(String::from("three")).is_err());
+        assert_eq!(cx.peek().unwrap().pop(), "one");
+        px.try_send(String::from("three")).unwrap();
+
+        cx.peek_mut().unwrap().push('!');
+        assert_eq!(cx.recv().unwrap(), "two!");
+        cx.peek_mut().unwrap().push('?');
+        assert_eq!(cx.peek_mut().unwrap().pop(), "three?");
+        assert!(cx.peek().is_none());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_does_not_wait_for_borrowed_head() {
+        let (mut px, mut cx) = channel();
+        px.send(0).unwrap();
+
+        // the consumer holds on to the head, which must not stall sends to
+        // the other slots
+        let head = cx.recv_ref().unwrap();
+        let handle = thread::spawn(move || {
+            for i in 1..BUFFER_SIZE {
+                px.send(i).unwrap();
+            }
+        });
+        handle.join().unwrap();
+        assert_eq!(*head, 0);
+        drop(head);
+
+        for i in 1..BUFFER_SIZE {
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn flush_waits_for_consumer() {
+        let (mut px, mut cx) = channel();
+        for i in 0..10 {
+            px.send(i).unwrap();
+        }
+
+        let handle = thread::spawn(move || {
+            for i in 0..10 {
+                thread::sleep(Duration::from_millis(5));
+                assert_eq!(cx.recv().unwrap(), i);
+            }
+            cx
+        });
+
+        px.flush().unwrap();
+        assert_eq!(px.inner.state.read_index.load(Ordering::SeqCst), 10);
+
+        // nothing left to wait for
+        px.flush().unwrap();
+
+        drop(handle.join().unwrap());
+        px.send(10).unwrap_err();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn flush_fails_on_disconnect() {
+        let (mut px, cx) = channel();
+        px.send(0).unwrap();
+        drop(cx);
+        assert!(px.flush().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_all_reports_count() {
+        let (mut px, mut cx) = channel();
+        let handle = thread::spawn(move || {
+            for i in ELEMS {
+                assert_eq!(i, cx.recv().unwrap());
+            }
+        });
+
+        assert_eq!(px.send_all(ELEMS).unwrap(), ELEMS.len());
+        assert!(handle.join().is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_all_stops_on_disconnect() {
+        let (mut px, cx) = channel();
+        drop(cx);
+
+        let (sent, SendError(val)) = px.send_all(ELEMS).unwrap_err();
+        assert_eq!(sent, 0);
+        assert_eq!(val, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_iter_fills_free_slots() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send(0).unwrap();
+        let mut iter = 1..10;
+        assert_eq!(px.send_iter(iter.by_ref()).unwrap(), 3);
+        assert_eq!(iter.next(), Some(4));
+        assert_eq!(px.send_iter(5..10).unwrap(), 0);
+
+        assert_eq!(cx.recv().unwrap(), 0);
+        assert_eq!(cx.recv().unwrap(), 1);
+        assert_eq!(px.send_iter(5..10).unwrap(), 2);
+        let received: Vec<_> = (0..4).map(|_| cx.recv().unwrap()).collect();
+        assert_eq!(received, [2, 3, 5, 6]);
+
+        drop(cx);
+        assert!(px.send_iter(0..1).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sequence_numbers_show_overwritten_messages() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        for i in 0..3 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.len(), 3);
+        assert_eq!(cx.recv_with_seq(), Ok((0, 0)));
+        for i in 3..8 {
+            px.send_overwrite(i).unwrap();
+        }
+        // 1 to 3 were dropped to make room
+        assert_eq!(cx.len(), 4);
+        let received: Vec<_> = (0..4).map(|_| cx.recv_with_seq().unwrap()).collect();
+        assert_eq!(received, [(4, 4), (5, 5), (6, 6), (7, 7)]);
+        assert_eq!(cx.len(), 0);
+        drop(px);
+        assert_eq!(cx.recv_with_seq(), Err(RecvError));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_overwrite_keeps_newest() {
+        let (mut px, mut cx) = channel();
+        let tracker = Arc::new(());
+
+        let count = 3 * BUFFER_SIZE + 5;
+        for i in 0..count {
+            px.send_overwrite((i, tracker.clone())).unwrap();
+        }
+        // every evicted message has been dropped once
+        assert_eq!(Arc::strong_count(&tracker), BUFFER_SIZE + 1);
+        drop(px);
+
+        for i in count - BUFFER_SIZE..count {
+            assert_eq!(cx.recv().unwrap().0, i);
+        }
+        assert!(cx.recv().is_err());
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn force_send_returns_evicted() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        for i in 0..4 {
+            assert_eq!(px.force_send(i).unwrap(), None);
+        }
+        assert_eq!(px.force_send(4).unwrap(), Some(0));
+        assert_eq!(px.force_send(5).unwrap(), Some(1));
+        assert_eq!(cx.recv().unwrap(), 2);
+        assert_eq!(px.force_send(6).unwrap(), None);
+
+        drop(cx);
+        assert_eq!(px.force_send(7), Err(ForceSendError::Disconnected(7)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_overwrite_spares_borrowed_head() {
+        let (mut px, mut cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+
+        // fails right away rather than waiting for the guard
+        let head = cx.recv_ref().unwrap();
+        assert_eq!(
+            px.send_overwrite(BUFFER_SIZE),
+            Err(ForceSendError::Held(BUFFER_SIZE))
+        );
+        assert_eq!(*head, 0);
+        drop(head);
+        // the head was released normally, so nothing had to be evicted
+        assert_eq!(px.force_send(BUFFER_SIZE), Ok(None));
+
+        let head = cx.peek().unwrap();
+        assert!(px.force_send(BUFFER_SIZE + 1).unwrap_err().is_held());
+        drop(head);
+        // a peek leaves the message queued, to be evicted now
+        assert_eq!(px.force_send(BUFFER_SIZE + 1), Ok(Some(1)));
+        for i in 2..=BUFFER_SIZE + 1 {
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+
+        // nor does it write the next slot while another producer reserves it
+        let (mut px, _cx) = channel_mpsc(BUFFER_SIZE);
+        let mut other = px.clone();
+        let slot = px.reserve().unwrap();
+        assert!(other.force_send(1).unwrap_err().is_held());
+        drop(slot);
+        assert_eq!(other.force_send(1), Ok(None));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn full_buffer_is_not_overwritten() {
+        let (mut px, mut cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+
+        let inner = px.inner.clone();
+        let handle = thread::spawn(move || px.send(BUFFER_SIZE).unwrap());
+        thread::sleep(Duration::from_millis(10));
+
+        // the extra send must still be waiting and slot 0 still be intact
+        assert_eq!(inner.state.write_index.load(Ordering::SeqCst), BUFFER_SIZE);
+        let head = cx.inner.message_buffer[0].with_mut(|slot| unsafe { (*slot).assume_init() });
+        assert_eq!(head, 0);
+
+        for i in 0..=BUFFER_SIZE {
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn elapsed_deadline_times_out() {
+        let (mut px, mut cx) = channel();
+        let deadline = Instant::now();
+
+        assert_eq!(cx.recv_deadline(deadline), Err(RecvTimeoutError::Timeout));
+
+        px.send(1).unwrap();
+        assert_eq!(cx.recv_deadline(deadline), Ok(1));
+
+        drop(px);
+        assert_eq!(
+            cx.recv_deadline(deadline),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_timeout_wakes_up_periodically() {
+        let (px, mut cx) = channel::<i32>();
+        let timeout = Duration::from_millis(20);
+        for _ in 0..3 {
+            let start = Instant::now();
+            assert_eq!(cx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+            assert!(start.elapsed() >= timeout);
+        }
+        drop(px);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn unrepresentable_timeout_waits_forever() {
+        let (mut px, mut cx) = channel();
+        px.send(1).unwrap();
+        assert_eq!(cx.recv_timeout(Duration::MAX), Ok(1));
+
+        drop(px);
+        assert_eq!(
+            cx.recv_timeout(Duration::MAX),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn blocked_endpoints_park() {
+        let (mut px, mut cx) = channel_with_capacity(1);
+        let inner = px.inner.clone();
+        let state = &inner.state;
+
+        // the handles are not Sync, so each goes to the thread and back
+        let mut cx = thread::scope(|s| {
+            let consumer = s.spawn(move || (cx.recv().unwrap(), cx));
+            while !state.consumers.has_waiters() {
+                thread::yield_now();
+            }
+            px.send(1).unwrap();
+            let (val, mut cx) = consumer.join().unwrap();
+            assert_eq!(val, 1);
+
+            px.send(2).unwrap();
+            let producer = s.spawn(move || px.send(3).unwrap());
+            while !state.producers.has_waiters() {
+                thread::yield_now();
+            }
+            assert_eq!(cx.recv().unwrap(), 2);
+            producer.join().unwrap();
+            cx
+        });
+        assert_eq!(cx.recv().unwrap(), 3);
+    }
+
+    #[cfg(feature = "std")]
+    const WAIT_STRATEGIES: [WaitStrategy; 4] = [
+        WaitStrategy::BusySpin,
+        WaitStrategy::Yield,
+        WaitStrategy::Backoff,
+        WaitStrategy::Park,
+    ];
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn every_wait_strategy_delivers() {
+        for wait_strategy in WAIT_STRATEGIES {
+            // a small buffer, so both sides have to wait for each other
+            let (mut px, mut cx) = channel_with_strategy(2, wait_strategy);
+            let handle = thread::spawn(move || {
+                for i in 0..100 {
+                    px.send(i).unwrap();
+                }
+                px.flush().is_ok()
+            });
+            for i in 0..100 {
+                assert_eq!(cx.recv().unwrap(), i, "{:?}", wait_strategy);
+            }
+            assert!(cx.recv().is_err());
+            // the consumer is still around, so flush succeeded
+            assert!(handle.join().unwrap());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn every_wait_strategy_times_out() {
+        for wait_strategy in WAIT_STRATEGIES {
+            let (mut px, mut cx) = channel_with_strategy(1, wait_strategy);
+            let timeout = Duration::from_millis(10);
+            assert_eq!(cx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+
+            px.send(1).unwrap();
+            let start = Instant::now();
+            assert!(matches!(
+                px.send_timeout(2, timeout),
+                Err(SendTimeoutError::Timeout(2))
+            ));
+            assert!(start.elapsed() >= timeout);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_timeout_waits_for_producer() {
+        let (mut px, mut cx) = channel();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            px.send(1).unwrap();
+        });
+
+        assert_eq!(cx.recv_timeout(Duration::from_secs(10)), Ok(1));
+        handle.join().unwrap();
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(1)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn weak_producer_does_not_keep_channel_open() {
+        let (px, mut cx) = channel_mpsc(BUFFER_SIZE);
+        let weak = px.downgrade();
+
+        let mut upgraded = weak.upgrade().unwrap();
+        upgraded.send(1).unwrap();
+        drop(upgraded);
+        assert_eq!(cx.recv().unwrap(), 1);
+
+        drop(px);
+        assert!(cx.recv().is_err());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn blocked_send_sees_disconnect() {
+        let (mut px, cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+
+        let inner = px.inner.clone();
+        let handle = thread::spawn(move || px.send(BUFFER_SIZE));
+        // wait until the producer is stuck on the full buffer
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(inner.state.write_index.load(Ordering::SeqCst), BUFFER_SIZE);
+
+        drop(cx);
+        let SendError(val) = handle.join().unwrap().unwrap_err();
+        assert_eq!(val, BUFFER_SIZE);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_returns_after_consumer_thread_drops() {
+        let (mut px, cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+
+        let (mut result_px, mut result_cx) = channel();
+        thread::spawn(move || result_px.send(px.send(BUFFER_SIZE)).unwrap());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(cx);
+        });
+
+        let result = result_cx.recv_timeout(Duration::from_secs(10));
+        assert!(matches!(result, Ok(Err(SendError(_)))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn clear_drops_buffered_elements() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel();
+
+        // start somewhere in the middle, so the cleared range wraps around
+        for i in 0..BUFFER_SIZE as i32 - 20 {
+            px.send(Foo::new(i)).unwrap();
+            cx.recv().unwrap();
+        }
+        for i in 0..50 {
+            px.send(Foo::new(i)).unwrap();
+        }
+
+        assert_eq!(cx.clear(), 50);
+        assert!(FOO_SET.lock().unwrap().is_empty());
+        assert_eq!(cx.clear(), 0);
+
+        px.send(Foo::new(50)).unwrap();
+        assert_eq!(cx.recv().unwrap().0, 50);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn queued_elements_are_dropped_with_channel() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel();
+
+        // wrap around once, so the leftovers sit on both ends of the buffer
+        for i in 0..BUFFER_SIZE as i32 - 5 {
+            px.send(Foo::new(i)).unwrap();
+            cx.recv().unwrap();
+        }
+        for i in 0..20 {
+            px.send(Foo::new(i)).unwrap();
+        }
+        drop(cx);
+        assert_eq!(FOO_SET.lock().unwrap().len(), 20);
+        drop(px);
+        assert!(FOO_SET.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn unreceived_elements_are_dropped_with_last_handle() {
+        let _lock = lock_foo_tests();
+        for producer_first in [true, false] {
+            let (mut px, mut cx) = channel();
+            for i in 0..500 {
+                px.send(Foo::new(i)).unwrap();
+            }
+            for i in 0..100 {
+                assert_eq!(cx.recv().unwrap().0, i);
+            }
+            let (first, last) = if producer_first {
+                (Box::new(px) as Box<dyn Send>, Box::new(cx) as Box<dyn Send>)
+            } else {
+                (Box::new(cx) as Box<dyn Send>, Box::new(px) as Box<dyn Send>)
+            };
+            drop(first);
+            // the other handle still owns the channel and the 400 in it
+            assert_eq!(FOO_SET.lock().unwrap().len(), 400);
+            drop(last);
+            assert!(FOO_SET.lock().unwrap().is_empty());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn overwritten_elements_are_dropped_once() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel();
+
+        for i in 0..2 * BUFFER_SIZE as i32 {
+            px.send_overwrite(Foo::new(i)).unwrap();
+        }
+        assert_eq!(FOO_SET.lock().unwrap().len(), BUFFER_SIZE);
+        assert_eq!(cx.recv().unwrap().0, BUFFER_SIZE as i32);
+        drop((px, cx));
+        assert!(FOO_SET.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn borrowed_elements_are_dropped_once() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel();
+        px.send(Foo::new(1)).unwrap();
+        px.send(Foo::new(2)).unwrap();
+
+        let msg = cx.recv_ref().unwrap();
+        assert_eq!(msg.0, 1);
+        drop(msg);
+        assert!(!FOO_SET.lock().unwrap().contains(&1));
+
+        // a borrowed head still belongs to the channel
+        let msg = cx.recv_ref().unwrap();
+        std::mem::forget(msg);
+        drop((px, cx));
+        assert!(FOO_SET.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sliced_elements_are_moved_out() {
+        let _lock = lock_foo_tests();
+        let (mut px, mut cx) = channel();
+        for i in 0..10 {
+            px.send(Foo::new(i)).unwrap();
+        }
+
+        let mut out: [MaybeUninit<Foo>; 4] = [const { MaybeUninit::uninit() }; 4];
+        assert_eq!(cx.recv_into_slice(&mut out), 4);
+        // the slice owns these now, the channel only the remaining six
+        drop((px, cx));
+        assert_eq!(FOO_SET.lock().unwrap().len(), 4);
+        for val in &mut out {
+            unsafe { val.assume_init_drop() };
+        }
+        assert!(FOO_SET.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn vacant_slices_are_sent_on_commit() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send_iter(0..3).unwrap();
+        cx.clear();
+
+        // starts at the last slot, so the free ones wrap around
+        let mut vacant = px.vacant_slices().unwrap();
+        let (first, second) = vacant.as_mut_slices();
+        assert_eq!((first.len(), second.len()), (1, 3));
+        for (i, slot) in first.iter_mut().chain(second).enumerate() {
+            slot.write(i);
+        }
+        // the slots are taken until the guard is gone
+        assert!(cx.try_recv().is_err());
+        unsafe { vacant.commit(3) };
+        let received: Vec<_> = (0..3).map(|_| cx.recv().unwrap()).collect();
+        assert_eq!(received, [0, 1, 2]);
+
+        // dropped without commit, nothing is sent
+        drop(px.vacant_slices().unwrap());
+        px.send(7).unwrap();
+        assert_eq!(cx.recv().unwrap(), 7);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn transaction_is_sent_all_or_nothing() {
+        let token = std::sync::Arc::new(());
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let mut tx = px.transaction(3).unwrap();
+        tx.push((0, token.clone())).unwrap();
+        tx.push((1, token.clone())).unwrap();
+        assert_eq!(tx.remaining(), 1);
+        // rolled back, the messages are dropped instead of sent
+        drop(tx);
+        assert_eq!(std::sync::Arc::strong_count(&token), 1);
+        assert!(cx.try_recv().is_err());
+
+        let mut tx = px.transaction(3).unwrap();
+        for i in 0..3 {
+            tx.push((i, token.clone())).unwrap();
+        }
+        assert!(tx.push((3, token.clone())).is_err());
+        assert!(cx.is_empty());
+        tx.commit();
+        assert_eq!(cx.len(), 3);
+        let received: Vec<_> = (0..3).map(|_| cx.recv().unwrap().0).collect();
+        assert_eq!(received, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn transaction_waits_for_room() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send_iter(0..3).unwrap();
+        let consumer = thread::spawn(move || {
+            let mut received = Vec::new();
+            while received.len() < 7 {
+                let val = cx.recv().unwrap();
+                // the rest of the group arrived along with its first message
+                if val == 3 {
+                    assert_eq!(cx.len(), 3);
+                }
+                received.push(val);
+            }
+            received
+        });
+        let mut tx = px.transaction(4).unwrap();
+        for i in 3..7 {
+            tx.push(i).unwrap();
+        }
+        tx.commit();
+        assert_eq!(consumer.join().unwrap(), (0..7).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn occupied_slices_are_received_on_release() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send_iter(["-"; 3].map(String::from)).unwrap();
+        cx.clear();
+        px.send_iter(["a", "b", "c"].map(String::from)).unwrap();
+
+        // starts at the last slot, so the messages wrap around
+        let mut occupied = cx.occupied_slices().unwrap();
+        let (first, second) = occupied.as_slices();
+        assert_eq!(first, ["a"]);
+        assert_eq!(second, ["b", "c"]);
+        occupied.as_mut_slices().1[1].push('!');
+        occupied.release(2);
+        assert_eq!(px.len(), 1);
+
+        // dropped without release, the messages stay queued
+        drop(cx.occupied_slices().unwrap());
+        assert_eq!(cx.recv().unwrap(), "c!");
+        drop(px);
+        assert!(cx.occupied_slices().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_with_builds_message_in_place() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        px.send_with(|slot| slot.write([7u8; 512])).unwrap();
+        assert_eq!(cx.recv().unwrap(), [7; 512]);
+
+        let mut slot = px.reserve().unwrap();
+        slot.slot().write([1; 512]);
+        unsafe { slot.commit() };
+        assert_eq!(cx.recv().unwrap(), [1; 512]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "init returned a reference to something other than the slot")]
+    fn send_with_rejects_foreign_reference() {
+        let (mut px, _cx) = channel_with_capacity::<i32>(2);
+        px.send_with(|_| Box::leak(Box::new(1))).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reserved_slot_is_sent_on_write() {
+        let (mut px, mut cx) = channel();
+
+        let slot = px.reserve().unwrap();
+        slot.write(String::from("reserved"));
+        // a cancelled reservation sends nothing
+        drop(px.reserve().unwrap());
+        px.send(String::from("sent")).unwrap();
+        drop(px);
+
+        assert_eq!(cx.recv().unwrap(), "reserved");
+        assert_eq!(cx.recv().unwrap(), "sent");
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reservation_blocks_other_producers() {
+        let (mut px, mut cx) = channel_mpsc(BUFFER_SIZE);
+        let mut other = px.downgrade().upgrade().unwrap();
+
+        let slot = px.reserve().unwrap();
+        let handle = thread::spawn(move || other.send(2).unwrap());
+        thread::sleep(Duration::from_millis(10));
+        slot.write(1);
+        handle.join().unwrap();
+
+        assert_eq!(cx.recv().unwrap(), 1);
+        assert_eq!(cx.recv().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "more than one consumer")]
+    fn second_consumer_is_detected() {
+        let (mut px, mut cx) = channel();
+        px.send(1).unwrap();
+        // what an accidental Clone impl would do
+        cx.inner
+            .state
+            .consumer_counter
+            .fetch_add(1, Ordering::SeqCst);
+        let _ = cx.recv();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_or_else_runs_hook_while_empty() {
+        let (mut px, mut cx) = channel();
+        px.send(0).unwrap();
+
+        // a message is ready, so the hook must not run
+        let mut calls = 0;
+        assert_eq!(cx.recv_or_else(|| calls += 1).unwrap(), 0);
+        assert_eq!(calls, 0);
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            px.send(1).unwrap();
+        });
+        let val = cx.recv_or_else(|| {
+            calls += 1;
+            thread::yield_now();
+        });
+        assert_eq!(val.unwrap(), 1);
+        assert!(calls > 0);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn try_send_reports_full_and_disconnected() {
+        let (mut px, mut cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.try_send(i).unwrap();
+        }
+        assert_eq!(
+            px.try_send(BUFFER_SIZE),
+            Err(TrySendError::Full(BUFFER_SIZE))
+        );
+
+        assert_eq!(cx.recv().unwrap(), 0);
+        px.try_send(BUFFER_SIZE).unwrap();
+
+        drop(cx);
+        assert_eq!(px.try_send(0), Err(TrySendError::Disconnected(0)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn send_timeout_hands_back_value() {
+        let (mut px, mut cx) = channel();
+        for i in 0..BUFFER_SIZE {
+            px.send(i).unwrap();
+        }
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+        assert_eq!(
+            px.send_timeout(BUFFER_SIZE, timeout),
+            Err(SendTimeoutError::Timeout(BUFFER_SIZE))
+        );
+        assert!(start.elapsed() >= timeout);
+
+        assert_eq!(cx.recv().unwrap(), 0);
+        assert_eq!(px.send_timeout(BUFFER_SIZE, timeout), Ok(()));
+
+        drop(cx);
+        assert_eq!(
+            px.send_timeout(0, Duration::MAX),
+            Err(SendTimeoutError::Disconnected(0))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn try_recv_reports_empty_and_disconnected() {
+        let (mut px, mut cx) = channel();
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+
+        px.send(1).unwrap();
+        drop(px);
+        // buffered messages are still delivered after the disconnect
+        assert_eq!(cx.try_recv(), Ok(1));
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn try_iter_stops_at_an_empty_buffer() {
+        let (mut px, mut cx) = channel();
+        assert_eq!(cx.try_iter().next(), None);
+
+        for i in 0..3 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        // the producer is still connected, the next batch is another call
+        px.send(3).unwrap();
+        drop(px);
+        assert_eq!(cx.try_iter().collect::<Vec<_>>(), [3]);
+        assert_eq!(cx.try_iter().next(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn iter_timeout_ends_once_the_producer_goes_quiet() {
+        let (mut px, mut cx) = channel();
+        let producer = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+            // connected, but idle for longer than the timeout
+            thread::sleep(Duration::from_millis(200));
+            px
+        });
+        let received: Vec<i32> = cx.iter_timeout(Duration::from_millis(50)).collect();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+        let mut px = producer.join().unwrap();
+
+        px.send(100).unwrap();
+        drop(px);
+        // a disconnect ends it without waiting out the timeout
+        let start = Instant::now();
+        assert_eq!(
+            cx.iter_timeout(Duration::from_secs(10)).collect::<Vec<_>>(),
+            [100]
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn iter_deadline_ends_at_the_deadline() {
+        let (mut px, mut cx) = channel();
+        px.send(1).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut iter = cx.iter_deadline(deadline);
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next(), None);
+        assert!(Instant::now() >= deadline);
+
+        // queued messages are still yielded once the deadline has passed
+        px.send(2).unwrap();
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reunite_reuses_the_buffer() {
+        let token = Arc::new(());
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let buffer = px.inner.message_buffer.as_ptr();
+        px.send(token.clone()).unwrap();
+        px.send(token.clone()).unwrap();
+        assert_eq!(cx.try_recv(), Ok(token.clone()));
+        px.close();
+
+        let (mut px, mut cx) = SPSC::reunite(px, cx).unwrap();
+        assert_eq!(px.inner.message_buffer.as_ptr(), buffer);
+        // the message left in the old channel was dropped
+        assert_eq!(Arc::strong_count(&token), 1);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        for _ in 0..4 {
+            px.send(token.clone()).unwrap();
+        }
+        assert!(px.is_full());
+        assert_eq!(cx.drain().len(), 4);
+    }
+
+    #[test]
+    fn reunite_needs_the_last_endpoints_of_one_channel() {
+        let (px, cx) = channel::<i32>();
+        let (other_px, other_cx) = channel::<i32>();
+        let ReuniteError(px, other_cx) = SPSC::reunite(px, other_cx).unwrap_err();
+
+        let weak = px.downgrade();
+        let ReuniteError(px, cx) = SPSC::reunite(px, cx).unwrap_err();
+        drop(weak);
+        assert!(SPSC::reunite(px, cx).is_ok());
+        assert!(SPSC::reunite(other_px, other_cx).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn monitor_follows_the_channel() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let monitor = px.monitor();
+        let clone = cx.monitor().clone();
+        assert_eq!(monitor.capacity(), 4);
+        assert!(monitor.is_empty());
+
+        for i in 0..4 {
+            px.send(i).unwrap();
+        }
+        assert!(monitor.is_full());
+        assert_eq!(cx.recv(), Ok(0));
+        assert_eq!(clone.len(), 3);
+        assert_eq!(clone.high_water_mark(), 4);
+
+        // a monitor does not keep either side connected
+        drop(px);
+        assert!(!monitor.is_producer_alive() && monitor.is_consumer_alive());
+        assert!(cx.is_disconnected());
+        drop(cx);
+        assert!(!monitor.is_consumer_alive());
+        // the messages left are still counted, until the last handle goes
+        assert_eq!(monitor.len(), 3);
+        thread::spawn(move || assert_eq!(clone.capacity(), 4))
+            .join()
+            .unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn handles_compose_in_generic_containers() {
+        fn send<T: Send>() {}
+        fn sync<T: Sync>() {}
+        // the endpoints are not Sync, see the compile_fail examples
+        send::<Vec<Producer<String>>>();
+        send::<Option<Consumer<String>>>();
+        send::<(Producer<i32>, Consumer<i32>)>();
+        send::<ReuniteError<i32>>();
+        send::<WeakProducer<i32>>();
+        sync::<WeakProducer<i32>>();
+        send::<Arc<Monitor<i32>>>();
+        sync::<Monitor<i32>>();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn the_other_handles_need_no_bounds_to_be_named() {
+        // would not compile if any of the structs required T: Send
+        #[allow(dead_code)]
+        struct Endpoints<'a, T> {
+            borrowed: borrowed::Producer<'a, T>,
+            unbounded: unbounded::Consumer<T>,
+            growable: growable::Producer<T>,
+            oneshot: oneshot::Consumer<T>,
+            rendezvous: rendezvous::Producer<T>,
+            mpmc: mpmc::Consumer<T>,
+            priority: priority::Producer<T>,
+            ttl: ttl::Consumer<T>,
+            merged: Merged<T>,
+        }
+        fn send<T: Send>() {}
+        send::<Endpoints<'static, String>>();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn consumer_iterates_until_disconnect() {
+        let (mut px, cx) = channel();
+        let producer = thread::spawn(move || {
+            for i in 0..10_000 {
+                px.send(i).unwrap();
+            }
+        });
+        let received: Vec<i32> = cx.collect();
+        producer.join().unwrap();
+        assert_eq!(received, (0..10_000).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn consumer_close_stops_producer() {
+        let (mut px, mut cx) = channel();
+        px.send(1).unwrap();
+        cx.close();
+
+        assert!(px.is_closed());
+        assert!(matches!(px.send(2), Err(SendError(2))));
+        // Both handles are still alive, drain what was sent before closing
+        assert!(px.is_consumer_alive() && cx.is_producer_alive());
+        assert_eq!(cx.recv().unwrap(), 1);
+        assert!(cx.recv().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn debug_shows_state_not_messages() {
+        struct Opaque;
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send(Opaque).unwrap();
+        px.send(Opaque).unwrap();
+        cx.recv().unwrap();
+        assert_eq!(
+            format!("{:?}", px),
+            "Producer { capacity: 4, len: 1, read_index: 1, write_index: 2, producers: 1, \
+             consumer_alive: true, closed: false, .. }"
+        );
+        drop(px);
+        assert!(format!("{:?}", cx).starts_with("Consumer { capacity: 4, len: 1,"));
+        assert!(format!("{:?}", cx).contains("producers: 0"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn builder_applies_its_options() {
+        let (px, cx) = ChannelBuilder::new()
+            .capacity(8)
+            .wait_strategy(WaitStrategy::Yield)
+            .label("builder")
+            .build::<i32>();
+        assert_eq!(px.capacity(), 8);
+        assert_eq!(px.inner.state.wait_strategy, WaitStrategy::Yield);
+        assert!(format!("{:?}", cx).starts_with("Consumer { label: \"builder\", capacity: 8,"));
+
+        // the rest as for channel()
+        let (px, _cx) = ChannelBuilder::new().build::<i32>();
+        assert_eq!(px.capacity(), BUFFER_SIZE);
+        assert_eq!(px.inner.state.wait_strategy, WaitStrategy::Park);
+        assert!(format!("{:?}", px).starts_with("Producer { capacity:"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panic_with_a_reserved_slot_poisons() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send(1).unwrap();
+        // the producer outlives the panic, so only the poison ends the recv
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _slot = px.reserve().unwrap();
+            panic!("while building the message");
+        }));
+        assert!(result.is_err());
+        assert!(px.is_poisoned() && cx.is_poisoned());
+        assert!(px.send(2).is_err());
+        assert_eq!(cx.recv(), Ok(1));
+        assert_eq!(cx.recv(), Err(RecvError));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panicking_producer_thread_poisons() {
+        let (mut px, mut cx) = channel_mpsc(BUFFER_SIZE);
+        let other = px.clone();
+        let handle = thread::spawn(move || {
+            px.send(1).unwrap();
+            panic!("producer thread");
+        });
+        assert!(handle.join().is_err());
+        // another producer is left, but it would wait for the dead one
+        assert_eq!(cx.recv(), Ok(1));
+        assert_eq!(cx.recv(), Err(RecvError));
+        assert!(other.is_poisoned());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panic_while_handling_a_message_poisons() {
+        let (mut px, mut cx) = channel_with_capacity(1);
+        px.send(1).unwrap();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _msg = cx.recv_ref().unwrap();
+            panic!("while handling the message");
+        }));
+        assert!(result.is_err());
+        // the consumer is still around, yet sends fail
+        assert!(px.is_poisoned());
+        assert!(matches!(px.try_send(2), Err(TrySendError::Disconnected(2))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn disconnect_is_visible_on_both_ends() {
+        let (mut px, mut cx) = channel_mpsc(BUFFER_SIZE);
+        assert!(!px.is_disconnected() && !cx.is_disconnected());
+        px.send(1).unwrap();
+        let px2 = px.clone();
+        drop(px);
+        // another producer is left, and it is merely idle
+        assert!(!cx.is_disconnected());
+        drop(px2);
+        assert!(cx.is_disconnected() && !cx.is_empty());
+        assert_eq!(cx.recv().unwrap(), 1);
+        assert!(cx.is_disconnected() && cx.is_empty());
+
+        let (px, cx) = channel::<i32>();
+        drop(cx);
+        assert!(px.is_disconnected() && !px.is_closed());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn consumer_close_unblocks_waiting_producer() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        px.send(0).unwrap();
+        px.send(1).unwrap();
+        let producer = thread::spawn(move || px.send(2).map_err(|SendError(val)| val));
+        thread::sleep(Duration::from_millis(10));
+        cx.close();
+        assert_eq!(producer.join().unwrap(), Err(2));
+        assert_eq!(cx.recv().unwrap(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn producer_close_ends_stream_after_drain() {
+        let (mut px, mut cx) = channel();
+        px.send(1).unwrap();
+        px.send(2).unwrap();
+        px.close();
+
+        assert!(matches!(px.send(3), Err(SendError(3))));
+        assert_eq!(px.capacity(), BUFFER_SIZE);
+        assert_eq!(cx.try_recv(), Ok(1));
+        assert_eq!(cx.recv().unwrap(), 2);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+        assert!(cx.is_producer_alive());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn producer_close_unblocks_waiting_consumer() {
+        let (px, mut cx) = channel::<i32>();
+        let consumer = thread::spawn(move || cx.recv());
+        thread::sleep(Duration::from_millis(10));
+        px.close();
+        assert!(consumer.join().unwrap().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn len_follows_sends_and_recvs() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        assert!(px.is_empty() && cx.is_empty());
+        for i in 0..3 {
+            px.send(i).unwrap();
+        }
+        assert_eq!((px.len(), cx.len()), (3, 3));
+        assert!(!cx.is_empty() && !cx.is_full());
+        px.send(3).unwrap();
+        assert!(px.is_full() && cx.is_full());
+        cx.recv().unwrap();
+        assert_eq!(px.len(), 3);
+        cx.clear();
+        assert!(px.is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn len_stays_within_capacity() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        let handle = thread::spawn(move || {
+            for i in 0..10_000 {
+                px.send(i).unwrap();
+            }
+        });
+        while cx.recv().is_ok() {
+            assert!(cx.len() <= 2);
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the producers of channel_mpsc")]
+    fn only_mpsc_producers_can_be_cloned() {
+        let (px, _cx) = channel::<i32>();
+        let _other = px.clone();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "only the producers of channel_mpsc")]
+    fn sharded_mpsc_producers_can_not_be_cloned() {
+        let (producers, _cx) = sharded_mpsc::<i32>(2, 4);
+        let _other = producers[0].clone();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the producers of channel_mpsc")]
+    fn only_mpsc_producers_can_be_upgraded() {
+        let (px, _cx) = channel::<i32>();
+        let _other = px.downgrade().upgrade();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn cloned_producers_share_channel() {
+        let (px, cx) = channel_mpsc(8);
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let mut px = px.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        px.send((id, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(px);
+
+        let mut next = [0; 4];
+        for (id, i) in cx {
+            assert_eq!(next[id], i);
+            next[id] += 1;
+        }
+        assert_eq!(next, [1000; 4]);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn capacity_is_chosen_per_channel() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        assert_eq!(px.capacity(), 4);
+        for i in 0..4 {
+            px.try_send(i).unwrap();
+        }
+        assert_eq!(px.try_send(4), Err(TrySendError::Full(4)));
+
+        // wrap around the small buffer a few times
+        for i in 4..20 {
+            assert_eq!(cx.recv().unwrap(), i - 4);
+            px.send(i).unwrap();
+        }
+        assert_eq!(channel::<i32>().1.capacity(), BUFFER_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer capacity must be at least 1")]
+    fn zero_capacity_is_rejected() {
+        channel_with_capacity::<i32>(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer capacity must be a power of two")]
+    fn odd_capacity_is_rejected() {
+        channel_with_capacity::<i32>(3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_many_appends_up_to_max() {
+        let (mut px, mut cx) = channel_with_capacity(8);
+        for i in 0..6 {
+            px.send(i).unwrap();
+        }
+        let mut out = vec![-1];
+        assert_eq!(cx.recv_many(&mut out, 4), 4);
+        assert_eq!(cx.recv_many(&mut out, usize::MAX), 2);
+        assert_eq!(cx.recv_many(&mut out, 4), 0);
+        assert_eq!(out, [-1, 0, 1, 2, 3, 4, 5]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_exact_waits_for_a_full_frame() {
+        let (mut px, mut cx) = channel_with_capacity(64);
+        let producer = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+        });
+        assert_eq!(cx.recv_exact(64), Ok((0..64).collect::<Vec<_>>()));
+        assert!(cx.wait_for_messages(16).unwrap() >= 16);
+        producer.join().unwrap();
+        // the rest, now that no more are coming
+        assert_eq!(cx.recv_exact(64), Ok((64..100).collect::<Vec<_>>()));
+        assert_eq!(cx.recv_exact(64), Err(RecvError));
+        assert_eq!(cx.wait_for_messages(0), Ok(0));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn wait_for_space_makes_room_for_a_burst() {
+        let (mut px, mut cx) = channel_with_capacity(8);
+        px.send_iter(0..8).unwrap();
+        let consumer = thread::spawn(move || {
+            for i in 0..5 {
+                assert_eq!(cx.recv(), Ok(i));
+            }
+            cx
+        });
+        assert!(px.wait_for_space(5).unwrap() >= 5);
+        assert_eq!(px.send_iter(8..13), Ok(5));
+        let mut cx = consumer.join().unwrap();
+        assert_eq!(cx.drain(), (5..13).collect::<Vec<_>>());
+        drop(cx);
+        assert_eq!(px.wait_for_space(1), Err(SendError(())));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn drain_takes_what_is_queued() {
+        let (mut px, mut cx) = channel_with_capacity(8);
+        assert!(cx.drain().is_empty());
+        for i in 0..6 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.drain(), [0, 1, 2, 3, 4, 5]);
+        // a full buffer, wrapped around its end
+        for i in 6..14 {
+            px.send(i).unwrap();
+        }
+        assert!(px.is_full());
+        assert_eq!(cx.drain(), (6..14).collect::<Vec<_>>());
+        assert!(cx.is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recv_into_slice_takes_what_is_buffered() {
+        let (mut px, mut cx) = channel();
+        // Start close to the end of the buffer, so the first batch wraps
+        for _ in 0..BUFFER_SIZE - 10 {
+            px.send(-1).unwrap();
+        }
+        assert_eq!(cx.clear(), BUFFER_SIZE - 10);
+        for i in 0..100 {
+            px.send(i).unwrap();
+        }
+
+        let mut out = [MaybeUninit::<i32>::uninit(); 64];
+        assert_eq!(cx.recv_into_slice(&mut out), 64);
+        let first: Vec<i32> = out.iter().map(|val| unsafe { val.assume_init() }).collect();
+        assert_eq!(first, (0..64).collect::<Vec<_>>());
+
+        assert_eq!(cx.recv_into_slice(&mut out), 36);
+        let second: Vec<i32> = out[..36]
+            .iter()
+            .map(|val| unsafe { val.assume_init() })
+            .collect();
+        assert_eq!(second, (64..100).collect::<Vec<_>>());
+        assert_eq!(cx.recv_into_slice(&mut out), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn high_water_mark_tracks_max_len() {
+        let (mut px, mut cx) = channel();
+        assert_eq!(px.high_water_mark(), 0);
+
+        for i in 0..3000 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(cx.high_water_mark(), 3000);
+
+        for _ in 0..2000 {
+            cx.recv().unwrap();
+        }
+        px.send(0).unwrap();
+        assert_eq!(px.high_water_mark(), 3000);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn notify_hooks_run_on_transitions() {
+        let (mut px, mut cx) = channel_with_capacity(2);
+        let messages = Arc::new(AtomicUsize::new(0));
+        let spaces = Arc::new(AtomicUsize::new(0));
+        let counter = messages.clone();
+        cx.set_notify(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let counter = spaces.clone();
+        px.set_notify(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // only the first send into the empty buffer
+        px.send(1).unwrap();
+        px.send(2).unwrap();
+        assert_eq!(messages.load(Ordering::Relaxed), 1);
+        assert_eq!(px.try_send(3), Err(TrySendError::Full(3)));
+
+        // only the first receive out of the full buffer
+        assert_eq!(cx.recv(), Ok(1));
+        assert_eq!(spaces.load(Ordering::Relaxed), 1);
+        assert_eq!(cx.recv(), Ok(2));
+        assert_eq!(spaces.load(Ordering::Relaxed), 1);
+
+        px.send(3).unwrap();
+        assert_eq!(messages.load(Ordering::Relaxed), 2);
+        assert_eq!(cx.try_recv(), Ok(3));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn watermarks_take_turns() {
+        let (mut px, mut cx) = channel_with_capacity(8);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (high, low) = (events.clone(), events.clone());
+        px.set_watermarks(
+            6,
+            2,
+            move || high.lock().unwrap().push("high"),
+            move || low.lock().unwrap().push("low"),
+        );
+
+        for i in 0..7 {
+            px.send(i).unwrap();
+        }
+        // once at 6, not again at 7
+        assert_eq!(*events.lock().unwrap(), ["high"]);
+        for i in 0..5 {
+            assert_eq!(cx.recv(), Ok(i));
+        }
+        // once at 2
+        assert_eq!(*events.lock().unwrap(), ["high", "low"]);
+        assert_eq!(cx.recv(), Ok(5));
+        // filling up again without having reached the high mark
+        for i in 7..10 {
+            px.send(i).unwrap();
+        }
+        assert_eq!(*events.lock().unwrap(), ["high", "low"]);
+        px.send(10).unwrap();
+        px.send(11).unwrap();
+        assert_eq!(*events.lock().unwrap(), ["high", "low", "high"]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn callbacks_may_call_into_the_channel() {
+        let (mut px, mut cx) = channel_mpsc(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (other, counter) = (Mutex::new(px.clone()), runs.clone());
+        // replaces itself with one that does nothing, from within
+        px.set_notify(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            other.lock().unwrap().set_notify(|| {});
+        });
+        for i in 0..2 {
+            px.send(i).unwrap();
+            assert_eq!(cx.recv(), Ok(i));
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        let (other, counter) = (Mutex::new(px.clone()), runs.clone());
+        px.set_watermarks(
+            1,
+            0,
+            move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                other.lock().unwrap().set_watermarks(1, 0, || {}, || {});
+            },
+            || {},
+        );
+        for i in 0..2 {
+            px.send(i).unwrap();
+            assert_eq!(cx.recv(), Ok(i));
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn watermarks_pause_a_source_before_the_buffer_fills() {
+        let (mut px, mut cx) = channel_with_capacity(64);
+        let paused = Arc::new(AtomicBool::new(false));
+        let (on_high, on_low) = (paused.clone(), paused.clone());
+        px.set_watermarks(
+            48,
+            16,
+            move || on_high.store(true, Ordering::Relaxed),
+            move || on_low.store(false, Ordering::Relaxed),
+        );
+
+        let handle = thread::spawn(move || {
+            let mut i = 0;
+            while i < 100_000 {
+                if paused.load(Ordering::Relaxed) {
+                    thread::yield_now();
+                    continue;
+                }
+                // a paused source never gets near a full buffer
+                assert!(px.try_send(i).is_ok());
+                i += 1;
+            }
+        });
+        for i in 0..100_000 {
+            assert_eq!(cx.recv(), Ok(i));
+        }
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "watermarks need low < high <= capacity")]
+    fn watermarks_above_capacity_panic() {
+        let (px, _cx) = channel_with_capacity::<i32>(4);
+        px.set_watermarks(5, 1, || {}, || {});
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_carries_the_label() {
+        use std::fmt::Debug;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        // Logs "<channel>: <event message or span name>"
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        #[derive(Default)]
+        struct Fields {
+            channel: String,
+            message: String,
+        }
+
+        impl Visit for Fields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "channel" {
+                    self.channel = value.to_string();
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                if field.name() == "message" {
+                    self.message = format!("{value:?}");
+                }
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                let name = span.metadata().name();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {name}", fields.channel));
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let line = format!("{}: {}", fields.channel, fields.message);
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(log.clone()), || {
+            let (mut px, mut cx) = channel_with_label(2, "jobs");
+            px.send(1).unwrap();
+            assert_eq!(cx.recv(), Ok(1));
+            let timeout = Duration::from_millis(1);
+            assert_eq!(cx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+            assert!(format!("{px:?}").contains("jobs"));
+            drop(px);
+        });
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "jobs: send",
+                "jobs: recv",
+                "jobs: blocked_on_empty",
+                "jobs: producer disconnected",
+                "jobs: consumer disconnected",
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sharded_mpsc_keeps_order_per_producer() {
+        let (producers, cx) = sharded_mpsc(4, 8);
+        let handles: Vec<_> = producers
+            .into_iter()
+            .enumerate()
+            .map(|(shard, mut px)| {
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        px.send((shard, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut next = [0; 4];
+        for (shard, i) in cx {
+            assert_eq!(next[shard], i);
+            next[shard] += 1;
+        }
+        assert_eq!(next, [1000; 4]);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn zero_sized_messages_need_no_buffer() {
+        let (mut px, cx) = channel::<()>();
+        assert_eq!(px.capacity(), BUFFER_SIZE);
+        assert_eq!(std::mem::size_of_val(&*px.inner.message_buffer), 0);
+
+        let handle = thread::spawn(move || {
+            for _ in 0..3 * BUFFER_SIZE {
+                px.send(()).unwrap();
+            }
+        });
+        assert_eq!(cx.count(), 3 * BUFFER_SIZE);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn scoped_threads_send_borrowed_data() {
+        let data: Vec<u8> = (0..=255).collect();
+        let (mut px, cx) = channel_with_capacity::<&[u8]>(4);
+        let total = thread::scope(|s| {
+            let data = &data;
+            s.spawn(move || {
+                for chunk in data.chunks(16) {
+                    px.send(chunk).unwrap();
+                }
+            });
+            s.spawn(move || cx.map(|chunk| chunk.len()).sum::<usize>())
+                .join()
+                .unwrap()
+        });
+        assert_eq!(total, 256);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn channel_in_uses_given_buffer() {
+        let buffer = Box::<[String]>::new_uninit_slice(8);
+        let address = buffer.as_ptr() as usize;
+        let (mut px, mut cx) = channel_in(buffer);
+        assert_eq!(px.capacity(), 8);
 
-pub struct Producer<T: Send> {
-    message_buffer: Arc<[UnsafeCell<Option<T>>; BUFFER_SIZE]>,
-    read_index: Arc<AtomicUsize>,
-    write_index: Arc<AtomicUsize>,
-    producer_counter: Arc<AtomicUsize>,
-    consumer_counter: Arc<AtomicUsize>,
-    synchronizer: Arc<AtomicBool>,
-    _marker: PhantomData<T>,
-}
-pub struct Consumer<T: Send> {
-    message_buffer: Arc<[UnsafeCell<Option<T>>; BUFFER_SIZE]>,
-    read_index: Arc<AtomicUsize>,
-    write_index: Arc<AtomicUsize>,
-    producer_counter: Arc<AtomicUsize>,
-    consumer_counter: Arc<AtomicUsize>,
-    synchronizer: Arc<AtomicBool>,
-    _marker: PhantomData<T>,
-}
-
-pub struct SPSC<T: Send> {
-    producer: Producer<T>,
-    consumer: Consumer<T>,
-}
+        px.send(String::from("a")).unwrap();
+        let head = cx.peek().unwrap();
+        assert_eq!(&*head as *const String as usize, address);
+        drop(head);
 
-#[derive(Debug)]
-pub struct SendError<T>(pub T);
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                px.send(i.to_string()).unwrap();
+            }
+        });
+        assert_eq!(cx.recv().unwrap(), "a");
+        for i in 0..100 {
+            assert_eq!(cx.recv().unwrap(), i.to_string());
+        }
+        handle.join().unwrap();
+    }
 
-#[derive(Debug)]
-pub struct RecvError;
+    #[cfg(feature = "std")]
+    #[test]
+    fn all_elements_arrive() {
+        for _ in 0..100 {
+            let (mut px, mut cx) = channel();
+            let handle = thread::spawn(move || {
+                let mut count = 0;
 
-impl<T: Send> SPSC<T> {
-    const INIT: UnsafeCell<Option<T>> = UnsafeCell::new(None);
-    pub fn new() -> Self {
-        // The only way I found for 2 threads to share a buffer is unsafe cells
-        let cell_array: [UnsafeCell<Option<T>>; BUFFER_SIZE] = [Self::INIT; BUFFER_SIZE];
-
-        let message_buffer: Arc<[UnsafeCell<Option<T>>; BUFFER_SIZE]> = Arc::new(cell_array);
-
-        let read_index: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
-        let write_index: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
-        let producer_counter: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(1));
-        let consumer_counter: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(1));
-
-        let synchronizer = Arc::new(AtomicBool::new(false));
-
-        let producer = Producer {
-            message_buffer: message_buffer.clone(),
-            read_index: read_index.clone(),
-            write_index: write_index.clone(),
-            producer_counter: producer_counter.clone(),
-            consumer_counter: consumer_counter.clone(),
-            synchronizer: synchronizer.clone(),
-            _marker: PhantomData,
-        };
+                while cx.recv().is_ok() {
+                    count += 1;
+                }
 
-        let consumer = Consumer {
-            message_buffer: message_buffer.clone(),
-            read_index: read_index.clone(),
-            write_index: write_index.clone(),
-            producer_counter: producer_counter.clone(),
-            consumer_counter: consumer_counter.clone(),
-            synchronizer: synchronizer.clone(),
-            _marker: PhantomData,
-        };
+                count
+            });
 
-        SPSC { producer, consumer }
+            thread::spawn(move || {
+                for i in ELEMS {
+                    px.send(i).unwrap();
+                }
+            });
+
+            match handle.join() {
+                Ok(count) => assert_eq!(count, ELEMS.len()),
+                Err(_) => panic!("Error: join() returned Err"),
+            }
+        }
     }
 }
 
-impl<T: Send> Producer<T> {
-    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
-        if self.consumer_counter.load(Ordering::SeqCst) == 0 {
-            return Err(SendError(val));
-        }
+// exhaustive checks of the atomic protocol, run with
+// RUSTFLAGS="--cfg loom" cargo test --release
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
 
-        loop {
-            while(self.synchronizer.swap(true, Ordering::SeqCst)){}
-            let write_index: usize = self.write_index.load(Ordering::SeqCst);
-            let read_index: usize = self.read_index.load(Ordering::SeqCst);
-
-            // The write index must not 'overtake' the read index
-            // when wrapping around the buffer
-            //
-            // Since we only have one producer, we do not need an atomic swap
-            // to synchronize the write_index increment
-            //
-            // If the read_index changes during the load, it is okay because
-            // the consumer will only read the message when the read index
-            // is smaller than the write index
-            //
-            // Initially, the read index and write index are 0,
-            // so we allow a write to the first element of the buffer
-            if write_index >= read_index && write_index < read_index + BUFFER_SIZE {
-                unsafe {
-                    self.message_buffer[write_index % BUFFER_SIZE]
-                        .get()
-                        .write(Some(val));
-                }
+    use super::*;
+
+    // Both sides retry in loops (the CAS on read_index that claims the head,
+    // which a receive and an evicting send race for, and the checks around
+    // parking), which takes many branches, so bound the preemptions to keep
+    // the search tractable
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = loom::model::Builder::new();
+        builder.max_branches = 100_000;
+        builder.preemption_bound.get_or_insert(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn messages_arrive_in_order() {
+        model(|| {
+            let (mut px, mut cx) = channel();
+
+            let handle = thread::spawn(move || {
+                px.send(1).unwrap();
+                px.send(2).unwrap();
+            });
+
+            assert_eq!(cx.recv().unwrap(), 1);
+            assert_eq!(cx.recv().unwrap(), 2);
+            assert!(cx.recv().is_err());
+
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn mpsc_producers_claim_by_cas() {
+        model(|| {
+            let (mut px, mut cx) = channel_mpsc(4);
+            let mut other = px.clone();
+
+            // Either producer may be between its claim and marking the slot
+            // written while the other claims the next one, or publishes past
+            // its own
+            let handle = thread::spawn(move || {
+                other.send(2).unwrap();
+                other.send(3).unwrap();
+            });
+            px.send(1).unwrap();
+            drop(px);
+
+            let received = [cx.recv().unwrap(), cx.recv().unwrap(), cx.recv().unwrap()];
+            let position = |val| received.iter().position(|&r| r == val).unwrap();
+            assert!(position(1) < 3);
+            assert!(position(2) < position(3));
+            assert!(cx.recv().is_err());
+
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn full_buffer_waits_for_consumer() {
+        model(|| {
+            let (mut px, mut cx) = channel();
 
-                self.write_index.fetch_add(1, Ordering::SeqCst);
+            let handle = thread::spawn(move || {
+                for i in 0..BUFFER_SIZE + 1 {
+                    px.send(Arc::new(i)).unwrap();
+                }
+            });
 
-                self.synchronizer.swap(false, Ordering::SeqCst);
-                return Ok(());
+            for i in 0..BUFFER_SIZE + 1 {
+                assert_eq!(*cx.recv().unwrap(), i);
             }
-            self.synchronizer.swap(false, Ordering::SeqCst);
-        }
+            assert!(cx.recv().is_err());
+
+            handle.join().unwrap();
+        });
     }
-}
 
-impl<T: Send> Consumer<T> {
-    pub fn recv(&self) -> Result<T, RecvError> {
-        loop {
-            while(self.synchronizer.swap(true, Ordering::SeqCst)){}
-            let write_index: usize = self.write_index.load(Ordering::SeqCst);
-            let read_index: usize = self.read_index.load(Ordering::SeqCst);
-
-            // When no producer is active and the consumer read all messages, we are done
-            if read_index == write_index && self.producer_counter.load(Ordering::SeqCst) == 0 {
-                self.synchronizer.swap(false, Ordering::SeqCst);
-                return Err(RecvError);
-            }
-
-            // since there is only one consumer, we do not need an atomic swap
-            // to synchronize the read_index increment
-            //
-            // If the write_index changes during the load, it is okay because
-            // the write index will always be greater than the read index and the
-            // producer ensures, that the write index never overtakes the read index
-            // when wrapping around the buffer
-            if read_index < write_index {
-                unsafe {
-                    let val = self.message_buffer[read_index % BUFFER_SIZE]
-                        .get()
-                        .replace(None);
-                    self.read_index.fetch_add(1, Ordering::SeqCst);
-                    self.synchronizer.swap(false, Ordering::SeqCst);
-                    return Ok(val.unwrap());
+    #[test]
+    fn dropped_consumer_unblocks_full_send() {
+        model(|| {
+            let (mut px, cx) = channel();
+
+            let handle = thread::spawn(move || {
+                for i in 0..BUFFER_SIZE + 1 {
+                    if px.send(i).is_err() {
+                        return;
+                    }
                 }
+            });
+
+            drop(cx);
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn overwrite_races_recv() {
+        model(|| {
+            let (mut px, mut cx) = channel();
+            let tracker = Arc::new(());
+            for i in 0..BUFFER_SIZE {
+                px.send((i, tracker.clone())).unwrap();
             }
-            self.synchronizer.swap(false, Ordering::SeqCst);
-        }
+
+            let t = tracker.clone();
+            let handle = thread::spawn(move || {
+                px.send_overwrite((BUFFER_SIZE, t)).unwrap();
+            });
+
+            // either the head was received or it was evicted, never both:
+            // it goes to whichever CAS moves read_index past it first, and
+            // while the consumer has it claimed the send waits
+            let (first, _) = cx.recv().unwrap();
+            assert!(first == 0 || first == 1);
+            handle.join().unwrap();
+
+            let rest: Vec<usize> = cx.map(|(i, _)| i).collect();
+            assert_eq!(rest.last(), Some(&BUFFER_SIZE));
+            assert!(rest.iter().all(|&i| i > first));
+            // nothing leaked or dropped twice
+            assert_eq!(Arc::strong_count(&tracker), 1);
+        });
     }
-}
 
-impl<T: Send> Iterator for Consumer<T> {
-    type Item = T;
-    fn next(&mut self) -> Option<Self::Item> {
-        // TODO: fill with life
-        unimplemented!()
+    #[test]
+    fn overwrite_single_slot() {
+        model(|| {
+            let (mut px, cx) = channel_with_capacity(1);
+            px.send(String::from("old")).unwrap();
+
+            let handle = thread::spawn(move || {
+                px.send_overwrite(String::from("new")).unwrap();
+            });
+
+            // the consumer may see the slot in the middle of the eviction,
+            // that must neither hand out an empty slot nor lose "new"
+            let received: Vec<String> = cx.collect();
+            handle.join().unwrap();
+            assert!(received == ["old", "new"] || received == ["new"]);
+        });
     }
-}
 
-unsafe impl<T: Send> Send for Producer<T> {}
-unsafe impl<T: Send> Send for Consumer<T> {}
+    #[test]
+    fn overwrite_spares_a_peek() {
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(1);
+            px.send(0).unwrap();
+
+            let handle = thread::spawn(move || px.force_send(2));
 
-impl<T: Send> Drop for Producer<T> {
-    fn drop(&mut self) {
-        self.producer_counter.fetch_sub(1, Ordering::SeqCst);
+            // force_send evicts the head either before the claim, and the
+            // change goes to its own message (unless the peek falls between
+            // the eviction and the refill, and finds none), or after the
+            // claim was given back, and evicts the change. In between the
+            // guard holds the head and it fails.
+            if let Some(mut head) = cx.peek_mut() {
+                *head += 1;
+            }
+            let evicted = handle.join().unwrap();
+            let received: Vec<i32> = cx.collect();
+            assert!(matches!(
+                (evicted, received.as_slice()),
+                (Ok(Some(0)), [3] | [2]) | (Ok(Some(1)), [2]) | (Err(ForceSendError::Held(2)), [1])
+            ));
+        });
     }
-}
 
-impl<T: Send> Drop for Consumer<T> {
-    fn drop(&mut self) {
-        self.consumer_counter.fetch_sub(1, Ordering::SeqCst);
+    #[test]
+    fn close_delivers_earlier_sends() {
+        model(|| {
+            let (mut px, mut cx) = channel();
+
+            let handle = thread::spawn(move || {
+                px.send(String::from("last")).unwrap();
+                px.close();
+                // keep the producer alive, only the close may end the recv
+                px
+            });
+
+            // the message must be visible once the close is
+            assert_eq!(cx.recv().unwrap(), "last");
+            assert!(cx.recv().is_err());
+            drop(handle.join().unwrap());
+        });
     }
-}
 
-pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
-    let spsc: SPSC<T> = SPSC::new();
-    return (spsc.producer, spsc.consumer);
-}
+    #[test]
+    fn slot_reuse_waits_for_recv() {
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(1);
 
-// vorimplementierte Testsuite; bei Bedarf erweitern!
+            // every send reuses the one slot, the loom cell reports a data
+            // race if a write can overlap the consumer moving the message out
+            let handle = thread::spawn(move || {
+                for i in 0..3 {
+                    px.send(vec![i]).unwrap();
+                }
+            });
 
-#[cfg(test)]
-mod tests {
-    use lazy_static::lazy_static;
-    use std::collections::HashSet;
-    use std::sync::Mutex;
-    use std::thread;
+            for i in 0..3 {
+                assert_eq!(cx.recv().unwrap(), [i]);
+            }
+            handle.join().unwrap();
+        });
+    }
 
-    use super::*;
+    #[test]
+    fn async_endpoints_are_woken() {
+        model(|| {
+            let (mut px, mut cx) = channel();
+            let handle = thread::spawn(move || {
+                // one more than fits, so the send future has to wait
+                for i in 0..BUFFER_SIZE + 1 {
+                    loom::future::block_on(px.send_async(i)).unwrap();
+                }
+            });
 
-    lazy_static! {
-        static ref FOO_SET: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+            for i in 0..BUFFER_SIZE + 1 {
+                assert_eq!(loom::future::block_on(cx.recv_async()).unwrap(), i);
+            }
+            handle.join().unwrap();
+        });
     }
 
-    #[derive(Debug)]
-    struct Foo(i32);
+    #[test]
+    fn async_mpsc_producers_are_all_woken() {
+        model(|| {
+            let (mut px, mut cx) = channel_mpsc(1);
+            px.send(0).unwrap();
+            // both wait for the slot the first recv frees, on the same queue
+            let handles: Vec<_> = [px.clone(), px]
+                .into_iter()
+                .zip(1..)
+                .map(|(mut px, i)| {
+                    thread::spawn(move || loom::future::block_on(px.send_async(i)).unwrap())
+                })
+                .collect();
 
-    impl Foo {
-        fn new(key: i32) -> Self {
-            assert!(
-                FOO_SET.lock().unwrap().insert(key),
-                "double initialisation of element {}",
-                key
-            );
-            Foo(key)
-        }
+            assert_eq!(cx.recv().unwrap(), 0);
+            let mut received = [cx.recv().unwrap(), cx.recv().unwrap()];
+            received.sort();
+            assert_eq!(received, [1, 2]);
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
     }
 
-    impl Drop for Foo {
-        fn drop(&mut self) {
-            assert!(
-                FOO_SET.lock().unwrap().remove(&self.0),
-                "double free of element {}",
-                self.0
-            );
-        }
+    #[test]
+    fn dropped_consumer_frees_buffered_messages() {
+        model(|| {
+            let (mut px, cx) = channel();
+            let tracker = Arc::new(());
+
+            let t = tracker.clone();
+            let handle = thread::spawn(move || {
+                let _ = px.send(t);
+            });
+
+            drop(cx);
+            handle.join().unwrap();
+
+            // the buffer is gone once both ends are, so must be its content
+            assert_eq!(Arc::strong_count(&tracker), 1);
+        });
     }
 
-    // range of elements to be moved across the channel during testing
-    const ELEMS: std::ops::Range<i32> = 0..1000;
+    #[test]
+    fn ends_dropped_at_once_free_messages_once() {
+        model(|| {
+            let (mut px, cx) = channel();
+            let tracker = Arc::new(());
+            px.send(tracker.clone()).unwrap();
+
+            // either end may be the last, and only that one drops the buffer
+            let handle = thread::spawn(move || drop(px));
+            drop(cx);
+            handle.join().unwrap();
+            assert_eq!(Arc::strong_count(&tracker), 1);
+        });
+    }
 
     #[test]
-    fn unused_elements_are_dropped() {
-        lazy_static::initialize(&FOO_SET);
+    fn try_calls_cross_empty_and_full() {
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(1);
 
-        for i in 0..100 {
-            println!("Thread {} ", i);
-            let (px, cx) = channel();
+            // no waiting at all, both sides only retry
             let handle = thread::spawn(move || {
-                for i in 0.. {
-                    if px.send(Foo::new(i)).is_err() {
-                        println!("AHHHHH on i: {}", i);
-                        return;
+                for i in 0..2 {
+                    let mut val = i;
+                    while let Err(TrySendError::Full(back)) = px.try_send(val) {
+                        val = back;
+                        thread::yield_now();
                     }
                 }
             });
 
-            for _ in 0..i {
-                cx.recv().unwrap();
+            for i in 0..2 {
+                loop {
+                    match cx.try_recv() {
+                        Ok(val) => {
+                            assert_eq!(val, i);
+                            break;
+                        }
+                        Err(TryRecvError::Empty) => thread::yield_now(),
+                        Err(TryRecvError::Disconnected) => panic!("lost a message"),
+                    }
+                }
             }
+            handle.join().unwrap();
+            assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+        });
+    }
 
-            drop(cx);
+    #[test]
+    fn indices_wrap_twice() {
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(2);
+            // overflow usize on the way, and go around the buffer twice
+            let state = &px.inner.state;
+            for index in [
+                &state.read_index,
+                &state.write_index,
+                &state.released_index,
+                &state.cached_released_index,
+                &state.cached_write_index,
+            ] {
+                index.store(usize::MAX - 1, Ordering::SeqCst);
+            }
 
-            assert!(handle.join().is_ok());
+            let handle = thread::spawn(move || {
+                for i in 0..5 {
+                    px.send(i).unwrap();
+                }
+            });
 
-            let map = FOO_SET.lock().unwrap();
-            if !map.is_empty() {
-                panic!("FOO_MAP not empty: {:?}", *map);
+            for i in 0..5 {
+                assert_eq!(cx.recv().unwrap(), i);
             }
-        }
+            assert!(cx.recv().is_err());
+            handle.join().unwrap();
+        });
     }
 
     #[test]
-    fn elements_arrive_ordered() {
-        let (px, cx) = channel();
+    fn drop_of_producer_ends_recv_after_last_message() {
+        model(|| {
+            let (mut px, mut cx) = channel();
 
-        thread::spawn(move || {
-            for i in ELEMS {
-                px.send(i).unwrap();
-            }
+            let handle = thread::spawn(move || {
+                px.send(1).unwrap();
+                drop(px);
+            });
+
+            // the disconnect must not overtake the message
+            assert_eq!(cx.recv(), Ok(1));
+            assert_eq!(cx.recv(), Err(RecvError));
+            handle.join().unwrap();
         });
+    }
 
-        for i in ELEMS {
-            assert_eq!(i, cx.recv().unwrap());
-        }
+    #[test]
+    fn message_hook_is_not_lost() {
+        use loom::sync::atomic::AtomicUsize;
 
-        assert!(cx.recv().is_err());
+        model(|| {
+            let (mut px, mut cx) = channel();
+            px.send(1).unwrap();
+            let runs = Arc::new(AtomicUsize::new(0));
+            let counter = runs.clone();
+            cx.set_notify(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+
+            let handle = thread::spawn(move || {
+                px.send(2).unwrap();
+                px
+            });
+
+            // emptying the buffer as the second message arrives: either we
+            // see it, or the producer saw the buffer empty and ran the hook
+            assert_eq!(cx.try_recv(), Ok(1));
+            let received = cx.try_recv().is_ok();
+            drop(handle.join().unwrap());
+            assert!(received || runs.load(Ordering::Relaxed) == 1);
+        });
     }
 
     #[test]
-    fn all_elements_arrive() {
-        for _ in 0..100 {
-            let (px, cx) = channel();
+    fn watermarks_end_low_on_a_drained_buffer() {
+        use loom::sync::atomic::AtomicBool;
+
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(2);
+            px.send(1).unwrap();
+            let paused = Arc::new(AtomicBool::new(false));
+            let (on_high, on_low) = (paused.clone(), paused.clone());
+            px.set_watermarks(
+                2,
+                0,
+                move || assert!(!on_high.swap(true, Ordering::Relaxed)),
+                move || assert!(on_low.swap(false, Ordering::Relaxed)),
+            );
+
             let handle = thread::spawn(move || {
-                let mut count = 0;
+                assert_eq!(cx.recv(), Ok(1));
+                assert_eq!(cx.recv(), Ok(2));
+                cx
+            });
 
-                while let Ok(_) = cx.recv() {
-                    count += 1;
-                }
+            // filling the buffer up to the high mark as it drains: the
+            // source must not stay paused
+            px.send(2).unwrap();
+            drop(handle.join().unwrap());
+            assert!(!paused.load(Ordering::Relaxed));
+        });
+    }
 
-                count
+    #[test]
+    fn space_hook_is_not_lost() {
+        use loom::sync::atomic::AtomicUsize;
+
+        model(|| {
+            let (mut px, mut cx) = channel_with_capacity(1);
+            px.send(1).unwrap();
+            let runs = Arc::new(AtomicUsize::new(0));
+            let counter = runs.clone();
+            px.set_notify(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
             });
 
-            thread::spawn(move || {
-                for i in ELEMS {
-                    px.send(i).unwrap();
-                }
+            let handle = thread::spawn(move || {
+                assert_eq!(cx.try_recv(), Ok(1));
+                cx
             });
 
-            match handle.join() {
-                Ok(count) => assert_eq!(count, ELEMS.len()),
-                Err(_) => panic!("Error: join() returned Err"),
-            }
-        }
+            let sent = px.try_send(2).is_ok();
+            drop(handle.join().unwrap());
+            assert!(sent || runs.load(Ordering::Relaxed) == 1);
+        });
+    }
+    #[test]
+    fn async_recv_returns_on_cancel() {
+        model(|| {
+            let (_px, mut cx) = channel_with_capacity::<i32>(1);
+            let token = CancelToken::new();
+            let canceller = token.clone();
+            let handle = thread::spawn(move || canceller.cancel());
+            assert_eq!(
+                loom::future::block_on(cx.recv_async_cancellable(&token)),
+                Err(RecvCancelError::Cancelled)
+            );
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn a_dropped_cancel_future_leaves_no_waker() {
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::Context;
+
+        model(|| {
+            let token = CancelToken::new();
+            let canceller = token.clone();
+            let handle = thread::spawn(move || canceller.cancel());
+            let mut cancelled = token.cancelled();
+            let waker = futures::task::noop_waker();
+            let _ = Pin::new(&mut cancelled).poll(&mut Context::from_waker(&waker));
+            // races the notify of the cancel for the cell
+            drop(cancelled);
+            handle.join().unwrap();
+            assert!(!token.waiters().has_waiters());
+        });
     }
 }
@@ -0,0 +1,240 @@
+//! A channel for code that runs on a single thread, e.g. two state machines
+//! of an event loop passing data along, or a generator and its caller.
+//!
+//! The ring is the same as that of the SPSC channel, but the indices are
+//! plain `Cell`s instead of atomics, and the endpoints share it through an
+//! `Rc`. Neither endpoint is `Send`, so both stay on the thread that created
+//! them, and `T` need not be `Send` either. With nobody to wait for on the
+//! same thread, there are only the calls that never wait.
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::mem::MaybeUninit;
+
+use crate::{index, ring, TryRecvError, TrySendError, BUFFER_SIZE};
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    read_index: Cell<usize>,
+    write_index: Cell<usize>,
+    producer_alive: Cell<bool>,
+    consumer_alive: Cell<bool>,
+}
+
+impl<T> Shared<T> {
+    fn len(&self) -> usize {
+        index::len(self.read_index.get(), self.write_index.get())
+    }
+
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        self.slots[index::slot(position, self.slots.len())].get()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    // The slots do not drop their content on their own
+    fn drop(&mut self) {
+        for position in index::range(self.read_index.get(), self.write_index.get()) {
+            unsafe { (*self.slot(position)).assume_init_drop() };
+        }
+    }
+}
+
+pub struct Producer<T> {
+    shared: Rc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Rc<Shared<T>>,
+}
+
+/// Creates a channel that buffers up to 4096 messages.
+pub fn channel<T>() -> (Producer<T>, Consumer<T>) {
+    channel_with_capacity(BUFFER_SIZE)
+}
+
+/// Like `channel`, with a buffer of `capacity` messages.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_capacity<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    ring::check_capacity(capacity);
+    let shared = Rc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        read_index: Cell::new(0),
+        write_index: Cell::new(0),
+        producer_alive: Cell::new(true),
+        consumer_alive: Cell::new(true),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Sends `val` if there is a free slot, otherwise hands it back.
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        if !shared.consumer_alive.get() {
+            return Err(TrySendError::Disconnected(val));
+        }
+        let write_index = shared.write_index.get();
+        if index::is_full(shared.read_index.get(), write_index, self.capacity()) {
+            return Err(TrySendError::Full(val));
+        }
+        // the slot is free, and nobody else can hold a reference into it
+        unsafe { (*shared.slot(write_index)).write(val) };
+        shared.write_index.set(index::advance(write_index, 1));
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Returns whether the consumer is gone, so every send fails.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.consumer_alive.get()
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Receives the oldest message, if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        let read_index = shared.read_index.get();
+        if index::is_empty(read_index, shared.write_index.get()) {
+            return Err(if shared.producer_alive.get() {
+                TryRecvError::Empty
+            } else {
+                TryRecvError::Disconnected
+            });
+        }
+        // written by try_send, and the index moves on before anything else
+        // can look at the slot
+        let val = unsafe { (*shared.slot(read_index)).assume_init_read() };
+        shared.read_index.set(index::advance(read_index, 1));
+        Ok(val)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the producer is gone, so no more messages arrive
+    /// than those queued.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.producer_alive.get()
+    }
+}
+
+// Ends once the buffer is empty, there is nothing to wait for
+impl<T> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.try_recv().ok()
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.set(false);
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.consumer_alive.set(false);
+    }
+}
+
+impl<T> fmt::Debug for Producer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Producer")
+            .field("capacity", &self.capacity())
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for Consumer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Consumer")
+            .field("capacity", &self.capacity())
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn messages_pass_in_order() {
+        let (px, cx) = channel_with_capacity(4);
+        for round in 0..3 {
+            for i in 0..4 {
+                px.try_send(round * 4 + i).unwrap();
+            }
+            assert_eq!(px.try_send(99), Err(TrySendError::Full(99)));
+            let received: Vec<_> = (0..4).map(|_| cx.try_recv().unwrap()).collect();
+            assert_eq!(received, [0, 1, 2, 3].map(|i| round * 4 + i));
+        }
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn messages_need_not_be_send() {
+        // an Rc can not go into the other channels
+        let token = Rc::new(());
+        let (px, cx) = channel_with_capacity(4);
+        px.try_send(token.clone()).unwrap();
+        px.try_send(token.clone()).unwrap();
+        assert_eq!(Rc::strong_count(&token), 3);
+        drop(px);
+        assert!(cx.is_disconnected());
+        assert!(cx.try_recv().is_ok());
+        // the one still queued is dropped with the consumer
+        drop(cx);
+        assert_eq!(Rc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn sends_fail_once_the_consumer_is_gone() {
+        let (px, cx) = channel::<i32>();
+        drop(cx);
+        assert!(px.is_disconnected());
+        assert_eq!(px.try_send(1), Err(TrySendError::Disconnected(1)));
+    }
+}
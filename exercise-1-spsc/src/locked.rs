@@ -0,0 +1,307 @@
+//! The textbook bounded buffer: a queue behind a mutex, with one condition
+//! variable for a consumer waiting on an empty buffer and one for a producer
+//! waiting on a full one.
+//!
+//! It has the same calls as the lock-free channel and behaves the same way,
+//! so it serves as the baseline the ring is measured against (see the
+//! streaming benchmark) and as an oracle to test it against. Every call
+//! takes the lock, which is exactly the cost the ring avoids.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{
+    ring, RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+    BUFFER_SIZE,
+};
+
+struct Shared<T> {
+    buffer: Mutex<Buffer<T>>,
+    capacity: usize,
+    // signalled by sends and by the producer going away
+    not_empty: Condvar,
+    // signalled by receives and by the consumer going away
+    not_full: Condvar,
+}
+
+struct Buffer<T> {
+    queue: VecDeque<T>,
+    producer_alive: bool,
+    consumer_alive: bool,
+}
+
+impl<T> Shared<T> {
+    // A panic while the lock was held can not have left the queue half
+    // changed, so a poisoned lock is as good as any
+    fn lock(&self) -> MutexGuard<'_, Buffer<T>> {
+        self.buffer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel that buffers up to 4096 messages.
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    channel_with_capacity(BUFFER_SIZE)
+}
+
+/// Like `channel`, with a buffer of `capacity` messages.
+///
+/// Panics if `capacity` is 0 or not a power of two, as the ring does.
+pub fn channel_with_capacity<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    ring::check_capacity(capacity);
+    let shared = Arc::new(Shared {
+        buffer: Mutex::new(Buffer {
+            queue: VecDeque::with_capacity(capacity),
+            producer_alive: true,
+            consumer_alive: true,
+        }),
+        capacity,
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Waits for a free slot and sends `val`, fails if the consumer is gone.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        self.send_until(val, None)
+            .map_err(|err| SendError(err.into_inner()))
+    }
+
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        let mut buffer = shared.lock();
+        if !buffer.consumer_alive {
+            return Err(TrySendError::Disconnected(val));
+        }
+        if buffer.queue.len() == shared.capacity {
+            return Err(TrySendError::Full(val));
+        }
+        buffer.queue.push_back(val);
+        drop(buffer);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn send_timeout(&self, val: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(val, Instant::now().checked_add(timeout))
+    }
+
+    fn send_until(&self, val: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let shared = &*self.shared;
+        let mut buffer = shared.lock();
+        loop {
+            if !buffer.consumer_alive {
+                return Err(SendTimeoutError::Disconnected(val));
+            }
+            if buffer.queue.len() < shared.capacity {
+                break;
+            }
+            buffer = match wait(&shared.not_full, buffer, deadline) {
+                Some(buffer) => buffer,
+                None => return Err(SendTimeoutError::Timeout(val)),
+            };
+        }
+        buffer.queue.push_back(val);
+        drop(buffer);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Returns whether the consumer is gone, so every send fails.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.lock().consumer_alive
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Waits for a message, fails once the producer is gone and the buffer
+    /// is drained.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        let mut buffer = shared.lock();
+        match buffer.queue.pop_front() {
+            Some(val) => {
+                drop(buffer);
+                shared.not_full.notify_one();
+                Ok(val)
+            }
+            None if buffer.producer_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let shared = &*self.shared;
+        let mut buffer = shared.lock();
+        loop {
+            if let Some(val) = buffer.queue.pop_front() {
+                drop(buffer);
+                shared.not_full.notify_one();
+                return Ok(val);
+            }
+            if !buffer.producer_alive {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            buffer = match wait(&shared.not_empty, buffer, deadline) {
+                Some(buffer) => buffer,
+                None => return Err(RecvTimeoutError::Timeout),
+            };
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the producer is gone, so no more messages arrive
+    /// than those queued.
+    pub fn is_disconnected(&self) -> bool {
+        !self.shared.lock().producer_alive
+    }
+}
+
+// Waits on condvar until notified, or returns None once the deadline has
+// passed. The caller checks its condition again either way.
+fn wait<'a, T>(
+    condvar: &Condvar,
+    buffer: MutexGuard<'a, Buffer<T>>,
+    deadline: Option<Instant>,
+) -> Option<MutexGuard<'a, Buffer<T>>> {
+    match deadline {
+        None => Some(condvar.wait(buffer).unwrap_or_else(PoisonError::into_inner)),
+        Some(deadline) => {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            let (buffer, _) = condvar
+                .wait_timeout(buffer, timeout)
+                .unwrap_or_else(PoisonError::into_inner);
+            Some(buffer)
+        }
+    }
+}
+
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.lock().producer_alive = false;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.lock().consumer_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // Runs the same calls on both channels, from one thread so the outcome
+    // is deterministic, and checks they agree on every one
+    #[test]
+    fn ring_behaves_like_the_bounded_buffer() {
+        let (mut px, mut cx) = crate::channel_with_capacity(8);
+        let (oracle_px, oracle_cx) = channel_with_capacity(8);
+        // xorshift, so a failure is reproducible
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for i in 0..10_000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            match seed % 3 {
+                0 => assert_eq!(px.try_send(i), oracle_px.try_send(i)),
+                1 => assert_eq!(cx.try_recv(), oracle_cx.try_recv()),
+                _ => assert_eq!(cx.len(), oracle_cx.len()),
+            }
+        }
+        drop((px, oracle_px));
+        assert_eq!(cx.collect::<Vec<_>>(), oracle_cx.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn messages_pass_between_threads() {
+        let (px, cx) = channel_with_capacity(4);
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                px.send(i).unwrap();
+            }
+        });
+        assert_eq!(cx.collect::<Vec<_>>(), (0..1000).collect::<Vec<_>>());
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn timeouts_and_disconnects() {
+        let (px, cx) = channel_with_capacity(1);
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        px.send(1).unwrap();
+        assert_eq!(
+            px.send_timeout(2, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(2))
+        );
+        drop(cx);
+        assert!(px.is_disconnected());
+        assert_eq!(px.send(3), Err(SendError(3)));
+    }
+}
@@ -1,15 +1,272 @@
+// Stress test and benchmark: streams messages from a producer through a
+// chain of forwarding stages to a consumer and reports what arrived, how
+// fast, and how long the messages took on the way.
+//
+//     cargo run --release --features cli -- --help
+
+use std::process;
 use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use spsc::*;
 
-fn main() {
-	let (px, cx) = channel();
-	
-	thread::spawn(move || {
-		px.send("Ping").unwrap();
-		px.send("Ping").unwrap();
+// every this many messages carries its latency into the percentiles
+const SAMPLE_EVERY: u64 = 64;
+
+const PERCENTILES: [f64; 4] = [50.0, 99.0, 99.9, 99.99];
+
+struct Message {
+	seq: u64,
+	sent: Instant,
+	// allocated by the producer, so a larger payload costs what it would in
+	// a real program
+	_payload: Vec<u8>,
+}
+
+// A snapshot of the counters of a channel, when there are any
+#[cfg(feature = "stats")]
+type Stats = ChannelStats;
+#[cfg(not(feature = "stats"))]
+type Stats = ();
+
+#[derive(Clone)]
+struct Config {
+	messages: u64,
+	payload: usize,
+	capacity: usize,
+	wait_strategy: WaitStrategy,
+	stages: usize,
+	duration: Option<Duration>,
+	// the cores of the producer, the stages and the consumer
+	pin: Option<Vec<usize>>,
+}
+
+fn command() -> Command {
+	Command::new("spsc")
+		.about("Streams messages through a chain of SPSC channels and reports throughput and latency")
+		.arg(Arg::new("messages")
+			.short('n')
+			.long("messages")
+			.help("Messages to send")
+			.value_parser(value_parser!(u64))
+			.default_value("10000000"))
+		.arg(Arg::new("payload")
+			.short('p')
+			.long("payload")
+			.help("Bytes of payload per message, 0 for none")
+			.value_parser(value_parser!(usize))
+			.default_value("0"))
+		.arg(Arg::new("capacity")
+			.short('c')
+			.long("capacity")
+			.help("Capacity of each channel, a power of two")
+			.value_parser(value_parser!(usize))
+			.default_value("4096"))
+		.arg(Arg::new("wait-strategy")
+			.short('w')
+			.long("wait-strategy")
+			.help("How blocked calls wait")
+			.value_parser(["park", "busy-spin", "yield", "backoff"])
+			.default_value("park"))
+		.arg(Arg::new("stages")
+			.short('s')
+			.long("stages")
+			.help("Forwarding threads between the producer and the consumer")
+			.value_parser(value_parser!(usize))
+			.default_value("0"))
+		.arg(Arg::new("duration")
+			.short('d')
+			.long("duration")
+			.help("Stop sending after this many seconds, even if not all messages are sent")
+			.value_parser(value_parser!(f64))
+			.action(ArgAction::Set))
+		.arg(Arg::new("pin")
+			.long("pin")
+			.help("Cores to pin the producer, each stage and the consumer to, in that order, e.g. 0,2")
+			.value_parser(value_parser!(usize))
+			.value_delimiter(',')
+			.num_args(1..))
+}
+
+fn config(matches: &ArgMatches) -> Config {
+	let capacity = *matches.get_one::<usize>("capacity").unwrap();
+	if !capacity.is_power_of_two() {
+		command().error(clap::error::ErrorKind::InvalidValue, "the capacity must be a power of two").exit();
+	}
+	let wait_strategy = match matches.get_one::<String>("wait-strategy").unwrap().as_str() {
+		"busy-spin" => WaitStrategy::BusySpin,
+		"yield" => WaitStrategy::Yield,
+		"backoff" => WaitStrategy::Backoff,
+		_ => WaitStrategy::Park,
+	};
+	let duration = matches.get_one::<f64>("duration").map(|&secs| {
+		Duration::try_from_secs_f64(secs).unwrap_or_else(|_| {
+			command().error(clap::error::ErrorKind::InvalidValue, "the duration must be a number of seconds").exit()
+		})
 	});
-	
-	println!("recv: {}", cx.recv().unwrap());
-	println!("recv: {}", cx.recv().unwrap());
-	assert!(cx.recv().is_err());
+	let stages = *matches.get_one::<usize>("stages").unwrap();
+	let pin = matches.get_many::<usize>("pin").map(|cores| cores.copied().collect::<Vec<_>>());
+	if pin.as_ref().is_some_and(|pin| pin.len() != stages + 2) {
+		command().error(clap::error::ErrorKind::WrongNumberOfValues, format!("pin takes {} cores, one per thread", stages + 2)).exit();
+	}
+	Config {
+		messages: *matches.get_one("messages").unwrap(),
+		payload: *matches.get_one("payload").unwrap(),
+		capacity,
+		wait_strategy,
+		stages,
+		duration,
+		pin,
+	}
+}
+
+// What the consumer saw
+struct Report {
+	received: u64,
+	out_of_order: u64,
+	latencies: Vec<u64>,
+	stats: Stats,
+}
+
+// Taken once the consumer ran out of messages, so both sides are done
+#[cfg(feature = "stats")]
+fn stats(cx: &Consumer<Message>) -> Stats {
+	cx.stats()
+}
+
+#[cfg(not(feature = "stats"))]
+fn stats(_cx: &Consumer<Message>) -> Stats {}
+
+#[cfg(feature = "stats")]
+fn print_stats(stats: &[Stats]) {
+	for (i, stats) in stats.iter().enumerate() {
+		println!("channel {}: {} full stalls ({:?}), {} empty stalls ({:?}), high water mark {}",
+			i, stats.full_stalls, stats.full_wait, stats.empty_stalls, stats.empty_wait, stats.high_water_mark);
+	}
+}
+
+#[cfg(not(feature = "stats"))]
+fn print_stats(_stats: &[Stats]) {}
+
+fn consume(mut cx: Consumer<Message>) -> Report {
+	let mut report = Report {
+		received: 0,
+		out_of_order: 0,
+		latencies: Vec::new(),
+		stats: Stats::default(),
+	};
+	let mut expected = 0;
+	while let Ok(msg) = cx.recv() {
+		if msg.seq % SAMPLE_EVERY == 0 {
+			report.latencies.push(msg.sent.elapsed().as_nanos() as u64);
+		}
+		if msg.seq != expected {
+			report.out_of_order += 1;
+		}
+		expected = msg.seq + 1;
+		report.received += 1;
+	}
+	report.stats = stats(&cx);
+	report
+}
+
+// Pins the calling thread to the `thread`th core of `--pin`, if given
+fn pin(config: &Config, thread: usize) {
+	if let Some(core) = config.pin.as_ref().map(|pin| pin[thread]) {
+		if let Err(err) = affinity::pin_current(core) {
+			eprintln!("error: can not pin to core {}: {}", core, err);
+			process::exit(1);
+		}
+	}
+}
+
+fn run(config: &Config) {
+	let channel = || channel_with_strategy::<Message>(config.capacity, config.wait_strategy);
+	let (mut px, mut cx) = channel();
+
+	let mut forwarders = Vec::new();
+	for stage in 0 .. config.stages {
+		let (mut next_px, next_cx) = channel();
+		let mut input = cx;
+		let config = config.clone();
+		forwarders.push(thread::spawn(move || {
+			pin(&config, stage + 1);
+			while let Ok(msg) = input.recv() {
+				if next_px.send(msg).is_err() {
+					break;
+				}
+			}
+			stats(&input)
+		}));
+		cx = next_cx;
+	}
+
+	let (messages, payload, duration) = (config.messages, config.payload, config.duration);
+	pin(config, config.stages + 1);
+	let start = Instant::now();
+	let producer_config = config.clone();
+	let producer = thread::spawn(move || {
+		pin(&producer_config, 0);
+		let mut sent = 0;
+		while sent < messages {
+			// checking the clock on every message would slow down the sends
+			if sent % 1024 == 0 && duration.is_some_and(|duration| start.elapsed() >= duration) {
+				break;
+			}
+			let msg = Message {
+				seq: sent,
+				sent: Instant::now(),
+				_payload: vec![0; payload],
+			};
+			if px.send(msg).is_err() {
+				break;
+			}
+			sent += 1;
+		}
+		sent
+	});
+	let mut report = consume(cx);
+	let elapsed = start.elapsed();
+
+	let sent = producer.join().unwrap();
+	let mut stats: Vec<Stats> = forwarders.into_iter().map(|forwarder| forwarder.join().unwrap()).collect();
+	stats.push(report.stats);
+
+	let secs = elapsed.as_secs_f64();
+	println!("sent        {}", sent);
+	println!("received    {}", report.received);
+	println!("lost        {}", sent - report.received);
+	println!("reordered   {}", report.out_of_order);
+	println!("elapsed     {:.3} s", secs);
+	println!("throughput  {:.0} messages/s, {:.1} MiB/s of payload",
+		report.received as f64 / secs,
+		(report.received * payload as u64) as f64 / secs / (1 << 20) as f64);
+
+	if !report.latencies.is_empty() {
+		report.latencies.sort_unstable();
+		println!("latency, 1 in {} messages", SAMPLE_EVERY);
+		for p in PERCENTILES {
+			println!("  {:>7} {:>12} ns", format!("p{}", p), percentile(&report.latencies, p));
+		}
+		println!("  {:>7} {:>12} ns", "max", report.latencies[report.latencies.len() - 1]);
+	}
+
+	print_stats(&stats);
+
+	if sent != report.received || report.out_of_order != 0 {
+		process::exit(1);
+	}
+}
+
+// The sample at or below which `percentile` percent of them are, by nearest
+// rank
+fn percentile(sorted: &[u64], percentile: f64) -> u64 {
+	let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+	sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn main() {
+	let config = config(&command().get_matches());
+	run(&config);
 }
@@ -0,0 +1,276 @@
+//! Where the buffer of a channel lives, see `channel_with_memory`.
+//!
+//! On machines with more than one NUMA node, a buffer on the node of the
+//! consumer (or of both threads) saves every message a trip across the
+//! interconnect, and a large buffer on huge pages takes fewer TLB entries.
+//! Both are requests rather than promises: a node that does not exist, or
+//! no huge pages being reserved, leaves the buffer on the node the kernel
+//! picks, in regular pages. On other systems than Linux the options make no
+//! difference, the buffer is allocated on the heap as usual.
+
+#[cfg(target_os = "linux")]
+use core::mem::{self, MaybeUninit};
+#[cfg(target_os = "linux")]
+use core::ops::Deref;
+#[cfg(target_os = "linux")]
+use core::ptr::{self, NonNull};
+#[cfg(target_os = "linux")]
+use core::slice;
+
+#[cfg(target_os = "linux")]
+use crate::primitives::UnsafeCell;
+#[cfg(target_os = "linux")]
+use crate::ring::Slot;
+
+/// The NUMA node(s) the pages of a buffer come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Node {
+    /// Whichever the kernel picks, usually the node of the thread that
+    /// first touches the page.
+    #[default]
+    Any,
+    /// Only this node.
+    Bind(usize),
+    /// Every node in turn, page by page.
+    Interleave,
+}
+
+/// How the buffer of a channel is allocated. The default takes regular
+/// pages from whichever node the kernel picks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Memory {
+    node: Node,
+    huge_pages: bool,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the pages from `node`, see `Node`.
+    pub fn node(self, node: Node) -> Self {
+        Memory { node, ..self }
+    }
+
+    /// Backs the buffer with huge pages, 2 MiB each on most systems. Falls
+    /// back to asking for transparent huge pages if none are reserved, and
+    /// to regular pages if those are off as well. A buffer much smaller
+    /// than a huge page only wastes the rest of it.
+    pub fn huge_pages(self) -> Self {
+        Memory {
+            huge_pages: true,
+            ..self
+        }
+    }
+}
+
+// The size of a huge page, unless the kernel says otherwise
+#[cfg(target_os = "linux")]
+const HUGE_PAGE: usize = 2 << 20;
+// Bits of the node mask passed to mbind, as many as the kernel supports
+#[cfg(target_os = "linux")]
+const MAX_NODES: usize = 1024;
+
+// The slots of a buffer in pages of their own, unmapped when dropped
+#[cfg(target_os = "linux")]
+pub(crate) struct Mapping<T> {
+    slots: NonNull<Slot<T>>,
+    capacity: usize,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Mapping<T> {
+    // None if the slots can not be mapped, and belong on the heap
+    pub(crate) fn new(capacity: usize, memory: Memory) -> Option<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = capacity.checked_mul(mem::size_of::<Slot<T>>())?;
+        // a zero-sized T needs no memory, and the pages are only page
+        // aligned
+        if size == 0 || mem::align_of::<Slot<T>>() > page {
+            return None;
+        }
+
+        let mapping = memory
+            .huge_pages
+            .then(|| {
+                Self::map(
+                    capacity,
+                    size.next_multiple_of(huge_page_size()),
+                    libc::MAP_HUGETLB,
+                )
+            })
+            .flatten()
+            .or_else(|| Self::map(capacity, size.next_multiple_of(page), 0))?;
+        if memory.huge_pages {
+            // asks for transparent huge pages instead if there were no
+            // reserved ones, and does nothing otherwise
+            unsafe {
+                libc::madvise(
+                    mapping.slots.as_ptr().cast(),
+                    mapping.len,
+                    libc::MADV_HUGEPAGE,
+                )
+            };
+        }
+        mapping.bind(memory.node);
+
+        // Only the cells need initializing, the messages in them do not
+        for i in 0..capacity {
+            unsafe {
+                mapping
+                    .slots
+                    .as_ptr()
+                    .add(i)
+                    .write(UnsafeCell::new(MaybeUninit::uninit()))
+            };
+        }
+        Some(mapping)
+    }
+
+    fn map(capacity: usize, len: usize, flags: libc::c_int) -> Option<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Mapping {
+            slots: NonNull::new(addr.cast())?,
+            capacity,
+            len,
+        })
+    }
+
+    // Sets the NUMA policy before the pages are touched, so they are
+    // allocated by it. A failure, e.g. a node that does not exist, keeps
+    // the default policy.
+    fn bind(&self, node: Node) {
+        let mut mask = [0 as libc::c_ulong; MAX_NODES / libc::c_ulong::BITS as usize];
+        let mode = match node {
+            Node::Any => return,
+            Node::Bind(node) if node >= MAX_NODES => return,
+            Node::Bind(node) => {
+                let bits = libc::c_ulong::BITS as usize;
+                mask[node / bits] |= 1 << (node % bits);
+                libc::MPOL_BIND
+            }
+            Node::Interleave => {
+                // the kernel leaves out the nodes that do not exist
+                mask.fill(libc::c_ulong::MAX);
+                libc::MPOL_INTERLEAVE
+            }
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.slots.as_ptr(),
+                self.len,
+                mode,
+                mask.as_ptr(),
+                MAX_NODES,
+                0,
+            )
+        };
+    }
+}
+
+// Owns the slots like the Box of a heap buffer would
+#[cfg(target_os = "linux")]
+unsafe impl<T: Send> Send for Mapping<T> {}
+
+#[cfg(target_os = "linux")]
+impl<T> Deref for Mapping<T> {
+    type Target = [Slot<T>];
+
+    fn deref(&self) -> &[Slot<T>] {
+        unsafe { slice::from_raw_parts(self.slots.as_ptr(), self.capacity) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Drop for Mapping<T> {
+    // The messages still queued are dropped by Inner, this only unmaps
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.slots.as_ptr().cast(), self.len) };
+    }
+}
+
+// The size the kernel reserves huge pages in by default
+#[cfg(target_os = "linux")]
+fn huge_page_size() -> usize {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            let line = meminfo
+                .lines()
+                .find(|line| line.starts_with("Hugepagesize:"))?;
+            let kib = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+            Some(kib << 10)
+        })
+        .unwrap_or(HUGE_PAGE)
+}
+
+// Miri does not emulate mbind or madvise
+#[cfg(all(test, target_os = "linux", not(miri)))]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::{channel_with_memory, TryRecvError};
+
+    use super::*;
+
+    #[test]
+    fn channel_on_mapped_pages() {
+        for memory in [
+            Memory::new().node(Node::Bind(0)),
+            Memory::new().node(Node::Interleave),
+            Memory::new().huge_pages(),
+            // no such node, so the kernel picks one
+            Memory::new().node(Node::Bind(MAX_NODES - 1)),
+        ] {
+            let (mut px, mut cx) = channel_with_memory(64, memory);
+            let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            assert_eq!(px.inner.message_buffer.as_ptr() as usize % page, 0);
+            assert_eq!(px.capacity(), 64);
+
+            let handle = thread::spawn(move || {
+                for i in 0..1000u64 {
+                    px.send(i).unwrap();
+                }
+            });
+            for i in 0..1000 {
+                assert_eq!(cx.recv(), Ok(i));
+            }
+            handle.join().unwrap();
+            assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+        }
+    }
+
+    #[test]
+    fn queued_messages_are_dropped_before_unmapping() {
+        let token = Arc::new(());
+        let (mut px, cx) = channel_with_memory(4, Memory::new().huge_pages());
+        px.send(token.clone()).unwrap();
+        px.send(token.clone()).unwrap();
+        drop((px, cx));
+        assert_eq!(Arc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn zero_sized_messages_stay_on_the_heap() {
+        let (mut px, mut cx) = channel_with_memory(4, Memory::new().huge_pages());
+        assert!(matches!(px.inner.message_buffer, crate::Buffer::Heap(_)));
+        px.send(()).unwrap();
+        assert_eq!(cx.try_recv(), Ok(()));
+    }
+}
@@ -0,0 +1,156 @@
+// Fan-in: a single receiving end over the consumers of several channels.
+// Each source keeps its own order, between the sources the messages arrive
+// as they come. A source whose channel is disconnected and drained is
+// dropped, and the merged end is disconnected once all of them are.
+
+use std::time::{Duration, Instant};
+
+use crate::select::Select;
+use crate::{Consumer, RecvError, RecvTimeoutError, TryRecvError};
+
+pub struct Merged<T> {
+    sources: Vec<Consumer<T>>,
+    // where try_recv looks first, so a busy source does not starve the
+    // others
+    next: usize,
+}
+
+/// Merges the consumers of several channels into one, see `Merged`.
+pub fn merge<T: Send>(sources: Vec<Consumer<T>>) -> Merged<T> {
+    Merged { sources, next: 0 }
+}
+
+impl<T: Send> Merged<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut unchecked = self.sources.len();
+        while unchecked > 0 {
+            let index = self.next % self.sources.len();
+            match self.sources[index].try_recv() {
+                Ok(val) => {
+                    self.next = index + 1;
+                    return Ok(val);
+                }
+                Err(TryRecvError::Empty) => self.next = index + 1,
+                // done for good, the next source takes its index
+                Err(TryRecvError::Disconnected) => {
+                    self.sources.remove(index);
+                    self.next = index;
+                }
+            }
+            unchecked -= 1;
+        }
+        Err(if self.sources.is_empty() {
+            TryRecvError::Disconnected
+        } else {
+            TryRecvError::Empty
+        })
+    }
+
+    /// Waits for a message from any source. Fails once every source is
+    /// disconnected and drained.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    /// Like `recv`, but waits at most for `timeout`. A timeout too large to
+    /// represent as an Instant is as good as none.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let mut select = Select::new();
+            for source in &mut self.sources {
+                select.recv(source);
+            }
+            let ready = match deadline {
+                None => Some(select.ready()),
+                Some(deadline) => select.ready_deadline(deadline),
+            };
+            if ready.is_none() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// Returns the number of sources that are not yet disconnected and
+    /// drained, as far as the receives so far have found.
+    pub fn sources(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+impl<T: Send> Iterator for Merged<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::channel_with_capacity;
+
+    #[test]
+    fn merged_receives_from_all_sources() {
+        let mut handles = Vec::new();
+        let mut consumers = Vec::new();
+        for source in 0..3 {
+            let (mut px, cx) = channel_with_capacity(4);
+            consumers.push(cx);
+            handles.push(thread::spawn(move || {
+                for i in 0..100 {
+                    px.send((source, i)).unwrap();
+                }
+            }));
+        }
+
+        let mut merged = merge(consumers);
+        let mut last = [None; 3];
+        let mut count = 0;
+        for (source, i) in &mut merged {
+            // each source keeps its order
+            assert!(last[source] < Some(i));
+            last[source] = Some(i);
+            count += 1;
+        }
+        assert_eq!(count, 300);
+        assert_eq!(merged.sources(), 0);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn disconnected_source_is_dropped() {
+        let (mut px1, cx1) = channel_with_capacity(4);
+        let (mut px2, cx2) = channel_with_capacity(4);
+        let mut merged = merge(vec![cx1, cx2]);
+        px1.send(1).unwrap();
+        drop(px1);
+        assert_eq!(merged.try_recv(), Ok(1));
+        assert_eq!(merged.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(merged.sources(), 1);
+        assert_eq!(
+            merged.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        px2.send(2).unwrap();
+        assert_eq!(merged.recv_timeout(Duration::MAX), Ok(2));
+        px2.send(2).unwrap();
+        drop(px2);
+        assert_eq!(merged.recv(), Ok(2));
+        assert_eq!(merged.recv(), Err(RecvError));
+    }
+}
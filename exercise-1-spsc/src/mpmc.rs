@@ -0,0 +1,356 @@
+//! A bounded channel for any number of producers and consumers.
+//!
+//! The SPSC ring can get away with one index per side, because each index
+//! has a single writer. Here several threads race for the same index, so
+//! every slot carries a sequence number as well (after Dmitry Vyukov's
+//! bounded MPMC queue). A sender claims the slot at the write index with a
+//! CAS once its sequence says the slot is free for this lap, and bumps the
+//! sequence when the message is in. A receiver does the same the other way
+//! round, and leaves the sequence at the position of the next lap.
+
+use std::mem::MaybeUninit;
+
+use crate::index;
+use crate::primitives::{Arc, AtomicUsize, CachePadded, Ordering, UnsafeCell};
+use crate::ring;
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError, TryRecvError, TrySendError};
+
+struct Slot<T> {
+    // position == sequence: free for the sender of that position,
+    // position + 1 == sequence: holds the message of that position
+    sequence: AtomicUsize,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Shared<T> {
+    slots: Box<[Slot<T>]>,
+    write_index: CachePadded<AtomicUsize>,
+    read_index: CachePadded<AtomicUsize>,
+    producer_counter: AtomicUsize,
+    consumer_counter: AtomicUsize,
+    // as in the SPSC channel: consumers wait for a message, producers for a
+    // free slot, either for the other side to disconnect
+    consumers: WaitQueue,
+    producers: WaitQueue,
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Nobody is left to race with, every claimed slot is complete
+        let write_index = self.write_index.load(Ordering::Relaxed);
+        let read_index = self.read_index.load(Ordering::Relaxed);
+        for position in index::range(read_index, write_index) {
+            let slot = &self.slots[index::slot(position, self.slots.len())];
+            slot.val
+                .with_mut(|val| unsafe { (*val).assume_init_drop() });
+        }
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel with a buffer of `capacity` messages. Both ends can be
+/// cloned, and the channel disconnects once all of one side are gone.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    ring::check_capacity(capacity);
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|position| Slot {
+                sequence: AtomicUsize::new(position),
+                val: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect(),
+        write_index: CachePadded::new(AtomicUsize::new(0)),
+        read_index: CachePadded::new(AtomicUsize::new(0)),
+        producer_counter: AtomicUsize::new(1),
+        consumer_counter: AtomicUsize::new(1),
+        consumers: WaitQueue::new(),
+        producers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Shared<T> {
+    fn slot(&self, position: usize) -> &Slot<T> {
+        &self.slots[index::slot(position, self.slots.len())]
+    }
+
+    // How far the sequence of the slot at position is from the one we are
+    // looking for: 0 means ours, negative that it is a lap behind, positive
+    // that another thread took position in the meantime
+    fn lag(&self, position: usize, expected: usize) -> isize {
+        let sequence = self.slot(position).sequence.load(Ordering::Acquire);
+        sequence.wrapping_sub(expected) as isize
+    }
+
+    // Whether a send may succeed now, for the waiting producers
+    fn slot_ready(&self) -> bool {
+        let position = self.write_index.load(Ordering::Relaxed);
+        self.lag(position, position) >= 0 || self.consumer_counter.load(Ordering::Acquire) == 0
+    }
+
+    // Whether a receive may succeed now, for the waiting consumers
+    fn message_ready(&self) -> bool {
+        let position = self.read_index.load(Ordering::Relaxed);
+        self.lag(position, index::advance(position, 1)) >= 0
+            || self.producer_counter.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T: Send> Producer<T> {
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        if shared.consumer_counter.load(Ordering::Acquire) == 0 {
+            return Err(TrySendError::Disconnected(val));
+        }
+        let mut position = shared.write_index.load(Ordering::Relaxed);
+        loop {
+            match shared.lag(position, position) {
+                0 => {
+                    let next = index::advance(position, 1);
+                    match shared.write_index.compare_exchange_weak(
+                        position,
+                        next,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            let slot = shared.slot(position);
+                            slot.val.with_mut(|slot| unsafe { (*slot).write(val) });
+                            // Release the message along with the sequence
+                            slot.sequence.store(next, Ordering::Release);
+                            shared.consumers.notify();
+                            return Ok(());
+                        }
+                        Err(current) => position = current,
+                    }
+                }
+                // the consumers have not freed this slot yet
+                lag if lag < 0 => return Err(TrySendError::Full(val)),
+                _ => position = shared.write_index.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Waits for a free slot, fails once all consumers are gone.
+    pub fn send(&self, mut val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_send(val) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(val)) => return Err(SendError(val)),
+                Err(TrySendError::Full(back)) => {
+                    val = back;
+                    shared.producers.wait(None, || shared.slot_ready());
+                }
+            }
+        }
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        // Before looking at the slots: once the producers are gone, all
+        // their messages are in
+        let disconnected = shared.producer_counter.load(Ordering::Acquire) == 0;
+        let mut position = shared.read_index.load(Ordering::Relaxed);
+        loop {
+            let next = index::advance(position, 1);
+            match shared.lag(position, next) {
+                0 => match shared.read_index.compare_exchange_weak(
+                    position,
+                    next,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let slot = shared.slot(position);
+                        let val = slot
+                            .val
+                            .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+                        // free for the sender one lap later
+                        slot.sequence.store(
+                            index::advance(position, shared.slots.len()),
+                            Ordering::Release,
+                        );
+                        shared.producers.notify();
+                        return Ok(val);
+                    }
+                    Err(current) => position = current,
+                },
+                lag if lag < 0 => {
+                    return Err(if disconnected {
+                        TryRecvError::Disconnected
+                    } else {
+                        TryRecvError::Empty
+                    })
+                }
+                _ => position = shared.read_index.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Waits for a message, fails once all producers are gone and the
+    /// buffer is drained.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => shared.consumers.wait(None, || shared.message_ready()),
+            }
+        }
+    }
+}
+
+impl<T> Clone for Producer<T> {
+    fn clone(&self) -> Self {
+        self.shared.producer_counter.fetch_add(1, Ordering::Relaxed);
+        Producer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Consumer<T> {
+    fn clone(&self) -> Self {
+        self.shared.consumer_counter.fetch_add(1, Ordering::Relaxed);
+        Consumer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_counter.fetch_sub(1, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.consumer_counter.fetch_sub(1, Ordering::Release);
+        self.shared.producers.notify();
+    }
+}
+
+// The slots are only reached through the sequence protocol above
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Sync for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+unsafe impl<T: Send> Sync for Consumer<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn every_message_arrives_once() {
+        let (px, cx) = channel(8);
+        let producers: Vec<_> = (0..3)
+            .map(|id| {
+                let px = px.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        px.send(id * 1000 + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(px);
+        let consumers: Vec<_> = (0..3)
+            .map(|_| {
+                let cx = cx.clone();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    while let Ok(val) = cx.recv() {
+                        received.push(val);
+                    }
+                    received
+                })
+            })
+            .collect();
+        drop(cx);
+
+        for handle in producers {
+            handle.join().unwrap();
+        }
+        let mut received: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        received.sort();
+        assert_eq!(received, (0..3000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_and_empty_are_reported() {
+        let (px, cx) = channel(2);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        px.try_send(1).unwrap();
+        px.try_send(2).unwrap();
+        assert_eq!(px.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(cx.try_recv(), Ok(1));
+        px.try_send(3).unwrap();
+
+        drop(px);
+        assert_eq!(cx.try_recv(), Ok(2));
+        assert_eq!(cx.try_recv(), Ok(3));
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn queued_messages_are_dropped_with_channel() {
+        let tracker = std::sync::Arc::new(());
+        let (px, cx) = channel(4);
+        px.send(tracker.clone()).unwrap();
+        px.send(tracker.clone()).unwrap();
+        drop(cx.recv().unwrap());
+        drop((px, cx));
+        assert_eq!(std::sync::Arc::strong_count(&tracker), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn racing_producers_and_consumers() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(2);
+        builder.check(|| {
+            let (px, cx) = channel(2);
+            let px2 = px.clone();
+            let cx2 = cx.clone();
+            let producer = thread::spawn(move || px2.send(1).unwrap());
+            let consumer = thread::spawn(move || cx2.recv().unwrap());
+            px.send(2).unwrap();
+            let mine = cx.recv().unwrap();
+            producer.join().unwrap();
+            let theirs = consumer.join().unwrap();
+            assert_eq!(mine + theirs, 3);
+        });
+    }
+}
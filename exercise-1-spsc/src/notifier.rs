@@ -0,0 +1,174 @@
+// A file descriptor that becomes readable when a message arrives in an empty
+// channel, so the consumer can wait for it in a poll/epoll/mio loop along
+// with sockets. An eventfd on Linux and Android, a pipe elsewhere.
+//
+// The producer only writes to it when its send makes the buffer non-empty
+// (and once when the channel disconnects), checked after the fence of
+// WaitQueue::notify. The consumer takes its messages behind the fence of
+// the other notify, so either the producer sees that the buffer was empty
+// or the consumer sees the message: none is left without a signal.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Readiness of a `Consumer` as a file descriptor, see
+/// `Consumer::notifier`.
+///
+/// Once the descriptor is readable, call `clear` and then receive with
+/// `try_recv` until it reports `Empty` (or `Disconnected`). A message that
+/// arrives after `clear` makes it readable again. The descriptor stays the
+/// same for the life of the channel, so a poll loop can keep it and borrow
+/// the notifier again for `clear` in between receives.
+#[derive(Debug)]
+pub struct Notifier {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl Notifier {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        Ok(Notifier {
+            read_fd: fd,
+            write_fd: fd,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let notifier = Notifier {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        };
+        for fd in fds {
+            unsafe {
+                cvt(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+                cvt(libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK))?;
+            }
+        }
+        Ok(notifier)
+    }
+
+    // Makes the descriptor readable. Both an eventfd at its limit and a full
+    // pipe are readable already, so a failed write loses nothing.
+    pub(crate) fn signal(&self) {
+        let one: u64 = 1;
+        // an eventfd takes exactly eight bytes, a pipe any
+        unsafe { libc::write(self.write_fd, (&one as *const u64).cast(), 8) };
+    }
+
+    /// Takes back the signals so far, so the descriptor is readable again
+    /// only once another message arrives.
+    pub fn clear(&self) {
+        let mut buf = [0u8; 64];
+        // an eventfd resets with one read, a pipe until it runs empty
+        while unsafe { libc::read(self.read_fd, buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+    }
+}
+
+impl AsRawFd for Notifier {
+    /// The descriptor to poll for readability. It belongs to the channel
+    /// and is closed with it.
+    fn as_raw_fd(&self) -> RawFd {
+        self.read_fd
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.read_fd) };
+        if self.write_fd != self.read_fd {
+            unsafe { libc::close(self.write_fd) };
+        }
+    }
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Miri does not emulate eventfd and poll
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use std::thread;
+
+    use crate::{channel_with_capacity, TryRecvError};
+
+    use super::*;
+
+    // Whether the descriptor is readable, without waiting
+    fn readable(fd: RawFd) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    // Waits until it is
+    fn wait_readable(fd: RawFd) {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, -1) }, 1);
+    }
+
+    #[test]
+    fn readable_on_first_message_only() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let notifier = cx.notifier().unwrap();
+        let fd = notifier.as_raw_fd();
+        // readable at first, there may have been messages before
+        assert!(readable(fd));
+        notifier.clear();
+        assert!(!readable(fd));
+
+        px.send(1).unwrap();
+        assert!(readable(fd));
+        notifier.clear();
+        // not empty before, so no signal
+        px.send(2).unwrap();
+        assert!(!readable(fd));
+        assert_eq!(cx.try_recv(), Ok(1));
+        assert_eq!(cx.try_recv(), Ok(2));
+
+        drop(px);
+        assert!(readable(fd));
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn poll_loop_gets_every_message() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let fd = cx.notifier().unwrap().as_raw_fd();
+        let handle = thread::spawn(move || {
+            for i in 0..10_000 {
+                px.send(i).unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        'poll: loop {
+            wait_readable(fd);
+            cx.notifier().unwrap().clear();
+            loop {
+                match cx.try_recv() {
+                    Ok(i) => received.push(i),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => break 'poll,
+                }
+            }
+        }
+        handle.join().unwrap();
+        assert_eq!(received, (0..10_000).collect::<Vec<_>>());
+    }
+}
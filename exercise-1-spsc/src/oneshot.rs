@@ -0,0 +1,222 @@
+//! A channel for exactly one message, e.g. the result of a spawned thread.
+//!
+//! Instead of a ring there is a single slot and a state that moves from
+//! EMPTY to SENT and on to TAKEN, or to CLOSED when either side goes away
+//! first. Each end only ever tries one transition, so there is nothing to
+//! lock.
+
+use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+
+use crate::primitives::{Arc, AtomicUsize, Ordering, UnsafeCell};
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+
+const EMPTY: usize = 0;
+const SENT: usize = 1;
+const TAKEN: usize = 2;
+const CLOSED: usize = 3;
+
+struct Shared<T> {
+    state: AtomicUsize,
+    // initialized while the state is SENT
+    slot: UnsafeCell<MaybeUninit<T>>,
+    // the consumer, waiting for the state to leave EMPTY
+    consumer: WaitQueue,
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        state: AtomicUsize::new(EMPTY),
+        slot: UnsafeCell::new(MaybeUninit::uninit()),
+        consumer: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Sends `val`, which fails if the consumer is gone already.
+    pub fn send(self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        if shared.state.load(Ordering::Relaxed) == CLOSED {
+            return Err(SendError(val));
+        }
+        // Only we write the slot, and only while the state is EMPTY
+        shared.slot.with_mut(|slot| unsafe { (*slot).write(val) });
+        match shared
+            .state
+            .compare_exchange(EMPTY, SENT, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                shared.consumer.notify();
+                Ok(())
+            }
+            // the consumer went away in the meantime, so the value is still
+            // ours
+            Err(_) => Err(SendError(
+                shared
+                    .slot
+                    .with_mut(|slot| unsafe { (*slot).assume_init_read() }),
+            )),
+        }
+    }
+
+    /// Returns whether the consumer still exists. This is only a snapshot.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.shared.state.load(Ordering::Relaxed) != CLOSED
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Takes the message if it has been sent. Once it is taken, or the
+    /// producer is gone without sending, this fails with `Disconnected`.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        match shared
+            .state
+            .compare_exchange(SENT, TAKEN, Ordering::Acquire, Ordering::Relaxed)
+        {
+            // the producer released the slot with SENT
+            Ok(_) => Ok(shared
+                .slot
+                .with_mut(|slot| unsafe { (*slot).assume_init_read() })),
+            Err(EMPTY) => Err(TryRecvError::Empty),
+            Err(_) => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Waits for the message.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    /// Like `recv`, but waits at most for `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            shared
+                .consumer
+                .wait(deadline, || shared.state.load(Ordering::Relaxed) != EMPTY);
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    // Does nothing after a send, the state is not EMPTY anymore then
+    fn drop(&mut self) {
+        let shared = &*self.shared;
+        if shared
+            .state
+            .compare_exchange(EMPTY, CLOSED, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            shared.consumer.notify();
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    // A message that was sent but not taken is ours to drop
+    fn drop(&mut self) {
+        let shared = &*self.shared;
+        if shared.state.swap(CLOSED, Ordering::Acquire) == SENT {
+            shared
+                .slot
+                .with_mut(|slot| unsafe { (*slot).assume_init_drop() });
+        }
+    }
+}
+
+// The slot is only reached through the state transitions above
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn result_arrives_from_thread() {
+        let (px, cx) = channel();
+        let handle = thread::spawn(move || px.send(6 * 7).unwrap());
+        assert_eq!(cx.recv().unwrap(), 42);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dropped_ends_are_reported() {
+        let (px, cx) = channel::<i32>();
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(1)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        drop(px);
+        assert!(cx.recv().is_err());
+
+        let (px, cx) = channel();
+        drop(cx);
+        assert!(!px.is_consumer_alive());
+        assert_eq!(px.send(1).unwrap_err().0, 1);
+    }
+
+    #[test]
+    fn unreceived_message_is_dropped() {
+        let tracker = std::sync::Arc::new(());
+        let (px, cx) = channel();
+        px.send(tracker.clone()).unwrap();
+        drop(cx);
+        assert_eq!(std::sync::Arc::strong_count(&tracker), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn send_races_with_drop_of_consumer() {
+        loom::model(|| {
+            let tracker = Arc::new(());
+            let (px, cx) = channel();
+            let t = tracker.clone();
+            let handle = thread::spawn(move || {
+                let _ = px.send(t);
+            });
+            drop(cx);
+            handle.join().unwrap();
+            // dropped by exactly one side
+            assert_eq!(Arc::strong_count(&tracker), 1);
+        });
+    }
+}
@@ -0,0 +1,155 @@
+// A chain of stages, each on a thread of its own and connected to the next
+// by a channel. The end of the source travels down the chain as the
+// channels disconnect. A stage that panics drops its consumer, so the send
+// of the stage before it fails and that one stops as well.
+
+use std::thread;
+
+use crate::{channel_with_capacity, Consumer, BUFFER_SIZE};
+
+type Stage = Box<dyn FnOnce() + Send>;
+
+/// The stages of a pipeline up to one whose output is `T`, see
+/// `Pipeline::new`. Nothing runs before `Complete::run`.
+pub struct Pipeline<T> {
+    capacity: usize,
+    stages: Vec<Stage>,
+    output: Consumer<T>,
+}
+
+/// A pipeline that ends in a sink, ready to run.
+pub struct Complete {
+    stages: Vec<Stage>,
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    /// Starts a pipeline with `source`, which is called for the next
+    /// message until it returns `None`.
+    pub fn new(source: impl FnMut() -> Option<T> + Send + 'static) -> Self {
+        Self::with_capacity(BUFFER_SIZE, source)
+    }
+
+    /// Like `new`, but the channels between the stages buffer `capacity`
+    /// messages.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    pub fn with_capacity(
+        capacity: usize,
+        mut source: impl FnMut() -> Option<T> + Send + 'static,
+    ) -> Self {
+        let (mut px, cx) = channel_with_capacity(capacity);
+        let stage = move || {
+            while let Some(val) = source() {
+                // the rest of the pipeline stopped
+                if px.send(val).is_err() {
+                    break;
+                }
+            }
+        };
+        Pipeline {
+            capacity,
+            stages: vec![Box::new(stage)],
+            output: cx,
+        }
+    }
+
+    /// Adds a stage that passes every message through `f`.
+    pub fn then<U: Send + 'static>(
+        mut self,
+        mut f: impl FnMut(T) -> U + Send + 'static,
+    ) -> Pipeline<U> {
+        let (mut px, cx) = channel_with_capacity(self.capacity);
+        let input = self.output;
+        self.stages.push(Box::new(move || {
+            for val in input {
+                if px.send(f(val)).is_err() {
+                    break;
+                }
+            }
+        }));
+        Pipeline {
+            capacity: self.capacity,
+            stages: self.stages,
+            output: cx,
+        }
+    }
+
+    /// Ends the pipeline with `sink`, which gets every message.
+    pub fn sink(mut self, sink: impl FnMut(T) + Send + 'static) -> Complete {
+        let input = self.output;
+        self.stages.push(Box::new(move || input.for_each(sink)));
+        Complete {
+            stages: self.stages,
+        }
+    }
+}
+
+impl Complete {
+    /// Runs every stage on a thread of its own, named `pipeline-<n>` in
+    /// the order the stages were added, and waits until all are done.
+    /// Returns the panic of the first stage that panicked, if any did.
+    pub fn run(self) -> thread::Result<()> {
+        let handles: Vec<_> = self
+            .stages
+            .into_iter()
+            .enumerate()
+            .map(|(index, stage)| {
+                thread::Builder::new()
+                    .name(format!("pipeline-{index}"))
+                    .spawn(stage)
+                    .expect("failed to spawn a pipeline stage")
+            })
+            .collect();
+        // every stage is joined, none outlives run
+        let mut result = Ok(());
+        for handle in handles {
+            let joined = handle.join();
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn stages_run_in_order() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let mut next = 0;
+        Pipeline::with_capacity(4, move || {
+            next += 1;
+            (next <= 100).then_some(next)
+        })
+        .then(|i| i * 2)
+        .then(|i| i.to_string())
+        .sink(move |s| sink.lock().unwrap().push(s))
+        .run()
+        .unwrap();
+
+        let expected: Vec<String> = (1..=100).map(|i| (i * 2).to_string()).collect();
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn panicking_stage_stops_the_pipeline() {
+        // an endless source, which only stops once its sends fail
+        let result = Pipeline::with_capacity(4, || Some(1))
+            .then(|i: i32| {
+                if i > 0 {
+                    panic!("stage failed");
+                }
+                i
+            })
+            .sink(drop)
+            .run();
+        let panic = result.unwrap_err();
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"stage failed"));
+    }
+}
@@ -0,0 +1,169 @@
+// Synchronization primitives used by the channel. Under `--cfg loom` they are
+// replaced by the loom equivalents so the protocol can be model checked, and
+// with the `chaos` feature by wrappers that shake up the interleavings (see
+// chaos.rs).
+
+// Declares a const fn, except under loom, whose primitives can not be built
+// in a const. This lets the channel state go into a static.
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])* $vis const fn $($rest)*
+        #[cfg(loom)]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+pub(crate) use const_fn;
+
+#[cfg(loom)]
+pub(crate) use loom::cell::UnsafeCell;
+#[cfg(loom)]
+pub(crate) use loom::hint::spin_loop;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::fence;
+#[cfg(all(any(feature = "stats", feature = "latency"), feature = "std", loom))]
+pub(crate) use loom::sync::atomic::AtomicU64;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, Mutex};
+#[cfg(loom)]
+pub(crate) use loom::thread::{current, park, Thread};
+#[cfg(loom)]
+pub(crate) use loom::thread_local;
+
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now;
+
+// loom can not park or sleep for a time, but the callers have to expect
+// early wakeups anyway
+#[cfg(loom)]
+pub(crate) fn park_timeout(_timeout: std::time::Duration) {
+    loom::thread::yield_now();
+}
+
+#[cfg(loom)]
+pub(crate) fn sleep(_duration: std::time::Duration) {
+    loom::thread::yield_now();
+}
+
+#[cfg(not(loom))]
+use alloc::boxed::Box;
+#[cfg(all(not(feature = "portable-atomic"), not(loom)))]
+pub(crate) use alloc::sync::Arc;
+#[cfg(not(loom))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(all(not(feature = "chaos"), not(feature = "portable-atomic"), not(loom)))]
+pub(crate) use core::sync::atomic::fence;
+#[cfg(all(feature = "std", not(feature = "portable-atomic"), not(loom)))]
+pub(crate) use core::sync::atomic::AtomicPtr;
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::Ordering;
+#[cfg(all(not(feature = "chaos"), not(feature = "portable-atomic"), not(loom)))]
+pub(crate) use core::sync::atomic::{AtomicBool, AtomicUsize};
+// the wait times of stats and the latencies, which would soon overflow 32
+// bits of nanoseconds
+#[cfg(all(
+    any(feature = "stats", feature = "latency"),
+    feature = "chaos",
+    not(loom)
+))]
+pub(crate) use crate::chaos::AtomicU64;
+#[cfg(all(feature = "chaos", not(loom)))]
+pub(crate) use crate::chaos::{fence, AtomicBool, AtomicUsize};
+#[cfg(all(
+    any(feature = "stats", feature = "latency"),
+    feature = "std",
+    not(feature = "chaos"),
+    not(feature = "portable-atomic"),
+    not(loom)
+))]
+pub(crate) use core::sync::atomic::AtomicU64;
+// The same from portable-atomic, for targets whose core atomics lack the
+// read-modify-write operations (thumbv6m and the like). Those still need
+// portable-atomic's critical-section or unsafe-assume-single-core feature
+// from the final binary, which alone knows how to provide them.
+#[cfg(all(feature = "portable-atomic", not(feature = "chaos"), not(loom)))]
+pub(crate) use portable_atomic::{fence, AtomicBool, AtomicUsize};
+#[cfg(all(feature = "portable-atomic", feature = "std", not(loom)))]
+pub(crate) use portable_atomic::AtomicPtr;
+#[cfg(all(
+    any(feature = "stats", feature = "latency"),
+    feature = "std",
+    feature = "portable-atomic",
+    not(feature = "chaos"),
+    not(loom)
+))]
+pub(crate) use portable_atomic::AtomicU64;
+#[cfg(all(feature = "portable-atomic", not(loom)))]
+pub(crate) use portable_atomic_util::Arc;
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::sync::Mutex;
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::thread::{current, park, park_timeout, sleep, yield_now, Thread};
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::thread_local;
+
+// std's UnsafeCell wrapped in the closure based API of loom's UnsafeCell
+#[cfg(not(loom))]
+#[derive(Debug)]
+#[repr(transparent)]
+pub(crate) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    pub(crate) const fn new(data: T) -> Self {
+        UnsafeCell(core::cell::UnsafeCell::new(data))
+    }
+
+    // Views an exclusively borrowed slice as shared cells
+    pub(crate) fn from_mut_slice(slice: &mut [T]) -> &[UnsafeCell<T>] {
+        // UnsafeCell<T> is transparent over T, and the exclusive borrow
+        // guarantees there is no other access to the elements
+        unsafe { &*(slice as *mut [T] as *const [UnsafeCell<T>]) }
+    }
+
+    // Takes over a boxed slice as cells, on the same grounds
+    pub(crate) fn from_boxed_slice(slice: Box<[T]>) -> Box<[UnsafeCell<T>]> {
+        unsafe { Box::from_raw(Box::into_raw(slice) as *mut [UnsafeCell<T>]) }
+    }
+
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        #[cfg(feature = "chaos")]
+        return crate::chaos::chaotic(|| f(self.0.get()));
+        #[cfg(not(feature = "chaos"))]
+        f(self.0.get())
+    }
+
+    // The contents of a run of cells as one raw slice, which loom could not
+    // track. Any access through it is up to the caller, as with with_mut.
+    #[cfg(feature = "std")]
+    pub(crate) fn raw_slice(cells: &[UnsafeCell<T>]) -> *mut [T] {
+        cells as *const [UnsafeCell<T>] as *mut [T]
+    }
+}
+
+// Aligns (and so pads) its content to a cache line of its own, so the
+// producer and the consumer do not invalidate each other's line when they
+// write different atomics. x86_64 and aarch64 prefetch lines in pairs, hence
+// the 128 bytes there.
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), repr(align(128)))]
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    repr(align(64))
+)]
+#[derive(Debug)]
+pub(crate) struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        CachePadded(value)
+    }
+}
+
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
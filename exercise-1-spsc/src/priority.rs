@@ -0,0 +1,212 @@
+//! A channel with two lanes: urgent messages, e.g. control commands, jump
+//! ahead of the bulk data that is already queued.
+//!
+//! Each lane is an SPSC ring of its own. The consumer always empties the
+//! urgent lane first and only then takes the next bulk message, and waits
+//! on both lanes at once while they are empty. Within a lane the order is
+//! kept; between them it is not, an urgent message may overtake bulk ones
+//! sent long before. A full bulk lane does not hold up urgent sends.
+
+use std::time::{Duration, Instant};
+
+use crate::select::Select;
+use crate::{
+    RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+    BUFFER_SIZE,
+};
+
+pub struct Producer<T> {
+    bulk: crate::Producer<T>,
+    urgent: crate::Producer<T>,
+}
+
+pub struct Consumer<T> {
+    bulk: crate::Consumer<T>,
+    urgent: crate::Consumer<T>,
+}
+
+/// Creates a priority channel with 4096 slots in each lane.
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    channel_with_capacity(BUFFER_SIZE)
+}
+
+/// Like `channel`, with `capacity` slots in each lane.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_capacity<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let (bulk_px, bulk_cx) = crate::channel_with_capacity(capacity);
+    let (urgent_px, urgent_cx) = crate::channel_with_capacity(capacity);
+    (
+        Producer {
+            bulk: bulk_px,
+            urgent: urgent_px,
+        },
+        Consumer {
+            bulk: bulk_cx,
+            urgent: urgent_cx,
+        },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Sends `val` in the bulk lane, waiting for a free slot there.
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        self.bulk.send(val)
+    }
+
+    pub fn try_send(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.bulk.try_send(val)
+    }
+
+    pub fn send_timeout(&mut self, val: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.bulk.send_timeout(val, timeout)
+    }
+
+    /// Sends `val` in the urgent lane, so it is received before any bulk
+    /// message still queued.
+    pub fn send_priority(&mut self, val: T) -> Result<(), SendError<T>> {
+        self.urgent.send(val)
+    }
+
+    pub fn try_send_priority(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.urgent.try_send(val)
+    }
+
+    /// Closes both lanes, see `crate::Producer::close`.
+    pub fn close(&self) {
+        self.bulk.close();
+        self.urgent.close();
+    }
+
+    /// Returns whether the consumer is gone or the channel was closed.
+    pub fn is_disconnected(&self) -> bool {
+        self.bulk.is_disconnected()
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Receives the next urgent message or, if there is none, the next
+    /// bulk one, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.urgent.try_recv() {
+            Ok(val) => Ok(val),
+            // the other lane may still have messages either way
+            Err(urgent) => match self.bulk.try_recv() {
+                Ok(val) => Ok(val),
+                Err(TryRecvError::Disconnected) if urgent.is_disconnected() => {
+                    Err(TryRecvError::Disconnected)
+                }
+                Err(_) => Err(TryRecvError::Empty),
+            },
+        }
+    }
+
+    /// Waits for a message in either lane, see `try_recv`. Fails once the
+    /// producer is gone and both lanes are drained.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let mut select = Select::new();
+            select.recv(&mut self.urgent);
+            select.recv(&mut self.bulk);
+            let ready = match deadline {
+                None => Some(select.ready()),
+                Some(deadline) => select.ready_deadline(deadline),
+            };
+            if ready.is_none() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// Returns how many messages are queued in both lanes right now.
+    pub fn len(&self) -> usize {
+        self.urgent.len() + self.bulk.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Closes both lanes, see `crate::Consumer::close`.
+    pub fn close(&self) {
+        self.urgent.close();
+        self.bulk.close();
+    }
+}
+
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn urgent_messages_jump_the_queue() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        for i in 0..4 {
+            px.send(i).unwrap();
+        }
+        // the bulk lane is full, the urgent one is not
+        assert!(px.try_send(4).unwrap_err().is_full());
+        px.send_priority(100).unwrap();
+        px.send_priority(101).unwrap();
+        assert_eq!(cx.len(), 6);
+
+        assert_eq!(cx.recv(), Ok(100));
+        assert_eq!(cx.recv(), Ok(101));
+        assert_eq!(cx.recv(), Ok(0));
+        px.send_priority(102).unwrap();
+        drop(px);
+        assert_eq!(cx.collect::<Vec<_>>(), [102, 1, 2, 3]);
+    }
+
+    #[test]
+    fn recv_waits_on_both_lanes() {
+        let (mut px, mut cx) = channel_with_capacity(4);
+        let producer = thread::spawn(move || {
+            px.send_priority("urgent").unwrap();
+            // wait until it is received, so the order is known
+            while !px.urgent.is_empty() {
+                thread::yield_now();
+            }
+            px.send("bulk").unwrap();
+        });
+        assert_eq!(cx.recv(), Ok("urgent"));
+        assert_eq!(cx.recv(), Ok("bulk"));
+        producer.join().unwrap();
+        assert_eq!(cx.recv(), Err(RecvError));
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn recv_timeout_on_empty_lanes() {
+        let (mut px, mut cx) = channel::<i32>();
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        px.send(1).unwrap();
+        assert_eq!(cx.recv_timeout(Duration::from_millis(10)), Ok(1));
+    }
+}
@@ -0,0 +1,132 @@
+// A plain FIFO on the same ring buffer layout as the channel, for when both
+// ends live on one thread and no synchronization is needed.
+
+use alloc::boxed::Box;
+
+use crate::index;
+use crate::ring;
+
+/// A bounded single-threaded queue.
+pub struct Queue<T> {
+    buffer: Box<[Option<T>]>,
+    read_index: usize,
+    write_index: usize,
+}
+
+impl<T> Queue<T> {
+    /// Creates an empty queue holding up to `capacity` elements.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ring::check_capacity(capacity);
+        Queue {
+            buffer: (0..capacity).map(|_| None).collect(),
+            read_index: 0,
+            write_index: 0,
+        }
+    }
+
+    /// Appends `val`, or hands it back if the queue is full.
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(val);
+        }
+        let slot = index::slot(self.write_index, self.capacity());
+        self.buffer[slot] = Some(val);
+        self.write_index = index::advance(self.write_index, 1);
+        Ok(())
+    }
+
+    /// Removes the oldest element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let slot = index::slot(self.read_index, self.capacity());
+        self.read_index = index::advance(self.read_index, 1);
+        self.buffer[slot].take()
+    }
+
+    pub fn len(&self) -> usize {
+        index::len(self.read_index, self.write_index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        index::is_empty(self.read_index, self.write_index)
+    }
+
+    pub fn is_full(&self) -> bool {
+        index::is_full(self.read_index, self.write_index, self.capacity())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop_wraps_around() {
+        let mut queue = Queue::with_capacity(4);
+        for i in 0..10 {
+            queue.push(i).unwrap();
+            queue.push(i + 100).unwrap();
+            assert_eq!(queue.len(), 2);
+            assert_eq!(queue.pop(), Some(i));
+            assert_eq!(queue.pop(), Some(i + 100));
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn indices_wrap_around_usize() {
+        let mut queue = Queue::with_capacity(4);
+        queue.read_index = usize::MAX - 1;
+        queue.write_index = usize::MAX - 1;
+
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.len(), 4);
+        for i in 0..4 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert!(queue.is_empty());
+        assert!(queue.write_index < 4);
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let mut queue = Queue::with_capacity(2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+
+        queue.push('a').unwrap();
+        queue.push('b').unwrap();
+        assert!(queue.is_full());
+        assert_eq!(queue.push('c'), Err('c'));
+
+        assert_eq!(queue.pop(), Some('a'));
+        assert!(!queue.is_full());
+        queue.push('c').unwrap();
+        assert_eq!(queue.pop(), Some('b'));
+        assert_eq!(queue.pop(), Some('c'));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer capacity must be at least 1")]
+    fn zero_capacity_is_rejected() {
+        Queue::<()>::with_capacity(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer capacity must be a power of two")]
+    fn odd_capacity_is_rejected() {
+        Queue::<()>::with_capacity(6);
+    }
+}
@@ -0,0 +1,365 @@
+//! Recording the operations on a channel, and replaying them (the `record`
+//! feature).
+//!
+//! Once `start_recording` is called on either handle, every send, receive,
+//! failed `try_send` and `try_recv` and eviction by `force_send` is logged
+//! with the position of its message in the stream, the time and the
+//! thread, up to a limit after which the oldest events make room. The
+//! trace is there on demand with `trace`, and is printed to stderr when a
+//! handle is dropped by a panic, which poisons the channel.
+//!
+//! `Trace::replay` feeds the receiving side of a trace through a new
+//! channel, on one thread, and checks that each receive gets the message it
+//! got back then. The sends are replayed as the receives need them, so the
+//! order in which concurrent events were logged does not matter. A message
+//! that arrived twice, or not at all, shows up as a `Divergence` at the
+//! event where it happened, the same one every time.
+//!
+//! ```
+//! let (mut px, mut cx) = spsc::channel_with_capacity(4);
+//! cx.start_recording(64);
+//! px.send(1).unwrap();
+//! cx.recv().unwrap();
+//! assert_eq!(cx.try_recv(), Err(spsc::TryRecvError::Empty));
+//!
+//! let trace = cx.trace();
+//! assert_eq!(trace.events.len(), 3);
+//! assert_eq!(trace.replay(), Ok(()));
+//! println!("{}", trace);
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::primitives::{const_fn, AtomicBool, Mutex, Ordering};
+use crate::{index, TryRecvError};
+
+/// What happened, see `Event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Messages were sent, by any of the sending calls.
+    Send,
+    /// Messages were received, by any of the receiving calls.
+    Recv,
+    /// A `try_send` found the buffer full.
+    FailedTrySend,
+    /// A `try_recv` found the buffer empty.
+    FailedTryRecv,
+    /// `force_send` dropped the oldest message to make room.
+    Evict,
+}
+
+/// One operation on the channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub op: Op,
+    /// The position of the first message in the stream, counting from 0 and
+    /// wrapping around. For the failed calls, the position they would have
+    /// sent or received at.
+    pub seq: usize,
+    /// How many messages, 0 for the failed calls.
+    pub count: usize,
+    /// The time since the recording started.
+    pub at: Duration,
+    pub thread: ThreadId,
+}
+
+/// The events recorded on a channel, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    /// The capacity of the channel.
+    pub capacity: usize,
+    pub events: Vec<Event>,
+    /// How many older events made room for these.
+    pub dropped: usize,
+}
+
+/// Where a replay went differently than the recording, see `Trace::replay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// The index of the event in `Trace::events`.
+    pub at: usize,
+    /// The position the recording says the receive got, or was at.
+    pub recorded: usize,
+    /// The position the replay got, or was at. None if it found the channel
+    /// empty.
+    pub replayed: Option<usize>,
+}
+
+impl Trace {
+    /// Replays the trace on a new channel of the same capacity, on the
+    /// calling thread, and returns the first event that went differently.
+    pub fn replay(&self) -> Result<(), Divergence> {
+        let Some(first) = self.events.first() else {
+            return Ok(());
+        };
+        // The trace may start in the middle of the stream, and receive
+        // messages sent before it started. The replay starts at the oldest
+        // position of any event, which becomes its 0.
+        let base = self
+            .events
+            .iter()
+            .map(|event| event.seq.wrapping_sub(first.seq) as isize)
+            .min()
+            .map_or(first.seq, |offset| first.seq.wrapping_add(offset as usize));
+        let position = |seq: usize| seq.wrapping_sub(base);
+
+        let (mut px, mut cx) = crate::channel_with_capacity::<usize>(self.capacity);
+        // the next position to send and to receive
+        let (mut sent, mut read) = (0, 0);
+        for (at, event) in self.events.iter().enumerate() {
+            let diverged = |recorded: usize, replayed: Option<usize>| Divergence {
+                at,
+                recorded,
+                replayed: replayed.map(|replayed| base.wrapping_add(replayed)),
+            };
+            match event.op {
+                Op::Recv | Op::Evict => {
+                    for seq in index::range(event.seq, index::advance(event.seq, event.count)) {
+                        let seq = position(seq);
+                        // As far as the producer had to have sent, and one
+                        // more than the replay received so far, to show what
+                        // a receive out of order got instead. A full buffer
+                        // means the recording skipped over messages.
+                        while sent <= seq.max(read) {
+                            if px.try_send(sent).is_err() {
+                                return Err(diverged(base.wrapping_add(seq), Some(read)));
+                            }
+                            sent += 1;
+                        }
+                        match cx.try_recv() {
+                            Ok(got) if got == seq => read = got + 1,
+                            Ok(got) => return Err(diverged(base.wrapping_add(seq), Some(got))),
+                            Err(_) => return Err(diverged(base.wrapping_add(seq), None)),
+                        }
+                    }
+                }
+                // The receive was where the replay is, and found nothing
+                Op::FailedTryRecv => {
+                    if position(event.seq) != read {
+                        return Err(diverged(event.seq, Some(read)));
+                    }
+                    if cx.try_recv() != Err(TryRecvError::Empty) {
+                        return Err(diverged(event.seq, Some(read)));
+                    }
+                }
+                Op::Send | Op::FailedTrySend => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Send => "send",
+            Op::Recv => "recv",
+            Op::FailedTrySend => "try_send full",
+            Op::FailedTryRecv => "try_recv empty",
+            Op::Evict => "evict",
+        })
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "trace of a channel of {} messages, {} events ({} dropped before)",
+            self.capacity,
+            self.events.len(),
+            self.dropped
+        )?;
+        for event in &self.events {
+            write!(
+                f,
+                "{:>14?} {:?} {} at {}",
+                event.at, event.thread, event.op, event.seq
+            )?;
+            if event.count > 1 {
+                write!(f, " x{}", event.count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "event {}: recorded {}, ", self.at, self.recorded)?;
+        match self.replayed {
+            Some(replayed) => write!(f, "replayed {}", replayed),
+            None => write!(f, "the replay found the channel empty"),
+        }
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+// The recorder of a channel, part of its state. Without a recording
+// running, logging an event is a relaxed load.
+pub(crate) struct Recorder {
+    recording: AtomicBool,
+    log: Mutex<Option<Log>>,
+}
+
+struct Log {
+    start: Instant,
+    limit: usize,
+    events: VecDeque<Event>,
+    dropped: usize,
+}
+
+impl Recorder {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Recorder {
+                recording: AtomicBool::new(false),
+                log: Mutex::new(None),
+            }
+        }
+    }
+
+    // Starts over with an empty log of up to limit events
+    pub(crate) fn start(&self, limit: usize) {
+        *self.log.lock().unwrap() = Some(Log {
+            start: Instant::now(),
+            limit,
+            events: VecDeque::with_capacity(limit.min(4096)),
+            dropped: 0,
+        });
+        self.recording.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, op: Op, seq: usize, count: usize) {
+        if !self.recording.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut log = self.log.lock().unwrap();
+        let Some(log) = &mut *log else { return };
+        if log.limit == 0 {
+            log.dropped += 1;
+            return;
+        }
+        if log.events.len() == log.limit {
+            log.events.pop_front();
+            log.dropped += 1;
+        }
+        log.events.push_back(Event {
+            op,
+            seq,
+            count,
+            at: log.start.elapsed(),
+            thread: thread::current().id(),
+        });
+    }
+
+    pub(crate) fn trace(&self, capacity: usize) -> Trace {
+        let log = self.log.lock().unwrap();
+        Trace {
+            capacity,
+            events: log
+                .iter()
+                .flat_map(|log| log.events.iter().copied())
+                .collect(),
+            dropped: log.as_ref().map_or(0, |log| log.dropped),
+        }
+    }
+
+    // Prints the trace, if there is one, for a channel that was poisoned.
+    // The lock may be poisoned by the same panic, the log is whole anyway.
+    pub(crate) fn dump(&self, capacity: usize) {
+        if self.recording.load(Ordering::Relaxed) {
+            eprint!("{}", self.trace(capacity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn a_recorded_run_replays() {
+        let (mut px, mut cx) = crate::channel_with_capacity(4);
+        px.start_recording(1 << 16);
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                px.send(i).unwrap();
+            }
+        });
+        let (mut expected, mut turn) = (0, 0);
+        while expected < 1000 {
+            turn += 1;
+            // now and then without waiting, which may find nothing
+            let got = if turn % 7 == 0 {
+                match cx.try_recv() {
+                    Ok(got) => got,
+                    Err(_) => continue,
+                }
+            } else {
+                cx.recv().unwrap()
+            };
+            assert_eq!(got, expected);
+            expected += 1;
+        }
+        producer.join().unwrap();
+        let trace = cx.trace();
+        assert!(trace.events.iter().any(|event| event.op == Op::Send));
+        assert_eq!(trace.replay(), Ok(()));
+    }
+
+    #[test]
+    fn a_message_received_twice_diverges() {
+        let (mut px, mut cx) = crate::channel_with_capacity(4);
+        cx.start_recording(16);
+        for i in 0..3 {
+            px.send(i).unwrap();
+        }
+        for _ in 0..3 {
+            cx.recv().unwrap();
+        }
+        let mut trace = cx.trace();
+        // as if the second receive had got the first message again
+        let recvs: Vec<_> = (0..trace.events.len())
+            .filter(|&i| trace.events[i].op == Op::Recv)
+            .collect();
+        trace.events[recvs[1]].seq = 0;
+        assert_eq!(
+            trace.replay(),
+            Err(Divergence {
+                at: recvs[1],
+                recorded: 0,
+                replayed: Some(1),
+            })
+        );
+    }
+
+    #[test]
+    fn the_oldest_events_make_room() {
+        let (mut px, mut cx) = crate::channel_with_capacity(8);
+        px.start_recording(2);
+        for i in 0..5 {
+            px.send(i).unwrap();
+        }
+        cx.recv().unwrap();
+        let trace = px.trace();
+        assert_eq!(trace.dropped, 4);
+        assert_eq!(
+            trace
+                .events
+                .iter()
+                .map(|event| event.op)
+                .collect::<Vec<_>>(),
+            [Op::Send, Op::Recv]
+        );
+        // starts in the middle of the stream, and still replays
+        assert_eq!(trace.replay(), Ok(()));
+    }
+}
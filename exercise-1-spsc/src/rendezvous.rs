@@ -0,0 +1,217 @@
+//! A channel without a buffer: every send waits until the consumer has
+//! taken the message, so the producer can never run ahead.
+//!
+//! The message is handed over in a single slot. The producer fills it and
+//! waits for the consumer to empty it again; only if the consumer goes away
+//! in the meantime does the producer take the message back out. Both take
+//! the message with a CAS on the state of the slot, so only one of them gets
+//! it.
+
+use std::mem::MaybeUninit;
+
+use crate::primitives::{Arc, AtomicBool, AtomicUsize, Ordering, UnsafeCell};
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError, TryRecvError};
+
+const EMPTY: usize = 0;
+const FULL: usize = 1;
+// the consumer is moving the message out
+const TAKING: usize = 2;
+
+struct Shared<T> {
+    state: AtomicUsize,
+    slot: UnsafeCell<MaybeUninit<T>>,
+    producer_alive: AtomicBool,
+    consumer_alive: AtomicBool,
+    // the consumer waits for a message, the producer for it to be taken
+    consumers: WaitQueue,
+    producers: WaitQueue,
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        state: AtomicUsize::new(EMPTY),
+        slot: UnsafeCell::new(MaybeUninit::uninit()),
+        producer_alive: AtomicBool::new(true),
+        consumer_alive: AtomicBool::new(true),
+        consumers: WaitQueue::new(),
+        producers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Hands `val` to the consumer and waits until it has taken it. Fails,
+    /// handing `val` back, if the consumer goes away before that.
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        if !shared.consumer_alive.load(Ordering::Acquire) {
+            return Err(SendError(val));
+        }
+        // The slot is empty between sends, nobody else touches it now
+        shared.slot.with_mut(|slot| unsafe { (*slot).write(val) });
+        shared.state.store(FULL, Ordering::Release);
+        shared.consumers.notify();
+
+        let is_taken = || shared.state.load(Ordering::Acquire) == EMPTY;
+        loop {
+            if is_taken() {
+                return Ok(());
+            }
+            // The consumer is done for good. Unless it is taking the message
+            // right now, the message is ours again.
+            if !shared.consumer_alive.load(Ordering::Acquire)
+                && shared
+                    .state
+                    .compare_exchange(FULL, EMPTY, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                let val = shared
+                    .slot
+                    .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+                return Err(SendError(val));
+            }
+            shared.producers.wait(None, || {
+                is_taken() || !shared.consumer_alive.load(Ordering::Acquire)
+            });
+        }
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Takes the message of a producer that is waiting in `send` right now.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        // Before the slot: once the producer is gone, it sends no more
+        let producer_alive = shared.producer_alive.load(Ordering::Acquire);
+        if shared
+            .state
+            .compare_exchange(FULL, TAKING, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            let val = shared
+                .slot
+                .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+            // Release, so the producer only writes the slot again once we
+            // are done with it
+            shared.state.store(EMPTY, Ordering::Release);
+            shared.producers.notify();
+            return Ok(val);
+        }
+        Err(if producer_alive {
+            TryRecvError::Empty
+        } else {
+            TryRecvError::Disconnected
+        })
+    }
+
+    /// Waits for the producer to send a message.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => shared.consumers.wait(None, || {
+                    shared.state.load(Ordering::Acquire) == FULL
+                        || !shared.producer_alive.load(Ordering::Acquire)
+                }),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    // A message left in the slot goes back to its producer
+    fn drop(&mut self) {
+        self.shared.consumer_alive.store(false, Ordering::Release);
+        self.shared.producers.notify();
+    }
+}
+
+// The slot is only reached through the state as described above
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn send_waits_for_consumer() {
+        let (mut px, cx) = channel();
+        let sent = std::sync::Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..10 {
+                px.send(i).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        for i in 0..10 {
+            thread::sleep(Duration::from_millis(1));
+            // however long we take, the producer does not get ahead
+            assert!(sent.load(Ordering::SeqCst) <= i);
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+        handle.join().unwrap();
+        assert!(cx.recv().is_err());
+    }
+
+    #[test]
+    fn message_returns_to_producer_without_consumer() {
+        let (mut px, cx) = channel();
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+        let handle = thread::spawn(move || px.send(String::from("back")));
+        thread::sleep(Duration::from_millis(10));
+        drop(cx);
+        assert_eq!(handle.join().unwrap().unwrap_err().0, "back");
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn hand_off_or_return() {
+        loom::model(|| {
+            let (mut px, cx) = channel();
+            let handle = thread::spawn(move || px.send(1).map_err(|SendError(val)| val));
+            let received = cx.try_recv().ok();
+            drop(cx);
+            // the message is either taken or handed back, never both
+            match handle.join().unwrap() {
+                Ok(()) => assert_eq!(received, Some(1)),
+                Err(val) => assert_eq!((received, val), (None, 1)),
+            }
+        });
+    }
+}
@@ -0,0 +1,1913 @@
+// The ring buffer protocol shared by all channel flavours. A `Ring` is only a
+// view of a buffer and the indices guarding it, the handles decide where the
+// two live (behind an `Arc`, or borrowed from the caller).
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr;
+#[cfg(feature = "std")]
+use core::task::{ready, Context, Poll};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(all(feature = "fd", unix, not(loom)))]
+use std::{io, sync::OnceLock};
+
+#[cfg(feature = "std")]
+use crate::hooks::{Hook, Watermarks};
+use crate::index;
+#[cfg(feature = "latency")]
+use crate::latency::{Latency, LatencyHistogram};
+#[cfg(all(feature = "fd", unix, not(loom)))]
+use crate::notifier::Notifier;
+use crate::primitives::{
+    const_fn, fence, spin_loop, AtomicBool, AtomicUsize, CachePadded, Ordering, UnsafeCell,
+};
+#[cfg(feature = "record")]
+use crate::record::{Op, Recorder, Trace};
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+use crate::wait_queue::WaitQueue;
+use crate::wait_strategy::WaitStrategy;
+#[cfg(feature = "std")]
+use crate::wait_strategy::Waiter;
+#[cfg(feature = "std")]
+use crate::CancelToken;
+#[cfg(feature = "stats")]
+use crate::ChannelStats;
+#[cfg(feature = "std")]
+use crate::{
+    FlushError, RecvCancelError, RecvError, RecvTimeoutError, SendCancelError, SendTimeoutError,
+};
+use crate::{ForceSendError, SendError, TryRecvError, TrySendError};
+
+// An event of the tracing feature, labeled with the channel of state
+macro_rules! trace_event {
+    ($level:ident, $state:expr, $($rest:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!(channel = $state.label, $($rest)+);
+    };
+}
+
+pub(crate) const ZERO_CAPACITY: &str = "buffer capacity must be at least 1";
+pub(crate) const NOT_POWER_OF_TWO: &str = "buffer capacity must be a power of two";
+
+// Every buffer size goes through here, index::slot relies on it
+pub(crate) const fn check_capacity(capacity: usize) {
+    assert!(capacity > 0, "{}", ZERO_CAPACITY);
+    assert!(capacity.is_power_of_two(), "{}", NOT_POWER_OF_TWO);
+}
+
+// Capacity parameter of a Ring whose capacity is only known at runtime
+pub(crate) const DYNAMIC: usize = 0;
+
+// A slot holds a message exactly while its position is in released..write,
+// the indices are the only record of which slots are initialized
+pub(crate) type Slot<T> = UnsafeCell<MaybeUninit<T>>;
+
+// The producer side only ever writes write_index and the consumer side only
+// read_index and released_index, so a send and a recv never wait for each
+// other. The consumer claims the message at read_index by moving the index
+// past it with a CAS, and hands its slot back by storing released_index once
+// it is done with it. The one exception is force_send, which evicts the head
+// of a full buffer through the same CAS, so only one of the two gets the
+// message. It only wins while nothing is claimed, that is while read_index
+// is where released_index is.
+//
+// The producers of channel_mpsc claim their slots by a CAS on claim_index
+// instead, and mark each slot in written once its message is in. write_index
+// stays the boundary the consumer reads up to: whichever producer finds the
+// slots at write_index marked moves it over them, so a producer stalled in
+// the middle of a send holds back the messages after its own, but not the
+// other producers.
+//
+// The indices are written on every message, each on a cache line of its own.
+// Each side also keeps a copy of the other side's index and only reloads it
+// when the copy says it would have to wait, which spares most of the misses
+// on the other side's line.
+pub(crate) struct State {
+    // the position the consumer claimed the messages up to
+    pub(crate) read_index: CachePadded<AtomicUsize>,
+    pub(crate) write_index: CachePadded<AtomicUsize>,
+    // the position the consumer handed the slots back up to, never ahead of
+    // read_index. The messages in between are those of a RecvGuard,
+    // PeekGuard or OccupiedSlices, or the one a receive is taking.
+    pub(crate) released_index: CachePadded<AtomicUsize>,
+    // the producers' copy of released_index, a lower bound of it
+    pub(crate) cached_released_index: CachePadded<AtomicUsize>,
+    // the consumer's copy of write_index, a lower bound of it
+    pub(crate) cached_write_index: CachePadded<AtomicUsize>,
+    pub(crate) producer_counter: AtomicUsize,
+    pub(crate) consumer_counter: AtomicUsize,
+    // the position the producers of channel_mpsc claimed the slots up to,
+    // ahead of write_index by those still being written. Unused with a lone
+    // producer, which claims a slot by publishing it.
+    pub(crate) claim_index: CachePadded<AtomicUsize>,
+    // for each slot, the position + 1 of the last message written to it.
+    // Only the channels of channel_mpsc have them, the only ones whose
+    // producer can be cloned, and set them before any handle exists.
+    pub(crate) written: Option<Box<[AtomicUsize]>>,
+    // set while a producer holds a reservation, a SlotGuard on the next free
+    // slot or the like. No other producer claims a slot until it is gone.
+    pub(crate) slot_reserved: AtomicBool,
+    // set while a RecvGuard, PeekGuard, PeekMutGuard or OccupiedSlices holds
+    // the head. Only force_send looks at it, to tell a claim that lasts from
+    // one a receive gives back in a moment.
+    pub(crate) head_held: AtomicBool,
+    // set by close() on either side, sends fail and recv fails once drained
+    pub(crate) closed: AtomicBool,
+    // set along with closed when a handle or guard is dropped by a panic
+    #[cfg(feature = "std")]
+    pub(crate) poisoned: AtomicBool,
+    // the largest number of queued messages seen so far
+    pub(crate) high_water_mark: AtomicUsize,
+    pub(crate) wait_strategy: WaitStrategy,
+    // names the channel in its tracing events and Debug output
+    pub(crate) label: Option<&'static str>,
+    // consumers waiting until a message arrives, producers until a slot
+    // frees up (or flush until the buffer drains), and either until the
+    // channel disconnects. Threads only park on them with WaitStrategy::Park,
+    // the tasks of the async calls wait on them whatever the strategy.
+    pub(crate) consumers: WaitQueue,
+    pub(crate) producers: WaitQueue,
+    #[cfg(feature = "stats")]
+    pub(crate) stats: Stats,
+    #[cfg(feature = "record")]
+    pub(crate) recorder: Recorder,
+    #[cfg(feature = "latency")]
+    pub(crate) latency: Latency,
+    // what the consumer wants run on a send into the empty buffer, and the
+    // producers on a receive out of the full one
+    #[cfg(feature = "std")]
+    pub(crate) on_message: Hook,
+    #[cfg(feature = "std")]
+    pub(crate) on_space: Hook,
+    // the producers' thresholds for pausing and resuming their source
+    #[cfg(feature = "std")]
+    pub(crate) watermarks: Watermarks,
+    // set once the consumer asks for it, signaled by the producers from then
+    // on
+    #[cfg(all(feature = "fd", unix, not(loom)))]
+    pub(crate) notifier: OnceLock<Notifier>,
+}
+
+impl State {
+    const_fn! {
+        pub(crate) fn new(wait_strategy: WaitStrategy) -> Self {
+            State {
+                read_index: CachePadded::new(AtomicUsize::new(0)),
+                write_index: CachePadded::new(AtomicUsize::new(0)),
+                released_index: CachePadded::new(AtomicUsize::new(0)),
+                cached_released_index: CachePadded::new(AtomicUsize::new(0)),
+                cached_write_index: CachePadded::new(AtomicUsize::new(0)),
+                producer_counter: AtomicUsize::new(1),
+                consumer_counter: AtomicUsize::new(1),
+                claim_index: CachePadded::new(AtomicUsize::new(0)),
+                written: None,
+                slot_reserved: AtomicBool::new(false),
+                head_held: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+                #[cfg(feature = "std")]
+                poisoned: AtomicBool::new(false),
+                high_water_mark: AtomicUsize::new(0),
+                wait_strategy,
+                label: None,
+                consumers: WaitQueue::with_strategy(wait_strategy),
+                producers: WaitQueue::with_strategy(wait_strategy),
+                #[cfg(feature = "stats")]
+                stats: Stats::new(),
+                #[cfg(feature = "record")]
+                recorder: Recorder::new(),
+                #[cfg(feature = "latency")]
+                latency: Latency::new(),
+                #[cfg(feature = "std")]
+                on_message: Hook::new(),
+                #[cfg(feature = "std")]
+                on_space: Hook::new(),
+                #[cfg(feature = "std")]
+                watermarks: Watermarks::new(),
+                #[cfg(all(feature = "fd", unix, not(loom)))]
+                notifier: OnceLock::new(),
+            }
+        }
+    }
+
+    // The written marks of a channel_mpsc of capacity slots. Position p is
+    // marked p + 1, so none of the first lap looks written yet.
+    pub(crate) fn written_marks(capacity: usize) -> Box<[AtomicUsize]> {
+        (0..capacity).map(|_| AtomicUsize::new(0)).collect()
+    }
+
+    pub(crate) fn multi_producer(&self) -> bool {
+        self.written.is_some()
+    }
+
+    // Whether no more messages are sent, though some may still be queued
+    pub(crate) fn is_disconnected_from_producers(&self) -> bool {
+        self.producer_counter.load(Ordering::Acquire) == 0 || self.closed.load(Ordering::Acquire)
+    }
+
+    // Whether a receive would not report Empty. Needs no ring, so Select can
+    // check consumers of any T. Only the consumer's thread may call it, it
+    // trusts read_index to be its own.
+    #[cfg(feature = "std")]
+    pub(crate) fn message_ready(&self) -> bool {
+        let read_index = self.read_index.load(Ordering::Relaxed);
+        index::has_message(read_index, self.write_index.load(Ordering::Acquire))
+            || self.is_disconnected_from_producers()
+    }
+}
+
+// Gives the consumer's claim back from position on once dropped, which
+// drop_claimed moves along as it drops the messages before it. A guard, so
+// a panicking T::drop does not leave the rest claimed for good.
+struct Unclaim<'a> {
+    read_index: &'a AtomicUsize,
+    position: usize,
+}
+
+impl Drop for Unclaim<'_> {
+    fn drop(&mut self) {
+        // Release, as in Ring::unclaim_head
+        self.read_index.store(self.position, Ordering::Release);
+    }
+}
+
+// With N other than DYNAMIC, the buffer holds exactly N slots and the index
+// arithmetic works with a constant the compiler can fold.
+pub(crate) struct Ring<'a, T, const N: usize = DYNAMIC> {
+    pub(crate) buffer: &'a [Slot<T>],
+    pub(crate) state: &'a State,
+}
+
+impl<T, const N: usize> Clone for Ring<'_, T, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const N: usize> Copy for Ring<'_, T, N> {}
+
+impl<'a, T, const N: usize> Ring<'a, T, N> {
+    pub(crate) fn capacity(&self) -> usize {
+        if N == DYNAMIC {
+            self.buffer.len()
+        } else {
+            N
+        }
+    }
+
+    // A snapshot, the other side may move its index right after.
+    // released_index is loaded first so it can not pass write_index, but the
+    // producer may have filled the slots freed in between, hence the clamp.
+    // The messages the consumer claimed still count until it hands them back.
+    pub(crate) fn len(&self) -> usize {
+        let released_index = self.state.released_index.load(Ordering::Acquire);
+        let write_index = self.state.write_index.load(Ordering::Acquire);
+        index::len(released_index, write_index).min(self.capacity())
+    }
+
+    // The counters of the channel along with its depth
+    #[cfg(feature = "stats")]
+    pub(crate) fn stats(&self) -> ChannelStats {
+        let high_water_mark = self.state.high_water_mark.load(Ordering::Relaxed);
+        self.state.stats.snapshot(self.len(), high_water_mark)
+    }
+
+    #[cfg(feature = "record")]
+    pub(crate) fn start_recording(&self, limit: usize) {
+        self.state.recorder.start(limit);
+    }
+
+    #[cfg(feature = "record")]
+    pub(crate) fn trace(&self) -> Trace {
+        self.state.recorder.trace(self.capacity())
+    }
+
+    #[cfg(feature = "latency")]
+    pub(crate) fn measure_latency(&self) {
+        self.state.latency.start(self.capacity());
+    }
+
+    #[cfg(feature = "latency")]
+    pub(crate) fn latency(&self) -> LatencyHistogram {
+        self.state.latency.histogram()
+    }
+
+    // Like recv_into, and returns the time the message spent in the queue
+    #[cfg(feature = "latency")]
+    pub(crate) fn recv_into_with_latency(
+        &self,
+        dst: &mut MaybeUninit<T>,
+    ) -> Result<Option<Duration>, RecvError> {
+        let read_index = self.recv_into_seq(dst)?;
+        Ok(self.state.latency.of(read_index))
+    }
+
+    // The state of the channel for the Debug output of the handles, leaving
+    // out the messages
+    pub(crate) fn fmt_debug(&self, name: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state;
+        let mut debug = f.debug_struct(name);
+        // only for the channels that have one
+        if let Some(label) = state.label {
+            debug.field("label", &label);
+        }
+        debug
+            .field("capacity", &self.capacity())
+            .field("len", &self.len())
+            .field("read_index", &state.read_index.load(Ordering::Relaxed))
+            .field("write_index", &state.write_index.load(Ordering::Relaxed))
+            .field("producers", &state.producer_counter.load(Ordering::Relaxed))
+            .field(
+                "consumer_alive",
+                &(state.consumer_counter.load(Ordering::Relaxed) != 0),
+            )
+            .field("closed", &self.is_closed())
+            .finish_non_exhaustive()
+    }
+
+    // The len slots from position on, as the run up to the end of the buffer
+    // and the one that continues at its start
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn runs(
+        &self,
+        position: usize,
+        len: usize,
+    ) -> (*mut [MaybeUninit<T>], *mut [MaybeUninit<T>]) {
+        let start = index::slot(position, self.capacity());
+        let first = len.min(self.capacity() - start);
+        (
+            UnsafeCell::raw_slice(&self.buffer[start..start + first]),
+            UnsafeCell::raw_slice(&self.buffer[..len - first]),
+        )
+    }
+
+    fn slot(&self, position: usize) -> &'a Slot<T> {
+        &self.buffer[index::slot(position, self.capacity())]
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn send(&self, val: T) -> Result<(), SendError<T>> {
+        match self.send_until(val, None) {
+            Ok(()) => Ok(()),
+            // there is no deadline to miss
+            Err(SendTimeoutError::Timeout(val) | SendTimeoutError::Disconnected(val)) => {
+                Err(SendError(val))
+            }
+        }
+    }
+
+    // Like send, but gives up once the deadline (if any) has passed
+    #[cfg(feature = "std")]
+    pub(crate) fn send_until(
+        &self,
+        val: T,
+        deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<T>> {
+        match self.wait_for_slot(deadline, None, 1, || self.try_slot()) {
+            Ok(position) => {
+                self.push(position, val);
+                #[cfg(feature = "stats")]
+                self.state.stats.record_send();
+                Ok(())
+            }
+            Err(SendTimeoutError::Timeout(())) => Err(SendTimeoutError::Timeout(val)),
+            Err(SendTimeoutError::Disconnected(())) => Err(SendTimeoutError::Disconnected(val)),
+        }
+    }
+
+    // Like send_until, with the deadline timeout from now
+    #[cfg(feature = "std")]
+    pub(crate) fn send_timeout(
+        &self,
+        val: T,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(val, Instant::now().checked_add(timeout))
+    }
+
+    // Like send, but gives up once the token is cancelled
+    #[cfg(feature = "std")]
+    pub(crate) fn send_cancellable(
+        &self,
+        val: T,
+        cancel: &CancelToken,
+    ) -> Result<(), SendCancelError<T>> {
+        match self.wait_for_slot(None, Some(cancel), 1, || self.try_slot()) {
+            Ok(position) => {
+                self.push(position, val);
+                #[cfg(feature = "stats")]
+                self.state.stats.record_send();
+                Ok(())
+            }
+            Err(SendTimeoutError::Timeout(())) => Err(SendCancelError::Cancelled(val)),
+            Err(SendTimeoutError::Disconnected(())) => Err(SendCancelError::Disconnected(val)),
+        }
+    }
+
+    pub(crate) fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        match self.try_slot() {
+            Ok(position) => {
+                self.push(position, val);
+                #[cfg(feature = "stats")]
+                self.state.stats.record_send();
+                Ok(())
+            }
+            Err(TrySendError::Full(())) => {
+                #[cfg(feature = "stats")]
+                self.state.stats.record_failed_try_send();
+                #[cfg(feature = "record")]
+                self.state.recorder.record(
+                    Op::FailedTrySend,
+                    self.state.write_index.load(Ordering::Relaxed),
+                    0,
+                );
+                Err(TrySendError::Full(val))
+            }
+            Err(TrySendError::Disconnected(())) => Err(TrySendError::Disconnected(val)),
+        }
+    }
+
+    // Waits for a free slot and reserves it for a later commit_reserved
+    #[cfg(feature = "std")]
+    pub(crate) fn reserve(&self) -> Result<usize, SendError<()>> {
+        self.wait_for_slot(None, None, 1, || self.try_reserve(1, false))
+            .map(|(position, _)| position)
+            .map_err(|_| SendError(()))
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn commit_reserved(&self, position: usize, val: T) {
+        self.slot(position)
+            .with_mut(|slot| unsafe { (*slot).write(val) });
+        self.commit_written(position);
+    }
+
+    // The slot from reserve, for the caller to write in place
+    #[cfg(feature = "std")]
+    pub(crate) fn reserved_slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        self.slot(position).with_mut(|slot| slot)
+    }
+
+    // Publishes the slot from reserve, which the caller has written
+    #[cfg(feature = "std")]
+    pub(crate) fn commit_written(&self, position: usize) {
+        self.commit(position, 1);
+        self.unreserve();
+        #[cfg(feature = "stats")]
+        self.state.stats.record_send();
+    }
+
+    // Like reserve, but takes every free slot. Returns the position of the
+    // first one and their number.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn reserve_vacant(&self) -> Result<(usize, usize), SendError<()>> {
+        self.wait_for_slot(None, None, 1, || self.try_reserve(1, true))
+            .map_err(|_| SendError(()))
+    }
+
+    // Like reserve, but waits until count slots are free and reserves them
+    // all. Returns the position of the first one.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn reserve_many(&self, count: usize) -> Result<usize, SendError<()>> {
+        self.wait_for_slot(None, None, count, || self.try_reserve(count, false))
+            .map(|(position, _)| position)
+            .map_err(|_| SendError(()))
+    }
+
+    // Waits until count slots are free and returns how many are. Fails once
+    // the consumer is gone, room or not.
+    #[cfg(feature = "std")]
+    pub(crate) fn wait_for_space(&self, count: usize) -> Result<usize, SendError<()>> {
+        let mut waiter = Waiter::new(self.state.wait_strategy);
+        loop {
+            if self.is_disconnected_from_consumer() {
+                return Err(SendError(()));
+            }
+            let free = self.capacity() - self.len();
+            if free >= count {
+                return Ok(free);
+            }
+            // every receive notifies, so this wakes up as the room grows
+            waiter.wait(&self.state.producers, None, || {
+                self.capacity() - self.len() >= count || self.is_disconnected_from_consumer()
+            });
+        }
+    }
+
+    // Publishes the first count of the slots from reserve_vacant, which the
+    // caller has written, and gives the others back
+    pub(crate) fn commit_vacant(&self, position: usize, count: usize) {
+        let end = index::advance(position, count);
+        self.return_claims(end);
+        if count > 0 {
+            self.commit(position, count);
+        }
+        self.unreserve();
+        #[cfg(feature = "stats")]
+        self.state.stats.record_sends(count);
+    }
+
+    // Gives the slots from position on back, along with the reservation
+    #[cfg(feature = "std")]
+    pub(crate) fn cancel_reserved(&self, position: usize) {
+        self.return_claims(position);
+        self.unreserve();
+    }
+
+    // Waits until attempt gets hold of count free slots and returns what it
+    // got. Without a deadline, this only fails with Disconnected.
+    #[cfg(feature = "std")]
+    fn wait_for_slot<R>(
+        &self,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+        count: usize,
+        attempt: impl Fn() -> Result<R, TrySendError<()>>,
+    ) -> Result<R, SendTimeoutError<()>> {
+        let mut waiter = Waiter::new(self.state.wait_strategy);
+        // the stall of this call, from the first time it waits on
+        #[cfg(feature = "stats")]
+        let mut stall = None;
+        // entered from the first time it waits on, for as long as it does
+        #[cfg(feature = "tracing")]
+        let mut span = None;
+        loop {
+            match attempt() {
+                Ok(slots) => return Ok(slots),
+                Err(TrySendError::Disconnected(())) => {
+                    return Err(SendTimeoutError::Disconnected(()))
+                }
+                Err(TrySendError::Full(())) => {}
+            }
+            // a cancel reads as a timeout, the calls with a token have no
+            // deadline
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                || cancel.is_some_and(CancelToken::is_cancelled)
+            {
+                return Err(SendTimeoutError::Timeout(()));
+            }
+            #[cfg(feature = "stats")]
+            if stall.is_none() {
+                stall = Some(self.state.stats.record_full_stall());
+            }
+            #[cfg(feature = "tracing")]
+            if span.is_none() {
+                let label = self.state.label;
+                span = Some(tracing::debug_span!("blocked_on_full", channel = label).entered());
+            }
+            match cancel {
+                Some(cancel) => {
+                    waiter.wait_any([&self.state.producers, cancel.waiters()], deadline, || {
+                        self.slot_ready(count) || cancel.is_cancelled()
+                    })
+                }
+                None => waiter.wait(&self.state.producers, deadline, || self.slot_ready(count)),
+            }
+        }
+    }
+
+    // The async counterpart of send: tries once, and if the buffer is full,
+    // registers the task to be woken once it may not be anymore. The value
+    // stays in val until it is sent.
+    #[cfg(feature = "std")]
+    pub(crate) fn poll_send(
+        &self,
+        val: &mut Option<T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        let result = ready!(self.poll_slot(cx, || self.try_slot()));
+        let val = val.take().expect("send future polled after completion");
+        Poll::Ready(match result {
+            Ok(position) => {
+                self.push(position, val);
+                #[cfg(feature = "stats")]
+                self.state.stats.record_send();
+                Ok(())
+            }
+            Err(SendError(())) => Err(SendError(val)),
+        })
+    }
+
+    // The async counterpart of reserve
+    #[cfg(feature = "futures")]
+    pub(crate) fn poll_reserve(&self, cx: &mut Context<'_>) -> Poll<Result<usize, SendError<()>>> {
+        let (position, _) = ready!(self.poll_slot(cx, || self.try_reserve(1, false)))?;
+        Poll::Ready(Ok(position))
+    }
+
+    // The async counterpart of wait_for_slot, for a single slot
+    #[cfg(feature = "std")]
+    fn poll_slot<R>(
+        &self,
+        cx: &mut Context<'_>,
+        attempt: impl Fn() -> Result<R, TrySendError<()>>,
+    ) -> Poll<Result<R, SendError<()>>> {
+        loop {
+            match attempt() {
+                Ok(slots) => return Poll::Ready(Ok(slots)),
+                Err(TrySendError::Disconnected(())) => return Poll::Ready(Err(SendError(()))),
+                Err(TrySendError::Full(())) => {}
+            }
+            if self.state.multi_producer() {
+                self.state.producers.register_shared(cx.waker());
+            } else {
+                self.state.producers.register(cx.waker());
+            }
+            if !self.slot_ready(1) {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    // Whether count slots are free and not held back by a reservation,
+    // checked before parking. released_index is loaded first so it can not
+    // pass the claimed index, and stale indices can only make the buffer
+    // look less full than it is, which costs another attempt at most.
+    #[cfg(feature = "std")]
+    fn slot_ready(&self, count: usize) -> bool {
+        let state = self.state;
+        let released_index = state.released_index.load(Ordering::Acquire);
+        let claimed = index::len(released_index, self.claimed_index());
+        (claimed <= self.capacity() - count && !state.slot_reserved.load(Ordering::Relaxed))
+            || self.is_disconnected_from_consumer()
+    }
+
+    // Whether sends fail from now on
+    pub(crate) fn is_disconnected_from_consumer(&self) -> bool {
+        self.state.consumer_counter.load(Ordering::Relaxed) == 0 || self.is_closed()
+    }
+
+    // See State::is_disconnected_from_producers
+    pub(crate) fn is_disconnected_from_producers(&self) -> bool {
+        self.state.is_disconnected_from_producers()
+    }
+
+    // The position the producers claimed the slots up to: write_index, unless
+    // there are several of them, claiming ahead of it
+    fn claimed_index(&self) -> usize {
+        let state = self.state;
+        if state.multi_producer() {
+            self.latest_claim()
+        } else {
+            state.write_index.load(Ordering::Acquire)
+        }
+    }
+
+    // claim_index as it is now. Read by an RMW rather than a load, which may
+    // return a claim the other producers moved past already: a CAS from
+    // there is bound to fail, and a producer about to park would find the
+    // buffer less full than it is and try again. The RMW takes the line for
+    // the CAS that follows anyway.
+    fn latest_claim(&self) -> usize {
+        self.state.claim_index.fetch_add(0, Ordering::Acquire)
+    }
+
+    // A single attempt of wait_for_slot
+    pub(crate) fn try_slot(&self) -> Result<usize, TrySendError<()>> {
+        let state = self.state;
+        // Checked on every attempt: once the consumer is gone, a full
+        // buffer is never going to drain
+        if self.is_disconnected_from_consumer() {
+            return Err(TrySendError::Disconnected(()));
+        }
+        if state.multi_producer() {
+            return match self.claim(1, false, false) {
+                Some((position, _)) => Ok(position),
+                None => Err(TrySendError::Full(())),
+            };
+        }
+        // Our own index, the lone producer stored it last
+        let write_index: usize = state.write_index.load(Ordering::Relaxed);
+        let released_index: usize = self.released_index_for(write_index, 1);
+
+        // The write index must not 'overtake' the released index
+        // when wrapping around the buffer
+        //
+        // We are the only writer of write_index, so we do not need an atomic
+        // swap to synchronize its increment
+        //
+        // If the released_index changes during the load, it is okay because
+        // the consumer only hands back slots it is done with
+        //
+        // write_index - released_index is the number of queued messages, so
+        // write_index == released_index means empty (as initially, when both
+        // are 0) and write_index == released_index + len means full. Writing
+        // then would clobber the message at released_index, which the
+        // consumer may be reading.
+        //
+        // A reserved slot is taken as well, until it is committed.
+        if !index::is_full(released_index, write_index, self.capacity())
+            && !state.slot_reserved.load(Ordering::Relaxed)
+        {
+            return Ok(write_index);
+        }
+        Err(TrySendError::Full(()))
+    }
+
+    // Claims slots for one of several producers: min of them, or every free
+    // one if all. Returns the position of the first and their number, or
+    // None if fewer than min are free. Backs off while a reservation is
+    // held, unless reserving for it.
+    //
+    // A producer looks at the flag after acquiring claim_index, and the
+    // reservation sets the flag before it claims. So a producer that would
+    // claim after the reservation acquired its flag along with claim_index
+    // and backs off, and the reservation can give back what it leaves unused
+    // by storing claim_index.
+    fn claim(&self, min: usize, all: bool, reserving: bool) -> Option<(usize, usize)> {
+        let state = self.state;
+        let capacity = self.capacity();
+        let mut claim_index = self.latest_claim();
+        loop {
+            if !reserving && state.slot_reserved.load(Ordering::Relaxed) {
+                return None;
+            }
+            let wanted = if all { capacity } else { min };
+            let released_index = self.released_index_for(claim_index, wanted);
+            let queued = index::len(released_index, claim_index);
+            // else claim_index is stale, the consumer got past it already
+            if queued <= capacity {
+                let free = capacity - queued;
+                if free < min {
+                    return None;
+                }
+                let count = if all { free } else { min };
+                match state.claim_index.compare_exchange(
+                    claim_index,
+                    index::advance(claim_index, count),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some((claim_index, count)),
+                    Err(current) => claim_index = current,
+                }
+            } else {
+                claim_index = self.latest_claim();
+            }
+        }
+    }
+
+    // A single attempt of the reservations: takes the reservation and claims
+    // min slots, or every free one if all. Returns the position of the first
+    // and their number.
+    fn try_reserve(&self, min: usize, all: bool) -> Result<(usize, usize), TrySendError<()>> {
+        let state = self.state;
+        if self.is_disconnected_from_consumer() {
+            return Err(TrySendError::Disconnected(()));
+        }
+        // Acquires the claims given back by the reservation before, if any
+        if state
+            .slot_reserved
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(TrySendError::Full(()));
+        }
+        let claimed = if state.multi_producer() {
+            self.claim(min, all, true)
+        } else {
+            let write_index = state.write_index.load(Ordering::Relaxed);
+            let wanted = if all { self.capacity() } else { min };
+            let released_index = self.released_index_for(write_index, wanted);
+            let free = self.capacity() - index::len(released_index, write_index);
+            (free >= min).then_some((write_index, if all { free } else { min }))
+        };
+        if claimed.is_none() {
+            self.unreserve();
+        }
+        claimed.ok_or(TrySendError::Full(()))
+    }
+
+    // Gives the claims of the reservation back from end on. Nobody claimed
+    // after it, see claim.
+    fn return_claims(&self, end: usize) {
+        let state = self.state;
+        if state.multi_producer() {
+            state.claim_index.store(end, Ordering::Release);
+        }
+    }
+
+    // Lets the other producers claim again, after return_claims
+    fn unreserve(&self) {
+        self.state.slot_reserved.store(false, Ordering::Release);
+        // other producers may be waiting for the reservation to go away
+        self.state.producers.notify();
+    }
+
+    // The released index as far as a producer claiming count slots from
+    // position on needs to know: the copy, unless fewer than count slots are
+    // free with it. The reload is acquired, so the consumer is done with the
+    // slots it released before we write them again, and the copy passes that
+    // on to the other producers, if any.
+    fn released_index_for(&self, position: usize, count: usize) -> usize {
+        let state = self.state;
+        let cached = state.cached_released_index.load(Ordering::Acquire);
+        if index::len(cached, position) <= self.capacity() - count {
+            return cached;
+        }
+        let released_index = state.released_index.load(Ordering::Acquire);
+        state
+            .cached_released_index
+            .store(released_index, Ordering::Release);
+        released_index
+    }
+
+    // Like send, but on a full buffer the oldest message is evicted to make
+    // room instead of waiting for the consumer, and returned. A head the
+    // consumer claimed can not be evicted, that would pull the message from
+    // under it. While a receive has it, we wait for the slot to come back,
+    // which takes a moment, but a guard may hold on to it for good, as may
+    // another producer to a reserved slot, so then this fails with Held.
+    pub(crate) fn force_send(&self, val: T) -> Result<Option<T>, ForceSendError<T>> {
+        let state = self.state;
+        let mut evicted = None;
+        loop {
+            match self.try_slot() {
+                Ok(position) => {
+                    self.push(position, val);
+                    #[cfg(feature = "stats")]
+                    state.stats.record_send();
+                    // dropped by the caller
+                    return Ok(evicted);
+                }
+                Err(TrySendError::Disconnected(())) => {
+                    return Err(ForceSendError::Disconnected(val))
+                }
+                Err(TrySendError::Full(())) => {}
+            }
+            // another producer is about to write the next slot
+            if state.slot_reserved.load(Ordering::Relaxed) {
+                return Err(ForceSendError::Held(val));
+            }
+            let released_index = state.released_index.load(Ordering::Acquire);
+            if !index::is_full(released_index, self.claimed_index(), self.capacity()) {
+                // room again, or a stale index
+                continue;
+            }
+            match self.evict_head(released_index) {
+                // Another producer may take the slot we freed before we get
+                // to claim it, then we evict again. The caller gets the
+                // oldest message and the others are dropped here.
+                Some(head) => {
+                    evicted.get_or_insert(head);
+                }
+                // The guards set the flag only after their claim, so we may
+                // wait a little for one as well before we see it
+                None if state.head_held.load(Ordering::Relaxed) => {
+                    return Err(ForceSendError::Held(val))
+                }
+                // The consumer is receiving the head, or another producer
+                // writing it, and the slot is back as soon as it is done
+                None => spin_loop(),
+            }
+        }
+    }
+
+    // Moves the head of the full buffer out and hands its slot back, unless
+    // the consumer claimed it or it is not published yet. The buffer being
+    // full, the head is at released_index, and ours if read_index is there
+    // as well. The consumer and the other producers claim it through the
+    // same CAS, so only one of us gets it. Acquire, to see what a
+    // PeekMutGuard changed before giving its claim back.
+    fn evict_head(&self, released_index: usize) -> Option<T> {
+        let state = self.state;
+        if !index::has_message(released_index, state.write_index.load(Ordering::Acquire)) {
+            return None;
+        }
+        let next = index::advance(released_index, 1);
+        state
+            .read_index
+            .compare_exchange(released_index, next, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        let evicted = self
+            .slot(released_index)
+            .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+        #[cfg(feature = "record")]
+        state.recorder.record(Op::Evict, released_index, 1);
+        // Unless the consumer has received past the slot by now and handed
+        // it back already. No producer claims it before we are done.
+        let _ = state.released_index.compare_exchange(
+            released_index,
+            next,
+            Ordering::Release,
+            Ordering::Relaxed,
+        );
+        Some(evicted)
+    }
+
+    // Writes val to the slot claimed at position and hands it over to the
+    // consumer
+    pub(crate) fn push(&self, position: usize, val: T) {
+        self.slot(position)
+            .with_mut(|slot| unsafe { (*slot).write(val) });
+        self.commit(position, 1);
+    }
+
+    // Hands the count slots written from position on over to the consumer.
+    // The lone producer publishes them itself. One of several does so as
+    // well if write_index is at its slots already, or else marks them
+    // written, and either way goes on to publish what the others marked, see
+    // publish_written.
+    fn commit(&self, position: usize, count: usize) {
+        let state = self.state;
+        let end = index::advance(position, count);
+        #[cfg(feature = "latency")]
+        state.latency.sent(position, end);
+        let Some(written) = &state.written else {
+            // Release the slot contents (and stamps) along with the index
+            state.write_index.store(end, Ordering::Release);
+            self.sent(position, end);
+            return;
+        };
+        let published = if state.write_index.load(Ordering::Acquire) == position {
+            // Nobody moves write_index over a slot that is not marked, so it
+            // is ours to store
+            state.write_index.store(end, Ordering::Release);
+            Some(position)
+        } else {
+            for position in index::range(position, end) {
+                // releases the slot contents (and stamps) to whoever
+                // publishes them
+                written[index::slot(position, self.capacity())]
+                    .store(index::advance(position, 1), Ordering::Release);
+            }
+            None
+        };
+        // before looking at the marks of the others, see publish_written
+        fence(Ordering::SeqCst);
+        self.publish_written(written, published, end);
+    }
+
+    // Moves write_index over the slots marked written after it, on behalf of
+    // whichever producers wrote them. Every producer marks its slots (or
+    // moves write_index over them) and then, past a SeqCst fence, looks at
+    // the marks after write_index. Of two producers, the one that does so
+    // later sees what the other did, so the last one done finds every slot
+    // before its own published or marked, and write_index never stops short
+    // of a written slot once the producers are done.
+    //
+    // published is where write_index was if commit moved it on up to end
+    // already, and the slots this call publishes are sent along with those.
+    fn publish_written(&self, written: &[AtomicUsize], mut published: Option<usize>, end: usize) {
+        let state = self.state;
+        let mut previous = match published {
+            Some(_) => end,
+            None => state.write_index.load(Ordering::Acquire),
+        };
+        loop {
+            let mut end = previous;
+            while written[index::slot(end, self.capacity())].load(Ordering::Acquire)
+                == index::advance(end, 1)
+            {
+                end = index::advance(end, 1);
+            }
+            if end == previous {
+                break;
+            }
+            // releases the contents acquired with the marks to the consumer
+            match state.write_index.compare_exchange(
+                previous,
+                end,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    published.get_or_insert(previous);
+                }
+                Err(current) => {
+                    // another producer moved it on, what we published so far
+                    // ends here
+                    if let Some(published) = published.take() {
+                        self.sent(published, previous);
+                    }
+                    previous = current;
+                    continue;
+                }
+            }
+            previous = end;
+        }
+        if let Some(published) = published {
+            self.sent(published, previous);
+        }
+    }
+
+    // Called once write_index moved on from previous to end, by the producer
+    // that moved it
+    fn sent(&self, previous: usize, end: usize) {
+        let state = self.state;
+        state.consumers.notify();
+        #[cfg(feature = "std")]
+        self.published(previous);
+        trace_event!(trace, state, count = index::len(previous, end), "send");
+        #[cfg(feature = "record")]
+        state
+            .recorder
+            .record(Op::Send, previous, index::len(previous, end));
+
+        // The copy of the released index can only make the buffer look
+        // fuller than it is, so take a fresh look before raising the mark.
+        // With several producers, the consumer may be past end by now.
+        let high_water_mark = state.high_water_mark.load(Ordering::Relaxed);
+        let cached = state.cached_released_index.load(Ordering::Relaxed);
+        if index::len(cached, end) > high_water_mark {
+            let released_index = state.released_index.load(Ordering::Acquire);
+            state
+                .cached_released_index
+                .store(released_index, Ordering::Release);
+            let len = index::len(released_index, end);
+            if len > high_water_mark && len <= self.capacity() {
+                state.high_water_mark.fetch_max(len, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Tells the consumer that the buffer is no longer empty, if it was before
+    // the send that moved write_index on from previous and the consumer has
+    // not taken anything since. After the fence in notify, see hooks.rs.
+    #[cfg(feature = "std")]
+    fn published(&self, previous: usize) {
+        let state = self.state;
+        state.watermarks.sent(|| self.len());
+        let hook = state.on_message.is_installed();
+        #[cfg(all(feature = "fd", unix, not(loom)))]
+        let notifier = state.notifier.get();
+        #[cfg(all(feature = "fd", unix, not(loom)))]
+        let hook = hook || notifier.is_some();
+        if !hook || state.read_index.load(Ordering::Relaxed) != previous {
+            return;
+        }
+        #[cfg(all(feature = "fd", unix, not(loom)))]
+        if let Some(notifier) = notifier {
+            notifier.signal();
+        }
+        if state.on_message.is_installed() {
+            state.on_message.run();
+        }
+    }
+
+    // Called once the consumer handed back the slots from previous up to end,
+    // by the calls that receive
+    fn received(&self, previous: usize, end: usize) {
+        trace_event!(trace, self.state, count = index::len(previous, end), "recv");
+        #[cfg(feature = "record")]
+        self.state
+            .recorder
+            .record(Op::Recv, previous, index::len(previous, end));
+        #[cfg(feature = "latency")]
+        self.state.latency.received(previous, end);
+        #[cfg(feature = "std")]
+        self.released(previous);
+    }
+
+    // Tells the producers that the buffer is no longer full, if it was before
+    // the receive that moved read_index on from previous and they have not
+    // sent anything since. After the fence in notify, like published.
+    #[cfg(feature = "std")]
+    fn released(&self, previous: usize) {
+        let state = self.state;
+        state.watermarks.received(|| self.len());
+        if state.on_space.is_installed()
+            && index::is_full(previous, self.claimed_index(), self.capacity())
+        {
+            state.on_space.run();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn send_all<I>(&self, iter: I) -> Result<usize, (usize, SendError<T>)>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut sent = 0;
+        for val in iter {
+            self.send(val).map_err(|err| (sent, err))?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    // Moves messages from iter into the free slots without waiting for more
+    // room, and publishes all of them at once. iter is only advanced for
+    // messages that fit.
+    pub(crate) fn send_iter<I>(&self, iter: I) -> Result<usize, SendError<()>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let state = self.state;
+        if state.multi_producer() {
+            // Only the batch knows how many slots it fills, so it claims them
+            // all under the reservation, which lets it give back the rest
+            let (position, free) = match self.try_reserve(1, true) {
+                Ok(slots) => slots,
+                Err(TrySendError::Disconnected(())) => return Err(SendError(())),
+                Err(TrySendError::Full(())) => return Ok(0),
+            };
+            let sent = self.write_iter(position, free, iter);
+            self.commit_vacant(position, sent);
+            return Ok(sent);
+        }
+        if self.is_disconnected_from_consumer() {
+            return Err(SendError(()));
+        }
+        // a reserved slot comes first, nothing can be published before it
+        if state.slot_reserved.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let write_index: usize = state.write_index.load(Ordering::Relaxed);
+        // A fresh look rather than the copy, which would make the batch
+        // smaller than it can be
+        let released_index = self.released_index_for(write_index, self.capacity());
+        let free = self.capacity() - index::len(released_index, write_index);
+
+        let sent = self.write_iter(write_index, free, iter);
+        if sent > 0 {
+            self.commit(write_index, sent);
+        }
+        #[cfg(feature = "stats")]
+        state.stats.record_sends(sent);
+        Ok(sent)
+    }
+
+    // Moves up to count messages from iter into the slots from position on
+    // and returns how many it moved. Should iter panic, the messages written
+    // so far are leaked.
+    fn write_iter<I>(&self, position: usize, count: usize, iter: I) -> usize
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut end = position;
+        for val in iter.into_iter().take(count) {
+            self.slot(end)
+                .with_mut(|slot| unsafe { (*slot).write(val) });
+            end = index::advance(end, 1);
+        }
+        index::len(position, end)
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn flush(&self) -> Result<(), FlushError> {
+        let state = self.state;
+        // Once the released index caught up with the slots claimed by now,
+        // everything sent before has been delivered (or evicted by
+        // force_send)
+        let claimed = self.claimed_index();
+        let is_drained =
+            || !index::has_message(state.released_index.load(Ordering::Acquire), claimed);
+        let is_disconnected = || state.consumer_counter.load(Ordering::Relaxed) == 0;
+        let mut waiter = Waiter::new(state.wait_strategy);
+        while !is_drained() {
+            if is_disconnected() {
+                return Err(FlushError);
+            }
+            waiter.wait(&state.producers, None, || is_drained() || is_disconnected());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into(&self, dst: &mut MaybeUninit<T>) -> Result<(), RecvError> {
+        self.recv_into_until(dst, None).map_err(|_| RecvError)
+    }
+
+    // Like recv_into_until, with the deadline timeout from now. A timeout too
+    // large to represent as an Instant is as good as none.
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into_timeout(
+        &self,
+        dst: &mut MaybeUninit<T>,
+        timeout: Duration,
+    ) -> Result<(), RecvTimeoutError> {
+        self.recv_into_until(dst, Instant::now().checked_add(timeout))
+    }
+
+    // Like recv_into, but gives up once the deadline (if any) has passed
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into_until(
+        &self,
+        dst: &mut MaybeUninit<T>,
+        deadline: Option<Instant>,
+    ) -> Result<(), RecvTimeoutError> {
+        let read_index = self.wait_for_message(deadline, None, self.wait_for_producer(deadline))?;
+        self.take_head(read_index, dst);
+        Ok(())
+    }
+
+    // Like recv_into, but gives up once the token is cancelled
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into_cancellable(
+        &self,
+        dst: &mut MaybeUninit<T>,
+        cancel: &CancelToken,
+    ) -> Result<(), RecvCancelError> {
+        let mut waiter = Waiter::new(self.state.wait_strategy);
+        let on_empty = || {
+            waiter.wait_any([&self.state.consumers, cancel.waiters()], None, || {
+                self.message_ready() || cancel.is_cancelled()
+            })
+        };
+        let read_index = self
+            .wait_for_message(None, Some(cancel), on_empty)
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => RecvCancelError::Cancelled,
+                RecvTimeoutError::Disconnected => RecvCancelError::Disconnected,
+            })?;
+        self.take_head(read_index, dst);
+        Ok(())
+    }
+
+    // Like recv_into, and returns the position the message was sent at
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into_seq(&self, dst: &mut MaybeUninit<T>) -> Result<usize, RecvError> {
+        let read_index = self
+            .wait_for_message(None, None, self.wait_for_producer(None))
+            .map_err(|_| RecvError)?;
+        self.take_head(read_index, dst);
+        Ok(read_index)
+    }
+
+    pub(crate) fn try_recv_into(&self, dst: &mut MaybeUninit<T>) -> Result<(), TryRecvError> {
+        let result = self.try_message();
+        #[cfg(feature = "stats")]
+        if let Err(TryRecvError::Empty) = result {
+            self.state.stats.record_failed_try_recv();
+        }
+        #[cfg(feature = "record")]
+        if let Err(TryRecvError::Empty) = result {
+            self.state.recorder.record(
+                Op::FailedTryRecv,
+                self.state.read_index.load(Ordering::Relaxed),
+                0,
+            );
+        }
+        let read_index = result?;
+        self.take_head(read_index, dst);
+        Ok(())
+    }
+
+    // Like recv_into, but calls on_empty instead of parking
+    #[cfg(feature = "std")]
+    pub(crate) fn recv_into_or_else(
+        &self,
+        dst: &mut MaybeUninit<T>,
+        on_empty: impl FnMut(),
+    ) -> Result<(), RecvError> {
+        let read_index = self
+            .wait_for_message(None, None, on_empty)
+            .map_err(|_| RecvError)?;
+        self.take_head(read_index, dst);
+        Ok(())
+    }
+
+    // Moves the message claimed at read_index into dst and releases its slot
+    fn take_head(&self, read_index: usize, dst: &mut MaybeUninit<T>) {
+        self.slot(read_index).with_mut(|slot| unsafe {
+            // Copy the payload straight out of the slot, moving the released
+            // index on is what marks the slot as empty
+            ptr::copy_nonoverlapping((*slot).as_ptr(), dst.as_mut_ptr(), 1);
+        });
+        self.release(read_index, index::advance(read_index, 1));
+        #[cfg(feature = "stats")]
+        self.state.stats.record_recv();
+    }
+
+    // Hands the slots the consumer claimed from read_index up to end back to
+    // the producer, once it is done with their messages
+    fn release(&self, read_index: usize, end: usize) {
+        // Release, so the producer only reuses the slots once we are done
+        self.state.released_index.store(end, Ordering::Release);
+        self.state.producers.notify();
+        self.received(read_index, end);
+    }
+
+    // Moves as many queued messages as fit into out, without waiting for
+    // more. Returns how many leading entries of out were initialized.
+    pub(crate) fn recv_into_slice(&self, out: &mut [MaybeUninit<T>]) -> usize {
+        let state = self.state;
+        let (read_index, end) = loop {
+            let read_index = state.read_index.load(Ordering::Relaxed);
+            let write_index = state.write_index.load(Ordering::Acquire);
+            if out.is_empty() || !index::has_message(read_index, write_index) {
+                return 0;
+            }
+            let count = index::len(read_index, write_index).min(out.len());
+            let end = index::advance(read_index, count);
+            // as in try_message
+            if state
+                .read_index
+                .compare_exchange(read_index, end, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                break (read_index, end);
+            }
+        };
+
+        // slot() maps each position onto the buffer, so a run that wraps
+        // around the end needs no special casing
+        for (position, dst) in index::range(read_index, end).zip(out.iter_mut()) {
+            self.slot(position).with_mut(|slot| unsafe {
+                ptr::copy_nonoverlapping((*slot).as_ptr(), dst.as_mut_ptr(), 1);
+            });
+            #[cfg(feature = "stats")]
+            state.stats.record_recv();
+        }
+        self.release(read_index, end);
+        index::len(read_index, end)
+    }
+
+    // Shuts the channel down for both sides without dropping a handle
+    // Releases what the closing side sent before, so the consumer can drain
+    // it once it sees the flag
+    pub(crate) fn close(&self) {
+        self.state.closed.store(true, Ordering::Release);
+        trace_event!(debug, self.state, "closed");
+        self.state.consumers.notify();
+        self.state.producers.notify();
+        #[cfg(all(feature = "fd", unix, not(loom)))]
+        self.signal_disconnect();
+    }
+
+    // Called as a handle or guard is dropped. If its thread is unwinding,
+    // the panic may have cut an operation short, or taken down the only
+    // thread that would ever have sent or received again: closing keeps the
+    // other side from waiting for it forever.
+    #[cfg(feature = "std")]
+    pub(crate) fn poison_if_panicking(&self) {
+        if std::thread::panicking() && !self.state.poisoned.swap(true, Ordering::Relaxed) {
+            trace_event!(debug, self.state, "poisoned");
+            #[cfg(feature = "record")]
+            self.state.recorder.dump(self.capacity());
+            self.close();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.state.poisoned.load(Ordering::Relaxed)
+    }
+
+    // Called by the handles when a producer goes away. Releases our sends to
+    // a consumer that finds the counter at 0.
+    pub(crate) fn drop_producer(&self) {
+        #[cfg(feature = "std")]
+        self.poison_if_panicking();
+        let producers = self.state.producer_counter.fetch_sub(1, Ordering::Release);
+        if producers == 1 {
+            trace_event!(debug, self.state, "producer disconnected");
+        }
+        self.state.consumers.notify();
+        #[cfg(all(feature = "fd", unix, not(loom)))]
+        self.signal_disconnect();
+    }
+
+    // A receive reports Disconnected now (once drained), which the consumer
+    // has to learn of as well
+    #[cfg(all(feature = "fd", unix, not(loom)))]
+    fn signal_disconnect(&self) {
+        if let Some(notifier) = self.state.notifier.get() {
+            notifier.signal();
+        }
+    }
+
+    // The notifier of the channel, created on the first call. Only the
+    // consumer calls it.
+    #[cfg(all(feature = "fd", unix, not(loom)))]
+    pub(crate) fn notifier(&self) -> io::Result<&'a Notifier> {
+        let state = self.state;
+        if let Some(notifier) = state.notifier.get() {
+            return Ok(notifier);
+        }
+        let _ = state.notifier.set(Notifier::new()?);
+        // Pairs with the fence of a producer that publishes before it sees
+        // the notifier: then the consumer sees its message
+        crate::primitives::fence(Ordering::SeqCst);
+        let notifier = state.notifier.get().expect("notifier was just set");
+        // there may be messages from before already
+        notifier.signal();
+        Ok(notifier)
+    }
+
+    // Called by the handles when a consumer goes away
+    pub(crate) fn drop_consumer(&self) {
+        #[cfg(feature = "std")]
+        self.poison_if_panicking();
+        let consumers = self.state.consumer_counter.fetch_sub(1, Ordering::Release);
+        if consumers == 1 {
+            trace_event!(debug, self.state, "consumer disconnected");
+        }
+        self.state.producers.notify();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::Acquire)
+    }
+
+    // Drops all queued messages, returning how many there were
+    pub(crate) fn clear(&self) -> usize {
+        let state = self.state;
+        // Claims them all at once, as far as force_send leaves them to us
+        let (read_index, write_index) = loop {
+            let read_index = state.read_index.load(Ordering::Relaxed);
+            let write_index = state.write_index.load(Ordering::Acquire);
+            if !index::has_message(read_index, write_index) {
+                return 0;
+            }
+            if state
+                .read_index
+                .compare_exchange(
+                    read_index,
+                    write_index,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break (read_index, write_index);
+            }
+        };
+        self.drop_claimed(read_index, write_index);
+        state.producers.notify();
+        #[cfg(feature = "std")]
+        self.released(read_index);
+        index::len(read_index, write_index)
+    }
+
+    // Drops the messages claimed from read_index up to end and gives the
+    // claim back from end on
+    fn drop_claimed(&self, read_index: usize, end: usize) {
+        let mut unclaim = Unclaim {
+            read_index: &self.state.read_index,
+            position: read_index,
+        };
+        for position in index::range(read_index, end) {
+            let val = self
+                .slot(position)
+                .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+            // Release each slot before dropping its message, so a panicking
+            // T::drop leaves the remaining ones consistent
+            unclaim.position = index::advance(position, 1);
+            self.state
+                .released_index
+                .store(unclaim.position, Ordering::Release);
+            drop(val);
+        }
+    }
+
+    // Drops the messages still queued once no endpoint is left. Unlike
+    // clear, this drops the ones a leaked guard claimed as well, which it
+    // would never give back.
+    //
+    // Safety: there must be no other access to the ring, now or later.
+    pub(crate) unsafe fn drop_queued(&self) {
+        let state = self.state;
+        let write_index = state.write_index.load(Ordering::Acquire);
+        let released_index = state.released_index.load(Ordering::Acquire);
+        self.drop_claimed(released_index, write_index);
+    }
+
+    // Waits for a message and returns a pointer to it without releasing the
+    // slot. The head stays claimed, so the caller must not recv again before
+    // calling release_head.
+    #[cfg(feature = "std")]
+    pub(crate) fn peek_head(&self) -> Result<*const T, RecvError> {
+        let read_index = self
+            .wait_for_message(None, None, self.wait_for_producer(None))
+            .map_err(|_| RecvError)?;
+        self.state.head_held.store(true, Ordering::Relaxed);
+        let val = self
+            .slot(read_index)
+            .with_mut(|slot| unsafe { (*slot).as_ptr() });
+        Ok(val)
+    }
+
+    // Like peek_head, but returns None instead of waiting for a message
+    pub(crate) fn try_peek_head(&self) -> Option<*mut T> {
+        let read_index = self.try_message().ok()?;
+        self.state.head_held.store(true, Ordering::Relaxed);
+        let val = self
+            .slot(read_index)
+            .with_mut(|slot| unsafe { (*slot).as_mut_ptr() });
+        Some(val)
+    }
+
+    // The position of the message peek_head claimed, just before read_index.
+    // force_send does not move read_index while the claim lasts.
+    fn claimed_head(&self) -> usize {
+        self.state
+            .read_index
+            .load(Ordering::Relaxed)
+            .wrapping_sub(1)
+    }
+
+    // Drops the message at the head and hands its slot back to the producer
+    #[cfg(feature = "std")]
+    pub(crate) fn release_head(&self) {
+        drop(self.pop_head());
+    }
+
+    // Moves the message at the head out and hands its slot back to the
+    // producer
+    pub(crate) fn pop_head(&self) -> T {
+        // the claim is still ours from peek_head
+        let read_index = self.claimed_head();
+        self.let_go_of_head();
+        let val = self
+            .slot(read_index)
+            .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+        self.release(read_index, index::advance(read_index, 1));
+        #[cfg(feature = "stats")]
+        self.state.stats.record_recv();
+        val
+    }
+
+    // Like peek_head, but for every queued message. Returns the read index
+    // of the first one and their number.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn claim_occupied(&self) -> Result<(usize, usize), RecvError> {
+        let read_index = self
+            .wait_for_message(None, None, self.wait_for_producer(None))
+            .map_err(|_| RecvError)?;
+        self.state.head_held.store(true, Ordering::Relaxed);
+        // With the head claimed, read_index is ours alone to move, on to
+        // everything queued by now
+        let write_index = self.state.write_index.load(Ordering::Acquire);
+        self.state.read_index.store(write_index, Ordering::Relaxed);
+        Ok((read_index, index::len(read_index, write_index)))
+    }
+
+    // Drops the first count of the messages from claim_occupied, hands their
+    // slots back to the producer and gives up the claim on the rest, even
+    // if a T::drop panics
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn release_occupied(&self, read_index: usize, count: usize) {
+        let end = index::advance(read_index, count);
+        self.let_go_of_head();
+        self.drop_claimed(read_index, end);
+        self.state.producers.notify();
+        self.received(read_index, end);
+        #[cfg(feature = "stats")]
+        self.state.stats.record_recvs(count);
+    }
+
+    // Gives up the claim of claim_occupied, the messages stay queued
+    #[cfg(all(feature = "std", not(loom)))]
+    pub(crate) fn unclaim_occupied(&self, read_index: usize) {
+        self.let_go_of_head();
+        // as in unclaim_head
+        self.state.read_index.store(read_index, Ordering::Release);
+    }
+
+    // Gives up the claim of peek_head, the message stays queued. Release,
+    // so force_send sees what a PeekMutGuard changed should it evict the
+    // message next.
+    pub(crate) fn unclaim_head(&self) {
+        self.let_go_of_head();
+        self.state
+            .read_index
+            .store(self.claimed_head(), Ordering::Release);
+    }
+
+    // Clears head_held ahead of giving the claim of a guard back, from then
+    // on force_send waits for the claim instead of failing. The store of
+    // the claim is released, so it never shows before this.
+    fn let_go_of_head(&self) {
+        self.state.head_held.store(false, Ordering::Relaxed);
+    }
+
+    // Waits until a message is available, claims it and returns its read
+    // index. Without a deadline or a
+    // token, this only fails with Disconnected. The waiting itself is done
+    // by on_empty, which runs between attempts.
+    #[cfg(feature = "std")]
+    fn wait_for_message(
+        &self,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+        mut on_empty: impl FnMut(),
+    ) -> Result<usize, RecvTimeoutError> {
+        #[cfg(feature = "stats")]
+        let mut stall = None;
+        #[cfg(feature = "tracing")]
+        let mut span = None;
+        loop {
+            match self.try_message() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            // as in wait_for_slot
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                || cancel.is_some_and(CancelToken::is_cancelled)
+            {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            #[cfg(feature = "stats")]
+            if stall.is_none() {
+                stall = Some(self.state.stats.record_empty_stall());
+            }
+            #[cfg(feature = "tracing")]
+            if span.is_none() {
+                let label = self.state.label;
+                span = Some(tracing::debug_span!("blocked_on_empty", channel = label).entered());
+            }
+            on_empty();
+        }
+    }
+
+    // Waits until count messages are queued, or fewer once no more are
+    // coming. Returns how many are queued then, and fails only if that is
+    // none at all.
+    #[cfg(feature = "std")]
+    pub(crate) fn wait_for_messages(&self, count: usize) -> Result<usize, RecvError> {
+        let mut waiter = Waiter::new(self.state.wait_strategy);
+        loop {
+            // Before the length: once the producers are gone, the length we
+            // load next is final
+            let disconnected = self.is_disconnected_from_producers();
+            let len = self.len();
+            if len >= count {
+                return Ok(len);
+            }
+            if disconnected {
+                return if len == 0 { Err(RecvError) } else { Ok(len) };
+            }
+            // every send notifies, so this wakes up as the queue grows
+            waiter.wait(&self.state.consumers, None, || {
+                self.len() >= count || self.is_disconnected_from_producers()
+            });
+        }
+    }
+
+    // The async counterpart of recv_into, see poll_send
+    #[cfg(feature = "std")]
+    pub(crate) fn poll_recv_into(
+        &self,
+        dst: &mut MaybeUninit<T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), RecvError>> {
+        loop {
+            match self.try_message() {
+                Ok(read_index) => {
+                    self.take_head(read_index, dst);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(TryRecvError::Disconnected) => return Poll::Ready(Err(RecvError)),
+                Err(TryRecvError::Empty) => {}
+            }
+            self.state.consumers.register(cx.waker());
+            if !self.message_ready() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    // The on_empty of wait_for_message for the blocking calls, which waits
+    // as the channel's strategy says
+    #[cfg(feature = "std")]
+    fn wait_for_producer(&self, deadline: Option<Instant>) -> impl FnMut() + '_ {
+        let mut waiter = Waiter::new(self.state.wait_strategy);
+        move || waiter.wait(&self.state.consumers, deadline, || self.message_ready())
+    }
+
+    // Whether try_message would not report Empty, checked before parking
+    #[cfg(feature = "std")]
+    fn message_ready(&self) -> bool {
+        self.state.message_ready()
+    }
+
+    // A single attempt of wait_for_message
+    fn try_message(&self) -> Result<usize, TryRecvError> {
+        let state = self.state;
+        // Nothing but a bug creates a second consumer, and two of them would
+        // race for the same head slot. (The producer counter may legitimately
+        // exceed 1 on the channels of channel_mpsc, whose producers claim
+        // their slots by CAS.)
+        debug_assert!(
+            state.consumer_counter.load(Ordering::Relaxed) <= 1,
+            "more than one consumer on a single consumer channel"
+        );
+        loop {
+            // Our own read index may be stale if force_send moved it, then
+            // the claim below fails and we look again
+            let read_index: usize = state.read_index.load(Ordering::Relaxed);
+
+            // If the write_index changes after the load, it is okay because
+            // the write index will always be greater than the read index and
+            // the producer ensures, that the write index never overtakes the
+            // released index when wrapping around the buffer
+            if !index::has_message(read_index, self.write_index_for(read_index)) {
+                // When no producer is active (or the channel was closed) and
+                // the consumer read all messages, we are done. A producer
+                // publishes its messages before it drops its counter, so look
+                // at the write index once more to not miss the last ones.
+                // (Dropping the counter releases, is_closed acquires.)
+                if !self.is_disconnected_from_producers() {
+                    return Err(TryRecvError::Empty);
+                }
+                if !index::has_message(read_index, state.write_index.load(Ordering::Acquire)) {
+                    return Err(TryRecvError::Disconnected);
+                }
+            }
+
+            // Only force_send competes for the message, by moving read_index
+            // past it as well. The slot contents were acquired along with the
+            // write index above, so the claim itself orders nothing.
+            if state
+                .read_index
+                .compare_exchange(
+                    read_index,
+                    index::advance(read_index, 1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Ok(read_index);
+            }
+        }
+    }
+
+    // The write index as far as the consumer needs to know: the copy, unless
+    // it shows no message at read_index. Acquiring the write index makes the
+    // contents of the slots before it visible. The copy is acquired and
+    // released as well, in case the consumer is shared between threads.
+    fn write_index_for(&self, read_index: usize) -> usize {
+        let state = self.state;
+        let cached = state.cached_write_index.load(Ordering::Acquire);
+        if index::has_message(read_index, cached) {
+            return cached;
+        }
+        let write_index = state.write_index.load(Ordering::Acquire);
+        state
+            .cached_write_index
+            .store(write_index, Ordering::Release);
+        write_index
+    }
+}
+
+// Proofs that the ring itself, not only the index arithmetic of index.rs,
+// keeps its indices consistent. Each harness runs any sequence of the
+// operations of both sides on a ring of two slots, one after the other, and
+// checks the atomics after every step against a model that only counts
+// messages. The messages are their sequence numbers, so the order is
+// checked along the way.
+//
+//     cargo kani --lib
+#[cfg(kani)]
+mod verification {
+    use super::*;
+
+    const CAPACITY: usize = 2;
+    const STEPS: usize = 6;
+
+    fn buffer() -> [Slot<usize>; CAPACITY] {
+        core::array::from_fn(|_| UnsafeCell::new(MaybeUninit::uninit()))
+    }
+
+    // head is the next message the consumer gets, tail the next one sent,
+    // so tail - head are queued, a claimed one included
+    fn assert_indices(state: &State, head: usize, tail: usize, claimed: bool) {
+        let released_index = state.released_index.load(Ordering::Relaxed);
+        let read_index = state.read_index.load(Ordering::Relaxed);
+        let write_index = state.write_index.load(Ordering::Relaxed);
+        assert_eq!(released_index, head);
+        assert_eq!(write_index, tail);
+        assert_eq!(read_index, index::advance(head, claimed as usize));
+        assert!(index::len(released_index, write_index) <= CAPACITY);
+        // the producers' copy only ever lags
+        let cached = state.cached_released_index.load(Ordering::Relaxed);
+        assert!(index::len(cached, write_index) <= CAPACITY);
+    }
+
+    // The lone producer sends, evicts with force_send, the consumer
+    // receives or holds the head as a PeekGuard does, from any start
+    // position, the wrap included
+    #[kani::proof]
+    #[kani::unwind(7)]
+    fn any_spsc_sequence_keeps_the_indices() {
+        let buffer = buffer();
+        let state = State::new(WaitStrategy::BusySpin);
+        let start: usize = kani::any();
+        for index in [
+            &state.read_index,
+            &state.write_index,
+            &state.released_index,
+            &state.cached_released_index,
+            &state.cached_write_index,
+        ] {
+            index.store(start, Ordering::Relaxed);
+        }
+        let ring: Ring<'_, usize, CAPACITY> = Ring {
+            buffer: &buffer,
+            state: &state,
+        };
+        let (mut head, mut tail) = (start, start);
+        let mut claimed = false;
+        for _ in 0..STEPS {
+            let queued = index::len(head, tail);
+            match kani::any::<u8>() {
+                0 => match ring.try_send(tail) {
+                    Ok(()) => tail = index::advance(tail, 1),
+                    Err(err) => assert!(err.is_full() && queued == CAPACITY),
+                },
+                1 => match ring.force_send(tail) {
+                    Ok(evicted) => {
+                        if let Some(val) = evicted {
+                            assert!(queued == CAPACITY && val == head);
+                            head = index::advance(head, 1);
+                        }
+                        tail = index::advance(tail, 1);
+                    }
+                    // only ever for a guard on the head of the full buffer
+                    Err(err) => assert!(err.is_held() && claimed && queued == CAPACITY),
+                },
+                2 if !claimed => {
+                    let mut val = MaybeUninit::uninit();
+                    match ring.try_recv_into(&mut val) {
+                        Ok(()) => {
+                            assert_eq!(unsafe { val.assume_init() }, head);
+                            head = index::advance(head, 1);
+                        }
+                        Err(_) => assert_eq!(queued, 0),
+                    }
+                }
+                3 if !claimed => match ring.try_peek_head() {
+                    Some(val) => {
+                        assert_eq!(unsafe { *val }, head);
+                        claimed = true;
+                    }
+                    None => assert_eq!(queued, 0),
+                },
+                4 if claimed => {
+                    assert_eq!(ring.pop_head(), head);
+                    head = index::advance(head, 1);
+                    claimed = false;
+                }
+                5 if claimed => {
+                    ring.unclaim_head();
+                    claimed = false;
+                }
+                _ => {}
+            }
+            assert_indices(&state, head, tail, claimed);
+        }
+    }
+
+    // Two producers of channel_mpsc, one of which may stall between the
+    // claim of its slot and the write, and the consumer. write_index stops
+    // at the stalled slot, claim_index goes on past it.
+    #[kani::proof]
+    #[kani::unwind(7)]
+    fn any_mpsc_sequence_keeps_the_indices() {
+        let buffer = buffer();
+        let mut state = State::new(WaitStrategy::BusySpin);
+        state.written = Some(State::written_marks(CAPACITY));
+        let ring: Ring<'_, usize, CAPACITY> = Ring {
+            buffer: &buffer,
+            state: &state,
+        };
+        let (mut head, mut tail) = (0, 0);
+        // the position of the stalled producer's slot, also its message
+        let mut stalled: Option<usize> = None;
+        for _ in 0..STEPS {
+            let queued = index::len(head, tail);
+            match kani::any::<u8>() {
+                0 => match ring.try_send(tail) {
+                    Ok(()) => tail = index::advance(tail, 1),
+                    Err(err) => assert!(err.is_full() && queued == CAPACITY),
+                },
+                1 if stalled.is_none() => match ring.try_slot() {
+                    Ok(position) => {
+                        assert_eq!(position, tail);
+                        stalled = Some(position);
+                        tail = index::advance(tail, 1);
+                    }
+                    Err(err) => assert!(err.is_full() && queued == CAPACITY),
+                },
+                2 => {
+                    if let Some(position) = stalled.take() {
+                        ring.push(position, position);
+                    }
+                }
+                _ => {
+                    let mut val = MaybeUninit::uninit();
+                    match ring.try_recv_into(&mut val) {
+                        Ok(()) => {
+                            assert_eq!(unsafe { val.assume_init() }, head);
+                            head = index::advance(head, 1);
+                        }
+                        // nothing sent, or the next message not written yet
+                        Err(_) => assert!(queued == 0 || stalled == Some(head)),
+                    }
+                }
+            }
+            let published = stalled.unwrap_or(tail);
+            assert_eq!(state.claim_index.load(Ordering::Relaxed), tail);
+            assert_eq!(state.write_index.load(Ordering::Relaxed), published);
+            assert_eq!(state.released_index.load(Ordering::Relaxed), head);
+            assert_eq!(state.read_index.load(Ordering::Relaxed), head);
+            assert!(index::len(head, tail) <= CAPACITY);
+        }
+    }
+}
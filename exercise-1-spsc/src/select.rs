@@ -0,0 +1,318 @@
+//! Waiting on several consumers at once.
+//!
+//! A `Select` reports which of the consumers added to it has a message (or
+//! was disconnected), and the caller then receives from that one. While none
+//! has, the thread parks on the wait queues of all of them, so a send on any
+//! of the channels wakes it. Unless one of the channels does not park: the
+//! sends there wake nobody, so the thread waits the way that channel does.
+//! The consumers may carry different types, each is known by the index
+//! `recv` returned for it. The select borrows them mutably, so the receive
+//! comes once it is done with them, as in `select!`.
+
+use std::time::{Duration, Instant};
+
+use crate::ring::State;
+use crate::wait_strategy::Waiter;
+use crate::{Consumer, WaitStrategy};
+
+pub struct Select<'a> {
+    states: Vec<&'a State>,
+    // Park, or the strategy of the first consumer added that does not park
+    wait_strategy: WaitStrategy,
+    // where the next search starts, so a busy consumer does not starve the
+    // ones added after it
+    start: usize,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Select {
+            states: Vec::new(),
+            wait_strategy: WaitStrategy::Park,
+            start: 0,
+        }
+    }
+
+    /// Adds `consumer` and returns its index. It is borrowed for as long as
+    /// the select is used.
+    pub fn recv<T: Send>(&mut self, consumer: &'a mut Consumer<T>) -> usize {
+        let state = &consumer.inner.state;
+        if self.wait_strategy == WaitStrategy::Park {
+            self.wait_strategy = state.wait_strategy;
+        }
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    /// Returns the index of a consumer whose next `try_recv` does not
+    /// report `Empty`: it has a message, or its channel is disconnected.
+    /// Returns `None` if there is none right now.
+    pub fn try_ready(&mut self) -> Option<usize> {
+        let len = self.states.len();
+        let index = (0..len)
+            .map(|offset| (self.start + offset) % len)
+            .find(|&index| self.states[index].message_ready())?;
+        self.start = (index + 1) % len;
+        Some(index)
+    }
+
+    /// Like `try_ready`, but waits until a consumer is ready. The thread
+    /// parks if all the channels park, else it waits as the first one that
+    /// does not.
+    ///
+    /// Panics if no consumer was added, nothing could wake it then.
+    pub fn ready(&mut self) -> usize {
+        assert!(!self.states.is_empty(), "no consumer to wait for");
+        self.ready_until(None).unwrap()
+    }
+
+    /// Like `ready`, but returns `None` if no consumer is ready within
+    /// `timeout`. A timeout too large to represent as an Instant is as good
+    /// as none.
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Option<usize> {
+        self.ready_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Like `ready`, but returns `None` if no consumer is ready by
+    /// `deadline`.
+    pub fn ready_deadline(&mut self, deadline: Instant) -> Option<usize> {
+        self.ready_until(Some(deadline))
+    }
+
+    fn ready_until(&mut self, deadline: Option<Instant>) -> Option<usize> {
+        let mut waiter = Waiter::new(self.wait_strategy);
+        loop {
+            if let Some(index) = self.try_ready() {
+                return Some(index);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            let states = &self.states;
+            let queues = states.iter().map(|&state| &state.consumers);
+            waiter.wait_any(queues, deadline, || {
+                states.iter().any(|state| state.message_ready())
+            });
+        }
+    }
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receives from whichever of several consumers is ready first, on top of
+/// `Select`:
+///
+/// ```ignore
+/// select! {
+///     msg = cx1 => println!("cx1: {:?}", msg),
+///     msg = cx2 => println!("cx2: {:?}", msg),
+///     timeout(Duration::from_secs(1)) => println!("nothing for a second"),
+/// }
+/// ```
+///
+/// Each `msg` is the `Result<T, RecvError>` of a `recv` on its consumer,
+/// which does not wait, as the consumer is ready. A `default` arm runs if no
+/// consumer is ready right now, a `timeout` arm if none is ready in time.
+/// Without either, `select!` waits for as long as it takes. The arms are
+/// separated by commas, the consumer expressions are evaluated once each.
+#[macro_export]
+macro_rules! select {
+    (@arms [$($arms:tt)*] default => $default:expr $(,)?) => {
+        $crate::select!(@run [$($arms)*]
+            |select: &mut $crate::select::Select<'_>| select.try_ready(), $default)
+    };
+    (@arms [$($arms:tt)*] timeout($timeout:expr) => $on_timeout:expr $(,)?) => {
+        $crate::select!(@run [$($arms)*]
+            |select: &mut $crate::select::Select<'_>| select.ready_timeout($timeout), $on_timeout)
+    };
+    // Every expansion of this rule binds a consumer of its own, which the
+    // later rules tell apart by hygiene
+    (@arms [$($arms:tt)*] $msg:pat = $cx:expr => $body:expr $(, $($rest:tt)*)?) => {{
+        let consumer = &mut $cx;
+        $crate::select!(@arms [$($arms)* (consumer, $msg, $body)] $($($rest)*)?)
+    }};
+    (@arms [$($arms:tt)*]) => {
+        $crate::select!(@run [$($arms)*]
+            |select: &mut $crate::select::Select<'_>| Some(select.ready()), unreachable!())
+    };
+    (@run [$(($consumer:ident, $msg:pat, $body:expr))*] $ready:expr, $otherwise:expr) => {{
+        // dropped before the arms run, which may want the consumers back
+        let index = {
+            let mut select = $crate::select::Select::new();
+            $(select.recv(&mut *$consumer);)*
+            ($ready)(&mut select)
+        };
+        $crate::select!(@dispatch index, 0usize; [$(($consumer, $msg, $body))*] $otherwise)
+    }};
+    (@dispatch $index:ident, $arm:expr;
+        [($consumer:ident, $msg:pat, $body:expr) $($rest:tt)*] $otherwise:expr) => {
+        if $index == Some($arm) {
+            let $msg = $consumer.recv();
+            $body
+        } else {
+            $crate::select!(@dispatch $index, $arm + 1; [$($rest)*] $otherwise)
+        }
+    };
+    (@dispatch $index:ident, $arm:expr; [] $otherwise:expr) => {
+        $otherwise
+    };
+    ($($input:tt)+) => {
+        $crate::select!(@arms [] $($input)+)
+    };
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{channel, TryRecvError};
+
+    #[test]
+    fn ready_waits_for_any_consumer() {
+        let (mut px1, mut cx1) = channel::<u32>();
+        let (mut px2, mut cx2) = channel::<String>();
+        let mut select = Select::new();
+        assert_eq!(select.recv(&mut cx1), 0);
+        assert_eq!(select.recv(&mut cx2), 1);
+        assert_eq!(select.try_ready(), None);
+        assert_eq!(select.ready_timeout(Duration::from_millis(10)), None);
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            px2.send(String::from("two")).unwrap();
+            px2
+        });
+        assert_eq!(select.ready(), 1);
+        assert_eq!(select.ready_timeout(Duration::MAX), Some(1));
+        // done with the select, the consumers are free again
+        assert_eq!(cx2.try_recv().unwrap(), "two");
+        let px2 = handle.join().unwrap();
+
+        px1.send(1).unwrap();
+        let mut select = Select::new();
+        select.recv(&mut cx1);
+        select.recv(&mut cx2);
+        assert_eq!(select.ready(), 0);
+        assert_eq!(cx1.try_recv(), Ok(1));
+
+        // a disconnected consumer is ready as well, its recv does not wait
+        drop(px2);
+        let mut select = Select::new();
+        select.recv(&mut cx1);
+        select.recv(&mut cx2);
+        assert_eq!(select.ready(), 1);
+        assert_eq!(cx2.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn ready_waits_out_a_channel_that_does_not_park() {
+        let (px1, mut cx1) = channel::<u32>();
+        let (mut px2, mut cx2) = crate::channel_with_strategy::<u32>(4, WaitStrategy::Yield);
+        let mut select = Select::new();
+        select.recv(&mut cx1);
+        select.recv(&mut cx2);
+        assert_eq!(select.wait_strategy, WaitStrategy::Yield);
+
+        // the send does not notify, the select finds it all the same
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            px2.send(2).unwrap();
+            px2
+        });
+        assert_eq!(select.ready(), 1);
+        assert_eq!(cx2.try_recv(), Ok(2));
+        handle.join().unwrap();
+        drop(px1);
+        let mut select = Select::new();
+        select.recv(&mut cx1);
+        select.recv(&mut cx2);
+        assert_eq!(select.ready(), 0);
+    }
+
+    #[test]
+    fn select_macro_runs_the_ready_arm() {
+        let (mut px1, mut cx1) = channel::<u32>();
+        let (mut px2, mut cx2) = channel::<&str>();
+
+        let got = select! {
+            msg = cx1 => format!("cx1 {:?}", msg),
+            msg = cx2 => format!("cx2 {:?}", msg),
+            default => String::from("none"),
+        };
+        assert_eq!(got, "none");
+
+        let got = select! {
+            _ = cx1 => false,
+            _ = cx2 => false,
+            timeout(Duration::from_millis(10)) => true,
+        };
+        assert!(got);
+
+        px2.send("b").unwrap();
+        let mut received = Vec::new();
+        // break and continue reach the loop around the select
+        loop {
+            select! {
+                msg = cx1 => match msg {
+                    Ok(val) => received.push(val.to_string()),
+                    Err(_) => break,
+                },
+                msg = cx2 => {
+                    received.push(msg.unwrap().to_string());
+                    px1.send(1).unwrap();
+                    continue;
+                },
+            }
+            break;
+        }
+        assert_eq!(received, ["b", "1"]);
+    }
+
+    #[test]
+    fn busy_consumer_does_not_starve_others() {
+        let (mut px1, mut cx1) = channel();
+        let (mut px2, mut cx2) = channel();
+        let mut select = Select::new();
+        select.recv(&mut cx1);
+        select.recv(&mut cx2);
+        for i in 0..4 {
+            px1.send(i).unwrap();
+            px2.send(i).unwrap();
+        }
+        let order: Vec<usize> = (0..4).map(|_| select.ready()).collect();
+        assert_eq!(order, [0, 1, 0, 1]);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+    use crate::channel_with_capacity;
+
+    #[test]
+    fn send_on_either_channel_wakes_select() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(2);
+        builder.check(|| {
+            let (px1, mut cx1) = channel_with_capacity::<u32>(2);
+            let (mut px2, mut cx2) = channel_with_capacity::<u32>(2);
+            let handle = thread::spawn(move || {
+                px2.try_send(2).unwrap();
+                px1
+            });
+            let mut select = Select::new();
+            select.recv(&mut cx1);
+            select.recv(&mut cx2);
+            assert_eq!(select.ready(), 1);
+            assert_eq!(cx2.try_recv(), Ok(2));
+            drop(handle.join().unwrap());
+        });
+    }
+}
@@ -0,0 +1,551 @@
+//! A channel between two processes, on a ring that lives in a shared file
+//! mapping (a file on a tmpfs like /dev/shm keeps it in memory).
+//!
+//! One process creates the file with its end of the channel, the other
+//! opens it with the other end. The mapping starts with a header holding
+//! the indices and the state of both ends, followed by the slots; each
+//! process maps it at an address of its own, so nothing in it is a pointer.
+//! The messages are copied in and out bit for bit, which is why they have
+//! to be `Pod`.
+//!
+//! There is no way to park a thread for another process to wake, so `send`
+//! and `recv` spin and then yield while they wait. The file is left behind
+//! when both ends are gone, removing it is up to the caller.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+// The std atomics, not those of primitives: loom can not model another
+// process, and the layout must be the same in both
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+use crate::index;
+use crate::primitives::{spin_loop, CachePadded};
+use crate::{ring, RecvError, SendError, TryRecvError, TrySendError};
+
+/// Types that can be shared with another process as their bytes: they hold
+/// no pointers or references, need no drop, and every bit pattern is a
+/// valid value, as the other process may write anything.
+///
+/// # Safety
+///
+/// Implementing it for a type that does not meet all of the above is
+/// undefined behavior once a message of it is received.
+pub unsafe trait Pod: Copy + Send + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// "spsc shm" and the layout version, to reject files that are something else
+const MAGIC: u64 = 0x7370_7363_0073_686d;
+const VERSION: u64 = 1;
+
+// The state of each end
+const DETACHED: u32 = 0;
+const ATTACHED: u32 = 1;
+const GONE: u32 = 2;
+
+// A blocked send or recv spins this many times before it starts yielding
+const YIELD_AFTER: u32 = 64;
+
+#[repr(C)]
+struct Header {
+    // written last, with Release, so the rest is in once it reads right
+    magic: AtomicU64,
+    version: u64,
+    capacity: u64,
+    slot_size: u64,
+    slot_align: u64,
+    producer: AtomicU32,
+    consumer: AtomicU32,
+    read_index: CachePadded<AtomicUsize>,
+    write_index: CachePadded<AtomicUsize>,
+}
+
+// One process's view of the file: its own mapping, and the capacity as it
+// was checked when the file was opened, whatever the other side writes
+// into the header later
+struct Mapping<T> {
+    base: *mut u8,
+    len: usize,
+    capacity: usize,
+    _marker: PhantomData<T>,
+}
+
+/// Where the slots start, behind the header
+fn slots_offset<T>() -> usize {
+    mem::size_of::<Header>().next_multiple_of(mem::align_of::<T>())
+}
+
+/// The length of the file for `capacity` slots, None if it does not fit in
+/// the address space
+fn mapping_len<T>(capacity: usize) -> Option<usize> {
+    capacity
+        .checked_mul(mem::size_of::<T>())?
+        .checked_add(slots_offset::<T>())
+}
+
+impl<T: Pod> Mapping<T> {
+    fn create(path: &Path, capacity: usize) -> io::Result<Self> {
+        ring::check_capacity(capacity);
+        let len = mapping_len::<T>(capacity).expect("channel too large for the address space");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        let mapping = Self::map(&file, len, capacity)?;
+        // The file is new and zeroed, nobody uses it before we wrote the
+        // header. The magic goes last: the other side checks it first.
+        let header = mapping.base as *mut Header;
+        unsafe {
+            ptr::addr_of_mut!((*header).version).write(VERSION);
+            ptr::addr_of_mut!((*header).capacity).write(capacity as u64);
+            ptr::addr_of_mut!((*header).slot_size).write(mem::size_of::<T>() as u64);
+            ptr::addr_of_mut!((*header).slot_align).write(mem::align_of::<T>() as u64);
+        }
+        mapping.header().magic.store(MAGIC, Ordering::Release);
+        Ok(mapping)
+    }
+
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        if len < mem::size_of::<Header>() {
+            return Err(invalid("file too short for a channel"));
+        }
+        let mut mapping = Self::map(&file, len, 0)?;
+        let header = mapping.header();
+        if header.magic.load(Ordering::Acquire) != MAGIC || header.version != VERSION {
+            return Err(invalid("not a channel file"));
+        }
+        if header.slot_size != mem::size_of::<T>() as u64
+            || header.slot_align != mem::align_of::<T>() as u64
+        {
+            return Err(invalid("channel holds a different message type"));
+        }
+        // Whatever the file says, the slots have to be inside the mapping
+        let capacity = usize::try_from(header.capacity)
+            .ok()
+            .filter(|capacity| capacity.is_power_of_two());
+        let Some(capacity) = capacity
+            .filter(|&capacity| mapping_len::<T>(capacity).is_some_and(|needed| needed <= len))
+        else {
+            return Err(invalid("corrupt channel header"));
+        };
+        mapping.capacity = capacity;
+        Ok(mapping)
+    }
+
+    fn map(file: &File, len: usize, capacity: usize) -> io::Result<Self> {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The mapping stays valid once the file is closed
+        Ok(Mapping {
+            base: base as *mut u8,
+            len,
+            capacity,
+            _marker: PhantomData,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        // The mapping starts page aligned and is at least a header long
+        unsafe { &*(self.base as *const Header) }
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        let slot = index::slot(index, self.capacity);
+        unsafe { self.base.add(slots_offset::<T>()).cast::<T>().add(slot) }
+    }
+
+    // The state of the channel for the Debug output of the ends, leaving
+    // out the messages
+    fn fmt_debug(&self, name: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = self.header();
+        let read_index = header.read_index.load(Ordering::Relaxed);
+        let write_index = header.write_index.load(Ordering::Relaxed);
+        f.debug_struct(name)
+            .field("capacity", &self.capacity)
+            .field(
+                "len",
+                &index::len(read_index, write_index).min(self.capacity),
+            )
+            .field("read_index", &read_index)
+            .field("write_index", &write_index)
+            .field("producer", &header.producer.load(Ordering::Relaxed))
+            .field("consumer", &header.consumer.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+
+    // Marks this end as attached, unless it already is in another process
+    fn attach(self, state: impl Fn(&Header) -> &AtomicU32) -> io::Result<Self> {
+        match state(self.header()).compare_exchange(
+            DETACHED,
+            ATTACHED,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Ok(self),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "this end of the channel is taken",
+            )),
+        }
+    }
+}
+
+impl<T> Drop for Mapping<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.len) };
+    }
+}
+
+fn backoff(step: &mut u32) {
+    if *step < YIELD_AFTER {
+        spin_loop();
+    } else {
+        thread::yield_now();
+    }
+    *step = step.saturating_add(1);
+}
+
+pub struct Producer<T: Pod> {
+    mapping: Mapping<T>,
+}
+
+pub struct Consumer<T: Pod> {
+    mapping: Mapping<T>,
+}
+
+impl<T: Pod> Producer<T> {
+    /// Creates the channel file at `path` with `capacity` slots, for a
+    /// consumer to open. Fails if the file exists.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let mapping = Mapping::create(path.as_ref(), capacity)?;
+        Ok(Producer {
+            mapping: mapping.attach(|header| &header.producer)?,
+        })
+    }
+
+    /// Opens the channel file a consumer created. Fails if the file is not
+    /// a channel of `T`, or another producer opened it before.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mapping = Mapping::open(path.as_ref())?;
+        Ok(Producer {
+            mapping: mapping.attach(|header| &header.producer)?,
+        })
+    }
+
+    /// Sends `val` if there is a free slot right now. Fails if the consumer
+    /// is gone; one that did not open the channel yet is not.
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let mapping = &self.mapping;
+        let header = mapping.header();
+        if header.consumer.load(Ordering::Relaxed) == GONE {
+            return Err(TrySendError::Disconnected(val));
+        }
+        // our own index
+        let write_index = header.write_index.load(Ordering::Relaxed);
+        if index::is_full(
+            header.read_index.load(Ordering::Acquire),
+            write_index,
+            mapping.capacity,
+        ) {
+            return Err(TrySendError::Full(val));
+        }
+        // The consumer is done with the slot, it released it with the read
+        // index
+        unsafe { mapping.slot(write_index).write(val) };
+        header
+            .write_index
+            .store(index::advance(write_index, 1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Sends `val`, spinning and then yielding while the buffer is full.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let mut step = 0;
+        loop {
+            match self.try_send(val) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(val)) => return Err(SendError(val)),
+                Err(TrySendError::Full(_)) => backoff(&mut step),
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mapping.capacity
+    }
+}
+
+impl<T: Pod> Consumer<T> {
+    /// Creates the channel file at `path` with `capacity` slots, for a
+    /// producer to open. Fails if the file exists.
+    ///
+    /// Panics if `capacity` is 0 or not a power of two.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let mapping = Mapping::create(path.as_ref(), capacity)?;
+        Ok(Consumer {
+            mapping: mapping.attach(|header| &header.consumer)?,
+        })
+    }
+
+    /// Opens the channel file a producer created. Fails if the file is not
+    /// a channel of `T`, or another consumer opened it before.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mapping = Mapping::open(path.as_ref())?;
+        Ok(Consumer {
+            mapping: mapping.attach(|header| &header.consumer)?,
+        })
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mapping = &self.mapping;
+        let header = mapping.header();
+        // Before the index: once the producer is gone, all its messages are
+        // in
+        let producer_gone = header.producer.load(Ordering::Acquire) == GONE;
+        let read_index = header.read_index.load(Ordering::Relaxed);
+        if !index::has_message(read_index, header.write_index.load(Ordering::Acquire)) {
+            return Err(if producer_gone {
+                TryRecvError::Disconnected
+            } else {
+                TryRecvError::Empty
+            });
+        }
+        // Published by the write index, and the producer leaves the slot
+        // alone until we release it. Any bit pattern is a T.
+        let val = unsafe { mapping.slot(read_index).read() };
+        header
+            .read_index
+            .store(index::advance(read_index, 1), Ordering::Release);
+        Ok(val)
+    }
+
+    /// Waits for a message, spinning and then yielding. Fails once the
+    /// producer is gone and all its messages are received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut step = 0;
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => backoff(&mut step),
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mapping.capacity
+    }
+}
+
+// A channel of bytes as a byte stream, e.g. for `framed`. Every byte is a
+// message of its own, so this is no match for `bytes::pipe` within a process.
+impl Write for Producer<u8> {
+    // Waits for room for the first byte, the rest go in as far as they fit.
+    // Fails with BrokenPipe once the consumer is gone.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some((&first, rest)) = buf.split_first() else {
+            return Ok(0);
+        };
+        self.send(first)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let fitted = rest
+            .iter()
+            .take_while(|&&byte| self.try_send(byte).is_ok())
+            .count();
+        Ok(1 + fitted)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Consumer<u8> {
+    // Waits for the first byte, then takes what else is there. Returns 0
+    // once the producer is gone and every byte is read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((first, rest)) = buf.split_first_mut() else {
+            return Ok(0);
+        };
+        let Ok(byte) = self.recv() else {
+            return Ok(0);
+        };
+        *first = byte;
+        let mut read = 1;
+        for slot in rest {
+            let Ok(byte) = self.try_recv() else {
+                break;
+            };
+            *slot = byte;
+            read += 1;
+        }
+        Ok(read)
+    }
+}
+
+impl<T: Pod> Drop for Producer<T> {
+    fn drop(&mut self) {
+        // Release, so the consumer sees our last messages once it sees this
+        self.mapping
+            .header()
+            .producer
+            .store(GONE, Ordering::Release);
+    }
+}
+
+impl<T: Pod> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.mapping
+            .header()
+            .consumer
+            .store(GONE, Ordering::Release);
+    }
+}
+
+impl<T: Pod> fmt::Debug for Producer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.mapping.fmt_debug("Producer", f)
+    }
+}
+
+impl<T: Pod> fmt::Debug for Consumer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.mapping.fmt_debug("Consumer", f)
+    }
+}
+
+// Each end only touches its own index and the slots the protocol above
+// hands it, as in ring
+unsafe impl<T: Pod> Send for Producer<T> {}
+unsafe impl<T: Pod> Send for Consumer<T> {}
+
+// Miri does not emulate shared memory objects
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use std::os::unix::fs::FileExt;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // A file of its own for each test, removed again when done
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("spsc-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn messages_cross_separate_mappings() {
+        let path = TempPath::new("round-trip");
+        let px = Producer::<[u32; 3]>::create(&path.0, 4).unwrap();
+        // another mapping at another address, as in another process
+        let cx = Consumer::<[u32; 3]>::open(&path.0).unwrap();
+        assert_eq!(cx.capacity(), 4);
+        assert_ne!(px.mapping.base, cx.mapping.base);
+
+        let handle = thread::spawn(move || {
+            for i in 0..1000 {
+                px.send([i, i + 1, i + 2]).unwrap();
+            }
+        });
+        for i in 0..1000 {
+            assert_eq!(cx.recv(), Ok([i, i + 1, i + 2]));
+        }
+        handle.join().unwrap();
+        assert_eq!(cx.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn byte_channel_is_a_stream() {
+        let path = TempPath::new("stream");
+        let mut px = Producer::<u8>::create(&path.0, 8).unwrap();
+        let mut cx = Consumer::<u8>::open(&path.0).unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        let expected = data.clone();
+        let handle = thread::spawn(move || px.write_all(&data).unwrap());
+
+        let mut received = Vec::new();
+        cx.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn open_checks_the_file() {
+        let path = TempPath::new("checks");
+        assert!(Consumer::<u64>::open(&path.0).is_err());
+        let cx = Consumer::<u64>::create(&path.0, 8).unwrap();
+        assert_eq!(
+            Consumer::<u64>::open(&path.0).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            Producer::<u32>::open(&path.0).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // a producer may send before the consumer is there and after
+        let px = Producer::<u64>::open(&path.0).unwrap();
+        px.try_send(7).unwrap();
+        assert_eq!(cx.try_recv(), Ok(7));
+        drop(cx);
+        assert_eq!(px.try_send(8), Err(TrySendError::Disconnected(8)));
+    }
+
+    #[test]
+    fn open_rejects_a_forged_capacity() {
+        let path = TempPath::new("forged");
+        drop(Consumer::<u64>::create(&path.0, 8).unwrap());
+        // slots that would reach far beyond the end of the file, their
+        // size overflowing a usize
+        let file = OpenOptions::new().write(true).open(&path.0).unwrap();
+        let at = mem::offset_of!(Header, capacity) as u64;
+        file.write_all_at(&(1u64 << 63).to_ne_bytes(), at).unwrap();
+        let err = Producer::<u64>::open(&path.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "corrupt channel header");
+    }
+}
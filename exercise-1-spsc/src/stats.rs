@@ -0,0 +1,155 @@
+// Optional instrumentation of the channel, enabled with the `stats` feature.
+// All counters are relaxed, they are statistics and not used for
+// synchronization.
+
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use crate::primitives::AtomicU64;
+use crate::primitives::{const_fn, AtomicUsize, Ordering};
+
+/// Snapshot of the counters of a channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Messages successfully sent.
+    pub sends: usize,
+    /// Messages successfully received.
+    pub recvs: usize,
+    /// Calls to `try_send` that failed because the buffer was full.
+    pub failed_try_sends: usize,
+    /// Calls to `try_recv` that failed because the buffer was empty.
+    pub failed_try_recvs: usize,
+    /// Calls to `send` that found the buffer full and had to wait.
+    pub full_stalls: usize,
+    /// Calls to `recv` that found the buffer empty and had to wait.
+    pub empty_stalls: usize,
+    /// The time the full stalls took, together.
+    pub full_wait: Duration,
+    /// The time the empty stalls took, together.
+    pub empty_wait: Duration,
+    /// Messages queued when the snapshot was taken.
+    pub len: usize,
+    /// The most messages ever queued at once, see `high_water_mark`.
+    pub high_water_mark: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct Stats {
+    sends: AtomicUsize,
+    recvs: AtomicUsize,
+    failed_try_sends: AtomicUsize,
+    failed_try_recvs: AtomicUsize,
+    full_stalls: AtomicUsize,
+    empty_stalls: AtomicUsize,
+    // in nanoseconds
+    #[cfg(feature = "std")]
+    full_wait: AtomicU64,
+    #[cfg(feature = "std")]
+    empty_wait: AtomicU64,
+}
+
+// A stalled call, which adds the time it waited when it returns
+#[cfg(feature = "std")]
+pub(crate) struct Stall<'a> {
+    wait: &'a AtomicU64,
+    since: Instant,
+}
+
+impl Stats {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Stats {
+                sends: AtomicUsize::new(0),
+                recvs: AtomicUsize::new(0),
+                failed_try_sends: AtomicUsize::new(0),
+                failed_try_recvs: AtomicUsize::new(0),
+                full_stalls: AtomicUsize::new(0),
+                empty_stalls: AtomicUsize::new(0),
+                #[cfg(feature = "std")]
+                full_wait: AtomicU64::new(0),
+                #[cfg(feature = "std")]
+                empty_wait: AtomicU64::new(0),
+            }
+        }
+    }
+
+    pub(crate) fn record_send(&self) {
+        self.record_sends(1);
+    }
+
+    pub(crate) fn record_sends(&self, count: usize) {
+        self.sends.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_recv(&self) {
+        self.record_recvs(1);
+    }
+
+    pub(crate) fn record_recvs(&self, count: usize) {
+        self.recvs.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failed_try_send(&self) {
+        self.failed_try_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failed_try_recv(&self) {
+        self.failed_try_recvs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // stalls only happen in the calls that wait
+    #[cfg(feature = "std")]
+    pub(crate) fn record_full_stall(&self) -> Stall<'_> {
+        self.full_stalls.fetch_add(1, Ordering::Relaxed);
+        Stall::new(&self.full_wait)
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn record_empty_stall(&self) -> Stall<'_> {
+        self.empty_stalls.fetch_add(1, Ordering::Relaxed);
+        Stall::new(&self.empty_wait)
+    }
+
+    // The counters, with the depth of the buffer filled in by the caller
+    pub(crate) fn snapshot(&self, len: usize, high_water_mark: usize) -> ChannelStats {
+        #[cfg(feature = "std")]
+        let wait = |wait: &AtomicU64| Duration::from_nanos(wait.load(Ordering::Relaxed));
+        #[cfg(feature = "std")]
+        let (full_wait, empty_wait) = (wait(&self.full_wait), wait(&self.empty_wait));
+        // nothing waits without std
+        #[cfg(not(feature = "std"))]
+        let (full_wait, empty_wait) = (Duration::ZERO, Duration::ZERO);
+        ChannelStats {
+            sends: self.sends.load(Ordering::Relaxed),
+            recvs: self.recvs.load(Ordering::Relaxed),
+            failed_try_sends: self.failed_try_sends.load(Ordering::Relaxed),
+            failed_try_recvs: self.failed_try_recvs.load(Ordering::Relaxed),
+            full_stalls: self.full_stalls.load(Ordering::Relaxed),
+            empty_stalls: self.empty_stalls.load(Ordering::Relaxed),
+            full_wait,
+            empty_wait,
+            len,
+            high_water_mark,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Stall<'a> {
+    fn new(wait: &'a AtomicU64) -> Self {
+        Stall {
+            wait,
+            since: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Stall<'_> {
+    fn drop(&mut self) {
+        let nanos = self.since.elapsed().as_nanos().min(u64::MAX.into()) as u64;
+        self.wait.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
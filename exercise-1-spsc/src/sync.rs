@@ -0,0 +1,795 @@
+//! Building blocks for waiting in lock-free code, the ones the channels are
+//! made of.
+//!
+//! A `SpinLock` is for critical sections of a few instructions, where
+//! parking a thread would cost more than the wait. It works without `std`.
+//! The others need it, they put threads to sleep.
+//!
+//! An `EventCount` lets a thread sleep until some condition that lives
+//! outside of it, e.g. in a few atomics, may have changed, without a lock
+//! around the condition. The waiter announces itself with `prepare_wait`,
+//! checks the condition once more, and only then waits on the key it got.
+//! The notifier changes the state first and calls `notify` after. Either
+//! the waiter's check sees the change, or the notify sees the waiter and
+//! wakes it: the notify takes the registration of the key out, so one
+//! between the check and the wait is not lost. The waiters take slots
+//! without a lock, and a `notify` with nobody waiting is only a fence and a
+//! look at the slots.
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! use spsc::sync::EventCount;
+//!
+//! let state = Arc::new((AtomicBool::new(false), EventCount::new()));
+//! let setter = state.clone();
+//! let handle = thread::spawn(move || {
+//!     setter.0.store(true, Ordering::Release);
+//!     setter.1.notify();
+//! });
+//! let (flag, event) = &*state;
+//! while !flag.load(Ordering::Acquire) {
+//!     let key = event.prepare_wait();
+//!     if flag.load(Ordering::Acquire) {
+//!         break;
+//!     }
+//!     key.wait();
+//! }
+//! handle.join().unwrap();
+//! # }
+//! ```
+//!
+//! `Semaphore` and `Barrier` are the classic two, built on an `EventCount`.
+
+#[cfg(feature = "std")]
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use core::ptr;
+// std's even under loom: the slots are what loom checks, not the counts
+#[cfg(feature = "std")]
+use std::sync::Arc as StdArc;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use crate::primitives::{const_fn, spin_loop, AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use crate::primitives::{
+    current, fence, park, park_timeout, thread_local, AtomicPtr, AtomicUsize, Thread,
+};
+
+/// A lock that spins until it is free. The guard unlocks on drop, also when
+/// unwinding, so a panic in the critical section does not leave it locked.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    // core's cell even under loom: loom checks the flag, and the ring only
+    // locks ()
+    data: UnsafeCell<T>,
+}
+
+/// The access to the data of a locked `SpinLock`, see `SpinLock::lock`.
+#[must_use = "the lock is released right away if the guard is not kept"]
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+}
+
+// Like a Mutex: the lock hands the data from one thread to the next
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SpinLockGuard<'_, T> {}
+
+impl<T> SpinLock<T> {
+    const_fn! {
+        pub fn new(data: T) -> Self {
+            SpinLock {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(data),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Spins until the lock is free and takes it. Taking it acquires and
+    /// dropping the guard releases, so whatever the previous holder did is
+    /// visible to the next one.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self.locked.swap(true, Ordering::Acquire) {
+            spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Takes the lock if it is free, without spinning.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    /// Returns whether the lock is taken right now, a snapshot.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// The lock is not needed with exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Returns a guard for the lock, without taking it, e.g. to release a
+    /// lock whose guard was forgotten at the end of a scope.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held, and its guard forgotten.
+    pub unsafe fn make_guard_unchecked(&self) -> SpinLockGuard<'_, T> {
+        SpinLockGuard { lock: self }
+    }
+
+    /// Releases the lock, as dropping its guard would.
+    ///
+    /// # Safety
+    ///
+    /// As for `make_guard_unchecked`.
+    pub unsafe fn force_unlock(&self) {
+        drop(self.make_guard_unchecked());
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the lock is ours while the guard lives
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // A swap rather than a store: loom lets a later swap of the flag
+        // miss a plain store and would spin forever
+        self.lock.locked.swap(false, Ordering::Release);
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpinLock")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+/// See the module documentation.
+#[cfg(feature = "std")]
+pub struct EventCount {
+    // the registered threads, each in a slot of its own
+    slots: Slots,
+}
+
+// The slots of an EventCount are taken in blocks of this many, the first
+// one inline, the others allocated once that many threads wait at the same
+// time and kept from then on
+#[cfg(feature = "std")]
+const SLOTS: usize = 4;
+
+// A slot holds the PARKER of a registered thread, a reference counted in
+// its Arc. Whoever swaps it out owns that reference: the notify that
+// unparks the thread, or else the key once it is done waiting.
+#[cfg(feature = "std")]
+struct Slots {
+    threads: [AtomicPtr<Thread>; SLOTS],
+    next: AtomicPtr<Slots>,
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    // The handle of the current thread that goes into the slots, shared by
+    // all its keys so that registering allocates nothing
+    static PARKER: StdArc<Thread> = StdArc::new(current());
+}
+
+#[cfg(feature = "std")]
+/// A registration with an `EventCount`, see `EventCount::prepare_wait`.
+/// Dropping it cancels the wait.
+#[must_use = "a key does nothing unless waited on"]
+pub struct Key<'a> {
+    slot: &'a AtomicPtr<Thread>,
+    // what we put into slot, which is ours until a notify takes it out
+    thread: *mut Thread,
+}
+
+#[cfg(feature = "std")]
+impl EventCount {
+    const_fn! {
+        pub fn new() -> Self {
+            EventCount {
+                slots: Slots::new(),
+            }
+        }
+    }
+
+    /// Registers the current thread for the next `notify`. Check the
+    /// condition after this, and wait on the key only if it does not hold.
+    /// A thread holds one key of an `EventCount` at a time.
+    pub fn prepare_wait(&self) -> Key<'_> {
+        let thread = StdArc::into_raw(PARKER.with(StdArc::clone)).cast_mut();
+        let slot = self.slots.claim(thread);
+        fence(Ordering::SeqCst);
+        Key { slot, thread }
+    }
+
+    /// Wakes every thread that registered before, to be called after
+    /// changing the condition they may be waiting for.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    // The part of notify after the fence, for a caller that took it already
+    pub(crate) fn wake_all(&self) {
+        let mut slots = &self.slots;
+        loop {
+            for slot in &slots.threads {
+                // A thread that registers after our fence sees the change
+                // itself. AcqRel, to release the change to its is_notified
+                // and acquire the handle.
+                if !slot.load(Ordering::Relaxed).is_null() {
+                    let thread = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+                    if !thread.is_null() {
+                        unsafe { StdArc::from_raw(thread) }.unpark();
+                    }
+                }
+            }
+            match slots.next() {
+                Some(next) => slots = next,
+                None => return,
+            }
+        }
+    }
+
+    // Whether a thread is registered, for the tests of the channels
+    #[cfg(test)]
+    pub(crate) fn has_waiters(&self) -> bool {
+        self.waiting() != 0
+    }
+
+    fn waiting(&self) -> usize {
+        let mut waiting = 0;
+        let mut slots = Some(&self.slots);
+        while let Some(block) = slots {
+            waiting += block
+                .threads
+                .iter()
+                .filter(|slot| !slot.load(Ordering::SeqCst).is_null())
+                .count();
+            slots = block.next();
+        }
+        waiting
+    }
+
+    // Parks until notified returns true or the deadline passes. Lets Select
+    // sleep on several channels at once, with a key for each.
+    pub(crate) fn park_until(deadline: Option<Instant>, notified: impl Fn() -> bool) {
+        while !notified() {
+            match deadline {
+                None => park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return;
+                    }
+                    park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Slots {
+    const_fn! {
+        fn new() -> Self {
+            #[cfg(not(loom))]
+            let threads = [const { AtomicPtr::new(ptr::null_mut()) }; SLOTS];
+            #[cfg(loom)]
+            let threads = core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut()));
+            Slots {
+                threads,
+                next: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+    }
+
+    // Puts thread into the first free slot, adding a block if none is
+    fn claim(&self, thread: *mut Thread) -> &AtomicPtr<Thread> {
+        let mut slots = self;
+        loop {
+            for slot in &slots.threads {
+                // Release, so the notify that takes it out gets a valid handle
+                if slot
+                    .compare_exchange(
+                        ptr::null_mut(),
+                        thread,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+                {
+                    return slot;
+                }
+            }
+            slots = match slots.next() {
+                Some(next) => next,
+                None => slots.grow(),
+            };
+        }
+    }
+
+    fn next(&self) -> Option<&Slots> {
+        unsafe { self.next.load(Ordering::Acquire).as_ref() }
+    }
+
+    // Links a new block after this one, unless another thread did first
+    fn grow(&self) -> &Slots {
+        let block = Box::into_raw(Box::new(Slots::new()));
+        match self.next.compare_exchange(
+            ptr::null_mut(),
+            block,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => unsafe { &*block },
+            Err(next) => {
+                drop(unsafe { Box::from_raw(block) });
+                unsafe { &*next }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Slots {
+    fn drop(&mut self) {
+        // A key borrows the EventCount, so only a forgotten one is left
+        for slot in &self.threads {
+            let thread = slot.load(Ordering::Relaxed);
+            if !thread.is_null() {
+                drop(unsafe { StdArc::from_raw(thread) });
+            }
+        }
+        let next = self.next.load(Ordering::Relaxed);
+        if !next.is_null() {
+            drop(unsafe { Box::from_raw(next) });
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Key<'_> {
+    /// Waits for a `notify` since the key was made. Returns right away if
+    /// there was one already.
+    pub fn wait(self) {
+        EventCount::park_until(None, || self.is_notified());
+    }
+
+    /// Like `wait`, but gives up at `deadline`. Returns whether there was a
+    /// notify.
+    pub fn wait_deadline(self, deadline: Instant) -> bool {
+        EventCount::park_until(Some(deadline), || self.is_notified());
+        self.is_notified()
+    }
+
+    /// Returns whether there was a `notify` since the key was made.
+    pub fn is_notified(&self) -> bool {
+        // Acquire, so the condition the notify changed is visible to the
+        // check that follows
+        self.slot.load(Ordering::Acquire) != self.thread
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Key<'_> {
+    fn drop(&mut self) {
+        // Unless a notify took the handle out, in which case it drops it
+        if self
+            .slot
+            .compare_exchange(
+                self.thread,
+                ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            drop(unsafe { StdArc::from_raw(self.thread) });
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for EventCount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for EventCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventCount")
+            .field("waiting", &self.waiting())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A counting semaphore: hands out up to a number of permits at once and
+/// makes the others wait for one to come back.
+#[cfg(feature = "std")]
+pub struct Semaphore {
+    permits: AtomicUsize,
+    // the threads waiting for a permit
+    released: EventCount,
+}
+
+/// A permit of a `Semaphore`, given back on drop.
+#[cfg(feature = "std")]
+#[must_use = "the permit is given back right away if it is not kept"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+#[cfg(feature = "std")]
+impl Semaphore {
+    const_fn! {
+        pub fn new(permits: usize) -> Self {
+            Semaphore {
+                permits: AtomicUsize::new(permits),
+                released: EventCount::new(),
+            }
+        }
+    }
+
+    /// Waits for a permit and takes it.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            let key = self.released.prepare_wait();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            key.wait();
+        }
+    }
+
+    /// Takes a permit if one is free, without waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        loop {
+            if permits == 0 {
+                return None;
+            }
+            // Acquire, to see what the thread that gave the permit back did
+            // while holding it
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(SemaphorePermit { semaphore: self }),
+                Err(current) => permits = current,
+            }
+        }
+    }
+
+    /// Adds `count` permits, e.g. once a resource that was short grew.
+    pub fn add_permits(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::Release);
+        self.released.notify();
+    }
+
+    /// Returns how many permits are free right now, a snapshot.
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "std")]
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken for good, e.g. for a resource that is gone.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("available_permits", &self.available_permits())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lets a number of threads wait for each other, e.g. to start the two
+/// sides of a benchmark at the same time. Unlike `std::sync::Barrier`, a
+/// thread only blocks in `wait`, and it can be used round after round.
+#[cfg(feature = "std")]
+pub struct Barrier {
+    parties: usize,
+    arrived: AtomicUsize,
+    // bumped by the last one to arrive, which lets the others go
+    generation: AtomicUsize,
+    released: EventCount,
+}
+
+#[cfg(feature = "std")]
+impl Barrier {
+    /// A barrier for `parties` threads. One for 0 of them never waits, as
+    /// one for a single thread.
+    pub fn new(parties: usize) -> Self {
+        Barrier {
+            parties: parties.max(1),
+            arrived: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            released: EventCount::new(),
+        }
+    }
+
+    /// Waits until all parties have called `wait`. Returns true in exactly
+    /// one of them each round, the leader, e.g. to collect the results.
+    pub fn wait(&self) -> bool {
+        // Before arriving: the leader of this round can only bump it after
+        // our arrival
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.parties {
+            // Nobody arrives for the next round before the bump below
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation
+                .store(generation.wrapping_add(1), Ordering::Release);
+            self.released.notify();
+            return true;
+        }
+        while self.generation.load(Ordering::Acquire) == generation {
+            let key = self.released.prepare_wait();
+            if self.generation.load(Ordering::Acquire) != generation {
+                break;
+            }
+            key.wait();
+        }
+        false
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier")
+            .field("parties", &self.parties)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn notify_wakes_a_waiter() {
+        let state = Arc::new((AtomicBool::new(false), EventCount::new()));
+        let waiter = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let (flag, event) = &*state;
+                loop {
+                    let key = event.prepare_wait();
+                    if flag.load(Ordering::Acquire) {
+                        return;
+                    }
+                    key.wait();
+                }
+            })
+        };
+        while !state.1.has_waiters() {
+            thread::yield_now();
+        }
+        state.0.store(true, Ordering::Release);
+        state.1.notify();
+        waiter.join().unwrap();
+        assert!(!state.1.has_waiters());
+    }
+
+    #[test]
+    fn a_notify_before_the_wait_is_not_lost() {
+        let event = EventCount::new();
+        let key = event.prepare_wait();
+        assert!(!key.is_notified());
+        event.notify();
+        assert!(key.is_notified());
+        key.wait();
+
+        // one after the key is made does not count
+        event.notify();
+        let key = event.prepare_wait();
+        assert!(!key.wait_deadline(Instant::now() + Duration::from_millis(10)));
+        assert!(!event.has_waiters());
+    }
+
+    #[test]
+    fn notify_wakes_more_waiters_than_a_block_holds() {
+        const WAITERS: usize = 2 * SLOTS + 1;
+        let state = Arc::new((AtomicBool::new(false), EventCount::new()));
+        let waiters: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let state = state.clone();
+                thread::spawn(move || {
+                    let (flag, event) = &*state;
+                    while !flag.load(Ordering::Acquire) {
+                        let key = event.prepare_wait();
+                        if flag.load(Ordering::Acquire) {
+                            return;
+                        }
+                        key.wait();
+                    }
+                })
+            })
+            .collect();
+        while state.1.waiting() < WAITERS {
+            thread::yield_now();
+        }
+        state.0.store(true, Ordering::Release);
+        state.1.notify();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        // the blocks stay for the next round, empty
+        assert!(state.1.slots.next().is_some());
+        assert!(!state.1.has_waiters());
+    }
+
+    #[test]
+    fn spin_lock_serializes_the_increments() {
+        let counter = Arc::new(SpinLock::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *counter.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let counter = Arc::try_unwrap(counter).unwrap();
+        assert!(!counter.is_locked());
+        assert_eq!(counter.into_inner(), 4000);
+    }
+
+    #[test]
+    fn spin_lock_is_released_by_its_guard() {
+        let lock = SpinLock::new(());
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(lock.try_lock().is_some());
+
+        core::mem::forget(lock.lock());
+        assert!(lock.is_locked());
+        unsafe { lock.force_unlock() };
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn semaphore_bounds_the_holders() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let holding = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (semaphore, holding) = (semaphore.clone(), holding.clone());
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let _permit = semaphore.acquire();
+                        assert!(holding.fetch_add(1, Ordering::SeqCst) < 2);
+                        thread::yield_now();
+                        holding.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn semaphore_waits_for_a_permit() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire();
+        assert!(semaphore.try_acquire().is_none());
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || semaphore.acquire().forget())
+        };
+        while !semaphore.released.has_waiters() {
+            thread::yield_now();
+        }
+        drop(permit);
+        waiter.join().unwrap();
+        // the waiter kept it
+        assert_eq!(semaphore.available_permits(), 0);
+        semaphore.add_permits(3);
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn barrier_is_reusable() {
+        const PARTIES: usize = 4;
+        let barrier = Arc::new(Barrier::new(PARTIES));
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..PARTIES)
+            .map(|_| {
+                let (barrier, rounds) = (barrier.clone(), rounds.clone());
+                thread::spawn(move || {
+                    let mut leader = 0;
+                    for round in 0..50 {
+                        // everyone sees the count of the round before
+                        assert_eq!(rounds.load(Ordering::SeqCst), round);
+                        if barrier.wait() {
+                            rounds.fetch_add(1, Ordering::SeqCst);
+                            leader += 1;
+                        }
+                        barrier.wait();
+                    }
+                    leader
+                })
+            })
+            .collect();
+        let leaders: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(leaders, 50);
+        assert!(Barrier::new(0).wait());
+    }
+}
@@ -0,0 +1,117 @@
+// Fan-out: a thread that receives from one consumer and sends a copy of
+// every message into each of several new channels. The branches have the
+// capacity of the source, and what happens while one of them is full is up
+// to the caller, see `Lag`.
+
+use std::thread;
+
+use crate::{channel_with_capacity, Consumer, Producer};
+
+/// What `tee` does with a message for a branch whose buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lag {
+    /// Waits until the branch has room, so the slowest branch sets the pace
+    /// for all of them.
+    Wait,
+    /// Leaves the message out for that branch.
+    Skip,
+    /// Drops the oldest message of that branch to make room, see
+    /// `Producer::send_overwrite`.
+    Overwrite,
+}
+
+/// Spawns a thread that copies every message of `source` into `n` new
+/// channels and returns their consumers. The branches disconnect once
+/// `source` does and they are drained. A branch whose consumer is dropped
+/// gets no more messages; once all are gone, the thread drops `source` with
+/// the next message it receives.
+pub fn tee<T: Send + Clone + 'static>(source: Consumer<T>, n: usize, lag: Lag) -> Vec<Consumer<T>> {
+    let capacity = source.capacity();
+    let (branches, consumers) = (0..n).map(|_| channel_with_capacity(capacity)).unzip();
+    thread::spawn(move || forward(source, branches, lag));
+    consumers
+}
+
+fn forward<T: Send + Clone>(mut source: Consumer<T>, mut branches: Vec<Producer<T>>, lag: Lag) {
+    while !branches.is_empty() {
+        let Ok(val) = source.recv() else {
+            return;
+        };
+        branches.retain(|branch| branch.is_consumer_alive());
+        // the last one gets the message itself instead of a clone
+        if let Some((last, rest)) = branches.split_last_mut() {
+            for branch in rest {
+                send(branch, val.clone(), lag);
+            }
+            send(last, val, lag);
+        }
+    }
+}
+
+// A branch that is gone by now is removed with the next message
+fn send<T: Send>(branch: &mut Producer<T>, val: T, lag: Lag) {
+    let _ = match lag {
+        Lag::Wait => branch.send(val).map_err(drop),
+        Lag::Skip => branch.try_send(val).map_err(drop),
+        Lag::Overwrite => branch.send_overwrite(val).map_err(drop),
+    };
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_branch_gets_every_message() {
+        let (mut px, cx) = channel_with_capacity(4);
+        let branches = tee(cx, 3, Lag::Wait);
+        let handles: Vec<_> = branches
+            .into_iter()
+            .map(|branch| thread::spawn(move || branch.collect::<Vec<String>>()))
+            .collect();
+        for i in 0..100 {
+            px.send(i.to_string()).unwrap();
+        }
+        drop(px);
+
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn lagging_branch_skips_or_overwrites() {
+        for (lag, expected) in [(Lag::Skip, 0..4), (Lag::Overwrite, 96..100)] {
+            let (mut px, cx) = channel_with_capacity(4);
+            let mut branches = tee(cx, 2, lag);
+            let lagging = branches.pop().unwrap();
+            let reading = branches.pop().unwrap();
+            for i in 0..100 {
+                px.send(i).unwrap();
+            }
+            drop(px);
+
+            // Once the reading branch ends, the tee thread is done. It may
+            // have left messages out for this one too, but kept their order.
+            let received: Vec<i32> = reading.collect();
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(lagging.collect::<Vec<_>>(), expected.collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn dropped_branch_does_not_block_the_others() {
+        let (mut px, cx) = channel_with_capacity(2);
+        let mut branches = tee(cx, 2, Lag::Wait);
+        drop(branches.pop());
+        let branch = branches.pop().unwrap();
+        let handle = thread::spawn(move || {
+            for i in 0..10 {
+                px.send(i).unwrap();
+            }
+        });
+        assert_eq!(branch.collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        handle.join().unwrap();
+    }
+}
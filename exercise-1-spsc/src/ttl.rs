@@ -0,0 +1,225 @@
+//! A channel whose messages can expire: a message sent with a time to live
+//! that has run out by the time the consumer gets to it is dropped instead
+//! of received, e.g. a telemetry sample that is of no use anymore after a
+//! stall of the consumer.
+//!
+//! Each message carries its deadline through the buffer. The receives skip
+//! the expired ones as they come across them and count them, see
+//! `Consumer::expired`. An expired message still takes up its slot until
+//! then.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+    BUFFER_SIZE,
+};
+
+// A message and when it expires, if ever
+struct Stamped<T> {
+    val: T,
+    deadline: Option<Instant>,
+}
+
+impl<T> Stamped<T> {
+    // Only reads the clock for a message that can expire
+    fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+pub struct Producer<T> {
+    inner: crate::Producer<Stamped<T>>,
+}
+
+pub struct Consumer<T> {
+    inner: crate::Consumer<Stamped<T>>,
+    expired: usize,
+}
+
+/// Creates a channel that buffers up to 4096 messages.
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    channel_with_capacity(BUFFER_SIZE)
+}
+
+/// Like `channel`, with a buffer of `capacity` messages.
+///
+/// Panics if `capacity` is 0 or not a power of two.
+pub fn channel_with_capacity<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let (px, cx) = crate::channel_with_capacity(capacity);
+    (
+        Producer { inner: px },
+        Consumer {
+            inner: cx,
+            expired: 0,
+        },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Sends `val` without a time to live, it never expires.
+    pub fn send(&mut self, val: T) -> Result<(), SendError<T>> {
+        self.send_until(val, None)
+    }
+
+    /// Sends `val` to be dropped instead of received if the consumer gets to
+    /// it only after `ttl`. The time spent waiting for a free slot counts.
+    pub fn send_with_ttl(&mut self, val: T, ttl: Duration) -> Result<(), SendError<T>> {
+        self.send_until(val, Instant::now().checked_add(ttl))
+    }
+
+    pub fn try_send(&mut self, val: T) -> Result<(), TrySendError<T>> {
+        self.try_send_until(val, None)
+    }
+
+    pub fn try_send_with_ttl(&mut self, val: T, ttl: Duration) -> Result<(), TrySendError<T>> {
+        self.try_send_until(val, Instant::now().checked_add(ttl))
+    }
+
+    /// Like `send_with_ttl`, but waits at most for `timeout` for a free
+    /// slot.
+    pub fn send_timeout_with_ttl(
+        &mut self,
+        val: T,
+        ttl: Duration,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let deadline = Instant::now().checked_add(ttl);
+        self.inner
+            .send_timeout(Stamped { val, deadline }, timeout)
+            .map_err(|err| match err {
+                SendTimeoutError::Timeout(msg) => SendTimeoutError::Timeout(msg.val),
+                SendTimeoutError::Disconnected(msg) => SendTimeoutError::Disconnected(msg.val),
+            })
+    }
+
+    fn send_until(&mut self, val: T, deadline: Option<Instant>) -> Result<(), SendError<T>> {
+        self.inner
+            .send(Stamped { val, deadline })
+            .map_err(|SendError(msg)| SendError(msg.val))
+    }
+
+    fn try_send_until(&mut self, val: T, deadline: Option<Instant>) -> Result<(), TrySendError<T>> {
+        self.inner
+            .try_send(Stamped { val, deadline })
+            .map_err(|err| match err {
+                TrySendError::Full(msg) => TrySendError::Full(msg.val),
+                TrySendError::Disconnected(msg) => TrySendError::Disconnected(msg.val),
+            })
+    }
+
+    /// See `crate::Producer::is_disconnected`.
+    pub fn is_disconnected(&self) -> bool {
+        self.inner.is_disconnected()
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Waits for a message that has not expired, dropping the expired ones
+    /// on the way.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            let msg = self.inner.recv()?;
+            if let Some(val) = self.unexpired(msg) {
+                return Ok(val);
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        loop {
+            let msg = self.inner.try_recv()?;
+            if let Some(val) = self.unexpired(msg) {
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Like `recv`, but waits at most for `timeout`, however many expired
+    /// messages arrive meanwhile.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return Ok(self.recv()?);
+        };
+        loop {
+            let msg = self.inner.recv_deadline(deadline)?;
+            if let Some(val) = self.unexpired(msg) {
+                return Ok(val);
+            }
+        }
+    }
+
+    // Counts and drops an expired message
+    fn unexpired(&mut self, msg: Stamped<T>) -> Option<T> {
+        if msg.is_expired() {
+            self.expired += 1;
+            return None;
+        }
+        Some(msg.val)
+    }
+
+    /// Returns how many expired messages the receives dropped so far.
+    pub fn expired(&self) -> usize {
+        self.expired
+    }
+
+    /// Returns how many messages are queued right now, expired ones
+    /// included.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn expired_messages_are_skipped() {
+        let (mut px, mut cx) = channel_with_capacity(8);
+        px.send_with_ttl(0, Duration::ZERO).unwrap();
+        px.send(1).unwrap();
+        px.send_with_ttl(2, Duration::from_millis(10)).unwrap();
+        px.send_with_ttl(3, Duration::from_secs(60)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(cx.recv(), Ok(1));
+        assert_eq!(cx.expired(), 1);
+        assert_eq!(cx.try_recv(), Ok(3));
+        assert_eq!(cx.expired(), 2);
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn recv_timeout_skips_to_the_timeout() {
+        let token = std::sync::Arc::new(());
+        let (mut px, mut cx) = channel_with_capacity(4);
+        px.send_with_ttl(token.clone(), Duration::ZERO).unwrap();
+        assert_eq!(
+            cx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        // dropped, not just left in the buffer
+        assert_eq!(std::sync::Arc::strong_count(&token), 1);
+
+        px.try_send_with_ttl(token.clone(), Duration::ZERO).unwrap();
+        drop(px);
+        assert_eq!(cx.recv(), Err(RecvError));
+        assert_eq!(cx.expired(), 2);
+    }
+}
@@ -0,0 +1,307 @@
+//! A channel without a bound on its buffer, for producers that must never
+//! wait for the consumer.
+//!
+//! The messages go into fixed-size segments that are chained into a list.
+//! The producer fills the last segment and appends a new one when it is
+//! full; the consumer empties the first one and frees it once it has moved
+//! on to the next. Each slot has a flag that says whether it holds a
+//! message, so the only thing the two ends share is the slot at the boundary
+//! between them, and the link to the next segment.
+
+use std::cell::Cell;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use crate::primitives::{Arc, AtomicBool, AtomicPtr, Ordering, UnsafeCell};
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError, TryRecvError};
+
+// Small under loom, so the model gets to the end of a segment
+#[cfg(not(loom))]
+const SEGMENT_SIZE: usize = 32;
+#[cfg(loom)]
+const SEGMENT_SIZE: usize = 2;
+
+struct Slot<T> {
+    // set by the producer once the message is in, cleared by the consumer
+    // once it is out again
+    ready: AtomicBool,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Segment<T> {
+    slots: [Slot<T>; SEGMENT_SIZE],
+    // null until the producer has filled this segment
+    next: AtomicPtr<Segment<T>>,
+}
+
+impl<T> Segment<T> {
+    fn alloc() -> *mut Segment<T> {
+        Box::into_raw(Box::new(Segment {
+            slots: std::array::from_fn(|_| Slot {
+                ready: AtomicBool::new(false),
+                val: UnsafeCell::new(MaybeUninit::uninit()),
+            }),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+}
+
+struct Shared<T> {
+    // The consumer's segment, the start of the list. Only kept here so the
+    // last end can free the list, the consumer works with its own copy.
+    head: AtomicPtr<Segment<T>>,
+    producer_alive: AtomicBool,
+    consumer_alive: AtomicBool,
+    // the consumer, waiting for a message or the producer to go away
+    consumers: WaitQueue,
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Both ends are gone, the whole list is ours
+        let mut segment = self.head.load(Ordering::Relaxed);
+        while !segment.is_null() {
+            let boxed = unsafe { Box::from_raw(segment) };
+            for slot in &boxed.slots {
+                if slot.ready.load(Ordering::Relaxed) {
+                    slot.val
+                        .with_mut(|val| unsafe { (*val).assume_init_drop() });
+                }
+            }
+            segment = boxed.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    // the last segment and the next slot in it to fill
+    tail: Cell<*mut Segment<T>>,
+    offset: Cell<usize>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    // the first segment and the next slot in it to empty
+    head: Cell<*mut Segment<T>>,
+    offset: Cell<usize>,
+}
+
+pub fn channel<T: Send>() -> (Producer<T>, Consumer<T>) {
+    let segment = Segment::alloc();
+    let shared = Arc::new(Shared {
+        head: AtomicPtr::new(segment),
+        producer_alive: AtomicBool::new(true),
+        consumer_alive: AtomicBool::new(true),
+        consumers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+            tail: Cell::new(segment),
+            offset: Cell::new(0),
+        },
+        Consumer {
+            shared,
+            head: Cell::new(segment),
+            offset: Cell::new(0),
+        },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Sends `val` without waiting, however far behind the consumer is.
+    /// Fails only if the consumer is gone.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        if !shared.consumer_alive.load(Ordering::Acquire) {
+            return Err(SendError(val));
+        }
+        if self.offset.get() == SEGMENT_SIZE {
+            let next = Segment::alloc();
+            // The consumer only follows the link once it has emptied the
+            // segment, and we do not touch the segment after linking it
+            unsafe { (*self.tail.get()).next.store(next, Ordering::Release) };
+            self.tail.set(next);
+            self.offset.set(0);
+        }
+        // The consumer leaves the slot alone until it is ready
+        let slot = unsafe { &(*self.tail.get()).slots[self.offset.get()] };
+        slot.val.with_mut(|slot| unsafe { (*slot).write(val) });
+        slot.ready.store(true, Ordering::Release);
+        self.offset.set(self.offset.get() + 1);
+        shared.consumers.notify();
+        Ok(())
+    }
+
+    /// Returns whether the consumer still exists. This is only a snapshot.
+    pub fn is_consumer_alive(&self) -> bool {
+        self.shared.consumer_alive.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        // Before the slots: once the producer is gone, all its messages are
+        // in
+        let producer_alive = self.shared.producer_alive.load(Ordering::Acquire);
+        let nothing = || {
+            Err(if producer_alive {
+                TryRecvError::Empty
+            } else {
+                TryRecvError::Disconnected
+            })
+        };
+        if self.offset.get() == SEGMENT_SIZE {
+            let head = self.head.get();
+            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            if next.is_null() {
+                return nothing();
+            }
+            // Every slot of head is empty, and the producer has moved on
+            self.shared.head.store(next, Ordering::Relaxed);
+            drop(unsafe { Box::from_raw(head) });
+            self.head.set(next);
+            self.offset.set(0);
+        }
+        let slot = unsafe { &(*self.head.get()).slots[self.offset.get()] };
+        if !slot.ready.load(Ordering::Acquire) {
+            return nothing();
+        }
+        let val = slot
+            .val
+            .with_mut(|slot| unsafe { (*slot).assume_init_read() });
+        slot.ready.store(false, Ordering::Relaxed);
+        self.offset.set(self.offset.get() + 1);
+        Ok(val)
+    }
+
+    /// Waits for a message, fails once the producer is gone and all its
+    /// messages are received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {
+                    self.shared.consumers.wait(None, || self.message_ready())
+                }
+            }
+        }
+    }
+
+    // Whether a receive may succeed now, without moving on to the next
+    // segment
+    fn message_ready(&self) -> bool {
+        let head = unsafe { &*self.head.get() };
+        let ready = match head.slots.get(self.offset.get()) {
+            Some(slot) => slot.ready.load(Ordering::Acquire),
+            None => !head.next.load(Ordering::Acquire).is_null(),
+        };
+        ready || !self.shared.producer_alive.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the producer still exists. This is only a snapshot.
+    pub fn is_producer_alive(&self) -> bool {
+        self.shared.producer_alive.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    // The messages still queued are dropped along with the list
+    fn drop(&mut self) {
+        self.shared.consumer_alive.store(false, Ordering::Release);
+    }
+}
+
+// Each end only touches its own segment and the slots as described above.
+// Neither is Sync, the cells hold the position of a single thread.
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn send_does_not_wait_for_consumer() {
+        let (px, cx) = channel();
+        // many segments' worth, with nobody receiving
+        for i in 0..10_000 {
+            px.send(i).unwrap();
+        }
+        drop(px);
+        for i in 0..10_000 {
+            assert_eq!(cx.try_recv(), Ok(i));
+        }
+        assert_eq!(cx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn messages_arrive_in_order_across_threads() {
+        let (px, cx) = channel();
+        let handle = thread::spawn(move || {
+            for i in 0..100_000 {
+                px.send(i).unwrap();
+            }
+        });
+        for i in 0..100_000 {
+            assert_eq!(cx.recv().unwrap(), i);
+        }
+        assert!(cx.recv().is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn queued_messages_are_dropped_with_channel() {
+        let tracker = std::sync::Arc::new(());
+        let (px, cx) = channel();
+        for _ in 0..SEGMENT_SIZE * 2 + 1 {
+            px.send(tracker.clone()).unwrap();
+        }
+        for _ in 0..SEGMENT_SIZE + 1 {
+            drop(cx.recv().unwrap());
+        }
+        drop(cx);
+        assert_eq!(px.send(tracker.clone()).unwrap_err().0, tracker);
+        drop(px);
+        assert_eq!(std::sync::Arc::strong_count(&tracker), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn consumer_follows_into_next_segment() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(2);
+        builder.check(|| {
+            let (px, cx) = channel();
+            let handle = thread::spawn(move || {
+                for i in 0..SEGMENT_SIZE + 1 {
+                    px.send(i).unwrap();
+                }
+            });
+            let mut received = Vec::new();
+            while let Ok(val) = cx.recv() {
+                received.push(val);
+            }
+            handle.join().unwrap();
+            assert_eq!(received, (0..SEGMENT_SIZE + 1).collect::<Vec<_>>());
+        });
+    }
+}
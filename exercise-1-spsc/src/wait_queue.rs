@@ -0,0 +1,361 @@
+// Parks the threads waiting for the other side of a channel, so a blocked
+// send or recv does not burn a core, and keeps the wakers of async calls
+// waiting for the same.
+//
+// The threads wait on an EventCount, see `sync`: a waiter registers before
+// checking its condition one last time, and a notifier looks for waiters
+// after publishing its change. The tasks follow the same protocol, behind
+// the same fence of notify: with a SeqCst fence between the two steps on
+// both sides, at least one of them sees the other. Either the waiter finds
+// the change, or the notifier finds the waiter and wakes it. Neither side
+// takes a lock, and a thread allocates nothing to wait.
+
+#[cfg(feature = "std")]
+use core::mem;
+#[cfg(feature = "std")]
+use std::task::Waker;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use crate::primitives::{const_fn, fence, spin_loop, AtomicUsize, Ordering, UnsafeCell};
+#[cfg(feature = "std")]
+use crate::sync::EventCount;
+use crate::wait_strategy::WaitStrategy;
+
+#[cfg(feature = "std")]
+pub(crate) struct WaitQueue {
+    // whether threads park on the queue. On a channel only WaitStrategy::Park
+    // does, under the others notify leaves the threads alone.
+    parks: bool,
+    threads: EventCount,
+    tasks: AtomicWaker,
+}
+
+// The wakers of the pending futures. register and notify take turns on the
+// cell without a lock, as in the AtomicWaker of futures: REGISTERING or
+// WAKING marks it taken, and whoever finds it taken leaves the work to the
+// one inside. REGISTERED says that it holds a waker, so notify can skip it.
+#[cfg(feature = "std")]
+struct AtomicWaker {
+    state: AtomicUsize,
+    wakers: UnsafeCell<Wakers>,
+}
+
+#[cfg(feature = "std")]
+const REGISTERING: usize = 0b001;
+#[cfg(feature = "std")]
+const WAKING: usize = 0b010;
+#[cfg(feature = "std")]
+const REGISTERED: usize = 0b100;
+
+// Most queues have one task on the other end, which a new waker replaces: a
+// future polled from another task, or one dropped before it was woken. Only
+// the shared ones (the producers of channel_mpsc, a CancelToken) keep the
+// others as well. The futures of a CancelToken may never see a notify, so
+// each has a key of its own: a new waker replaces the one under its key,
+// and the future takes it out when dropped.
+#[cfg(feature = "std")]
+struct Wakers {
+    first: Option<Waker>,
+    others: Vec<(Option<usize>, Waker)>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Copy)]
+enum Registration {
+    Single,
+    Shared,
+    Keyed(usize),
+}
+
+#[cfg(feature = "std")]
+impl WaitQueue {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self::with_strategy(WaitStrategy::Park)
+        }
+    }
+
+    const_fn! {
+        pub(crate) fn with_strategy(wait_strategy: WaitStrategy) -> Self {
+            WaitQueue {
+                parks: matches!(wait_strategy, WaitStrategy::Park),
+                threads: EventCount::new(),
+                tasks: AtomicWaker::new(),
+            }
+        }
+    }
+
+    // Parks the current thread unless is_ready, until a notify or the
+    // deadline. May return early, the caller has to check again either way.
+    pub(crate) fn wait(&self, deadline: Option<Instant>, is_ready: impl FnOnce() -> bool) {
+        Self::wait_any([self], deadline, is_ready);
+    }
+
+    // Like wait, but a notify on any of queues wakes the thread, for Select
+    pub(crate) fn wait_any<'q>(
+        queues: impl IntoIterator<Item = &'q WaitQueue>,
+        deadline: Option<Instant>,
+        is_ready: impl FnOnce() -> bool,
+    ) {
+        Self::wait_registered(queues.into_iter(), &|| false, deadline, is_ready);
+    }
+
+    // Registers with the queues one at a time, each key in a frame of its
+    // own rather than in a Vec, and parks once it is registered with all
+    fn wait_registered<'q>(
+        mut queues: impl Iterator<Item = &'q WaitQueue>,
+        notified: &dyn Fn() -> bool,
+        deadline: Option<Instant>,
+        is_ready: impl FnOnce() -> bool,
+    ) {
+        match queues.next() {
+            Some(queue) => {
+                debug_assert!(queue.parks, "nobody wakes a thread on this queue");
+                let key = queue.threads.prepare_wait();
+                let notified = || key.is_notified() || notified();
+                Self::wait_registered(queues, &notified, deadline, is_ready);
+            }
+            None => {
+                if !is_ready() {
+                    EventCount::park_until(deadline, notified);
+                }
+            }
+        }
+    }
+
+    // The async counterpart of wait: registers waker for the next notify.
+    // The caller checks its condition again afterwards, and returns Pending
+    // only if it still is not ready. Replaces the waker of any other task.
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.tasks.register(waker, Registration::Single);
+        fence(Ordering::SeqCst);
+    }
+
+    // Like register, for a queue several tasks may wait on at once
+    pub(crate) fn register_shared(&self, waker: &Waker) {
+        self.tasks.register(waker, Registration::Shared);
+        fence(Ordering::SeqCst);
+    }
+
+    // Like register_shared, replacing the waker registered under key before.
+    // The keys are up to the caller, one for each future.
+    pub(crate) fn register_keyed(&self, key: usize, waker: &Waker) {
+        self.tasks.register(waker, Registration::Keyed(key));
+        fence(Ordering::SeqCst);
+    }
+
+    // Takes out the waker registered under key, if a notify did not already
+    pub(crate) fn deregister(&self, key: usize) {
+        self.tasks.deregister(key);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_waiters(&self) -> bool {
+        self.threads.has_waiters() || self.tasks.state.load(Ordering::SeqCst) != 0
+    }
+
+    // Wakes all waiting threads and tasks, to be called after publishing a
+    // change they may be waiting for
+    pub(crate) fn notify(&self) {
+        // The tasks wait whatever the strategy, so the fence stays
+        fence(Ordering::SeqCst);
+        if self.parks {
+            self.threads.wake_all();
+        }
+        self.tasks.wake();
+    }
+}
+
+#[cfg(feature = "std")]
+impl AtomicWaker {
+    const_fn! {
+        fn new() -> Self {
+            AtomicWaker {
+                state: AtomicUsize::new(0),
+                wakers: UnsafeCell::new(Wakers {
+                    first: None,
+                    others: Vec::new(),
+                }),
+            }
+        }
+    }
+
+    fn register(&self, waker: &Waker, registration: Registration) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & (REGISTERING | WAKING) != 0 {
+                // A notify is taking the wakers out, or another task of a
+                // shared queue registers: poll again rather than wait for it
+                waker.wake_by_ref();
+                spin_loop();
+                return;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state | REGISTERING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        self.wakers
+            .with_mut(|wakers| unsafe { (*wakers).insert(waker, registration) });
+        // Release, for the notify that takes the wakers out
+        if self
+            .state
+            .compare_exchange(
+                state | REGISTERING,
+                REGISTERED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // A notify came in meanwhile and left the waking to us, ours
+            // included
+            let wakers = self.take();
+            self.state.swap(0, Ordering::AcqRel);
+            wakers.wake();
+        }
+    }
+
+    // Unlike register, waits for the cell when it is taken: a dropped
+    // future can not come back later. Whoever holds it is quick about it.
+    fn deregister(&self, key: usize) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & (REGISTERING | WAKING) != 0 {
+                spin_loop();
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+            if state == 0 {
+                // nothing registered, a notify took the waker
+                return;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state | REGISTERING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        let empty = self.wakers.with_mut(|wakers| unsafe {
+            (*wakers).remove(key);
+            (*wakers).is_empty()
+        });
+        let next = if empty { 0 } else { REGISTERED };
+        if self
+            .state
+            .compare_exchange(
+                state | REGISTERING,
+                next,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // A notify came in meanwhile and left the waking to us
+            let wakers = self.take();
+            self.state.swap(0, Ordering::AcqRel);
+            wakers.wake();
+        }
+    }
+
+    // To be called after the fence of notify, which orders the look at the
+    // state after the change the tasks wait for
+    fn wake(&self) {
+        if self.state.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        // Unless a task registers, and wakes them on its way out, or another
+        // notify takes them already
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) & (REGISTERING | WAKING) != 0 {
+            return;
+        }
+        let wakers = self.take();
+        self.state.store(0, Ordering::Release);
+        // Outside of the cell, a waker may run its task right away
+        wakers.wake();
+    }
+
+    // Empties the cell, which the caller holds
+    fn take(&self) -> Wakers {
+        self.wakers.with_mut(|wakers| unsafe {
+            Wakers {
+                first: (*wakers).first.take(),
+                others: mem::take(&mut (*wakers).others),
+            }
+        })
+    }
+}
+
+// Handed from register to notify through the state
+#[cfg(feature = "std")]
+unsafe impl Send for AtomicWaker {}
+#[cfg(feature = "std")]
+unsafe impl Sync for AtomicWaker {}
+
+#[cfg(feature = "std")]
+impl Wakers {
+    fn insert(&mut self, waker: &Waker, registration: Registration) {
+        if let Registration::Keyed(key) = registration {
+            match self
+                .others
+                .iter_mut()
+                .find(|(other, _)| *other == Some(key))
+            {
+                Some((_, other)) if other.will_wake(waker) => {}
+                Some((_, other)) => *other = waker.clone(),
+                None => self.others.push((Some(key), waker.clone())),
+            }
+            return;
+        }
+        match &self.first {
+            // a future polled again before the notify
+            Some(first) if first.will_wake(waker) => {}
+            Some(_) if matches!(registration, Registration::Shared) => {
+                if !self.others.iter().any(|(_, other)| other.will_wake(waker)) {
+                    self.others.push((None, waker.clone()));
+                }
+            }
+            _ => self.first = Some(waker.clone()),
+        }
+    }
+
+    fn remove(&mut self, key: usize) {
+        self.others.retain(|(other, _)| *other != Some(key));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first.is_none() && self.others.is_empty()
+    }
+
+    fn wake(self) {
+        self.first
+            .into_iter()
+            .chain(self.others.into_iter().map(|(_, waker)| waker))
+            .for_each(Waker::wake);
+    }
+}
+
+// Without std nothing can wait: there is no thread to park, and the channel
+// only has the calls that never block. The ring notifies all the same.
+#[cfg(not(feature = "std"))]
+pub(crate) struct WaitQueue;
+
+#[cfg(not(feature = "std"))]
+impl WaitQueue {
+    pub(crate) const fn with_strategy(_wait_strategy: WaitStrategy) -> Self {
+        WaitQueue
+    }
+
+    pub(crate) fn notify(&self) {}
+}
@@ -0,0 +1,92 @@
+// How a blocked call waits for the other side of the channel, picked per
+// channel at construction.
+
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use crate::primitives::{sleep, spin_loop, yield_now};
+#[cfg(feature = "std")]
+use crate::wait_queue::WaitQueue;
+
+/// How `send` and `recv` (and the other blocking calls) wait while the
+/// buffer is full or empty. Without the `std` feature there are no blocking
+/// calls, and the strategy makes no difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WaitStrategy {
+    /// Spins with `spin_loop` hints. Reacts the fastest, but keeps a core
+    /// busy for as long as it waits.
+    BusySpin,
+    /// Spins for a short while, then yields to the scheduler between checks.
+    Yield,
+    /// Spins for exponentially growing stretches, then sleeps for
+    /// exponentially growing durations of up to a millisecond.
+    Backoff,
+    /// Parks the thread until the other side wakes it up. Costs the other
+    /// side a check for sleepers on every operation, but nothing while idle.
+    #[default]
+    Park,
+}
+
+// Yield spins this many times before it starts yielding
+#[cfg(feature = "std")]
+const YIELD_AFTER: u32 = 64;
+// Backoff spins 2^step times up to this step, then sleeps
+#[cfg(feature = "std")]
+const BACKOFF_SPIN_STEPS: u32 = 6;
+#[cfg(feature = "std")]
+const BACKOFF_MAX_SLEEP: Duration = Duration::from_millis(1);
+
+// The progress of a single blocked call through its strategy
+#[cfg(feature = "std")]
+pub(crate) struct Waiter {
+    strategy: WaitStrategy,
+    step: u32,
+}
+
+#[cfg(feature = "std")]
+impl Waiter {
+    pub(crate) fn new(strategy: WaitStrategy) -> Self {
+        Waiter { strategy, step: 0 }
+    }
+
+    // Waits a little before the caller checks again. Only Park consults
+    // is_ready, and relies on the other side to notify queue.
+    pub(crate) fn wait(
+        &mut self,
+        queue: &WaitQueue,
+        deadline: Option<Instant>,
+        is_ready: impl FnOnce() -> bool,
+    ) {
+        self.wait_any([queue], deadline, is_ready);
+    }
+
+    // Like wait, but Park wakes up on a notify of any of queues
+    pub(crate) fn wait_any<'q>(
+        &mut self,
+        queues: impl IntoIterator<Item = &'q WaitQueue>,
+        deadline: Option<Instant>,
+        is_ready: impl FnOnce() -> bool,
+    ) {
+        match self.strategy {
+            WaitStrategy::BusySpin => spin_loop(),
+            WaitStrategy::Yield if self.step < YIELD_AFTER => spin_loop(),
+            WaitStrategy::Yield => yield_now(),
+            WaitStrategy::Backoff if self.step <= BACKOFF_SPIN_STEPS => {
+                for _ in 0..1 << self.step {
+                    spin_loop();
+                }
+            }
+            WaitStrategy::Backoff => {
+                let shift = (self.step - BACKOFF_SPIN_STEPS).min(10);
+                let mut duration = Duration::from_micros(1 << shift).min(BACKOFF_MAX_SLEEP);
+                if let Some(deadline) = deadline {
+                    duration = duration.min(deadline.saturating_duration_since(Instant::now()));
+                }
+                sleep(duration);
+            }
+            WaitStrategy::Park => WaitQueue::wait_any(queues, deadline, is_ready),
+        }
+        self.step = self.step.saturating_add(1);
+    }
+}
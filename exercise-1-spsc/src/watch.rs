@@ -0,0 +1,201 @@
+//! A channel that only keeps the latest value.
+//!
+//! The producer overwrites a single shared value, and every consumer reads
+//! whatever is there at the time. A version counter, bumped with each send,
+//! tells a consumer whether the value changed since it last looked.
+
+use std::sync::{RwLock, RwLockReadGuard};
+
+use crate::primitives::{Arc, AtomicBool, AtomicUsize, Ordering};
+use crate::wait_queue::WaitQueue;
+use crate::{RecvError, SendError};
+
+struct Shared<T> {
+    value: RwLock<T>,
+    // bumped under the write lock, so it always matches the value
+    version: AtomicUsize,
+    producer_alive: AtomicBool,
+    consumer_counter: AtomicUsize,
+    // consumers waiting for a new version or the producer to go away
+    consumers: WaitQueue,
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    // the version of the value this consumer saw last
+    seen: usize,
+}
+
+/// Creates a watch channel that starts out with `initial`, which counts as
+/// seen.
+pub fn channel<T>(initial: T) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        value: RwLock::new(initial),
+        version: AtomicUsize::new(0),
+        producer_alive: AtomicBool::new(true),
+        consumer_counter: AtomicUsize::new(1),
+        consumers: WaitQueue::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared, seen: 0 },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Replaces the value, fails if there is no consumer.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        if shared.consumer_counter.load(Ordering::Acquire) == 0 {
+            return Err(SendError(val));
+        }
+        let old = {
+            let mut value = shared.value.write().unwrap();
+            shared.version.fetch_add(1, Ordering::Release);
+            std::mem::replace(&mut *value, val)
+        };
+        shared.consumers.notify();
+        // outside of the lock, T::drop may be slow
+        drop(old);
+        Ok(())
+    }
+
+    /// Returns a new consumer, to which the current value counts as seen.
+    pub fn subscribe(&self) -> Consumer<T> {
+        let shared = &self.shared;
+        shared.consumer_counter.fetch_add(1, Ordering::Relaxed);
+        Consumer {
+            shared: shared.clone(),
+            seen: shared.version.load(Ordering::Acquire),
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.producer_alive.store(false, Ordering::Release);
+        self.shared.consumers.notify();
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Borrows the current value without marking it as seen. The producer
+    /// waits for the borrow to end before it can send, so keep it short.
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.shared.value.read().unwrap()
+    }
+
+    /// Returns whether a value was sent since this consumer last marked one
+    /// as seen.
+    pub fn has_changed(&self) -> bool {
+        self.shared.version.load(Ordering::Acquire) != self.seen
+    }
+
+    /// Returns the version of the current value, which counts the sends.
+    pub fn version(&self) -> usize {
+        self.shared.version.load(Ordering::Acquire)
+    }
+
+    /// Waits until a value is sent that this consumer has not seen yet. Fails
+    /// once the producer is gone and there is none.
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        let shared = &*self.shared;
+        loop {
+            // Before the version: once the producer is gone, the version we
+            // load is its last one
+            let producer_alive = shared.producer_alive.load(Ordering::Acquire);
+            if self.has_changed() {
+                return Ok(());
+            }
+            if !producer_alive {
+                return Err(RecvError);
+            }
+            let seen = self.seen;
+            shared.consumers.wait(None, || {
+                shared.version.load(Ordering::Acquire) != seen
+                    || !shared.producer_alive.load(Ordering::Acquire)
+            });
+        }
+    }
+}
+
+impl<T: Clone> Consumer<T> {
+    /// Returns a clone of the current value and marks it as seen.
+    pub fn get(&mut self) -> T {
+        let value = self.shared.value.read().unwrap();
+        self.seen = self.shared.version.load(Ordering::Acquire);
+        value.clone()
+    }
+}
+
+impl<T> Clone for Consumer<T> {
+    fn clone(&self) -> Self {
+        self.shared.consumer_counter.fetch_add(1, Ordering::Relaxed);
+        Consumer {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.consumer_counter.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn consumer_sees_latest_value() {
+        let (px, mut cx) = channel(0);
+        assert!(!cx.has_changed());
+        px.send(1).unwrap();
+        px.send(2).unwrap();
+        assert!(cx.has_changed());
+        assert_eq!(*cx.borrow(), 2);
+        assert!(cx.has_changed());
+        assert_eq!(cx.get(), 2);
+        assert!(!cx.has_changed());
+        assert_eq!(cx.version(), 2);
+    }
+
+    #[test]
+    fn changed_waits_for_new_version() {
+        let (px, mut cx) = channel(0);
+        let handle = thread::spawn(move || {
+            for i in 1..=100 {
+                px.send(i).unwrap();
+            }
+        });
+        let mut last = 0;
+        while cx.changed().is_ok() {
+            let value = cx.get();
+            assert!(value > last);
+            last = value;
+        }
+        assert_eq!(last, 100);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn subscriber_starts_with_current_value_seen() {
+        let (px, cx) = channel("a");
+        px.send("b").unwrap();
+        let mut late = px.subscribe();
+        assert!(!late.has_changed());
+        assert_eq!(late.get(), "b");
+        drop((cx, late));
+        assert!(px.send("c").is_err());
+    }
+}